@@ -0,0 +1,101 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+// Compares the queue locks in portable-atomic-util against the spin mutexes under contention.
+
+#![warn(rust_2018_idioms, single_use_lifetimes, unsafe_op_in_unsafe_fn)]
+
+use std::{hint::black_box, sync::Barrier, thread};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use portable_atomic_util::{ClhLock, McsLock, SpinMutex, TicketMutex};
+
+const N: u32 = 5000;
+
+trait Lock: Sync {
+    fn new(v: u64) -> Self;
+    fn with(&self, f: impl FnOnce(&mut u64));
+}
+impl Lock for SpinMutex<u64> {
+    fn new(v: u64) -> Self {
+        Self::new(v)
+    }
+    #[inline]
+    fn with(&self, f: impl FnOnce(&mut u64)) {
+        f(&mut self.lock());
+    }
+}
+impl Lock for TicketMutex<u64> {
+    fn new(v: u64) -> Self {
+        Self::new(v)
+    }
+    #[inline]
+    fn with(&self, f: impl FnOnce(&mut u64)) {
+        f(&mut self.lock());
+    }
+}
+impl Lock for McsLock<u64> {
+    fn new(v: u64) -> Self {
+        Self::new(v)
+    }
+    #[inline]
+    fn with(&self, f: impl FnOnce(&mut u64)) {
+        self.with_lock(f);
+    }
+}
+impl Lock for ClhLock<u64> {
+    fn new(v: u64) -> Self {
+        Self::new(v)
+    }
+    #[inline]
+    fn with(&self, f: impl FnOnce(&mut u64)) {
+        self.with_lock(f);
+    }
+}
+
+fn bench_uncontended<L: Lock>() -> L {
+    let l = black_box(L::new(0));
+    for _ in 0..N {
+        l.with(|x| *x += 1);
+    }
+    l
+}
+fn bench_contended<L: Lock>(threads: usize) -> L {
+    let l = black_box(L::new(0));
+    let barrier = Barrier::new(threads);
+    thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| {
+                barrier.wait();
+                for _ in 0..N {
+                    l.with(|x| *x = black_box(*x + 1));
+                }
+            });
+        }
+    });
+    l
+}
+
+macro_rules! benches {
+    ($name:ident, $lock_type:ty) => {
+        fn $name(c: &mut Criterion) {
+            type L = $lock_type;
+            let mut g = c.benchmark_group(stringify!($name));
+            g.bench_function("uncontended", |b| {
+                b.iter(bench_uncontended::<L>);
+            });
+            for &threads in &[2, 4, 8] {
+                g.bench_function(&format!("contended_{}", threads), |b| {
+                    b.iter(|| bench_contended::<L>(threads));
+                });
+            }
+        }
+    };
+}
+
+benches!(bench_spin_mutex, SpinMutex<u64>);
+benches!(bench_ticket_mutex, TicketMutex<u64>);
+benches!(bench_mcs_lock, McsLock<u64>);
+benches!(bench_clh_lock, ClhLock<u64>);
+
+criterion_group!(benches, bench_spin_mutex, bench_ticket_mutex, bench_mcs_lock, bench_clh_lock);
+criterion_main!(benches);
@@ -36,10 +36,30 @@ fn main() {
     if !version.probe(36, 2019, 4, 14) {
         println!("cargo:rustc-cfg=portable_atomic_no_alloc");
     }
+    // MaybeUninit stabilized in Rust 1.36 (nightly-2019-05-21) https://github.com/rust-lang/rust/pull/60445
+    if !version.probe(36, 2019, 5, 20) {
+        println!("cargo:rustc-cfg=portable_atomic_no_maybe_uninit");
+    }
+    // futures_api stabilized in Rust 1.36 (nightly-2019-04-25) https://github.com/rust-lang/rust/pull/59739
+    if !version.probe(36, 2019, 4, 24) {
+        println!("cargo:rustc-cfg=portable_atomic_no_futures_api");
+    }
+    // min_const_generics stabilized in Rust 1.51 (nightly-2020-12-28): https://github.com/rust-lang/rust/pull/79135
+    if !version.probe(51, 2020, 12, 27) {
+        println!("cargo:rustc-cfg=portable_atomic_no_min_const_generics");
+    }
     // unsafe_op_in_unsafe_fn stabilized in Rust 1.52 (nightly-2021-03-11): https://github.com/rust-lang/rust/pull/79208
     if !version.probe(52, 2021, 3, 10) {
         println!("cargo:rustc-cfg=portable_atomic_no_unsafe_op_in_unsafe_fn");
     }
+    // asm stabilized in Rust 1.59 (nightly-2021-12-16): https://github.com/rust-lang/rust/pull/91728
+    if !version.probe(59, 2021, 12, 15) {
+        println!("cargo:rustc-cfg=portable_atomic_no_asm");
+    }
+    // cfg(target_has_atomic) stabilized in Rust 1.60 (nightly-2022-02-11): https://github.com/rust-lang/rust/pull/93824
+    if !version.probe(60, 2022, 2, 10) {
+        println!("cargo:rustc-cfg=portable_atomic_no_cfg_target_has_atomic");
+    }
 
     if version.nightly {
         // `cfg(sanitize = "..")` is not stabilized.
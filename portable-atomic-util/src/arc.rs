@@ -9,35 +9,20 @@ use portable_atomic::{
 
 use alloc::boxed::Box;
 
+use crate::utils::{abort, strict, MAX_REFCOUNT};
+
 use core::{
-    borrow::Borrow,
+    borrow::{Borrow, BorrowMut},
     fmt,
     hash::Hash,
-    isize,
     marker::PhantomData,
     mem,
-    ops::Deref,
+    ops::{Deref, DerefMut},
     pin::Pin,
     ptr::{self, NonNull},
     usize,
 };
 
-const MAX_REFCOUNT: usize = (isize::MAX as usize) - 1;
-
-#[cfg(not(portable_atomic_sanitize_thread))]
-macro_rules! acquire {
-    ($x:expr) => {{
-        portable_atomic::fence(Acquire);
-    }};
-}
-
-#[cfg(portable_atomic_sanitize_thread)]
-macro_rules! acquire {
-    ($x:expr) => {{
-        ($x).load(Acquire);
-    }};
-}
-
 /// The inner heap allocation of an `Arc`.
 #[repr(C)]
 struct Shared<T: ?Sized> {
@@ -136,6 +121,59 @@ pub struct Weak<T: ?Sized> {
     shared: NonNull<Shared<T>>,
 }
 
+/// A uniquely owned [`Arc`].
+///
+/// This represents an `Arc` that is known to be uniquely owned -- that is, have exactly one strong
+/// reference. Multiple weak pointers can be created, but attempts to upgrade those to strong
+/// references will fail unless the `UniqueArc` they point to has been converted into a regular `Arc`.
+///
+/// Because they are uniquely owned, the contents of a `UniqueArc` can be freely mutated. A common
+/// use case is to have an object be mutable during its initialization phase but then have it become
+/// immutable and converted to a normal `Arc`.
+///
+/// The conversion into [`Arc`] reuses the same allocation, so it never reallocates.
+///
+/// This is an equivalent to [`std::sync::UniqueArc`], but using [`portable-atomic`] for synchronization.
+///
+/// [`std::sync::UniqueArc`]: https://doc.rust-lang.org/nightly/std/sync/struct.UniqueArc.html
+/// [`portable-atomic`]: https://crates.io/crates/portable-atomic
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::{Arc, UniqueArc};
+///
+/// let mut v = UniqueArc::new(vec![1, 2]);
+/// v.push(3);
+///
+/// let v: Arc<Vec<i32>> = UniqueArc::into_arc(v);
+/// assert_eq!(*v, [1, 2, 3]);
+/// ```
+///
+/// Creating a cyclic structure:
+///
+/// ```
+/// use portable_atomic_util::{Arc, UniqueArc, Weak};
+///
+/// struct Gadget {
+///     me: Weak<Gadget>,
+/// }
+///
+/// let mut gadget = UniqueArc::new(Gadget { me: Weak::new() });
+/// gadget.me = UniqueArc::downgrade(&gadget);
+/// let gadget = UniqueArc::into_arc(gadget);
+///
+/// assert!(Arc::ptr_eq(&gadget.me.upgrade().unwrap(), &gadget));
+/// ```
+pub struct UniqueArc<T: ?Sized> {
+    /// The inner heap allocation.
+    ///
+    /// The strong count is zero while the `UniqueArc` is alive, so weak pointers cannot be upgraded.
+    shared: NonNull<Shared<T>>,
+
+    _marker: PhantomData<Shared<T>>,
+}
+
 // SAFETY: This value is accessible from many threads, it has to be Sync.
 unsafe impl<T: ?Sized + Send + Sync> Send for Arc<T> {}
 // SAFETY: This value is accessible from many threads, it has to be Sync.
@@ -145,7 +183,14 @@ unsafe impl<T: ?Sized + Send + Sync> Send for Weak<T> {}
 // SAFETY: This value is accessible from many threads, it has to be Sync.
 unsafe impl<T: ?Sized + Send + Sync> Sync for Weak<T> {}
 
+// SAFETY: Weak pointers created from this value can be sent to other threads and upgraded after
+// it has been converted into an `Arc`, so this has the same requirements as `Arc`.
+unsafe impl<T: ?Sized + Send + Sync> Send for UniqueArc<T> {}
+// SAFETY: Sharing a `UniqueArc` only gives out `&T`, and the same requirements as `Arc` apply.
+unsafe impl<T: ?Sized + Send + Sync> Sync for UniqueArc<T> {}
+
 impl<T: ?Sized> Unpin for Arc<T> {}
+impl<T: ?Sized> Unpin for UniqueArc<T> {}
 
 impl<T: ?Sized + fmt::Debug> fmt::Debug for Arc<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -796,84 +841,274 @@ impl<T: ?Sized> Drop for Weak<T> {
     }
 }
 
-fn abort() -> ! {
-    struct Abort;
+impl<T: ?Sized + fmt::Debug> fmt::Debug for UniqueArc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
 
-    impl Drop for Abort {
-        fn drop(&mut self) {
-            panic!();
-        }
+impl<T: Default> Default for UniqueArc<T> {
+    fn default() -> Self {
+        Self::new(T::default())
     }
+}
 
-    let _abort = Abort;
+impl<T> UniqueArc<T> {
+    /// Create a new [`UniqueArc`].
+    ///
+    /// Weak references to this allocation can be created with [`UniqueArc::downgrade`].
+    /// Upgrading these weak references will fail before the `UniqueArc` has been converted into an
+    /// [`Arc`]. After converting the `UniqueArc` into an `Arc`, any weak references created beforehand
+    /// will point to the new `Arc`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::UniqueArc;
+    ///
+    /// let five = UniqueArc::new(5);
+    /// ```
+    pub fn new(item: T) -> UniqueArc<T> {
+        let shared = Box::into_raw(Box::new(Shared {
+            header: Header { strong: AtomicUsize::new(0), weak: AtomicUsize::new(1) },
+            value: item,
+        }));
 
-    panic!("abort")
-}
+        // SAFETY: The newly created allocation is valid.
+        unsafe { Self::from_inner(NonNull::new_unchecked(shared)) }
+    }
 
-fn is_dangling<T: ?Sized>(ptr: *mut T) -> bool {
-    ptr as *mut () as usize == usize::MAX
-}
+    /// Create a new [`UniqueArc`] with uninitialized contents.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::UniqueArc;
+    ///
+    /// let mut five = UniqueArc::<u32>::new_uninit();
+    /// *five = std::mem::MaybeUninit::new(5);
+    ///
+    /// // SAFETY: The value has been initialized.
+    /// let five = unsafe { five.assume_init() };
+    /// assert_eq!(*five, 5);
+    /// ```
+    #[cfg(not(portable_atomic_no_maybe_uninit))]
+    #[must_use]
+    pub fn new_uninit() -> UniqueArc<mem::MaybeUninit<T>> {
+        UniqueArc::new(mem::MaybeUninit::uninit())
+    }
 
-/// Emulate strict provenance.
-///
-/// Once strict_provenance is stable, migrate to the standard library's APIs.
-#[allow(
-    clippy::cast_possible_wrap,
-    clippy::transmutes_expressible_as_ptr_casts,
-    clippy::useless_transmute
-)]
-mod strict {
-    /// Create a new, invalid pointer from an address.
-    #[inline]
+    /// Unwrap the inner value.
+    ///
+    /// Any weak references created from this `UniqueArc` can no longer be upgraded.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::UniqueArc;
+    ///
+    /// let five = UniqueArc::new(5);
+    /// assert_eq!(UniqueArc::into_inner(five), 5);
+    /// ```
     #[must_use]
-    pub(super) const fn invalid<T>(addr: usize) -> *mut T {
-        // SAFETY: Every integer is a valid pointer as long as it is not dereferenced.
-        #[cfg(miri)]
+    pub fn into_inner(this: Self) -> T {
+        // SAFETY: The strong count is zero and we hold the only reference to the value, so reading
+        // it out is valid. The value is not accessed again because `this` is forgotten.
         unsafe {
-            core::mem::transmute(addr)
-        }
-        // const transmute requires Rust 1.56.
-        #[cfg(not(miri))]
-        {
-            addr as *mut T
+            let element = ptr::read(&this.inner().value);
+
+            // Create a new weak pointer to deallocate.
+            let _weak = Weak::from_inner(this.shared);
+            mem::forget(this);
+
+            element
         }
     }
+}
+
+#[cfg(not(portable_atomic_no_maybe_uninit))]
+impl<T> UniqueArc<mem::MaybeUninit<T>> {
+    /// Convert to `UniqueArc<T>`.
+    ///
+    /// # Safety
+    ///
+    /// The caller must guarantee that the inner value really is in an initialized state.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::UniqueArc;
+    ///
+    /// let mut five = UniqueArc::<u32>::new_uninit();
+    /// *five = std::mem::MaybeUninit::new(5);
+    ///
+    /// // SAFETY: The value has been initialized.
+    /// let five = unsafe { five.assume_init() };
+    /// assert_eq!(*five, 5);
+    /// ```
+    #[must_use]
+    pub unsafe fn assume_init(self) -> UniqueArc<T> {
+        let shared = self.shared.cast::<Shared<T>>();
+        mem::forget(self);
+
+        // SAFETY: `MaybeUninit<T>` has the same layout as `T` and `Shared` is `repr(C)`, so the
+        // allocation is also a valid `Shared<T>`. The caller guarantees that the value is initialized.
+        unsafe { UniqueArc::from_inner(shared) }
+    }
 
-    /// Create a new pointer with the metadata of `other`.
-    #[inline]
+    /// Write the value and convert to `UniqueArc<T>`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::UniqueArc;
+    ///
+    /// let five = UniqueArc::<u32>::new_uninit();
+    /// let five = UniqueArc::write(five, 5);
+    /// assert_eq!(*five, 5);
+    /// ```
     #[must_use]
-    pub(super) fn with_metadata_of<T, U: ?Sized>(this: *mut T, mut other: *mut U) -> *mut U {
-        let target = &mut other as *mut *mut U as *mut *mut u8;
+    pub fn write(mut this: Self, value: T) -> UniqueArc<T> {
+        *this = mem::MaybeUninit::new(value);
+
+        // SAFETY: The value has just been initialized.
+        unsafe { this.assume_init() }
+    }
+}
 
-        // SAFETY: In case of a thin pointer, this operations is identical
-        // to a simple assignment. In case of a fat pointer, with the current
-        // fat pointer layout implementation, the first field of such a
-        // pointer is always the data pointer, which is likewise assigned.
-        unsafe { *target = this as *mut u8 };
-        other
+impl<T: ?Sized> UniqueArc<T> {
+    fn inner(&self) -> &Shared<T> {
+        // SAFETY: self.shared is always a valid pointer to a `Shared<T>`.
+        unsafe { self.shared.as_ref() }
     }
 
-    /// Replace the address portion of this pointer with a new address.
-    #[inline]
+    unsafe fn from_inner(ptr: NonNull<Shared<T>>) -> Self {
+        Self { shared: ptr, _marker: PhantomData }
+    }
+
+    /// Get a [`Weak`] reference to this allocation.
+    ///
+    /// Upgrading the returned `Weak` will fail until this `UniqueArc` has been converted into an
+    /// [`Arc`] with [`UniqueArc::into_arc`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::UniqueArc;
+    ///
+    /// let five = UniqueArc::new(5);
+    /// let weak_five = UniqueArc::downgrade(&five);
+    /// assert!(weak_five.upgrade().is_none());
+    ///
+    /// let five = UniqueArc::into_arc(five);
+    /// assert!(weak_five.upgrade().is_some());
+    /// ```
     #[must_use]
-    pub(super) fn with_addr<T>(ptr: *mut T, addr: usize) -> *mut T {
-        // FIXME(strict_provenance_magic): I am magic and should be a compiler intrinsic.
-        //
-        // In the mean-time, this operation is defined to be "as if" it was
-        // a wrapping_offset, so we can emulate it as such. This should properly
-        // restore pointer provenance even under today's compiler.
-        let self_addr = ptr as usize as isize;
-        let dest_addr = addr as isize;
-        let offset = dest_addr.wrapping_sub(self_addr);
+    pub fn downgrade(this: &Self) -> Weak<T> {
+        // The weak counter is never "locked" here since `Arc::is_unique` can only be called on an
+        // `Arc`, and no `Arc` exists for this allocation yet.
+        let old_size = this.inner().weak().fetch_add(1, Relaxed);
 
-        // This is the canonical desugaring of this operation.
-        (ptr as *mut u8).wrapping_offset(offset) as *mut T
+        // Abort if the refcount overflowed.
+        if old_size > MAX_REFCOUNT {
+            abort();
+        }
+
+        // SAFETY: Now that the weak counter is incremented, the allocation for a weak pointer is valid.
+        unsafe { Weak::from_inner(this.shared) }
     }
 
-    /// Run an operation of some kind on a pointer.
-    #[inline]
+    /// Convert this `UniqueArc` into an [`Arc`].
+    ///
+    /// This reuses the existing allocation. Weak references previously created by
+    /// [`UniqueArc::downgrade`] can be upgraded after this call.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{Arc, UniqueArc};
+    ///
+    /// let mut five = UniqueArc::new(4);
+    /// *five += 1;
+    ///
+    /// let five = UniqueArc::into_arc(five);
+    /// assert_eq!(*five, 5);
+    /// assert_eq!(Arc::strong_count(&five), 1);
+    /// ```
     #[must_use]
-    pub(super) fn map_addr<T>(ptr: *mut T, f: impl FnOnce(usize) -> usize) -> *mut T {
-        with_addr(ptr, f(ptr as usize))
+    pub fn into_arc(this: Self) -> Arc<T> {
+        // Make the value visible to weak pointers that upgrade after this point.
+        // This synchronizes with the `Acquire` in `Weak::upgrade`.
+        this.inner().strong().store(1, Release);
+
+        let shared = this.shared;
+        mem::forget(this);
+
+        // SAFETY: The strong count has been set to one and is owned by the new `Arc`.
+        unsafe { Arc::from_inner(shared) }
+    }
+}
+
+impl<T: ?Sized> From<UniqueArc<T>> for Arc<T> {
+    fn from(unique: UniqueArc<T>) -> Self {
+        UniqueArc::into_arc(unique)
+    }
+}
+
+impl<T: ?Sized> Deref for UniqueArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner().value
+    }
+}
+
+impl<T: ?Sized> DerefMut for UniqueArc<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        // SAFETY: This pointer is uniquely owned and weak pointers cannot access the value while the
+        // strong count is zero.
+        unsafe { &mut (*self.shared.as_ptr()).value }
+    }
+}
+
+impl<T: ?Sized> AsRef<T> for UniqueArc<T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T: ?Sized> AsMut<T> for UniqueArc<T> {
+    fn as_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+impl<T: ?Sized> Borrow<T> for UniqueArc<T> {
+    fn borrow(&self) -> &T {
+        self
     }
 }
+
+impl<T: ?Sized> BorrowMut<T> for UniqueArc<T> {
+    fn borrow_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+impl<T: ?Sized> Drop for UniqueArc<T> {
+    fn drop(&mut self) {
+        // SAFETY: The strong count is zero and we hold the only reference to the value, so these
+        // operations are valid.
+        unsafe {
+            // Destroy the value itself.
+            ptr::drop_in_place(&mut **self);
+
+            // Drop the intrinsic weak reference to deallocate.
+            drop(Weak::from_inner(self.shared));
+        }
+    }
+}
+
+fn is_dangling<T: ?Sized>(ptr: *mut T) -> bool {
+    ptr as *mut () as usize == usize::MAX
+}
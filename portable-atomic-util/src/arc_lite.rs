@@ -0,0 +1,545 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A thread-safe reference counted pointer without weak reference support.
+//!
+//! Unlike [`Arc`](crate::Arc), the heap allocation of an [`ArcLite`] has a single reference
+//! counter. This saves a word per allocation, and one atomic operation per drop, which matters on
+//! targets where atomic operations are implemented by disabling interrupts or using critical
+//! sections.
+
+use portable_atomic::{
+    AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release},
+};
+
+use alloc::boxed::Box;
+
+use crate::{
+    utils::{abort, strict, MAX_REFCOUNT},
+    Arc,
+};
+
+use core::{
+    borrow::Borrow,
+    convert::TryFrom,
+    fmt,
+    hash::Hash,
+    marker::PhantomData,
+    mem,
+    ops::Deref,
+    pin::Pin,
+    ptr::NonNull,
+};
+
+/// The inner heap allocation of an `ArcLite`.
+#[repr(C)]
+struct Shared<T: ?Sized> {
+    /// The current reference count.
+    ///
+    /// As long as this is greater than zero, the `value` is initialized.
+    strong: AtomicUsize,
+
+    /// The value that is being reference counted.
+    value: T,
+}
+
+/// A thread-safe, reference counted pointer without weak reference support.
+///
+/// This is similar to [`Arc`], but the heap allocation only stores a single reference count
+/// instead of separate strong and weak counts. Use this when [`Weak`](crate::Weak) pointers are not
+/// needed to save memory and atomic operations, especially on targets where atomic operations are
+/// implemented by disabling interrupts or using critical sections.
+///
+/// An `Arc` can be converted into an `ArcLite` with [`TryFrom`] if it is the only strong
+/// reference and has no weak references.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::ArcLite;
+/// use std::thread;
+///
+/// let five = ArcLite::new(5);
+///
+/// for _ in 0..10 {
+///     let five = ArcLite::clone(&five);
+///     thread::spawn(move || {
+///         assert_eq!(*five, 5);
+///     });
+/// }
+/// # if cfg!(miri) { std::thread::sleep(std::time::Duration::from_millis(500)); } // wait for background threads closed: https://github.com/rust-lang/miri/issues/1371
+/// ```
+pub struct ArcLite<T: ?Sized> {
+    /// The inner heap allocation.
+    shared: NonNull<Shared<T>>,
+
+    _marker: PhantomData<Shared<T>>,
+}
+
+// SAFETY: This value is accessible from many threads, it has to be Sync.
+unsafe impl<T: ?Sized + Send + Sync> Send for ArcLite<T> {}
+// SAFETY: This value is accessible from many threads, it has to be Sync.
+unsafe impl<T: ?Sized + Send + Sync> Sync for ArcLite<T> {}
+
+impl<T: ?Sized> Unpin for ArcLite<T> {}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ArcLite<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized> Clone for ArcLite<T> {
+    fn clone(&self) -> Self {
+        // Bump the refcount.
+        let old_size = self.inner().strong.fetch_add(1, Relaxed);
+
+        // Abort if the refcount overflowed.
+        if old_size > MAX_REFCOUNT {
+            abort();
+        }
+
+        // Return a new `ArcLite`.
+        //
+        // SAFETY: The refcount was incremented, so the allocation is still valid.
+        unsafe { Self::from_inner(self.shared) }
+    }
+}
+
+impl<T: Default> Default for ArcLite<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<U: ?Sized, T: ?Sized + PartialEq<U>> PartialEq<ArcLite<U>> for ArcLite<T> {
+    fn eq(&self, other: &ArcLite<U>) -> bool {
+        PartialEq::eq(&**self, &**other)
+    }
+}
+
+impl<T: ?Sized + Eq> Eq for ArcLite<T> {}
+
+impl<U: ?Sized, T: ?Sized + PartialOrd<U>> PartialOrd<ArcLite<U>> for ArcLite<T> {
+    fn partial_cmp(&self, other: &ArcLite<U>) -> Option<core::cmp::Ordering> {
+        PartialOrd::partial_cmp(&**self, &**other)
+    }
+}
+
+impl<T: ?Sized + Ord> Ord for ArcLite<T> {
+    fn cmp(&self, other: &Self) -> core::cmp::Ordering {
+        Ord::cmp(&**self, &**other)
+    }
+}
+
+impl<T: ?Sized + Hash> Hash for ArcLite<T> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        (**self).hash(state);
+    }
+}
+
+impl<T: ?Sized> ArcLite<T> {
+    fn inner(&self) -> &Shared<T> {
+        // SAFETY: self.shared is always a valid pointer to a `Shared<T>`.
+        unsafe { self.shared.as_ref() }
+    }
+
+    unsafe fn from_inner(ptr: NonNull<Shared<T>>) -> Self {
+        Self { shared: ptr, _marker: PhantomData }
+    }
+
+    #[allow(clippy::needless_pass_by_ref_mut)] // https://github.com/rust-lang/rust-clippy/issues/11180
+    unsafe fn get_mut_unchecked(this: &mut Self) -> &mut T {
+        // SAFETY: Since we have an exclusive reference, as certified by the caller, this
+        // dereference is valid.
+        unsafe { &mut (*this.shared.as_ptr()).value }
+    }
+
+    #[inline(never)]
+    unsafe fn drop_slow(&mut self) {
+        // SAFETY: The refcount is zero, so we have exclusive access to the allocation.
+        unsafe { mem::drop(Box::from_raw(self.shared.as_ptr())) }
+    }
+
+    fn is_unique(&self) -> bool {
+        // There are no weak references, so the count can only be increased through an `ArcLite`
+        // we can see. If it is one, that is us.
+        self.inner().strong.load(Acquire) == 1
+    }
+}
+
+impl<T> ArcLite<T> {
+    /// Create a new [`ArcLite`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let five = ArcLite::new(5);
+    /// ```
+    pub fn new(item: T) -> ArcLite<T> {
+        let shared = Box::into_raw(Box::new(Shared { strong: AtomicUsize::new(1), value: item }));
+
+        // SAFETY: The newly created allocation is valid.
+        unsafe { Self::from_inner(NonNull::new_unchecked(shared)) }
+    }
+
+    /// Create a new [`ArcLite`] whose pointer is pinned to the heap.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let five = ArcLite::pin(5);
+    /// ```
+    pub fn pin(item: T) -> Pin<ArcLite<T>> {
+        // SAFETY: The inner object is now pinned to the heap.
+        unsafe { Pin::new_unchecked(Self::new(item)) }
+    }
+
+    /// Unwrap and try to get the inner value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let five = ArcLite::new(5);
+    /// assert_eq!(ArcLite::try_unwrap(five).unwrap(), 5);
+    ///
+    /// let five = ArcLite::new(5);
+    /// let five2 = ArcLite::clone(&five);
+    /// assert!(ArcLite::try_unwrap(five).is_err());
+    /// ```
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        // Try to decrement the refcount.
+        if this.inner().strong.compare_exchange(1, 0, Relaxed, Relaxed).is_err() {
+            return Err(this);
+        }
+
+        // Otherwise, we can safely unwrap the value.
+        acquire!(this.inner().strong);
+
+        let ptr = this.shared.as_ptr() as *mut Shared<mem::ManuallyDrop<T>>;
+        mem::forget(this);
+
+        // SAFETY: The refcount is zero and we hold the only reference, so we can move the value
+        // out and deallocate. Using ManuallyDrop here prevents T from being dropped twice.
+        let shared = unsafe { Box::from_raw(ptr) };
+        Ok(mem::ManuallyDrop::into_inner(shared.value))
+    }
+}
+
+impl<T: ?Sized> ArcLite<T> {
+    /// Consume this `ArcLite` and get the raw pointer to the inner value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let five = ArcLite::new(5);
+    /// let five_ptr = ArcLite::into_raw(five);
+    ///
+    /// // We should now free the pointer.
+    /// // SAFETY: The pointer is valid.
+    /// drop(unsafe { ArcLite::from_raw(five_ptr) });
+    /// ```
+    #[must_use]
+    pub fn into_raw(self) -> *const T {
+        let ptr = self.as_ptr();
+        mem::forget(self);
+        ptr
+    }
+
+    /// Get the raw pointer representing this `ArcLite<T>`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let five = ArcLite::new(5);
+    /// let five_ptr = ArcLite::as_ptr(&five);
+    /// ```
+    #[must_use]
+    pub fn as_ptr(&self) -> *const T {
+        &self.inner().value
+    }
+
+    /// Convert a raw pointer previously created by `into_raw` into a new `ArcLite`.
+    ///
+    /// # Safety
+    ///
+    /// This function can only be called with a pointer that was previously returned by `into_raw`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let five = ArcLite::new(5);
+    /// let five_ptr = ArcLite::into_raw(five);
+    ///
+    /// // SAFETY: The pointer is valid.
+    /// let five = unsafe { ArcLite::from_raw(five_ptr) };
+    /// assert_eq!(*five, 5);
+    /// ```
+    #[must_use]
+    pub unsafe fn from_raw(ptr: *const T) -> Self {
+        // SAFETY: The caller must ensure that the pointer is valid, so the value is still alive
+        // and its alignment can be read.
+        unsafe {
+            // The value is placed after the counter, padded to its alignment.
+            let align = mem::align_of_val(&*ptr);
+            let offset = (mem::size_of::<AtomicUsize>() + align - 1) & !(align - 1);
+
+            // Subtract the offset so that it points to the Shared allocation.
+            let new_ptr = strict::map_addr(ptr as *mut u8, |addr| addr - offset);
+
+            // Cast the pointer to the correct type.
+            let shared = strict::with_metadata_of(new_ptr, ptr as *mut Shared<T>);
+
+            Self::from_inner(NonNull::new_unchecked(shared))
+        }
+    }
+
+    /// Get the number of pointers to this allocation.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let five = ArcLite::new(5);
+    /// let five2 = ArcLite::clone(&five);
+    ///
+    /// assert_eq!(ArcLite::strong_count(&five), 2);
+    /// ```
+    #[must_use]
+    pub fn strong_count(this: &Self) -> usize {
+        this.inner().strong.load(Acquire)
+    }
+
+    /// Increment the reference count of the `ArcLite` pointed to by `ptr` by one.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be a pointer previously returned by `ArcLite::into_raw`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let five = ArcLite::new(5);
+    /// let five_ptr = ArcLite::into_raw(five);
+    ///
+    /// // SAFETY: The pointer is valid.
+    /// unsafe { ArcLite::increment_strong_count(five_ptr) };
+    ///
+    /// // SAFETY: The pointer is valid.
+    /// let five2 = unsafe { ArcLite::from_raw(five_ptr) };
+    /// assert_eq!(*five2, 5);
+    ///
+    /// // SAFETY: Since the refcount is incremented, we can get another.
+    /// let five3 = unsafe { ArcLite::from_raw(five_ptr) };
+    /// assert_eq!(*five3, 5);
+    /// ```
+    pub unsafe fn increment_strong_count(ptr: *const T) {
+        // Retain ArcLite, but don't touch refcount by wrapping in ManuallyDrop
+        //
+        // SAFETY: The caller must ensure that the pointer is valid.
+        let arc = unsafe { mem::ManuallyDrop::new(ArcLite::<T>::from_raw(ptr)) };
+
+        // Now increase refcount, but don't drop new refcount either
+        let _arc_clone: mem::ManuallyDrop<_> = arc.clone();
+    }
+
+    /// Decrement the reference count of the `ArcLite` pointed to by `ptr` by one.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be a pointer previously returned by `ArcLite::into_raw`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let five = ArcLite::new(5);
+    /// let five2 = ArcLite::clone(&five);
+    ///
+    /// let five_ptr = ArcLite::into_raw(five);
+    ///
+    /// // SAFETY: The pointer is valid.
+    /// unsafe { ArcLite::decrement_strong_count(five_ptr) };
+    /// ```
+    pub unsafe fn decrement_strong_count(ptr: *const T) {
+        // SAFETY: The caller must ensure that the pointer is valid.
+        unsafe { mem::drop(ArcLite::from_raw(ptr)) };
+    }
+
+    /// Tell if two `ArcLite`s point to the same allocation.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let five = ArcLite::new(5);
+    /// let five2 = ArcLite::clone(&five);
+    ///
+    /// assert!(ArcLite::ptr_eq(&five, &five2));
+    /// ```
+    #[must_use]
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.shared.as_ptr() as *mut () == other.shared.as_ptr() as *mut ()
+    }
+
+    /// Get a mutable pointer to the inner value if there are no other references.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let mut five = ArcLite::new(5);
+    /// assert!(ArcLite::get_mut(&mut five).is_some());
+    ///
+    /// let five2 = ArcLite::clone(&five);
+    /// assert!(ArcLite::get_mut(&mut five).is_none());
+    /// ```
+    #[must_use]
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        if this.is_unique() {
+            // SAFETY: The pointer is unique.
+            unsafe { Some(Self::get_mut_unchecked(this)) }
+        } else {
+            None
+        }
+    }
+}
+
+impl<T: Clone> ArcLite<T> {
+    /// Make a mutable reference into the given `ArcLite`.
+    ///
+    /// If there are other `ArcLite` pointers to the same allocation, the inner value is cloned into
+    /// a new allocation to ensure unique ownership.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let mut data = ArcLite::new(5);
+    ///
+    /// *ArcLite::make_mut(&mut data) += 1; // Won't clone anything
+    /// let mut other_data = ArcLite::clone(&data); // Won't clone inner data
+    /// *ArcLite::make_mut(&mut data) += 1; // Clones inner data
+    /// *ArcLite::make_mut(&mut data) += 1; // Won't clone anything
+    /// *ArcLite::make_mut(&mut other_data) *= 2; // Won't clone anything
+    ///
+    /// assert_eq!(*data, 8);
+    /// assert_eq!(*other_data, 12);
+    /// ```
+    pub fn make_mut(this: &mut Self) -> &mut T {
+        if !this.is_unique() {
+            // Another pointer exists, so we must clone.
+            *this = Self::new((**this).clone());
+        }
+
+        // SAFETY: The pointer is unique now.
+        unsafe { Self::get_mut_unchecked(this) }
+    }
+
+    /// Try to get the inner value or clone it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcLite;
+    ///
+    /// let five = ArcLite::new(5);
+    /// let five2 = ArcLite::clone(&five);
+    ///
+    /// assert_eq!(ArcLite::unwrap_or_clone(five), 5);
+    /// ```
+    #[must_use]
+    pub fn unwrap_or_clone(this: Self) -> T {
+        Self::try_unwrap(this).unwrap_or_else(|this| (*this).clone())
+    }
+}
+
+impl<T> TryFrom<Arc<T>> for ArcLite<T> {
+    type Error = Arc<T>;
+
+    /// Convert an [`Arc`] into an `ArcLite`.
+    ///
+    /// This fails and returns the `Arc` back if there are other strong or weak references to the
+    /// allocation. Otherwise, the value is moved into a new allocation without a weak counter.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{Arc, ArcLite};
+    /// use std::convert::TryFrom;
+    ///
+    /// let five = Arc::new(5);
+    /// let weak_five = Arc::downgrade(&five);
+    /// let five = ArcLite::try_from(five).unwrap_err();
+    ///
+    /// drop(weak_five);
+    /// let five = ArcLite::try_from(five).unwrap();
+    /// assert_eq!(*five, 5);
+    /// ```
+    fn try_from(arc: Arc<T>) -> Result<Self, Self::Error> {
+        // This check is best-effort: a `Weak` that upgrades concurrently can create and drop
+        // another reference between these loads and `try_unwrap`. In that case the conversion
+        // either fails or leaves a `Weak` that can no longer upgrade, both of which are sound.
+        if Arc::strong_count(&arc) != 1 || Arc::weak_count(&arc) != 0 {
+            return Err(arc);
+        }
+        Arc::try_unwrap(arc).map(Self::new)
+    }
+}
+
+impl<T: ?Sized> Deref for ArcLite<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner().value
+    }
+}
+
+impl<T: ?Sized> AsRef<T> for ArcLite<T> {
+    fn as_ref(&self) -> &T {
+        &self.inner().value
+    }
+}
+
+impl<T: ?Sized> Borrow<T> for ArcLite<T> {
+    fn borrow(&self) -> &T {
+        &self.inner().value
+    }
+}
+
+impl<T: ?Sized> Drop for ArcLite<T> {
+    fn drop(&mut self) {
+        // Decrement the refcount.
+        if self.inner().strong.fetch_sub(1, Release) != 1 {
+            return;
+        }
+
+        // Ensure we're synchronized with other threads.
+        acquire!(self.inner().strong);
+
+        // Drop the value and deallocate.
+        //
+        // SAFETY: Since the refcount is zero, we have exclusive access to the inner value.
+        unsafe { self.drop_slow() }
+    }
+}
+
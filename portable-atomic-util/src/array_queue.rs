@@ -0,0 +1,671 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+// Bounded multi-producer multi-consumer queues.
+//
+// This is Dmitry Vyukov's bounded MPMC queue, where each slot has a stamp that tells whether it
+// is ready to be written to or read from in the current lap:
+// https://www.1024cores.net/home/lock-free-algorithms/queues/bounded-mpmc-queue
+//
+// Adapted from https://github.com/crossbeam-rs/crossbeam/blob/crossbeam-queue-0.3.11/crossbeam-queue/src/array_queue.rs.
+//
+// Unlike crossbeam, the stamp of each slot is stored as an offset from the slot's index, so that
+// all slots of a new queue are zeroed and the array of slots of `StaticArrayQueue` can be
+// constructed in a `const` context.
+
+use portable_atomic::{
+    fence, AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+
+use crate::utils::{Backoff, CachePadded};
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+use alloc::{boxed::Box, vec::Vec};
+use core::{cell::UnsafeCell, fmt, mem::MaybeUninit, ptr};
+
+struct Slot<T> {
+    // The stamp minus the index of this slot.
+    stamp: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    #[allow(clippy::declare_interior_mutable_const)] // only used to initialize the array of slots
+    const NEW: Self =
+        Self { stamp: AtomicUsize::new(0), value: UnsafeCell::new(MaybeUninit::uninit()) };
+}
+
+/// The state shared by `ArrayQueue` and `StaticArrayQueue`.
+struct Inner {
+    // The head of the queue: the lower bits are the index of the slot and the upper bits are the
+    // lap. Elements are popped from the head.
+    head: CachePadded<AtomicUsize>,
+    // The tail of the queue, with the same layout as `head`. Elements are pushed to the tail.
+    tail: CachePadded<AtomicUsize>,
+    cap: usize,
+    // A stamp with the value of `{ lap: 1, index: 0 }`.
+    one_lap: usize,
+}
+
+impl Inner {
+    const fn new(cap: usize) -> Self {
+        Self {
+            head: CachePadded::new(AtomicUsize::new(0)),
+            tail: CachePadded::new(AtomicUsize::new(0)),
+            cap,
+            one_lap: (cap + 1).next_power_of_two(),
+        }
+    }
+
+    #[inline]
+    fn stamp<T>(slots: &[Slot<T>], index: usize) -> usize {
+        slots[index].stamp.load(Acquire).wrapping_add(index)
+    }
+
+    #[inline]
+    fn set_stamp<T>(slots: &[Slot<T>], index: usize, stamp: usize) {
+        slots[index].stamp.store(stamp.wrapping_sub(index), Release);
+    }
+
+    /// Push `value`, calling `full` when the queue looks full. `full` returns `Ok(value)` to retry
+    /// and `Err(value)` to give up.
+    fn push_or_else<T, F>(&self, slots: &[Slot<T>], mut value: T, full: F) -> Result<(), T>
+    where
+        F: Fn(T, usize, usize, usize) -> Result<T, T>,
+    {
+        if self.cap == 0 {
+            return Err(value);
+        }
+        let mut backoff = Backoff::new();
+        let mut tail = self.tail.load(Relaxed);
+        loop {
+            let index = tail & (self.one_lap - 1);
+            let lap = tail & !(self.one_lap - 1);
+            let new_tail =
+                if index + 1 < self.cap { tail + 1 } else { lap.wrapping_add(self.one_lap) };
+            let stamp = Self::stamp(slots, index);
+
+            if tail == stamp {
+                // The slot is ready to be written to in this lap.
+                match self.tail.compare_exchange_weak(tail, new_tail, SeqCst, Relaxed) {
+                    Ok(_) => {
+                        // SAFETY: We claimed the slot by moving the tail, and readers do not
+                        // access it until we update its stamp.
+                        unsafe { (*slots[index].value.get()) = MaybeUninit::new(value) }
+                        Self::set_stamp(slots, index, tail + 1);
+                        return Ok(());
+                    }
+                    Err(t) => tail = t,
+                }
+            } else if stamp.wrapping_add(self.one_lap) == tail + 1 {
+                // The slot still holds a value from the previous lap, so the queue may be full.
+                fence(SeqCst);
+                value = full(value, tail, new_tail, index)?;
+                tail = self.tail.load(Relaxed);
+            } else {
+                // Another thread is still writing to or reading from the slot.
+                backoff.snooze();
+                tail = self.tail.load(Relaxed);
+            }
+        }
+    }
+
+    fn push<T>(&self, slots: &[Slot<T>], value: T) -> Result<(), T> {
+        self.push_or_else(slots, value, |value, tail, _, _| {
+            let head = self.head.load(Relaxed);
+            if head.wrapping_add(self.one_lap) == tail {
+                Err(value)
+            } else {
+                Ok(value)
+            }
+        })
+    }
+
+    fn force_push<T>(&self, slots: &[Slot<T>], value: T) -> Option<T> {
+        if self.cap == 0 {
+            return Some(value);
+        }
+        self.push_or_else(slots, value, |value, tail, new_tail, index| {
+            let head = tail.wrapping_sub(self.one_lap);
+            let new_head = new_tail.wrapping_sub(self.one_lap);
+            // Evict the oldest element by moving the head past it.
+            if self.head.compare_exchange_weak(head, new_head, SeqCst, Relaxed).is_ok() {
+                self.tail.store(new_tail, SeqCst);
+                // SAFETY: We claimed the slot by moving both the head and the tail, and it holds
+                // the value from the previous lap.
+                let old = unsafe {
+                    let old = ptr::replace(slots[index].value.get(), MaybeUninit::new(value));
+                    old.as_ptr().read()
+                };
+                Self::set_stamp(slots, index, tail + 1);
+                Err(old)
+            } else {
+                Ok(value)
+            }
+        })
+        .err()
+    }
+
+    fn pop<T>(&self, slots: &[Slot<T>]) -> Option<T> {
+        if self.cap == 0 {
+            return None;
+        }
+        let mut backoff = Backoff::new();
+        let mut head = self.head.load(Relaxed);
+        loop {
+            let index = head & (self.one_lap - 1);
+            let lap = head & !(self.one_lap - 1);
+            let stamp = Self::stamp(slots, index);
+
+            if head + 1 == stamp {
+                // The slot is ready to be read from in this lap.
+                let new_head =
+                    if index + 1 < self.cap { head + 1 } else { lap.wrapping_add(self.one_lap) };
+                match self.head.compare_exchange_weak(head, new_head, SeqCst, Relaxed) {
+                    Ok(_) => {
+                        // SAFETY: We claimed the slot by moving the head, and writers do not
+                        // access it until we update its stamp.
+                        let value = unsafe { (*slots[index].value.get()).as_ptr().read() };
+                        Self::set_stamp(slots, index, head.wrapping_add(self.one_lap));
+                        return Some(value);
+                    }
+                    Err(h) => head = h,
+                }
+            } else if stamp == head {
+                // The slot has not been written to in this lap, so the queue may be empty.
+                fence(SeqCst);
+                let tail = self.tail.load(Relaxed);
+                if tail == head {
+                    return None;
+                }
+                head = self.head.load(Relaxed);
+            } else {
+                // Another thread is still writing to or reading from the slot.
+                backoff.snooze();
+                head = self.head.load(Relaxed);
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        loop {
+            let tail = self.tail.load(SeqCst);
+            let head = self.head.load(SeqCst);
+            // Make sure `tail` and `head` were read at the same point in time.
+            if self.tail.load(SeqCst) == tail {
+                let hix = head & (self.one_lap - 1);
+                let tix = tail & (self.one_lap - 1);
+                return if hix < tix {
+                    tix - hix
+                } else if hix > tix {
+                    self.cap - hix + tix
+                } else if tail == head {
+                    0
+                } else {
+                    self.cap
+                };
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        let head = self.head.load(SeqCst);
+        let tail = self.tail.load(SeqCst);
+        tail == head
+    }
+
+    fn is_full(&self) -> bool {
+        let tail = self.tail.load(SeqCst);
+        let head = self.head.load(SeqCst);
+        head.wrapping_add(self.one_lap) == tail
+    }
+
+    /// Drop the remaining elements.
+    fn drop_elements<T>(&mut self, slots: &mut [Slot<T>]) {
+        let hix = *self.head.get_mut() & (self.one_lap - 1);
+        for i in 0..self.len() {
+            let index = if hix + i < self.cap { hix + i } else { hix + i - self.cap };
+            // SAFETY: The slots between the head and the tail hold values, and we have exclusive
+            // access.
+            unsafe { ptr::drop_in_place((*slots[index].value.get()).as_mut_ptr()) }
+        }
+    }
+}
+
+macro_rules! array_queue_methods {
+    ($queue:ident, $new:expr) => {
+        doc_comment! {
+            concat!("Push an element into the queue.
+
+# Errors
+
+Returns `Err(value)` if the queue is full.
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($queue), ";
+
+let q = ", $new, ";
+assert_eq!(q.push(1), Ok(()));
+assert_eq!(q.push(2), Ok(()));
+assert_eq!(q.push(3), Err(3));
+```"),
+            pub fn push(&self, value: T) -> Result<(), T> {
+                self.inner.push(&self.slots[..], value)
+            }
+        }
+
+        doc_comment! {
+            concat!("Push an element into the queue, replacing the oldest element if the queue is
+full.
+
+Returns the replaced element, if any.
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($queue), ";
+
+let q = ", $new, ";
+assert_eq!(q.force_push(1), None);
+assert_eq!(q.force_push(2), None);
+assert_eq!(q.force_push(3), Some(1));
+assert_eq!(q.pop(), Some(2));
+```"),
+            pub fn force_push(&self, value: T) -> Option<T> {
+                self.inner.force_push(&self.slots[..], value)
+            }
+        }
+
+        doc_comment! {
+            concat!("Pop an element from the queue, or return `None` if the queue is empty.
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($queue), ";
+
+let q = ", $new, ";
+q.push(1).unwrap();
+assert_eq!(q.pop(), Some(1));
+assert_eq!(q.pop(), None);
+```"),
+            pub fn pop(&self) -> Option<T> {
+                self.inner.pop(&self.slots[..])
+            }
+        }
+
+        doc_comment! {
+            concat!("Return the number of elements in the queue.
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($queue), ";
+
+let q = ", $new, ";
+assert_eq!(q.len(), 0);
+q.push(1).unwrap();
+assert_eq!(q.len(), 1);
+```"),
+            #[must_use]
+            pub fn len(&self) -> usize {
+                self.inner.len()
+            }
+        }
+
+        doc_comment! {
+            concat!("Return `true` if the queue is empty.
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($queue), ";
+
+let q = ", $new, ";
+assert!(q.is_empty());
+q.push(1).unwrap();
+assert!(!q.is_empty());
+```"),
+            #[must_use]
+            pub fn is_empty(&self) -> bool {
+                self.inner.is_empty()
+            }
+        }
+
+        doc_comment! {
+            concat!("Return `true` if the queue is full.
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($queue), ";
+
+let q = ", $new, ";
+q.push(1).unwrap();
+assert!(!q.is_full());
+q.push(2).unwrap();
+assert!(q.is_full());
+```"),
+            #[must_use]
+            pub fn is_full(&self) -> bool {
+                self.inner.is_full()
+            }
+        }
+
+        doc_comment! {
+            concat!("Return the capacity of the queue.
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($queue), ";
+
+let q = ", $new, ";
+assert_eq!(q.capacity(), 2);
+# q.push(1).unwrap();
+```"),
+            #[must_use]
+            pub fn capacity(&self) -> usize {
+                self.inner.cap
+            }
+        }
+    };
+}
+
+/// A bounded multi-producer multi-consumer queue with a capacity chosen at runtime.
+///
+/// The elements are stored in a heap-allocated buffer. See [`StaticArrayQueue`] for a version
+/// with a const-generic capacity that does not require an allocator.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::ArrayQueue;
+/// use std::{sync::Arc, thread};
+///
+/// let q = Arc::new(ArrayQueue::new(16));
+/// let producers: Vec<_> = (0..4)
+///     .map(|i| {
+///         let q = Arc::clone(&q);
+///         thread::spawn(move || {
+///             for j in 0..100 {
+///                 while q.push(i * 100 + j).is_err() {}
+///             }
+///         })
+///     })
+///     .collect();
+/// let mut sum = 0;
+/// for _ in 0..400 {
+///     loop {
+///         if let Some(v) = q.pop() {
+///             sum += v;
+///             break;
+///         }
+///     }
+/// }
+/// for t in producers {
+///     t.join().unwrap();
+/// }
+/// assert_eq!(sum, (0..400).sum());
+/// ```
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub struct ArrayQueue<T> {
+    inner: Inner,
+    slots: Box<[Slot<T>]>,
+}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+// SAFETY: Elements are moved between threads, so `T` must be `Send`.
+unsafe impl<T: Send> Send for ArrayQueue<T> {}
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+// SAFETY: The queue never gives out references to its elements.
+unsafe impl<T: Send> Sync for ArrayQueue<T> {}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+impl<T> ArrayQueue<T> {
+    /// Create a new queue with the given capacity.
+    ///
+    /// A queue with a capacity of zero is always full and always empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArrayQueue;
+    ///
+    /// let q = ArrayQueue::<i32>::new(100);
+    /// assert_eq!(q.capacity(), 100);
+    /// ```
+    #[must_use]
+    pub fn new(cap: usize) -> Self {
+        let slots: Vec<Slot<T>> = (0..cap).map(|_| Slot::NEW).collect();
+        Self { inner: Inner::new(cap), slots: slots.into_boxed_slice() }
+    }
+
+    array_queue_methods!(ArrayQueue, "ArrayQueue::new(2)");
+}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+#[allow(clippy::missing_fields_in_debug)]
+impl<T> fmt::Debug for ArrayQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArrayQueue")
+            .field("len", &self.len())
+            .field("capacity", &self.inner.cap)
+            .finish()
+    }
+}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+impl<T> Drop for ArrayQueue<T> {
+    fn drop(&mut self) {
+        self.inner.drop_elements(&mut self.slots[..]);
+    }
+}
+
+/// A bounded multi-producer multi-consumer queue with capacity `N`.
+///
+/// The elements are stored inline, so this does not require an allocator and can be placed in a
+/// `static`. See [`ArrayQueue`] for a version with a capacity chosen at runtime.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::StaticArrayQueue;
+///
+/// static JOBS: StaticArrayQueue<u32, 8> = StaticArrayQueue::new();
+///
+/// JOBS.push(1).unwrap();
+/// JOBS.push(2).unwrap();
+/// assert_eq!(JOBS.pop(), Some(1));
+/// assert_eq!(JOBS.pop(), Some(2));
+/// ```
+pub struct StaticArrayQueue<T, const N: usize> {
+    inner: Inner,
+    slots: [Slot<T>; N],
+}
+
+// SAFETY: Elements are moved between threads, so `T` must be `Send`.
+unsafe impl<T: Send, const N: usize> Send for StaticArrayQueue<T, N> {}
+// SAFETY: The queue never gives out references to its elements.
+unsafe impl<T: Send, const N: usize> Sync for StaticArrayQueue<T, N> {}
+
+impl<T, const N: usize> StaticArrayQueue<T, N> {
+    /// Create a new empty queue.
+    ///
+    /// A queue with a capacity of zero is always full and always empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::StaticArrayQueue;
+    ///
+    /// static Q: StaticArrayQueue<i32, 100> = StaticArrayQueue::new();
+    /// assert_eq!(Q.capacity(), 100);
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { inner: Inner::new(N), slots: [Slot::NEW; N] }
+    }
+
+    array_queue_methods!(StaticArrayQueue, "StaticArrayQueue::<_, 2>::new()");
+}
+
+impl<T, const N: usize> Default for StaticArrayQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<T, const N: usize> fmt::Debug for StaticArrayQueue<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StaticArrayQueue").field("len", &self.len()).field("capacity", &N).finish()
+    }
+}
+
+impl<T, const N: usize> Drop for StaticArrayQueue<T, N> {
+    fn drop(&mut self) {
+        self.inner.drop_elements(&mut self.slots[..]);
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::DropCounter;
+
+    use std::{collections::VecDeque, sync::Barrier, thread, vec::Vec};
+
+    #[derive(Clone, Copy, Debug)]
+    enum Op {
+        Push(u8),
+        ForcePush(u8),
+        Pop,
+    }
+
+    impl quickcheck::Arbitrary for Op {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            match u8::arbitrary(g) % 3 {
+                0 => Op::Push(u8::arbitrary(g)),
+                1 => Op::ForcePush(u8::arbitrary(g)),
+                _ => Op::Pop,
+            }
+        }
+    }
+
+    // Check the queue against a `VecDeque` model.
+    fn check_model(q: &StaticArrayQueue<u8, 3>, ops: &[Op]) -> bool {
+        let mut model = VecDeque::new();
+        for &op in ops {
+            match op {
+                Op::Push(v) => {
+                    let res = q.push(v);
+                    if model.len() == 3 {
+                        assert_eq!(res, Err(v));
+                    } else {
+                        assert_eq!(res, Ok(()));
+                        model.push_back(v);
+                    }
+                }
+                Op::ForcePush(v) => {
+                    let evicted = if model.len() == 3 { model.pop_front() } else { None };
+                    model.push_back(v);
+                    assert_eq!(q.force_push(v), evicted);
+                }
+                Op::Pop => assert_eq!(q.pop(), model.pop_front()),
+            }
+            assert_eq!(q.len(), model.len());
+            assert_eq!(q.is_empty(), model.is_empty());
+            assert_eq!(q.is_full(), model.len() == 3);
+        }
+        true
+    }
+
+    ::quickcheck::quickcheck! {
+        fn quickcheck_sequential(ops: Vec<Op>) -> bool {
+            check_model(&StaticArrayQueue::new(), &ops)
+        }
+        fn quickcheck_heap(cap: u8, values: Vec<u16>) -> bool {
+            let cap = usize::from(cap % 16);
+            let q = ArrayQueue::new(cap);
+            let mut pushed = Vec::new();
+            for &v in &values {
+                if q.push(v).is_ok() {
+                    pushed.push(v);
+                }
+            }
+            assert_eq!(pushed.len(), values.len().min(cap));
+            assert_eq!(q.len(), pushed.len());
+            for &v in &pushed {
+                assert_eq!(q.pop(), Some(v));
+            }
+            q.pop().is_none()
+        }
+        fn quickcheck_concurrent(values: Vec<u32>) -> bool {
+            const THREADS: usize = 4;
+            let q = ArrayQueue::new(4);
+            let barrier = Barrier::new(THREADS * 2);
+            let popped = thread::scope(|s| {
+                for chunk in 0..THREADS {
+                    let (q, barrier, values) = (&q, &barrier, &values);
+                    s.spawn(move || {
+                        barrier.wait();
+                        for &v in values.iter().skip(chunk).step_by(THREADS) {
+                            while q.push(v).is_err() {
+                                thread::yield_now();
+                            }
+                        }
+                    });
+                }
+                let consumers: Vec<_> = (0..THREADS)
+                    .map(|i| {
+                        let (q, barrier, values) = (&q, &barrier, &values);
+                        s.spawn(move || {
+                            barrier.wait();
+                            let n = values.iter().skip(i).step_by(THREADS).count();
+                            let mut popped = Vec::with_capacity(n);
+                            while popped.len() < n {
+                                match q.pop() {
+                                    Some(v) => popped.push(v),
+                                    None => thread::yield_now(),
+                                }
+                            }
+                            popped
+                        })
+                    })
+                    .collect();
+                consumers.into_iter().flat_map(|c| c.join().unwrap()).collect::<Vec<_>>()
+            });
+            let mut popped = popped;
+            let mut values = values;
+            popped.sort_unstable();
+            values.sort_unstable();
+            popped == values
+        }
+    }
+
+    #[test]
+    fn drop_elements() {
+        let count = AtomicUsize::new(0);
+        let q = ArrayQueue::new(3);
+        for _ in 0..5 {
+            drop(q.force_push(DropCounter(&count)));
+        }
+        assert_eq!(count.load(Relaxed), 2);
+        drop(q.pop());
+        assert_eq!(count.load(Relaxed), 3);
+        drop(q);
+        assert_eq!(count.load(Relaxed), 5);
+
+        let count = AtomicUsize::new(0);
+        let q = StaticArrayQueue::<_, 3>::new();
+        q.push(DropCounter(&count)).ok().unwrap();
+        q.push(DropCounter(&count)).ok().unwrap();
+        drop(q);
+        assert_eq!(count.load(Relaxed), 2);
+    }
+}
@@ -0,0 +1,585 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+// The borrow state is a single `AtomicUsize`: the most significant bit is set while the value is
+// mutably borrowed, and the other bits count the shared borrows. Shared borrows are taken with a
+// compare-exchange loop rather than `fetch_add`, so the count never overflows into the
+// mutable-borrow bit.
+
+use portable_atomic::{
+    AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release},
+};
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    ops::{Deref, DerefMut},
+};
+
+const WRITER: usize = !(!0 >> 1);
+const MAX_READERS: usize = WRITER - 1;
+
+/// A thread-safe mutable memory location with dynamically checked borrow rules.
+///
+/// This is like [`RefCell`](core::cell::RefCell), but the borrow state is an [`AtomicUsize`], so
+/// it can be shared between threads. Like a reader-writer lock, it allows either any number of
+/// shared borrows or one mutable borrow at a time, but it never waits: a borrow that conflicts
+/// with an existing one fails (or panics) instead. This makes it a good fit for global resources
+/// that are not expected to be contended, where a conflicting borrow indicates a bug.
+///
+/// Taking and releasing a borrow is a single atomic read-modify-write operation on an
+/// `AtomicUsize`, so this works on every target where portable-atomic provides atomic CAS,
+/// including single-core targets with the `critical-section` feature.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::AtomicRefCell;
+///
+/// static CONFIG: AtomicRefCell<[u32; 4]> = AtomicRefCell::new([0; 4]);
+///
+/// CONFIG.borrow_mut()[0] = 1;
+///
+/// let a = CONFIG.borrow();
+/// let b = CONFIG.borrow();
+/// assert_eq!(a[0], b[0]);
+/// assert!(CONFIG.try_borrow_mut().is_err());
+/// ```
+pub struct AtomicRefCell<T: ?Sized> {
+    borrow: AtomicUsize,
+    value: UnsafeCell<T>,
+}
+
+// Send is implicitly implemented.
+// SAFETY: the borrow state ensures that the value is either shared or mutably borrowed by one
+// context at a time, like `RwLock`.
+unsafe impl<T: ?Sized + Send + Sync> Sync for AtomicRefCell<T> {}
+
+impl<T> AtomicRefCell<T> {
+    /// Create a new `AtomicRefCell` containing `value`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::AtomicRefCell;
+    ///
+    /// static CELL: AtomicRefCell<u32> = AtomicRefCell::new(5);
+    /// ```
+    pub const fn new(value: T) -> Self {
+        Self { borrow: AtomicUsize::new(0), value: UnsafeCell::new(value) }
+    }
+
+    /// Consume the `AtomicRefCell` and return the wrapped value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::AtomicRefCell;
+    ///
+    /// let cell = AtomicRefCell::new(5);
+    /// assert_eq!(cell.into_inner(), 5);
+    /// ```
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> AtomicRefCell<T> {
+    /// Immutably borrow the wrapped value.
+    ///
+    /// The borrow lasts until the returned [`AtomicRef`] is dropped. Multiple immutable borrows
+    /// can be taken out at the same time.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently mutably borrowed. For a non-panicking variant, use
+    /// [`try_borrow`](Self::try_borrow).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::AtomicRefCell;
+    ///
+    /// let cell = AtomicRefCell::new(5);
+    /// let a = cell.borrow();
+    /// let b = cell.borrow();
+    /// assert_eq!(*a + *b, 10);
+    /// ```
+    pub fn borrow(&self) -> AtomicRef<'_, T> {
+        match self.try_borrow() {
+            Ok(r) => r,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Immutably borrow the wrapped value, returning an error if the value is currently mutably
+    /// borrowed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BorrowError`] if the value is currently mutably borrowed, or if there are
+    /// already `usize::MAX / 2` immutable borrows.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::AtomicRefCell;
+    ///
+    /// let cell = AtomicRefCell::new(5);
+    /// {
+    ///     let _m = cell.borrow_mut();
+    ///     assert!(cell.try_borrow().is_err());
+    /// }
+    /// assert!(cell.try_borrow().is_ok());
+    /// ```
+    pub fn try_borrow(&self) -> Result<AtomicRef<'_, T>, BorrowError> {
+        let borrow = BorrowRef::new(&self.borrow).ok_or(BorrowError)?;
+        // SAFETY: we hold a shared borrow, so there is no mutable borrow until it is released.
+        Ok(AtomicRef { value: unsafe { &*self.value.get() }, borrow })
+    }
+
+    /// Mutably borrow the wrapped value.
+    ///
+    /// The borrow lasts until the returned [`AtomicRefMut`] is dropped. The value cannot be
+    /// borrowed while this borrow is active.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the value is currently borrowed. For a non-panicking variant, use
+    /// [`try_borrow_mut`](Self::try_borrow_mut).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::AtomicRefCell;
+    ///
+    /// let cell = AtomicRefCell::new(5);
+    /// *cell.borrow_mut() += 1;
+    /// assert_eq!(*cell.borrow(), 6);
+    /// ```
+    pub fn borrow_mut(&self) -> AtomicRefMut<'_, T> {
+        match self.try_borrow_mut() {
+            Ok(r) => r,
+            Err(e) => panic!("{}", e),
+        }
+    }
+
+    /// Mutably borrow the wrapped value, returning an error if the value is currently borrowed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`BorrowMutError`] if the value is currently borrowed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::AtomicRefCell;
+    ///
+    /// let cell = AtomicRefCell::new(5);
+    /// {
+    ///     let _r = cell.borrow();
+    ///     assert!(cell.try_borrow_mut().is_err());
+    /// }
+    /// assert!(cell.try_borrow_mut().is_ok());
+    /// ```
+    pub fn try_borrow_mut(&self) -> Result<AtomicRefMut<'_, T>, BorrowMutError> {
+        let borrow = BorrowRefMut::new(&self.borrow).ok_or(BorrowMutError)?;
+        // SAFETY: we hold the mutable borrow, so there are no other borrows until it is released.
+        Ok(AtomicRefMut { value: unsafe { &mut *self.value.get() }, borrow })
+    }
+
+    /// Return a mutable reference to the wrapped value.
+    ///
+    /// Since this call borrows the `AtomicRefCell` mutably, no dynamic checks are needed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::AtomicRefCell;
+    ///
+    /// let mut cell = AtomicRefCell::new(5);
+    /// *cell.get_mut() += 1;
+    /// assert_eq!(*cell.borrow(), 6);
+    /// ```
+    pub fn get_mut(&mut self) -> &mut T {
+        // SAFETY: the mutable reference guarantees unique ownership.
+        // (UnsafeCell::get_mut requires Rust 1.50)
+        unsafe { &mut *self.value.get() }
+    }
+}
+
+impl<T: Default> Default for AtomicRefCell<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for AtomicRefCell<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AtomicRefCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("AtomicRefCell");
+        match self.try_borrow() {
+            Ok(r) => d.field("value", &&*r),
+            Err(_) => d.field("value", &format_args!("<borrowed>")),
+        };
+        d.finish()
+    }
+}
+
+// Errors
+
+/// An error returned from [`AtomicRefCell::try_borrow`] when the value is currently mutably
+/// borrowed.
+#[allow(clippy::exhaustive_structs)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BorrowError;
+
+impl fmt::Display for BorrowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("already mutably borrowed")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BorrowError {}
+
+/// An error returned from [`AtomicRefCell::try_borrow_mut`] when the value is currently borrowed.
+#[allow(clippy::exhaustive_structs)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BorrowMutError;
+
+impl fmt::Display for BorrowMutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("already borrowed")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for BorrowMutError {}
+
+// Borrow state guards
+
+struct BorrowRef<'b> {
+    borrow: &'b AtomicUsize,
+}
+
+impl<'b> BorrowRef<'b> {
+    fn new(borrow: &'b AtomicUsize) -> Option<Self> {
+        let mut state = borrow.load(Relaxed);
+        loop {
+            if state & WRITER != 0 || state == MAX_READERS {
+                return None;
+            }
+            match borrow.compare_exchange_weak(state, state + 1, Acquire, Relaxed) {
+                Ok(_) => return Some(Self { borrow }),
+                Err(s) => state = s,
+            }
+        }
+    }
+}
+
+impl Clone for BorrowRef<'_> {
+    fn clone(&self) -> Self {
+        // We already hold a shared borrow, so this can only fail because of the reader count.
+        match Self::new(self.borrow) {
+            Some(borrow) => borrow,
+            None => panic!("too many immutable borrows"),
+        }
+    }
+}
+
+impl Drop for BorrowRef<'_> {
+    fn drop(&mut self) {
+        self.borrow.fetch_sub(1, Release);
+    }
+}
+
+struct BorrowRefMut<'b> {
+    borrow: &'b AtomicUsize,
+}
+
+impl<'b> BorrowRefMut<'b> {
+    fn new(borrow: &'b AtomicUsize) -> Option<Self> {
+        match borrow.compare_exchange(0, WRITER, Acquire, Relaxed) {
+            Ok(_) => Some(Self { borrow }),
+            Err(_) => None,
+        }
+    }
+}
+
+impl Drop for BorrowRefMut<'_> {
+    fn drop(&mut self) {
+        self.borrow.store(0, Release);
+    }
+}
+
+// Guards
+
+/// A wrapper type for an immutably borrowed value from an [`AtomicRefCell`].
+///
+/// See [`AtomicRefCell::borrow`] for details.
+#[must_use = "if unused the borrow will immediately end"]
+pub struct AtomicRef<'b, T: ?Sized> {
+    value: &'b T,
+    borrow: BorrowRef<'b>,
+}
+
+impl<'b, T: ?Sized> AtomicRef<'b, T> {
+    /// Copy an `AtomicRef`.
+    ///
+    /// This is an associated function that needs to be used as `AtomicRef::clone(...)`, so that
+    /// it doesn't interfere with a `clone` method on the wrapped value.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are already `usize::MAX / 2` immutable borrows.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{AtomicRef, AtomicRefCell};
+    ///
+    /// let cell = AtomicRefCell::new(5);
+    /// let a = cell.borrow();
+    /// let b = AtomicRef::clone(&a);
+    /// assert_eq!(*a, *b);
+    /// ```
+    #[allow(clippy::should_implement_trait)]
+    pub fn clone(orig: &Self) -> Self {
+        Self { value: orig.value, borrow: orig.borrow.clone() }
+    }
+
+    /// Make a new `AtomicRef` for a component of the borrowed value.
+    ///
+    /// This is an associated function that needs to be used as `AtomicRef::map(...)`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{AtomicRef, AtomicRefCell};
+    ///
+    /// let cell = AtomicRefCell::new((5, 'b'));
+    /// let c = AtomicRef::map(cell.borrow(), |t| &t.1);
+    /// assert_eq!(*c, 'b');
+    /// ```
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> AtomicRef<'b, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        AtomicRef { value: f(orig.value), borrow: orig.borrow }
+    }
+
+    /// Make a new `AtomicRef` for an optional component of the borrowed value, or return the
+    /// original guard if `f` returns `None`.
+    ///
+    /// This is an associated function that needs to be used as `AtomicRef::filter_map(...)`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{AtomicRef, AtomicRefCell};
+    ///
+    /// let cell = AtomicRefCell::new(vec![1, 2, 3]);
+    /// let second = AtomicRef::filter_map(cell.borrow(), |v| v.get(1));
+    /// assert_eq!(*second.unwrap(), 2);
+    /// let fourth = AtomicRef::filter_map(cell.borrow(), |v| v.get(3));
+    /// assert_eq!(*fourth.unwrap_err(), [1, 2, 3]);
+    /// ```
+    pub fn filter_map<U: ?Sized, F>(orig: Self, f: F) -> Result<AtomicRef<'b, U>, Self>
+    where
+        F: FnOnce(&T) -> Option<&U>,
+    {
+        match f(orig.value) {
+            Some(value) => Ok(AtomicRef { value, borrow: orig.borrow }),
+            None => Err(orig),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for AtomicRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AtomicRef<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for AtomicRef<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+/// A wrapper type for a mutably borrowed value from an [`AtomicRefCell`].
+///
+/// See [`AtomicRefCell::borrow_mut`] for details.
+#[must_use = "if unused the borrow will immediately end"]
+pub struct AtomicRefMut<'b, T: ?Sized> {
+    value: &'b mut T,
+    borrow: BorrowRefMut<'b>,
+}
+
+impl<'b, T: ?Sized> AtomicRefMut<'b, T> {
+    /// Make a new `AtomicRefMut` for a component of the borrowed value.
+    ///
+    /// This is an associated function that needs to be used as `AtomicRefMut::map(...)`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{AtomicRefCell, AtomicRefMut};
+    ///
+    /// let cell = AtomicRefCell::new((5, 'b'));
+    /// {
+    ///     let mut n = AtomicRefMut::map(cell.borrow_mut(), |t| &mut t.0);
+    ///     *n += 1;
+    /// }
+    /// assert_eq!(*cell.borrow(), (6, 'b'));
+    /// ```
+    pub fn map<U: ?Sized, F>(orig: Self, f: F) -> AtomicRefMut<'b, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        AtomicRefMut { value: f(orig.value), borrow: orig.borrow }
+    }
+
+    /// Make a new `AtomicRefMut` for an optional component of the borrowed value, or return the
+    /// original guard if `f` returns `None`.
+    ///
+    /// This is an associated function that needs to be used as `AtomicRefMut::filter_map(...)`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{AtomicRefCell, AtomicRefMut};
+    ///
+    /// let cell = AtomicRefCell::new(vec![1, 2, 3]);
+    /// {
+    ///     let mut second = AtomicRefMut::filter_map(cell.borrow_mut(), |v| v.get_mut(1)).unwrap();
+    ///     *second = 5;
+    /// }
+    /// assert_eq!(*cell.borrow(), [1, 5, 3]);
+    /// ```
+    pub fn filter_map<U: ?Sized, F>(orig: Self, f: F) -> Result<AtomicRefMut<'b, U>, Self>
+    where
+        F: FnOnce(&mut T) -> Option<&mut U>,
+    {
+        let AtomicRefMut { value, borrow } = orig;
+        let ptr: *mut T = value;
+        // SAFETY: `ptr` comes from a `&'b mut T`, and the reference passed to `f` is not used
+        // after `f` returns `None`, so the reference created below in that case is unique.
+        // (Returning the original reference in that case is rejected by the current borrow
+        // checker.)
+        match f(unsafe { &mut *ptr }) {
+            Some(value) => Ok(AtomicRefMut { value, borrow }),
+            // SAFETY: see above.
+            None => Err(AtomicRefMut { value: unsafe { &mut *ptr }, borrow }),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for AtomicRefMut<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+impl<T: ?Sized> DerefMut for AtomicRefMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for AtomicRefMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display> fmt::Display for AtomicRefMut<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&**self, f)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::{sync::Barrier, thread};
+
+    #[test]
+    fn concurrent_borrows() {
+        const THREADS: usize = 4;
+        const N: usize = 10_000;
+        // The two elements are always updated together, so a shared borrow that overlaps a mutable
+        // borrow would likely see them differ.
+        let cell = AtomicRefCell::new([0_usize; 2]);
+        let barrier = Barrier::new(THREADS);
+        let writes: usize = thread::scope(|s| {
+            let handles: std::vec::Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let (cell, barrier) = (&cell, &barrier);
+                    s.spawn(move || {
+                        barrier.wait();
+                        let mut writes = 0;
+                        for i in 0..N {
+                            if (i + t) % 4 == 0 {
+                                if let Ok(mut value) = cell.try_borrow_mut() {
+                                    value[0] += 1;
+                                    thread::yield_now();
+                                    value[1] += 1;
+                                    writes += 1;
+                                }
+                            } else if let Ok(value) = cell.try_borrow() {
+                                let a = value[0];
+                                thread::yield_now();
+                                assert_eq!(value[1], a);
+                                assert_eq!(value[0], a);
+                            }
+                        }
+                        writes
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(*cell.borrow(), [writes; 2]);
+        // All borrows were released.
+        assert_eq!(cell.borrow.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn projected_borrows_hold_the_borrow() {
+        let cell = AtomicRefCell::new((1, 2));
+        let a = AtomicRef::map(cell.borrow(), |v| &v.0);
+        let b = AtomicRef::clone(&a);
+        assert!(cell.try_borrow_mut().is_err());
+        drop(a);
+        assert!(cell.try_borrow_mut().is_err());
+        drop(b);
+        let m = AtomicRefMut::map(cell.borrow_mut(), |v| &mut v.1);
+        assert!(cell.try_borrow().is_err());
+        let m = match AtomicRefMut::filter_map(m, |_| None::<&mut u8>) {
+            Ok(_) => unreachable!(),
+            Err(m) => m,
+        };
+        assert!(cell.try_borrow().is_err());
+        drop(m);
+        assert_eq!(*cell.borrow(), (1, 2));
+        assert_eq!(cell.borrow.load(Relaxed), 0);
+    }
+}
@@ -0,0 +1,963 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Multi-producer single-consumer channels.
+//!
+//! - [`bounded`] creates a channel backed by an [`ArrayQueue`](crate::ArrayQueue).
+//! - [`unbounded`] creates a channel backed by an [`mpsc::Queue`](crate::mpsc::Queue).
+//! - [`oneshot`] creates a channel that can be used to send a single value.
+//!
+//! A channel is disconnected when all of its senders or its receiver are dropped. Receivers can
+//! wait for a value asynchronously with `recv_async`, and, with the `std` feature, block the
+//! current thread with `recv`.
+//!
+//! These channels only need the atomic operations provided by portable-atomic, so they also work
+//! on targets without native atomic CAS when portable-atomic's `critical-section` feature (or
+//! `unsafe-assume-single-core` cfg) is enabled.
+//!
+//! # Examples
+//!
+//! ```
+//! use portable_atomic_util::channel;
+//! use std::thread;
+//!
+//! let (tx, mut rx) = channel::unbounded();
+//! for i in 0..4 {
+//!     let tx = tx.clone();
+//!     thread::spawn(move || tx.send(i).unwrap());
+//! }
+//! drop(tx);
+//!
+//! let mut sum = 0;
+//! while let Ok(v) = rx.recv() {
+//!     sum += v;
+//! }
+//! assert_eq!(sum, 6);
+//! ```
+
+use portable_atomic::{
+    AtomicBool, AtomicUsize,
+    Ordering::{AcqRel, Acquire, Relaxed, Release},
+};
+
+use crate::{
+    mpsc::{PopResult, Queue},
+    utils::Backoff,
+    waker::AtomicWaker,
+    Arc, ArrayQueue,
+};
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+// Errors
+
+/// An error returned from [`Sender::send`] and [`OneshotSender::send`] when the receiver has
+/// been dropped.
+///
+/// The unsent value is returned.
+#[allow(clippy::exhaustive_structs)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SendError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("sending on a disconnected channel")
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> std::error::Error for SendError<T> {}
+
+/// An error returned from [`Sender::try_send`].
+///
+/// The unsent value is returned.
+#[allow(clippy::exhaustive_enums)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is full.
+    Full(T),
+    /// The receiver has been dropped.
+    Disconnected(T),
+}
+
+impl<T> TrySendError<T> {
+    /// Return the unsent value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel;
+    ///
+    /// let (tx, rx) = channel::bounded(1);
+    /// drop(rx);
+    /// assert_eq!(tx.try_send(1).unwrap_err().into_inner(), 1);
+    /// ```
+    pub fn into_inner(self) -> T {
+        match self {
+            Self::Full(v) | Self::Disconnected(v) => v,
+        }
+    }
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("Full(..)"),
+            Self::Disconnected(_) => f.write_str("Disconnected(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full(_) => f.write_str("sending on a full channel"),
+            Self::Disconnected(_) => f.write_str("sending on a disconnected channel"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T> std::error::Error for TrySendError<T> {}
+
+impl<T> From<SendError<T>> for TrySendError<T> {
+    fn from(err: SendError<T>) -> Self {
+        Self::Disconnected(err.0)
+    }
+}
+
+/// An error returned from `recv` and `recv_async` when the channel is empty and disconnected.
+#[allow(clippy::exhaustive_structs)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("receiving on an empty and disconnected channel")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for RecvError {}
+
+/// An error returned from `try_recv`.
+#[allow(clippy::exhaustive_enums)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// The channel is empty.
+    Empty,
+    /// The channel is empty and all senders have been dropped.
+    Disconnected,
+}
+
+impl fmt::Display for TryRecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => f.write_str("receiving on an empty channel"),
+            Self::Disconnected => f.write_str("receiving on an empty and disconnected channel"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TryRecvError {}
+
+impl From<RecvError> for TryRecvError {
+    fn from(_: RecvError) -> Self {
+        Self::Disconnected
+    }
+}
+
+// Shared helpers for receivers
+
+trait TryRecv {
+    type Item;
+    fn try_recv(&self) -> Result<Self::Item, TryRecvError>;
+    fn waker(&self) -> &AtomicWaker;
+
+    fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Result<Self::Item, RecvError>> {
+        match self.try_recv() {
+            Ok(v) => return Poll::Ready(Ok(v)),
+            Err(TryRecvError::Disconnected) => return Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => {}
+        }
+        self.waker().register(cx.waker());
+        // Check again in case a value was sent before the waker was registered.
+        match self.try_recv() {
+            Ok(v) => Poll::Ready(Ok(v)),
+            Err(TryRecvError::Disconnected) => Poll::Ready(Err(RecvError)),
+            Err(TryRecvError::Empty) => Poll::Pending,
+        }
+    }
+
+    #[cfg(feature = "std")]
+    fn recv(&self) -> Result<Self::Item, RecvError> {
+        match self.try_recv() {
+            Ok(v) => return Ok(v),
+            Err(TryRecvError::Disconnected) => return Err(RecvError),
+            Err(TryRecvError::Empty) => {}
+        }
+        let waker = crate::waker::current_thread_waker();
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(res) = self.poll_recv(&mut cx) {
+                return res;
+            }
+            std::thread::park();
+        }
+    }
+}
+
+/// A future returned by [`Receiver::recv_async`] and [`OneshotReceiver::recv_async`].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Recv<'a, R> {
+    receiver: &'a mut R,
+}
+
+impl<R> fmt::Debug for Recv<'_, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Recv").finish()
+    }
+}
+
+impl<T> Future for Recv<'_, Receiver<T>> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+impl<T> Future for Recv<'_, OneshotReceiver<T>> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+// Bounded and unbounded channels
+
+// `Chan` is always heap-allocated, so the size of the bounded variant does not matter.
+#[allow(clippy::large_enum_variant)]
+enum Flavor<T> {
+    Bounded(ArrayQueue<T>),
+    Unbounded(Queue<T>),
+}
+
+struct Chan<T> {
+    queue: Flavor<T>,
+    senders: AtomicUsize,
+    receiver_alive: AtomicBool,
+    recv_waker: AtomicWaker,
+}
+
+/// Create a channel that can hold at most `cap` values.
+///
+/// # Panics
+///
+/// Panics if `cap` is zero. Such a channel would always be full, so [`Sender::send`] would never
+/// return; rendezvous channels are not supported.
+///
+/// # Example
+///
+/// ```
+/// use portable_atomic_util::channel::{self, TrySendError};
+///
+/// let (tx, mut rx) = channel::bounded(1);
+/// assert_eq!(tx.try_send(1), Ok(()));
+/// assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+/// assert_eq!(rx.try_recv(), Ok(1));
+/// ```
+#[must_use]
+pub fn bounded<T>(cap: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(cap > 0, "channel capacity must be non-zero");
+    new_chan(Flavor::Bounded(ArrayQueue::new(cap)))
+}
+
+/// Create a channel that can hold any number of values.
+///
+/// # Example
+///
+/// ```
+/// use portable_atomic_util::channel;
+///
+/// let (tx, mut rx) = channel::unbounded();
+/// for i in 0..100 {
+///     tx.try_send(i).unwrap();
+/// }
+/// assert_eq!(rx.try_recv(), Ok(0));
+/// ```
+#[must_use]
+pub fn unbounded<T>() -> (Sender<T>, Receiver<T>) {
+    new_chan(Flavor::Unbounded(Queue::new()))
+}
+
+fn new_chan<T>(queue: Flavor<T>) -> (Sender<T>, Receiver<T>) {
+    let chan = Arc::new(Chan {
+        queue,
+        senders: AtomicUsize::new(1),
+        receiver_alive: AtomicBool::new(true),
+        recv_waker: AtomicWaker::new(),
+    });
+    (Sender { chan: Arc::clone(&chan) }, Receiver { chan })
+}
+
+/// The sending half of a [`bounded`] or [`unbounded`] channel.
+///
+/// Senders can be cloned to send from multiple threads.
+pub struct Sender<T> {
+    chan: Arc<Chan<T>>,
+}
+
+// SAFETY: Values are moved to the receiving thread, so `T` must be `Send`. The queues and the
+// waker are thread-safe.
+unsafe impl<T: Send> Send for Sender<T> {}
+// SAFETY: See above.
+unsafe impl<T: Send> Sync for Sender<T> {}
+
+impl<T> Sender<T> {
+    /// Attempt to send a value without blocking.
+    ///
+    /// This never fails with [`TrySendError::Full`] for an [`unbounded`] channel.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error containing the value if the channel is full or the receiver has been
+    /// dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel::{self, TrySendError};
+    ///
+    /// let (tx, rx) = channel::bounded(1);
+    /// assert_eq!(tx.try_send(1), Ok(()));
+    /// assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+    /// drop(rx);
+    /// assert_eq!(tx.try_send(3), Err(TrySendError::Disconnected(3)));
+    /// ```
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        if !self.chan.receiver_alive.load(Acquire) {
+            return Err(TrySendError::Disconnected(value));
+        }
+        match &self.chan.queue {
+            Flavor::Bounded(q) => q.push(value).map_err(TrySendError::Full)?,
+            Flavor::Unbounded(q) => q.push(value),
+        }
+        self.chan.recv_waker.wake();
+        Ok(())
+    }
+
+    /// Send a value, waiting while a [`bounded`] channel is full.
+    ///
+    /// While the channel is full, this spins, yielding the current thread if the `std` feature
+    /// is enabled. Sending on an [`unbounded`] channel never waits.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error containing the value if the receiver has been dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel;
+    ///
+    /// let (tx, mut rx) = channel::bounded(1);
+    /// assert!(tx.send(1).is_ok());
+    /// assert_eq!(rx.try_recv(), Ok(1));
+    /// drop(rx);
+    /// assert_eq!(tx.send(2).unwrap_err().0, 2);
+    /// ```
+    pub fn send(&self, mut value: T) -> Result<(), SendError<T>> {
+        let mut backoff = Backoff::new();
+        loop {
+            match self.try_send(value) {
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Disconnected(v)) => return Err(SendError(v)),
+                Err(TrySendError::Full(v)) => value = v,
+            }
+            backoff.snooze();
+        }
+    }
+
+    /// Return `true` if the receiver has been dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel;
+    ///
+    /// let (tx, rx) = channel::unbounded::<i32>();
+    /// assert!(!tx.is_disconnected());
+    /// drop(rx);
+    /// assert!(tx.is_disconnected());
+    /// ```
+    #[must_use]
+    pub fn is_disconnected(&self) -> bool {
+        !self.chan.receiver_alive.load(Acquire)
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        // Relaxed is enough, as in `Arc::clone`: the new sender is created from an existing one.
+        self.chan.senders.fetch_add(1, Relaxed);
+        Self { chan: Arc::clone(&self.chan) }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.chan.senders.fetch_sub(1, AcqRel) == 1 {
+            // This was the last sender: wake the receiver so that it observes the disconnection.
+            self.chan.recv_waker.wake();
+        }
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Sender").finish()
+    }
+}
+
+/// The receiving half of a [`bounded`] or [`unbounded`] channel.
+///
+/// There is only one receiver per channel, so it cannot be cloned, and receiving requires
+/// `&mut self`.
+pub struct Receiver<T> {
+    chan: Arc<Chan<T>>,
+}
+
+// SAFETY: Values are moved to the receiving thread, so `T` must be `Send`.
+unsafe impl<T: Send> Send for Receiver<T> {}
+// SAFETY: All methods that receive values take `&mut self`.
+unsafe impl<T: Send> Sync for Receiver<T> {}
+
+impl<T> TryRecv for Receiver<T> {
+    type Item = T;
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        let chan = &*self.chan;
+        let pop = || match &chan.queue {
+            Flavor::Bounded(q) => q.pop(),
+            // SAFETY: `Receiver` is not `Clone` and receives through `&mut self`, so we are the
+            // only consumer.
+            Flavor::Unbounded(q) => match unsafe { q.pop() } {
+                PopResult::Data(v) => Some(v),
+                // If a sender is in the middle of pushing a value, report the channel as empty
+                // instead of waiting for it: the sender wakes the receiver after the push.
+                PopResult::Empty | PopResult::Inconsistent => None,
+            },
+        };
+        if let Some(v) = pop() {
+            return Ok(v);
+        }
+        if chan.senders.load(Acquire) != 0 {
+            return Err(TryRecvError::Empty);
+        }
+        // All senders have been dropped, but the last ones may have sent values after our first
+        // check.
+        pop().ok_or(TryRecvError::Disconnected)
+    }
+
+    fn waker(&self) -> &AtomicWaker {
+        &self.chan.recv_waker
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Attempt to receive a value without blocking.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TryRecvError::Empty`] if the channel is empty, and
+    /// [`TryRecvError::Disconnected`] if it is empty and all senders have been dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel::{self, TryRecvError};
+    ///
+    /// let (tx, mut rx) = channel::unbounded();
+    /// assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    /// tx.send(1).unwrap();
+    /// drop(tx);
+    /// assert_eq!(rx.try_recv(), Ok(1));
+    /// assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    /// ```
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        TryRecv::try_recv(self)
+    }
+
+    /// Receive a value, blocking the current thread until one is available.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RecvError`] if the channel is empty and all senders have been dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel::{self, RecvError};
+    /// use std::thread;
+    ///
+    /// let (tx, mut rx) = channel::bounded(1);
+    /// thread::spawn(move || tx.send(1).unwrap());
+    /// assert_eq!(rx.recv(), Ok(1));
+    /// assert_eq!(rx.recv(), Err(RecvError));
+    /// ```
+    #[cfg(feature = "std")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        TryRecv::recv(self)
+    }
+
+    /// Receive a value asynchronously.
+    ///
+    /// The returned future resolves to [`RecvError`] if the channel is empty and all senders
+    /// have been dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel;
+    /// # fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    /// #     use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    /// #     static VTABLE: RawWakerVTable = RawWakerVTable::new(|p| RawWaker::new(p, &VTABLE), |_| {}, |_| {}, |_| {});
+    /// #     let waker = unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) };
+    /// #     let mut f = Box::pin(f);
+    /// #     loop {
+    /// #         if let Poll::Ready(v) = f.as_mut().poll(&mut Context::from_waker(&waker)) {
+    /// #             return v;
+    /// #         }
+    /// #     }
+    /// # }
+    ///
+    /// let (tx, mut rx) = channel::unbounded();
+    /// tx.send(1).unwrap();
+    /// assert_eq!(block_on(rx.recv_async()), Ok(1));
+    /// ```
+    pub fn recv_async(&mut self) -> Recv<'_, Self> {
+        Recv { receiver: self }
+    }
+
+    /// Return `true` if all senders have been dropped.
+    ///
+    /// There may still be values left in the channel.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel;
+    ///
+    /// let (tx, rx) = channel::unbounded::<i32>();
+    /// assert!(!rx.is_disconnected());
+    /// drop(tx);
+    /// assert!(rx.is_disconnected());
+    /// ```
+    #[must_use]
+    pub fn is_disconnected(&self) -> bool {
+        self.chan.senders.load(Acquire) == 0
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.chan.receiver_alive.store(false, Release);
+    }
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Receiver").finish()
+    }
+}
+
+// Oneshot channel
+
+const EMPTY: usize = 0;
+const FULL: usize = 1;
+const CLOSED: usize = 2;
+
+struct Oneshot<T> {
+    // `EMPTY` until the value is sent (`FULL`) or either half is dropped (`CLOSED`). The value is
+    // taken out before the receiver moves the state from `FULL` to `CLOSED`.
+    state: AtomicUsize,
+    value: UnsafeCell<Option<T>>,
+    recv_waker: AtomicWaker,
+}
+
+/// Create a channel that can be used to send a single value.
+///
+/// # Example
+///
+/// ```
+/// use portable_atomic_util::channel;
+/// use std::thread;
+///
+/// let (tx, mut rx) = channel::oneshot();
+/// thread::spawn(move || tx.send(1).unwrap());
+/// assert_eq!(rx.recv(), Ok(1));
+/// ```
+#[must_use]
+pub fn oneshot<T>() -> (OneshotSender<T>, OneshotReceiver<T>) {
+    let chan = Arc::new(Oneshot {
+        state: AtomicUsize::new(EMPTY),
+        value: UnsafeCell::new(None),
+        recv_waker: AtomicWaker::new(),
+    });
+    (OneshotSender { chan: Some(Arc::clone(&chan)) }, OneshotReceiver { chan })
+}
+
+/// The sending half of a [`oneshot`] channel.
+pub struct OneshotSender<T> {
+    // `None` once the value has been sent.
+    chan: Option<Arc<Oneshot<T>>>,
+}
+
+// SAFETY: The value is moved to the receiving thread, so `T` must be `Send`. `value` is only
+// written by the sender before it publishes the state.
+unsafe impl<T: Send> Send for OneshotSender<T> {}
+// SAFETY: All methods that access `value` take `self` by value.
+unsafe impl<T: Send> Sync for OneshotSender<T> {}
+
+impl<T> OneshotSender<T> {
+    /// Send the value.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error containing the value if the receiver has been dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel;
+    ///
+    /// let (tx, rx) = channel::oneshot();
+    /// drop(rx);
+    /// assert_eq!(tx.send(1).unwrap_err().0, 1);
+    /// ```
+    pub fn send(mut self, value: T) -> Result<(), SendError<T>> {
+        let chan = match self.chan.take() {
+            Some(chan) => chan,
+            None => unreachable!(),
+        };
+        // SAFETY: The receiver only accesses `value` after observing `FULL`, which we have not
+        // stored yet.
+        unsafe { *chan.value.get() = Some(value) }
+        match chan.state.compare_exchange(EMPTY, FULL, AcqRel, Acquire) {
+            Ok(_) => {
+                chan.recv_waker.wake();
+                Ok(())
+            }
+            Err(_) => {
+                // The receiver has been dropped.
+                // SAFETY: The state is `CLOSED`, so the receiver never accesses `value` again.
+                match unsafe { (*chan.value.get()).take() } {
+                    Some(value) => Err(SendError(value)),
+                    None => unreachable!(),
+                }
+            }
+        }
+    }
+
+    /// Return `true` if the receiver has been dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel;
+    ///
+    /// let (tx, rx) = channel::oneshot::<i32>();
+    /// assert!(!tx.is_disconnected());
+    /// drop(rx);
+    /// assert!(tx.is_disconnected());
+    /// ```
+    #[must_use]
+    pub fn is_disconnected(&self) -> bool {
+        match &self.chan {
+            Some(chan) => chan.state.load(Acquire) == CLOSED,
+            None => unreachable!(),
+        }
+    }
+}
+
+impl<T> Drop for OneshotSender<T> {
+    fn drop(&mut self) {
+        if let Some(chan) = &self.chan {
+            // The value was never sent.
+            if chan.state.compare_exchange(EMPTY, CLOSED, AcqRel, Acquire).is_ok() {
+                chan.recv_waker.wake();
+            }
+        }
+    }
+}
+
+impl<T> fmt::Debug for OneshotSender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OneshotSender").finish()
+    }
+}
+
+/// The receiving half of a [`oneshot`] channel.
+pub struct OneshotReceiver<T> {
+    chan: Arc<Oneshot<T>>,
+}
+
+// SAFETY: The value is moved to the receiving thread, so `T` must be `Send`.
+unsafe impl<T: Send> Send for OneshotReceiver<T> {}
+// SAFETY: All methods that receive the value take `&mut self`.
+unsafe impl<T: Send> Sync for OneshotReceiver<T> {}
+
+impl<T> TryRecv for OneshotReceiver<T> {
+    type Item = T;
+
+    fn try_recv(&self) -> Result<T, TryRecvError> {
+        match self.chan.state.load(Acquire) {
+            EMPTY => Err(TryRecvError::Empty),
+            FULL => {
+                // SAFETY: The sender finished writing `value` before storing `FULL`, and the
+                // receiver receives through `&mut self`, so no one else accesses it.
+                let value = unsafe { (*self.chan.value.get()).take() };
+                self.chan.state.store(CLOSED, Release);
+                match value {
+                    Some(value) => Ok(value),
+                    None => unreachable!(),
+                }
+            }
+            _ => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    fn waker(&self) -> &AtomicWaker {
+        &self.chan.recv_waker
+    }
+}
+
+impl<T> OneshotReceiver<T> {
+    /// Attempt to receive the value without blocking.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`TryRecvError::Empty`] if the value has not been sent yet, and
+    /// [`TryRecvError::Disconnected`] if the sender was dropped without sending or the value
+    /// has already been received.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel::{self, TryRecvError};
+    ///
+    /// let (tx, mut rx) = channel::oneshot();
+    /// assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+    /// tx.send(1).unwrap();
+    /// assert_eq!(rx.try_recv(), Ok(1));
+    /// assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+    /// ```
+    pub fn try_recv(&mut self) -> Result<T, TryRecvError> {
+        TryRecv::try_recv(self)
+    }
+
+    /// Receive the value, blocking the current thread until it is sent.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RecvError`] if the sender was dropped without sending or the value has already
+    /// been received.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel::{self, RecvError};
+    /// use std::thread;
+    ///
+    /// let (tx, mut rx) = channel::oneshot::<i32>();
+    /// thread::spawn(move || drop(tx));
+    /// assert_eq!(rx.recv(), Err(RecvError));
+    /// ```
+    #[cfg(feature = "std")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+    pub fn recv(&mut self) -> Result<T, RecvError> {
+        TryRecv::recv(self)
+    }
+
+    /// Receive the value asynchronously.
+    ///
+    /// The returned future resolves to [`RecvError`] if the sender was dropped without sending
+    /// or the value has already been received.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::channel;
+    /// # fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    /// #     use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    /// #     static VTABLE: RawWakerVTable = RawWakerVTable::new(|p| RawWaker::new(p, &VTABLE), |_| {}, |_| {}, |_| {});
+    /// #     let waker = unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) };
+    /// #     let mut f = Box::pin(f);
+    /// #     loop {
+    /// #         if let Poll::Ready(v) = f.as_mut().poll(&mut Context::from_waker(&waker)) {
+    /// #             return v;
+    /// #         }
+    /// #     }
+    /// # }
+    ///
+    /// let (tx, mut rx) = channel::oneshot();
+    /// tx.send(1).unwrap();
+    /// assert_eq!(block_on(rx.recv_async()), Ok(1));
+    /// ```
+    pub fn recv_async(&mut self) -> Recv<'_, Self> {
+        Recv { receiver: self }
+    }
+}
+
+impl<T> Drop for OneshotReceiver<T> {
+    fn drop(&mut self) {
+        // If the value was sent but not received, it is dropped with `chan`.
+        self.chan.state.store(CLOSED, Release);
+    }
+}
+
+impl<T> fmt::Debug for OneshotReceiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("OneshotReceiver").finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::{block_on, DropCounter};
+
+    use std::{thread, vec::Vec};
+
+    use portable_atomic::{AtomicUsize, Ordering::Relaxed};
+
+    // Send `values` from `THREADS` senders and receive them with `recv` until all senders are
+    // dropped, checking that the values of each sender are received in order.
+    fn check_concurrent(
+        (tx, mut rx): (Sender<(usize, u32)>, Receiver<(usize, u32)>),
+        values: &[u32],
+        mut recv: impl FnMut(&mut Receiver<(usize, u32)>) -> Result<(usize, u32), RecvError>,
+    ) -> bool {
+        const THREADS: usize = 4;
+        let received = thread::scope(|s| {
+            for chunk in 0..THREADS {
+                let tx = tx.clone();
+                s.spawn(move || {
+                    for &v in values.iter().skip(chunk).step_by(THREADS) {
+                        tx.send((chunk, v)).unwrap();
+                    }
+                });
+            }
+            drop(tx);
+            let mut received = Vec::with_capacity(values.len());
+            while let Ok(v) = recv(&mut rx) {
+                received.push(v);
+            }
+            received
+        });
+        received.len() == values.len()
+            && (0..THREADS).all(|chunk| {
+                let from_chunk: Vec<_> =
+                    received.iter().filter(|&&(c, _)| c == chunk).map(|&(_, v)| v).collect();
+                let expected: Vec<_> =
+                    values.iter().skip(chunk).step_by(THREADS).copied().collect();
+                from_chunk == expected
+            })
+    }
+
+    ::quickcheck::quickcheck! {
+        fn quickcheck_bounded(cap: u8, values: Vec<u32>) -> bool {
+            let cap = usize::from(cap % 4) + 1;
+            check_concurrent(bounded(cap), &values, Receiver::recv)
+        }
+        fn quickcheck_unbounded(values: Vec<u32>) -> bool {
+            check_concurrent(unbounded(), &values, Receiver::recv)
+        }
+        fn quickcheck_unbounded_async(values: Vec<u32>) -> bool {
+            check_concurrent(unbounded(), &values, |rx| block_on(rx.recv_async()))
+        }
+        fn quickcheck_try_recv(values: Vec<u32>) -> bool {
+            // `try_recv` never waits for a sender that is in the middle of sending.
+            check_concurrent(unbounded(), &values, |rx| loop {
+                match rx.try_recv() {
+                    Ok(v) => return Ok(v),
+                    Err(TryRecvError::Empty) => thread::yield_now(),
+                    Err(TryRecvError::Disconnected) => return Err(RecvError),
+                }
+            })
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "channel capacity must be non-zero")]
+    fn bounded_zero_capacity() {
+        let _ = bounded::<i32>(0);
+    }
+
+    #[test]
+    fn drop_elements() {
+        fn check(new: fn() -> (Sender<DropCounter<'static>>, Receiver<DropCounter<'static>>)) {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+            COUNT.store(0, Relaxed);
+            let (tx, rx) = new();
+            thread::scope(|s| {
+                s.spawn(move || {
+                    for _ in 0..4 {
+                        tx.send(DropCounter(&COUNT)).ok().unwrap();
+                    }
+                });
+            });
+            drop(rx.recv().unwrap());
+            assert_eq!(COUNT.load(Relaxed), 1);
+            drop(rx);
+            assert_eq!(COUNT.load(Relaxed), 4);
+        }
+        check(|| bounded(4));
+        check(unbounded);
+
+        // Values sent after the receiver was dropped are returned.
+        let count = AtomicUsize::new(0);
+        let (tx, rx) = unbounded();
+        drop(rx);
+        drop(tx.send(DropCounter(&count)).unwrap_err());
+        assert_eq!(count.load(Relaxed), 1);
+    }
+
+    #[test]
+    fn oneshot_concurrent() {
+        let count = AtomicUsize::new(0);
+        for i in 0..1000 {
+            let (tx, mut rx) = oneshot();
+            thread::scope(|s| {
+                s.spawn(|| match i % 3 {
+                    0 => tx.send(DropCounter(&count)).ok().unwrap(),
+                    1 => drop(tx),
+                    _ => drop(tx.send(DropCounter(&count))),
+                });
+                match i % 3 {
+                    0 => drop(rx.recv().unwrap()),
+                    1 => assert_eq!(block_on(rx.recv_async()).err(), Some(RecvError)),
+                    // The value is dropped with the channel if it is not received.
+                    _ => drop(rx),
+                }
+            });
+        }
+        assert_eq!(count.load(Relaxed), 667);
+    }
+}
@@ -0,0 +1,294 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+// Each stripe is in its own cache line (see `CachePadded`), so threads adding to different
+// stripes do not contend. Reading the total has to visit every stripe, so this trades slower
+// reads for faster writes.
+
+use portable_atomic::Ordering::Relaxed;
+
+use crate::utils::CachePadded;
+
+use core::fmt;
+
+// Use `AtomicU64` stripes only if 64-bit atomics are native; otherwise `AtomicU64` would be backed
+// by portable-atomic's lock-based fallback (or not be available at all).
+#[cfg_attr(portable_atomic_no_cfg_target_has_atomic, cfg(target_pointer_width = "64"))]
+#[cfg_attr(not(portable_atomic_no_cfg_target_has_atomic), cfg(target_has_atomic = "64"))]
+type Stripe = portable_atomic::AtomicU64;
+#[cfg_attr(portable_atomic_no_cfg_target_has_atomic, cfg(target_pointer_width = "64"))]
+#[cfg_attr(not(portable_atomic_no_cfg_target_has_atomic), cfg(target_has_atomic = "64"))]
+type Value = u64;
+#[cfg_attr(portable_atomic_no_cfg_target_has_atomic, cfg(not(target_pointer_width = "64")))]
+#[cfg_attr(not(portable_atomic_no_cfg_target_has_atomic), cfg(not(target_has_atomic = "64")))]
+type Stripe = portable_atomic::AtomicUsize;
+#[cfg_attr(portable_atomic_no_cfg_target_has_atomic, cfg(not(target_pointer_width = "64")))]
+#[cfg_attr(not(portable_atomic_no_cfg_target_has_atomic), cfg(not(target_has_atomic = "64")))]
+type Value = usize;
+
+/// A counter split into `N` cache-padded stripes, for statistics that are updated much more often
+/// than they are read.
+///
+/// [`add`](Self::add) only updates one stripe, chosen per thread, so concurrent updates from
+/// different threads rarely contend. [`sum`](Self::sum) adds up all stripes.
+///
+/// `N` must be at least 1; `ShardedCounter<0>` is rejected at compile time.
+///
+/// The counter wraps around on overflow. If the target does not support 64-bit atomics natively,
+/// the stripes are [`AtomicUsize`](portable_atomic::AtomicUsize) instead of
+/// [`AtomicU64`](portable_atomic::AtomicU64), and the counter wraps around at `usize::MAX`.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::ShardedCounter;
+/// use std::thread;
+///
+/// static REQUESTS: ShardedCounter<8> = ShardedCounter::new();
+///
+/// let handles: Vec<_> = (0..4)
+///     .map(|_| {
+///         thread::spawn(|| {
+///             for _ in 0..1000 {
+///                 REQUESTS.add(1);
+///             }
+///         })
+///     })
+///     .collect();
+/// for handle in handles {
+///     handle.join().unwrap();
+/// }
+/// assert_eq!(REQUESTS.sum(), 4000);
+/// ```
+pub struct ShardedCounter<const N: usize> {
+    stripes: [CachePadded<Stripe>; N],
+}
+
+impl<const N: usize> ShardedCounter<N> {
+    #[allow(clippy::declare_interior_mutable_const)] // only used to initialize the array of stripes
+    const STRIPE: CachePadded<Stripe> = CachePadded::new(Stripe::new(0));
+    // Evaluating this fails to compile if `N` is 0, since there would be no stripe to add to.
+    const STRIPES_CHECK: () = [()][(N == 0) as usize];
+
+    /// Create a new counter with the value 0.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ShardedCounter;
+    ///
+    /// static COUNTER: ShardedCounter<8> = ShardedCounter::new();
+    /// assert_eq!(COUNTER.sum(), 0);
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::STRIPES_CHECK;
+        Self { stripes: [Self::STRIPE; N] }
+    }
+
+    /// Add `delta` to the stripe of the current thread.
+    ///
+    /// With the `std` feature, each thread is assigned a stripe in round-robin order when it first
+    /// uses a counter. Otherwise, the stripe is chosen from the address of the current stack, so
+    /// that different threads and interrupt handlers (which usually have different stacks) tend
+    /// to use different stripes. Use [`add_with_hint`](Self::add_with_hint) to choose the stripe
+    /// explicitly, for example by CPU number.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ShardedCounter;
+    ///
+    /// let counter = ShardedCounter::<8>::new();
+    /// counter.add(2);
+    /// counter.add(3);
+    /// assert_eq!(counter.sum(), 5);
+    /// ```
+    pub fn add(&self, delta: u64) {
+        self.add_with_hint(stripe_hint(), delta);
+    }
+
+    /// Add `delta` to the stripe selected by `hint`.
+    ///
+    /// The stripe is `hint % N`, so callers that know which CPU they are running on can pass the
+    /// CPU number to avoid contention entirely.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ShardedCounter;
+    ///
+    /// let counter = ShardedCounter::<8>::new();
+    /// counter.add_with_hint(3, 1);
+    /// assert_eq!(counter.sum(), 1);
+    /// ```
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn add_with_hint(&self, hint: usize, delta: u64) {
+        self.stripes[hint % N].fetch_add(delta as Value, Relaxed);
+    }
+
+    /// Return the sum of all stripes.
+    ///
+    /// This is not a snapshot: additions that happen while the stripes are being read may or may
+    /// not be included.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ShardedCounter;
+    ///
+    /// let counter = ShardedCounter::<8>::new();
+    /// counter.add_with_hint(0, 1);
+    /// counter.add_with_hint(1, 2);
+    /// assert_eq!(counter.sum(), 3);
+    /// ```
+    #[must_use]
+    pub fn sum(&self) -> u64 {
+        let mut sum: Value = 0;
+        for stripe in &self.stripes {
+            sum = sum.wrapping_add(stripe.load(Relaxed));
+        }
+        sum as u64
+    }
+
+    /// Set all stripes to 0.
+    ///
+    /// Additions that happen while the stripes are being reset may be lost. Use
+    /// [`swap_all`](Self::swap_all) to read and reset the counter without losing additions.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ShardedCounter;
+    ///
+    /// let counter = ShardedCounter::<8>::new();
+    /// counter.add(5);
+    /// counter.reset();
+    /// assert_eq!(counter.sum(), 0);
+    /// ```
+    pub fn reset(&self) {
+        for stripe in &self.stripes {
+            stripe.store(0, Relaxed);
+        }
+    }
+
+    /// Set all stripes to 0, and return the sum of their previous values.
+    ///
+    /// Each addition is counted exactly once: either in the returned sum, or in the counter
+    /// afterwards. This is useful for periodically exporting the number of events since the last
+    /// export.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ShardedCounter;
+    ///
+    /// let counter = ShardedCounter::<8>::new();
+    /// counter.add(5);
+    /// assert_eq!(counter.swap_all(), 5);
+    /// assert_eq!(counter.sum(), 0);
+    /// ```
+    pub fn swap_all(&self) -> u64 {
+        let mut sum: Value = 0;
+        for stripe in &self.stripes {
+            sum = sum.wrapping_add(stripe.swap(0, Relaxed));
+        }
+        sum as u64
+    }
+}
+
+impl<const N: usize> Default for ShardedCounter<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<const N: usize> fmt::Debug for ShardedCounter<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardedCounter").field("sum", &self.sum()).field("stripes", &N).finish()
+    }
+}
+
+#[cfg(feature = "std")]
+fn stripe_hint() -> usize {
+    use portable_atomic::AtomicUsize;
+
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    std::thread_local! {
+        static INDEX: usize = NEXT.fetch_add(1, Relaxed);
+    }
+    INDEX.try_with(|index| *index).unwrap_or_else(|_| stack_hint())
+}
+
+#[cfg(not(feature = "std"))]
+fn stripe_hint() -> usize {
+    stack_hint()
+}
+
+// Hash the address of the current stack, ignoring the low bits that differ between calls from
+// the same thread.
+#[allow(clippy::cast_possible_truncation)]
+fn stack_hint() -> usize {
+    let local = 0_u8;
+    let addr = (&local as *const u8 as usize >> 12) as u64;
+    // Fibonacci hashing.
+    (addr.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 32) as usize
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use portable_atomic::AtomicUsize;
+    use std::{sync::Barrier, thread};
+
+    #[test]
+    fn concurrent_add() {
+        const THREADS: usize = 4;
+        const N: usize = 10_000;
+        let counter = ShardedCounter::<4>::new();
+        let barrier = Barrier::new(THREADS);
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let (counter, barrier) = (&counter, &barrier);
+                s.spawn(move || {
+                    barrier.wait();
+                    for i in 0..N {
+                        if i % 2 == 0 {
+                            counter.add(1);
+                        } else {
+                            counter.add_with_hint(t, 1);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.sum(), (THREADS * N) as u64);
+    }
+
+    #[test]
+    fn concurrent_swap_all() {
+        const THREADS: usize = 4;
+        const N: usize = 10_000;
+        let counter = ShardedCounter::<2>::new();
+        let done = AtomicUsize::new(0);
+        let mut swapped = 0;
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..N {
+                        counter.add(1);
+                    }
+                    done.fetch_add(1, Relaxed);
+                });
+            }
+            while done.load(Relaxed) != THREADS {
+                swapped += counter.swap_all();
+                thread::yield_now();
+            }
+        });
+        // Each addition is either swapped out or still in the counter.
+        assert_eq!(swapped + counter.sum(), (THREADS * N) as u64);
+    }
+}
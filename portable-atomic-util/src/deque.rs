@@ -0,0 +1,931 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A Chase-Lev work-stealing deque.
+//!
+//! A [`Worker`] owns the deque: it pushes tasks to the back and pops them from the back (LIFO,
+//! created with [`Worker::new_lifo`]) or from the front (FIFO, created with
+//! [`Worker::new_fifo`]). Any number of [`Stealer`]s, which can be shared between threads, take
+//! tasks from the front, either one at a time or in batches moved into another worker.
+//!
+//! The buffer grows when it is full. Because stealers may still be reading from an old buffer,
+//! old buffers are not freed until the deque itself is dropped (when the worker and all stealers
+//! have been dropped). Since the capacity doubles each time, the old buffers take at most as much
+//! memory as the current one. This does not require the standard library or an epoch-based
+//! garbage collector.
+//!
+//! See "Dynamic Circular Work-Stealing Deque" by Chase and Lev, and "Correct and Efficient
+//! Work-Stealing for Weak Memory Models" by Lê et al. for the algorithm. The implementation
+//! follows crossbeam-deque.
+//!
+//! # Examples
+//!
+//! ```
+//! use portable_atomic_util::deque::{Steal, Worker};
+//! use std::thread;
+//!
+//! let worker = Worker::new_lifo();
+//! for i in 0..1000 {
+//!     worker.push(i);
+//! }
+//!
+//! let stealers: Vec<_> = (0..4)
+//!     .map(|_| {
+//!         let stealer = worker.stealer();
+//!         thread::spawn(move || {
+//!             let mut sum = 0;
+//!             loop {
+//!                 match stealer.steal() {
+//!                     Steal::Success(v) => sum += v,
+//!                     Steal::Empty => return sum,
+//!                     Steal::Retry => {}
+//!                 }
+//!             }
+//!         })
+//!     })
+//!     .collect();
+//!
+//! let mut sum = 0;
+//! while let Some(v) = worker.pop() {
+//!     sum += v;
+//! }
+//! for t in stealers {
+//!     sum += t.join().unwrap();
+//! }
+//! assert_eq!(sum, (0..1000).sum());
+//! ```
+
+// `front` is the index of the oldest task and `back` is the index one past the newest task. Both
+// only increase (except for the worker temporarily decrementing `back` in LIFO `pop`), wrap
+// around, and are compared with wrapping subtraction. The slot for an index `i` is
+// `i & (cap - 1)`.
+//
+// Only the worker writes to the buffer and `back`. Stealers claim tasks by incrementing `front`
+// with CAS after speculatively reading them.
+
+use portable_atomic::{
+    fence, AtomicIsize, AtomicPtr,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+
+use crate::{utils::CachePadded, Arc};
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    cell::{Cell, UnsafeCell},
+    fmt,
+    mem::MaybeUninit,
+    ptr,
+};
+
+// The capacity of a new deque.
+const MIN_CAP: usize = 64;
+// The maximum number of tasks moved by one batch steal.
+const MAX_BATCH: usize = 32;
+
+struct Buffer<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    // The buffer this one replaced, which is freed with this one.
+    prev: *mut Buffer<T>,
+}
+
+impl<T> Buffer<T> {
+    fn alloc(cap: usize, prev: *mut Self) -> *mut Self {
+        debug_assert!(cap.is_power_of_two());
+        let slots: Vec<_> = (0..cap).map(|_| UnsafeCell::new(MaybeUninit::uninit())).collect();
+        Box::into_raw(Box::new(Self { slots: slots.into_boxed_slice(), prev }))
+    }
+
+    fn cap(&self) -> usize {
+        self.slots.len()
+    }
+
+    fn at(&self, index: isize) -> *mut MaybeUninit<T> {
+        #[allow(clippy::cast_sign_loss)]
+        self.slots[index as usize & (self.cap() - 1)].get()
+    }
+
+    // SAFETY: The slot must not be written concurrently. Stealers read speculatively with
+    // `read_volatile` and discard the value if they lose the race.
+    unsafe fn write(&self, index: isize, value: T) {
+        // SAFETY: The caller guarantees that the slot is not accessed concurrently.
+        unsafe { ptr::write_volatile(self.at(index), MaybeUninit::new(value)) }
+    }
+
+    // SAFETY: The caller must only treat the value as initialized if it owns the task at
+    // `index`.
+    unsafe fn read(&self, index: isize) -> MaybeUninit<T> {
+        // SAFETY: The caller guarantees the validity of the slot.
+        unsafe { ptr::read_volatile(self.at(index)) }
+    }
+}
+
+struct Inner<T> {
+    front: AtomicIsize,
+    back: AtomicIsize,
+    buffer: CachePadded<AtomicPtr<Buffer<T>>>,
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let b = self.back.load(Relaxed);
+        let f = self.front.load(Relaxed);
+        let buffer = self.buffer.load(Relaxed);
+        // SAFETY: We have exclusive access. Tasks in `f..b` are initialized and owned by the
+        // deque, and the buffer chain was allocated by `Buffer::alloc`.
+        unsafe {
+            let mut i = f;
+            while i != b {
+                ptr::drop_in_place((*(*buffer).at(i)).as_mut_ptr());
+                i = i.wrapping_add(1);
+            }
+            let mut buffer = buffer;
+            while !buffer.is_null() {
+                let prev = (*buffer).prev;
+                drop(Box::from_raw(buffer));
+                buffer = prev;
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Flavor {
+    Fifo,
+    Lifo,
+}
+
+/// The result of a steal operation.
+#[allow(clippy::exhaustive_enums)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Steal<T> {
+    /// The deque was empty.
+    Empty,
+    /// A task was stolen.
+    Success(T),
+    /// Another thread won the race for the task; the operation should be retried.
+    Retry,
+}
+
+impl<T> Steal<T> {
+    /// Return `true` if the deque was empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::<i32>::new_lifo();
+    /// assert!(worker.stealer().steal().is_empty());
+    /// ```
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        match self {
+            Self::Empty => true,
+            _ => false,
+        }
+    }
+
+    /// Return `true` if a task was stolen.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::new_lifo();
+    /// worker.push(1);
+    /// assert!(worker.stealer().steal().is_success());
+    /// ```
+    #[must_use]
+    pub fn is_success(&self) -> bool {
+        match self {
+            Self::Success(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Return `true` if the operation should be retried.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Steal;
+    ///
+    /// assert!(Steal::<i32>::Retry.is_retry());
+    /// ```
+    #[must_use]
+    pub fn is_retry(&self) -> bool {
+        match self {
+            Self::Retry => true,
+            _ => false,
+        }
+    }
+
+    /// Return the stolen task, if any.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::new_lifo();
+    /// worker.push(1);
+    /// assert_eq!(worker.stealer().steal().success(), Some(1));
+    /// ```
+    pub fn success(self) -> Option<T> {
+        match self {
+            Self::Success(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+/// The owner side of a work-stealing deque.
+///
+/// A worker is not [`Sync`]; it is meant to be owned by a single thread, which pushes and pops
+/// tasks. Use [`stealer`](Self::stealer) to create handles for other threads.
+pub struct Worker<T> {
+    inner: Arc<CachePadded<Inner<T>>>,
+    // A copy of `inner.buffer`, which only the worker changes.
+    buffer: Cell<*mut Buffer<T>>,
+    flavor: Flavor,
+}
+
+// SAFETY: Tasks are moved between threads, so `T` must be `Send`. `Worker` is not `Sync`.
+unsafe impl<T: Send> Send for Worker<T> {}
+
+impl<T> Worker<T> {
+    fn new(flavor: Flavor) -> Self {
+        let buffer = Buffer::alloc(MIN_CAP, ptr::null_mut());
+        let inner = Arc::new(CachePadded::new(Inner {
+            front: AtomicIsize::new(0),
+            back: AtomicIsize::new(0),
+            buffer: CachePadded::new(AtomicPtr::new(buffer)),
+        }));
+        Self { inner, buffer: Cell::new(buffer), flavor }
+    }
+
+    /// Create a deque whose worker pops the oldest task first.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::new_fifo();
+    /// worker.push(1);
+    /// worker.push(2);
+    /// assert_eq!(worker.pop(), Some(1));
+    /// ```
+    #[must_use]
+    pub fn new_fifo() -> Self {
+        Self::new(Flavor::Fifo)
+    }
+
+    /// Create a deque whose worker pops the newest task first.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::new_lifo();
+    /// worker.push(1);
+    /// worker.push(2);
+    /// assert_eq!(worker.pop(), Some(2));
+    /// ```
+    #[must_use]
+    pub fn new_lifo() -> Self {
+        Self::new(Flavor::Lifo)
+    }
+
+    /// Create a stealer for this deque.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::new_lifo();
+    /// worker.push(1);
+    /// assert_eq!(worker.stealer().steal().success(), Some(1));
+    /// ```
+    #[must_use]
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer { inner: Arc::clone(&self.inner), flavor: self.flavor }
+    }
+
+    /// Return `true` if the deque is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::new_lifo();
+    /// assert!(worker.is_empty());
+    /// worker.push(1);
+    /// assert!(!worker.is_empty());
+    /// ```
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the number of tasks in the deque.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::new_lifo();
+    /// worker.push(1);
+    /// worker.push(2);
+    /// assert_eq!(worker.len(), 2);
+    /// ```
+    #[must_use]
+    pub fn len(&self) -> usize {
+        let b = self.inner.back.load(Relaxed);
+        let f = self.inner.front.load(SeqCst);
+        #[allow(clippy::cast_sign_loss)]
+        let len = b.wrapping_sub(f).max(0) as usize;
+        len
+    }
+
+    /// Push a task to the back of the deque.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::new_lifo();
+    /// worker.push(1);
+    /// assert_eq!(worker.pop(), Some(1));
+    /// ```
+    pub fn push(&self, value: T) {
+        let b = self.inner.back.load(Relaxed);
+        let f = self.inner.front.load(Acquire);
+        let mut buffer = self.buffer.get();
+
+        // SAFETY: `buffer` is the current buffer, which only the worker replaces.
+        #[allow(clippy::cast_possible_wrap)]
+        unsafe {
+            if b.wrapping_sub(f) >= (*buffer).cap() as isize {
+                self.resize((*buffer).cap() * 2);
+                buffer = self.buffer.get();
+            }
+            // The slot at `b` is not in `front..back`, so stealers do not claim it.
+            (*buffer).write(b, value);
+        }
+
+        // Publish the task to stealers.
+        self.inner.back.store(b.wrapping_add(1), Release);
+    }
+
+    /// Pop a task from the deque.
+    ///
+    /// A LIFO worker pops the newest task, and a FIFO worker pops the oldest task.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::new_fifo();
+    /// worker.push(1);
+    /// worker.push(2);
+    /// assert_eq!(worker.pop(), Some(1));
+    /// assert_eq!(worker.pop(), Some(2));
+    /// assert_eq!(worker.pop(), None);
+    /// ```
+    pub fn pop(&self) -> Option<T> {
+        let inner = &self.inner;
+        let b = inner.back.load(Relaxed);
+        let f = inner.front.load(Relaxed);
+        if b.wrapping_sub(f) <= 0 {
+            return None;
+        }
+        let buffer = self.buffer.get();
+
+        match self.flavor {
+            Flavor::Fifo => {
+                // Claim the front task. This races with stealers, which use CAS.
+                let f = inner.front.fetch_add(1, SeqCst);
+                if b.wrapping_sub(f.wrapping_add(1)) < 0 {
+                    // A stealer took the last task.
+                    inner.front.store(f, Relaxed);
+                    return None;
+                }
+                // SAFETY: We claimed the task at `f`.
+                Some(unsafe { (*buffer).read(f).assume_init() })
+            }
+            Flavor::Lifo => {
+                // Reserve the back task, then check whether stealers got to it first.
+                let b = b.wrapping_sub(1);
+                inner.back.store(b, Relaxed);
+                fence(SeqCst);
+                let f = inner.front.load(Relaxed);
+                let len = b.wrapping_sub(f);
+                if len < 0 {
+                    // The deque was emptied by stealers.
+                    inner.back.store(b.wrapping_add(1), Relaxed);
+                    return None;
+                }
+                // SAFETY: Stealers cannot claim `b` unless `front` reaches it, which we check
+                // below for the last task.
+                let value = unsafe { (*buffer).read(b) };
+                if len == 0 {
+                    // This is the last task: race with stealers for it.
+                    let won = inner.front.compare_exchange(f, f.wrapping_add(1), SeqCst, Relaxed);
+                    inner.back.store(b.wrapping_add(1), Relaxed);
+                    if won.is_err() {
+                        return None;
+                    }
+                }
+                // SAFETY: We own the task at `b`.
+                Some(unsafe { value.assume_init() })
+            }
+        }
+    }
+
+    // Replace the buffer with one of capacity `cap` holding the same tasks. The old buffer is
+    // kept until the deque is dropped, because stealers may still read from it.
+    fn resize(&self, cap: usize) {
+        let inner = &self.inner;
+        let b = inner.back.load(Relaxed);
+        let f = inner.front.load(Relaxed);
+        let old = self.buffer.get();
+        let new = Buffer::alloc(cap, old);
+        // SAFETY: Only the worker writes to buffers, and `new` is not shared yet. Tasks are
+        // copied bitwise; whichever copy is claimed through `front` is the owned one.
+        unsafe {
+            let mut i = f;
+            while i != b {
+                ptr::copy_nonoverlapping((*old).at(i), (*new).at(i), 1);
+                i = i.wrapping_add(1);
+            }
+        }
+        self.buffer.set(new);
+        inner.buffer.store(new, Release);
+    }
+
+    // Reserve room for `n` more tasks.
+    fn reserve(&self, n: usize) {
+        let b = self.inner.back.load(Relaxed);
+        let f = self.inner.front.load(SeqCst);
+        #[allow(clippy::cast_sign_loss)]
+        let len = b.wrapping_sub(f).max(0) as usize;
+        // SAFETY: `buffer` is the current buffer, which only the worker replaces.
+        let cap = unsafe { (*self.buffer.get()).cap() };
+        if cap - len < n {
+            let mut new_cap = cap;
+            while new_cap - len < n {
+                new_cap *= 2;
+            }
+            self.resize(new_cap);
+        }
+    }
+}
+
+impl<T> fmt::Debug for Worker<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Worker").finish()
+    }
+}
+
+/// A stealer handle of a work-stealing deque.
+///
+/// Stealers can be cloned and shared between threads.
+pub struct Stealer<T> {
+    inner: Arc<CachePadded<Inner<T>>>,
+    flavor: Flavor,
+}
+
+// SAFETY: Tasks are moved between threads, so `T` must be `Send`. All shared state is accessed
+// atomically.
+unsafe impl<T: Send> Send for Stealer<T> {}
+// SAFETY: See above.
+unsafe impl<T: Send> Sync for Stealer<T> {}
+
+impl<T> Stealer<T> {
+    /// Return `true` if the deque is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::new_lifo();
+    /// let stealer = worker.stealer();
+    /// assert!(stealer.is_empty());
+    /// worker.push(1);
+    /// assert!(!stealer.is_empty());
+    /// ```
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the number of tasks in the deque.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::Worker;
+    ///
+    /// let worker = Worker::new_lifo();
+    /// worker.push(1);
+    /// assert_eq!(worker.stealer().len(), 1);
+    /// ```
+    #[must_use]
+    pub fn len(&self) -> usize {
+        let f = self.inner.front.load(Acquire);
+        fence(SeqCst);
+        let b = self.inner.back.load(Acquire);
+        #[allow(clippy::cast_sign_loss)]
+        let len = b.wrapping_sub(f).max(0) as usize;
+        len
+    }
+
+    /// Steal the oldest task from the deque.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::{Steal, Worker};
+    ///
+    /// let worker = Worker::new_lifo();
+    /// worker.push(1);
+    /// worker.push(2);
+    /// let stealer = worker.stealer();
+    /// assert_eq!(stealer.steal(), Steal::Success(1));
+    /// assert_eq!(stealer.steal(), Steal::Success(2));
+    /// assert_eq!(stealer.steal(), Steal::Empty);
+    /// ```
+    #[must_use]
+    pub fn steal(&self) -> Steal<T> {
+        let inner = &self.inner;
+        let f = inner.front.load(Acquire);
+        // Order the load of `front` before the load of `back`, matching the fence in LIFO `pop`.
+        fence(SeqCst);
+        let b = inner.back.load(Acquire);
+        if b.wrapping_sub(f) <= 0 {
+            return Steal::Empty;
+        }
+
+        let buffer = inner.buffer.load(Acquire);
+        // SAFETY: Buffers are not freed while the deque is alive. The value is only treated as
+        // initialized if we claim the task below.
+        let value = unsafe { (*buffer).read(f) };
+
+        // If the buffer was replaced, the value may have been read from a buffer that never held
+        // the task at `f`.
+        if inner.buffer.load(Acquire) != buffer
+            || inner.front.compare_exchange(f, f.wrapping_add(1), SeqCst, Relaxed).is_err()
+        {
+            return Steal::Retry;
+        }
+        // SAFETY: We claimed the task at `f`.
+        Steal::Success(unsafe { value.assume_init() })
+    }
+
+    /// Steal a batch of tasks and push them to `dest`.
+    ///
+    /// About half of the tasks are stolen, up to an implementation-defined limit.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::{Steal, Worker};
+    ///
+    /// let worker = Worker::new_fifo();
+    /// for i in 0..4 {
+    ///     worker.push(i);
+    /// }
+    /// let dest = Worker::new_fifo();
+    /// assert_eq!(worker.stealer().steal_batch(&dest), Steal::Success(()));
+    /// assert_eq!(dest.pop(), Some(0));
+    /// assert_eq!(dest.pop(), Some(1));
+    /// assert_eq!(worker.pop(), Some(2));
+    /// ```
+    pub fn steal_batch(&self, dest: &Worker<T>) -> Steal<()> {
+        match self.steal_batch_inner(dest, false) {
+            Steal::Success(_) => Steal::Success(()),
+            Steal::Empty => Steal::Empty,
+            Steal::Retry => Steal::Retry,
+        }
+    }
+
+    /// Steal a batch of tasks, push them to `dest`, and return one of them.
+    ///
+    /// The oldest stolen task is returned, and the rest are pushed to `dest`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::deque::{Steal, Worker};
+    ///
+    /// let worker = Worker::new_fifo();
+    /// for i in 0..6 {
+    ///     worker.push(i);
+    /// }
+    /// let dest = Worker::new_fifo();
+    /// assert_eq!(worker.stealer().steal_batch_and_pop(&dest), Steal::Success(0));
+    /// assert_eq!(dest.pop(), Some(1));
+    /// assert_eq!(dest.pop(), Some(2));
+    /// assert_eq!(dest.pop(), None);
+    /// ```
+    pub fn steal_batch_and_pop(&self, dest: &Worker<T>) -> Steal<T> {
+        match self.steal_batch_inner(dest, true) {
+            Steal::Success(v) => match v {
+                Some(v) => Steal::Success(v),
+                None => unreachable!(),
+            },
+            Steal::Empty => Steal::Empty,
+            Steal::Retry => Steal::Retry,
+        }
+    }
+
+    fn steal_batch_inner(&self, dest: &Worker<T>, pop: bool) -> Steal<Option<T>> {
+        if Arc::ptr_eq(&self.inner, &dest.inner) {
+            return Steal::Empty;
+        }
+        let inner = &self.inner;
+        let f = inner.front.load(Acquire);
+        fence(SeqCst);
+        let b = inner.back.load(Acquire);
+        let len = b.wrapping_sub(f);
+        if len <= 0 {
+            return Steal::Empty;
+        }
+        // Steal half of the tasks, rounded up. When popping, one of them is returned instead of
+        // being pushed to `dest`.
+        #[allow(clippy::cast_sign_loss)]
+        let len = len as usize;
+        let count = ((len + 1) / 2).min(MAX_BATCH + usize::from(pop));
+        let to_dest = count - usize::from(pop);
+
+        dest.reserve(to_dest);
+        let dest_b = dest.inner.back.load(Relaxed);
+        let dest_buffer = dest.buffer.get();
+        let buffer = inner.buffer.load(Acquire);
+
+        let (first, stolen) = match self.flavor {
+            Flavor::Fifo => {
+                // The worker also claims tasks at the front with an atomic increment of `front`,
+                // so a single CAS claims the whole batch.
+                //
+                // Speculatively copy the tasks; they only become part of `dest` if the CAS
+                // succeeds.
+                // SAFETY: Buffers are not freed while the deque is alive, and `dest` is owned by
+                // the current thread (a `Worker` is not `Sync`), so the slots past its back are
+                // not accessed by anyone else.
+                let first = unsafe {
+                    let first = if pop { Some((*buffer).read(f)) } else { None };
+                    let skip = usize::from(pop);
+                    for i in 0..to_dest {
+                        #[allow(clippy::cast_possible_wrap)]
+                        let src = f.wrapping_add((i + skip) as isize);
+                        // A LIFO destination pops from the back, so the oldest task is placed at
+                        // the back.
+                        let j = match dest.flavor {
+                            Flavor::Fifo => i,
+                            Flavor::Lifo => to_dest - 1 - i,
+                        };
+                        #[allow(clippy::cast_possible_wrap)]
+                        let dst = dest_b.wrapping_add(j as isize);
+                        ptr::write_volatile((*dest_buffer).at(dst), (*buffer).read(src));
+                    }
+                    first
+                };
+
+                #[allow(clippy::cast_possible_wrap)]
+                let new_f = f.wrapping_add(count as isize);
+                if inner.buffer.load(Acquire) != buffer
+                    || inner.front.compare_exchange(f, new_f, SeqCst, Relaxed).is_err()
+                {
+                    // The copies are discarded.
+                    return Steal::Retry;
+                }
+                // SAFETY: We claimed the task at `f`.
+                (first.map(|v| unsafe { v.assume_init() }), to_dest)
+            }
+            Flavor::Lifo => {
+                // The worker takes tasks from the back without touching `front` unless only one
+                // is left, so it may take tasks that a single CAS of `front` over the whole batch
+                // would claim. Steal the tasks one at a time instead, checking `back` before
+                // each of them, as `steal` does.
+                let mut f = f;
+                let mut first = None;
+                let mut stolen = 0;
+                for i in 0..count {
+                    if i != 0 {
+                        // Order our last CAS of `front` before the load of `back`, matching the
+                        // fence in LIFO `pop`.
+                        fence(SeqCst);
+                        if inner.back.load(Acquire).wrapping_sub(f) <= 0 {
+                            break;
+                        }
+                    }
+                    // SAFETY: Buffers are not freed while the deque is alive. The value is only
+                    // treated as initialized if we claim the task below.
+                    let value = unsafe { (*buffer).read(f) };
+                    if inner.buffer.load(Acquire) != buffer
+                        || inner
+                            .front
+                            .compare_exchange(f, f.wrapping_add(1), SeqCst, Relaxed)
+                            .is_err()
+                    {
+                        break;
+                    }
+                    // SAFETY: We claimed the task at `f`.
+                    let value = unsafe { value.assume_init() };
+                    if pop && i == 0 {
+                        first = Some(value);
+                    } else {
+                        #[allow(clippy::cast_possible_wrap)]
+                        let dst = dest_b.wrapping_add(stolen as isize);
+                        // SAFETY: `dest` is owned by the current thread, so the slots past its
+                        // back are not accessed by anyone else.
+                        unsafe { (*dest_buffer).write(dst, value) }
+                        stolen += 1;
+                    }
+                    f = f.wrapping_add(1);
+                }
+                if first.is_none() && stolen == 0 {
+                    return Steal::Retry;
+                }
+                if dest.flavor == Flavor::Lifo {
+                    // A LIFO destination pops from the back, so the oldest task is placed at the
+                    // back.
+                    for i in 0..stolen / 2 {
+                        #[allow(clippy::cast_possible_wrap)]
+                        let (a, b) = (
+                            dest_b.wrapping_add(i as isize),
+                            dest_b.wrapping_add((stolen - 1 - i) as isize),
+                        );
+                        // SAFETY: See above. `a` and `b` are different slots holding stolen tasks.
+                        unsafe { ptr::swap((*dest_buffer).at(a), (*dest_buffer).at(b)) }
+                    }
+                }
+                (first, stolen)
+            }
+        };
+
+        #[allow(clippy::cast_possible_wrap)]
+        dest.inner.back.store(dest_b.wrapping_add(stolen as isize), Release);
+        Steal::Success(first)
+    }
+}
+
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Self {
+        Self { inner: Arc::clone(&self.inner), flavor: self.flavor }
+    }
+}
+
+impl<T> fmt::Debug for Stealer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Stealer").finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::DropCounter;
+
+    use std::{collections::VecDeque, thread, vec::Vec};
+
+    use portable_atomic::{AtomicBool, AtomicUsize};
+
+    #[derive(Clone, Copy, Debug)]
+    enum Op {
+        Push(u8),
+        Pop,
+        Steal,
+    }
+
+    impl quickcheck::Arbitrary for Op {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            match u8::arbitrary(g) % 3 {
+                0 => Op::Push(u8::arbitrary(g)),
+                1 => Op::Pop,
+                _ => Op::Steal,
+            }
+        }
+    }
+
+    // Check the deque against a `VecDeque` model.
+    fn check_model(worker: &Worker<u8>, lifo: bool, ops: &[Op]) -> bool {
+        let stealer = worker.stealer();
+        let mut model = VecDeque::new();
+        for &op in ops {
+            match op {
+                Op::Push(v) => {
+                    worker.push(v);
+                    model.push_back(v);
+                }
+                Op::Pop => {
+                    let expected = if lifo { model.pop_back() } else { model.pop_front() };
+                    assert_eq!(worker.pop(), expected);
+                }
+                Op::Steal => assert_eq!(stealer.steal().success(), model.pop_front()),
+            }
+            assert_eq!(worker.len(), model.len());
+            assert_eq!(stealer.is_empty(), model.is_empty());
+        }
+        true
+    }
+
+    ::quickcheck::quickcheck! {
+        fn quickcheck_fifo(ops: Vec<Op>) -> bool {
+            check_model(&Worker::new_fifo(), false, &ops)
+        }
+        fn quickcheck_lifo(ops: Vec<Op>) -> bool {
+            check_model(&Worker::new_lifo(), true, &ops)
+        }
+    }
+
+    // The worker pushes and pops while stealers steal with all methods, and every task must be
+    // dropped exactly once.
+    fn stress(
+        new: fn() -> Worker<DropCounter<'static>>,
+        new_dest: fn() -> Worker<DropCounter<'static>>,
+    ) {
+        const THREADS: usize = 3;
+        const N: usize = 100_000;
+        let drops: &'static [AtomicUsize] =
+            Box::leak((0..N).map(|_| AtomicUsize::new(0)).collect::<Vec<_>>().into_boxed_slice());
+        let worker = new();
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                let (stealer, done) = (worker.stealer(), &done);
+                s.spawn(move || {
+                    let dest = new_dest();
+                    let mut i = 0_usize;
+                    while !done.load(Acquire) {
+                        match i % 3 {
+                            0 => drop(stealer.steal()),
+                            1 => drop(stealer.steal_batch(&dest)),
+                            _ => drop(stealer.steal_batch_and_pop(&dest)),
+                        }
+                        while dest.pop().is_some() {}
+                        i = i.wrapping_add(1);
+                    }
+                });
+            }
+            for chunk in (0..N).collect::<Vec<_>>().chunks(64) {
+                for &i in chunk {
+                    worker.push(DropCounter(&drops[i]));
+                }
+                // Pop into the tasks that stealers may be taking from the front, but leave one
+                // so that the last pop does not move `front`.
+                while worker.len() > 1 {
+                    drop(worker.pop());
+                }
+            }
+            while worker.pop().is_some() {}
+            done.store(true, Release);
+        });
+        drop(worker);
+        for (i, d) in drops.iter().enumerate() {
+            assert_eq!(d.load(Relaxed), 1, "task {} was dropped {} times", i, d.load(Relaxed));
+        }
+    }
+
+    #[test]
+    fn stress_fifo() {
+        stress(Worker::new_fifo, Worker::new_fifo);
+        stress(Worker::new_fifo, Worker::new_lifo);
+    }
+
+    #[test]
+    fn stress_lifo() {
+        stress(Worker::new_lifo, Worker::new_fifo);
+        stress(Worker::new_lifo, Worker::new_lifo);
+    }
+
+    #[test]
+    fn steal_batch_order() {
+        for &(lifo_src, lifo_dest) in &[(false, false), (false, true), (true, false), (true, true)]
+        {
+            let worker = if lifo_src { Worker::new_lifo() } else { Worker::new_fifo() };
+            for i in 0..8 {
+                worker.push(i);
+            }
+            let dest = if lifo_dest { Worker::new_lifo() } else { Worker::new_fifo() };
+            assert_eq!(worker.stealer().steal_batch_and_pop(&dest), Steal::Success(0));
+            // The oldest stolen task is popped first from either kind of destination.
+            assert_eq!(dest.pop(), Some(1));
+            assert_eq!(dest.pop(), Some(2));
+            assert_eq!(dest.pop(), Some(3));
+            assert_eq!(dest.pop(), None);
+            assert_eq!(worker.len(), 4);
+        }
+    }
+}
@@ -0,0 +1,1511 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Epoch-based memory reclamation.
+//!
+//! Lock-free data structures cannot free a node as soon as it is unlinked, because other threads
+//! may still be reading it. With epoch-based reclamation, threads [pin](LocalHandle::pin)
+//! themselves while they access shared nodes, and unlinked nodes are
+//! [deferred](Guard::defer_destroy) until every thread that was pinned at the time has unpinned.
+//!
+//! - A [`Collector`] holds the global epoch and the garbage of finished participants.
+//! - Each participant (usually a thread) [registers](Collector::register) a [`LocalHandle`],
+//!   which has a local epoch and a local bag of garbage.
+//! - Pinning returns a [`Guard`]. Pointers loaded from an [`Atomic`] while pinned are
+//!   [`Shared`] pointers bound to the lifetime of the guard.
+//!
+//! This only requires `alloc` and the atomic operations provided by portable-atomic, so it also
+//! works on targets without native atomic CAS when portable-atomic's `critical-section` feature
+//! (or `unsafe-assume-single-core` cfg) is enabled. With the `std` feature, [`pin`] pins the
+//! current thread to a default collector through a thread-local handle.
+//!
+//! The API and the algorithm follow crossbeam-epoch.
+//!
+//! # Examples
+//!
+//! ```
+//! use portable_atomic::Ordering::{AcqRel, Acquire};
+//! use portable_atomic_util::epoch::{self, Atomic, Owned};
+//!
+//! let collector = epoch::Collector::new();
+//! let handle = collector.register();
+//!
+//! let a = Atomic::new(1);
+//! let guard = handle.pin();
+//! let old = a.swap(Owned::new(2), AcqRel, &guard);
+//! // SAFETY: `old` was unlinked by the swap, and no new references to it can be created.
+//! unsafe { guard.defer_destroy(old) };
+//! // SAFETY: `a` is not null and its value is protected by `guard`.
+//! assert_eq!(unsafe { *a.load(Acquire, &guard).deref() }, 2);
+//! drop(guard);
+//!
+//! // SAFETY: We have the only reference to `a`.
+//! drop(unsafe { a.into_owned() });
+//! ```
+
+// Each participant has a `Local` node in the collector's list of participants. Nodes are never
+// unlinked while the collector is alive; a node whose participant is gone is marked as unused
+// and reused by the next registration.
+//
+// The global epoch advances by `EPOCH_STEP`, so that the low bit of a local epoch can mark
+// whether the participant is pinned. The global epoch can only advance when every pinned
+// participant is pinned in the current epoch, so garbage deferred in epoch `e` can be destroyed
+// once the global epoch reaches `e + 2 * EPOCH_STEP`: every participant that could still see it
+// has unpinned by then.
+
+use portable_atomic::{
+    fence, AtomicBool, AtomicPtr, AtomicUsize,
+    Ordering::{self, Acquire, Relaxed, Release, SeqCst},
+};
+
+use crate::{utils::CachePadded, Arc};
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    borrow::{Borrow, BorrowMut},
+    cell::{Cell, UnsafeCell},
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+    ptr,
+};
+
+const PINNED: usize = 1;
+const EPOCH_STEP: usize = 2;
+// Collect garbage on every `PINS_BETWEEN_COLLECT`th pin.
+const PINS_BETWEEN_COLLECT: usize = 128;
+// Collect garbage when a local bag has this many items.
+const MAX_LOCAL_GARBAGE: usize = 64;
+
+// Garbage
+
+// A deferred function, which is called at most once.
+struct Deferred {
+    call: unsafe fn(*mut u8),
+    data: *mut u8,
+}
+
+impl Deferred {
+    fn new<F: FnOnce()>(f: F) -> Self {
+        unsafe fn call<F: FnOnce()>(data: *mut u8) {
+            // SAFETY: `data` was created from `Box<F>` in `Deferred::new`.
+            let f = unsafe { Box::from_raw(data as *mut F) };
+            f();
+        }
+        Self { call: call::<F>, data: Box::into_raw(Box::new(f)) as *mut u8 }
+    }
+
+    fn call(self) {
+        // SAFETY: `call` and `data` were created together in `new`, and `self` is consumed.
+        unsafe { (self.call)(self.data) }
+    }
+}
+
+// Deferred functions, each with the global epoch in which it was deferred.
+type Garbage = Vec<(usize, Deferred)>;
+
+// Call the deferred functions that are no longer reachable in `global_epoch`, and keep the rest.
+fn collect_expired(garbage: &mut Garbage, global_epoch: usize) {
+    let mut i = 0;
+    while i < garbage.len() {
+        if global_epoch.wrapping_sub(garbage[i].0) >= 2 * EPOCH_STEP {
+            garbage.swap_remove(i).1.call();
+        } else {
+            i += 1;
+        }
+    }
+}
+
+// Garbage left behind by a participant that is gone.
+struct Bag {
+    garbage: Garbage,
+    next: *mut Bag,
+}
+
+// Global
+
+struct Global {
+    epoch: CachePadded<AtomicUsize>,
+    // The list of participants, linked through `Local::next`.
+    locals: AtomicPtr<Local>,
+    // A stack of bags left behind by participants that are gone.
+    orphans: AtomicPtr<Bag>,
+}
+
+impl Global {
+    // Advance the global epoch if every pinned participant is pinned in the current epoch, and
+    // return the (possibly new) global epoch.
+    fn try_advance(&self) -> usize {
+        let epoch = self.epoch.load(Relaxed);
+        fence(SeqCst);
+        let mut local = self.locals.load(Acquire);
+        while !local.is_null() {
+            // SAFETY: Nodes are not freed while the collector is alive.
+            let local_ref = unsafe { &*local };
+            let local_epoch = local_ref.epoch.load(Relaxed);
+            if local_epoch & PINNED != 0 && local_epoch & !PINNED != epoch {
+                return epoch;
+            }
+            local = local_ref.next;
+        }
+        fence(Acquire);
+        let new = epoch.wrapping_add(EPOCH_STEP);
+        match self.epoch.compare_exchange(epoch, new, Release, Relaxed) {
+            Ok(_) => new,
+            Err(current) => current,
+        }
+    }
+
+    fn push_orphan(&self, garbage: Garbage) {
+        let bag = Box::into_raw(Box::new(Bag { garbage, next: ptr::null_mut() }));
+        let mut head = self.orphans.load(Relaxed);
+        loop {
+            // SAFETY: `bag` is not shared until the CAS succeeds.
+            unsafe { (*bag).next = head }
+            match self.orphans.compare_exchange_weak(head, bag, Release, Relaxed) {
+                Ok(_) => return,
+                Err(h) => head = h,
+            }
+        }
+    }
+
+    fn collect_orphans(&self, global_epoch: usize) {
+        if self.orphans.load(Relaxed).is_null() {
+            return;
+        }
+        // Take the whole stack, so that no other thread accesses the bags.
+        let mut bag = self.orphans.swap(ptr::null_mut(), Acquire);
+        while !bag.is_null() {
+            // SAFETY: We took the bag out of the stack, and it was created by `push_orphan`.
+            let mut b = unsafe { Box::from_raw(bag) };
+            bag = b.next;
+            collect_expired(&mut b.garbage, global_epoch);
+            if !b.garbage.is_empty() {
+                self.push_orphan(mem::replace(&mut b.garbage, Vec::new()));
+            }
+        }
+    }
+}
+
+impl Drop for Global {
+    fn drop(&mut self) {
+        // All participants are gone, so all garbage can be destroyed.
+        // SAFETY: We have exclusive access, and the bags and nodes were created by
+        // `push_orphan` and `register`.
+        unsafe {
+            let mut bag = *self.orphans.get_mut();
+            while !bag.is_null() {
+                let b = Box::from_raw(bag);
+                bag = b.next;
+                for (_, deferred) in b.garbage {
+                    deferred.call();
+                }
+            }
+            let mut local = *self.locals.get_mut();
+            while !local.is_null() {
+                let l = Box::from_raw(local);
+                local = l.next;
+                debug_assert!((*l.garbage.get()).is_empty());
+            }
+        }
+    }
+}
+
+/// A garbage collector for epoch-based reclamation.
+///
+/// Participants register with [`register`](Self::register). Cloning a collector returns a
+/// handle to the same collector. The collector is freed, destroying all remaining garbage, when
+/// the last clone and the last participant are gone.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::epoch::Collector;
+///
+/// let collector = Collector::new();
+/// let handle = collector.register();
+/// let guard = handle.pin();
+/// guard.defer(|| println!("deferred"));
+/// ```
+pub struct Collector {
+    global: Arc<Global>,
+}
+
+impl Collector {
+    /// Create a new collector.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Collector;
+    ///
+    /// let collector = Collector::new();
+    /// ```
+    #[must_use]
+    pub fn new() -> Self {
+        Self {
+            global: Arc::new(Global {
+                epoch: CachePadded::new(AtomicUsize::new(0)),
+                locals: AtomicPtr::new(ptr::null_mut()),
+                orphans: AtomicPtr::new(ptr::null_mut()),
+            }),
+        }
+    }
+
+    /// Register a new participant.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Collector;
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// assert!(!handle.is_pinned());
+    /// ```
+    #[must_use]
+    pub fn register(&self) -> LocalHandle {
+        let global = &*self.global;
+        // Reuse the node of a participant that is gone, if any.
+        let mut local = global.locals.load(Acquire);
+        while !local.is_null() {
+            // SAFETY: Nodes are not freed while the collector is alive.
+            let l = unsafe { &*local };
+            if l.in_use.compare_exchange(false, true, Acquire, Relaxed).is_ok() {
+                break;
+            }
+            local = l.next;
+        }
+
+        if local.is_null() {
+            local = Box::into_raw(Box::new(Local {
+                next: ptr::null_mut(),
+                in_use: AtomicBool::new(true),
+                epoch: AtomicUsize::new(0),
+                collector: UnsafeCell::new(None),
+                guard_count: Cell::new(0),
+                handle_count: Cell::new(0),
+                pin_count: Cell::new(0),
+                garbage: UnsafeCell::new(Vec::new()),
+            }));
+            let mut head = global.locals.load(Relaxed);
+            loop {
+                // SAFETY: `local` is not shared until the CAS succeeds.
+                unsafe { (*local).next = head }
+                match global.locals.compare_exchange_weak(head, local, Release, Relaxed) {
+                    Ok(_) => break,
+                    Err(h) => head = h,
+                }
+            }
+        }
+
+        // SAFETY: We own the node, since we set `in_use`.
+        unsafe {
+            *(*local).collector.get() = Some(self.clone());
+            (*local).handle_count.set(1);
+        }
+        LocalHandle { local }
+    }
+}
+
+impl Clone for Collector {
+    fn clone(&self) -> Self {
+        Self { global: Arc::clone(&self.global) }
+    }
+}
+
+impl Default for Collector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Collector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Collector").finish()
+    }
+}
+
+impl PartialEq for Collector {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.global, &other.global)
+    }
+}
+impl Eq for Collector {}
+
+// Local
+
+struct Local {
+    next: *mut Local,
+    in_use: AtomicBool,
+    // The global epoch when pinned, with `PINNED` set, or 0 when not pinned.
+    epoch: AtomicUsize,
+    // The following fields are only accessed by the owner of the node (the thread holding the
+    // handle and guards) while `in_use` is set.
+    // Keeps the collector alive while the participant exists.
+    collector: UnsafeCell<Option<Collector>>,
+    guard_count: Cell<usize>,
+    handle_count: Cell<usize>,
+    pin_count: Cell<usize>,
+    garbage: UnsafeCell<Garbage>,
+}
+
+impl Local {
+    fn global(&self) -> &Global {
+        // SAFETY: `collector` is set while the node is in use, and only the owner accesses it.
+        match unsafe { &*self.collector.get() } {
+            Some(collector) => &collector.global,
+            None => unreachable!(),
+        }
+    }
+
+    fn pin(&self) -> Guard {
+        let guard = Guard { local: self };
+        let count = self.guard_count.get();
+        self.guard_count.set(count.checked_add(1).expect("guard count overflow"));
+        if count == 0 {
+            let global_epoch = self.global().epoch.load(Relaxed);
+            self.epoch.store(global_epoch | PINNED, Relaxed);
+            // Make the pin visible before any shared pointer is loaded, and pairs with the fence
+            // in `try_advance`.
+            fence(SeqCst);
+
+            let pins = self.pin_count.get().wrapping_add(1);
+            self.pin_count.set(pins);
+            if pins % PINS_BETWEEN_COLLECT == 0 {
+                self.collect();
+            }
+        }
+        guard
+    }
+
+    fn unpin(&self) {
+        let count = self.guard_count.get() - 1;
+        self.guard_count.set(count);
+        if count == 0 {
+            self.epoch.store(0, Release);
+            if self.handle_count.get() == 0 {
+                self.finalize();
+            }
+        }
+    }
+
+    fn defer(&self, deferred: Deferred) {
+        // The garbage was unlinked before this point, so stamp it with an epoch that is not
+        // older than the unlinking.
+        fence(SeqCst);
+        let epoch = self.global().epoch.load(Relaxed);
+        // SAFETY: Only the owner accesses `garbage`.
+        let len = unsafe {
+            let garbage = &mut *self.garbage.get();
+            garbage.push((epoch, deferred));
+            garbage.len()
+        };
+        if len >= MAX_LOCAL_GARBAGE {
+            self.collect();
+        }
+    }
+
+    fn collect(&self) {
+        let global = self.global();
+        let epoch = global.try_advance();
+        // Take the bag out while calling deferred functions, since they may defer more garbage.
+        // SAFETY: Only the owner accesses `garbage`.
+        let mut garbage = mem::replace(unsafe { &mut *self.garbage.get() }, Vec::new());
+        collect_expired(&mut garbage, epoch);
+        // SAFETY: See above.
+        unsafe { (*self.garbage.get()).append(&mut garbage) }
+        global.collect_orphans(epoch);
+    }
+
+    // Called when the last handle and guard are gone.
+    fn finalize(&self) {
+        debug_assert_eq!(self.guard_count.get(), 0);
+        // SAFETY: Only the owner accesses `collector`.
+        let collector = unsafe { (*self.collector.get()).take() };
+        let collector = match collector {
+            Some(collector) => collector,
+            None => unreachable!(),
+        };
+        // SAFETY: Only the owner accesses `garbage`.
+        let garbage = mem::replace(unsafe { &mut *self.garbage.get() }, Vec::new());
+        if !garbage.is_empty() {
+            collector.global.push_orphan(garbage);
+        }
+        self.in_use.store(false, Release);
+        // This may free the collector and this node; `self` must not be used after this.
+        drop(collector);
+    }
+}
+
+/// A participant of a [`Collector`].
+///
+/// A handle is bound to the thread that created it: it is neither [`Send`] nor [`Sync`].
+pub struct LocalHandle {
+    local: *const Local,
+}
+
+impl LocalHandle {
+    /// Pin the participant.
+    ///
+    /// While the returned guard (or any other guard of this participant) is alive, objects
+    /// deferred by other participants after this call are not destroyed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of guards overflows `usize`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Collector;
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// let guard = handle.pin();
+    /// assert!(handle.is_pinned());
+    /// drop(guard);
+    /// assert!(!handle.is_pinned());
+    /// ```
+    #[must_use]
+    pub fn pin(&self) -> Guard {
+        self.local().pin()
+    }
+
+    /// Return `true` if the participant is pinned.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Collector;
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// assert!(!handle.is_pinned());
+    /// ```
+    #[must_use]
+    pub fn is_pinned(&self) -> bool {
+        self.local().guard_count.get() > 0
+    }
+
+    /// Return the collector of this participant.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Collector;
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// assert!(*handle.collector() == collector);
+    /// ```
+    #[must_use]
+    pub fn collector(&self) -> &Collector {
+        // SAFETY: `collector` is set while the handle is alive.
+        match unsafe { &*self.local().collector.get() } {
+            Some(collector) => collector,
+            None => unreachable!(),
+        }
+    }
+
+    fn local(&self) -> &Local {
+        // SAFETY: The node is kept alive by the collector, which the node keeps alive while the
+        // handle exists.
+        unsafe { &*self.local }
+    }
+}
+
+impl Drop for LocalHandle {
+    fn drop(&mut self) {
+        let local = self.local();
+        let count = local.handle_count.get() - 1;
+        local.handle_count.set(count);
+        if count == 0 && local.guard_count.get() == 0 {
+            local.finalize();
+        }
+    }
+}
+
+impl fmt::Debug for LocalHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalHandle").finish()
+    }
+}
+
+// Guard
+
+/// A guard that keeps the current participant pinned.
+///
+/// [`Shared`] pointers loaded with a guard cannot outlive it. A guard is neither [`Send`] nor
+/// [`Sync`].
+pub struct Guard {
+    // Null for the guard returned by `unprotected`.
+    local: *const Local,
+}
+
+impl Guard {
+    /// Defer a function until all participants pinned at the time of the call have unpinned.
+    ///
+    /// If this is the [`unprotected`] guard, `f` is called immediately.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Collector;
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// let guard = handle.pin();
+    /// guard.defer(|| println!("deferred"));
+    /// ```
+    pub fn defer<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // SAFETY: `f` is `Send` and `'static`.
+        unsafe { self.defer_unchecked(f) }
+    }
+
+    /// Defer a function until all participants pinned at the time of the call have unpinned.
+    ///
+    /// If this is the [`unprotected`] guard, `f` is called immediately.
+    ///
+    /// # Safety
+    ///
+    /// `f` may be called on any thread, at any time after the call, until the collector is
+    /// dropped. It must be safe to do so even though `f` may not be `Send` or `'static`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Collector;
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// let guard = handle.pin();
+    /// // SAFETY: The closure only prints a message.
+    /// unsafe { guard.defer_unchecked(|| println!("deferred")) };
+    /// ```
+    pub unsafe fn defer_unchecked<F: FnOnce()>(&self, f: F) {
+        match self.local() {
+            Some(local) => local.defer(Deferred::new(f)),
+            None => f(),
+        }
+    }
+
+    /// Defer destroying the object `ptr` points to until all participants pinned at the time of
+    /// the call have unpinned.
+    ///
+    /// If this is the [`unprotected`] guard, the object is destroyed immediately.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been created from an [`Owned`], must have been unlinked from any shared
+    /// data structure so that no new references to it can be created, and must not be destroyed
+    /// twice. The object may be destroyed on any thread.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::Ordering::AcqRel;
+    /// use portable_atomic_util::epoch::{Atomic, Collector, Shared};
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// let a = Atomic::new(1);
+    /// let guard = handle.pin();
+    /// let old = a.swap(Shared::null(), AcqRel, &guard);
+    /// // SAFETY: `old` was unlinked by the swap and is destroyed only once.
+    /// unsafe { guard.defer_destroy(old) };
+    /// ```
+    pub unsafe fn defer_destroy<T>(&self, ptr: Shared<'_, T>) {
+        let raw = ptr.as_raw() as *mut T;
+        if raw.is_null() {
+            return;
+        }
+        // SAFETY: The caller guarantees that `ptr` was created from an `Owned` and is not
+        // destroyed twice.
+        unsafe { self.defer_unchecked(move || drop(Owned::from_raw(raw))) }
+    }
+
+    /// Try to advance the global epoch and destroy expired garbage.
+    ///
+    /// This is done automatically from time to time, so calling this is not required.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Collector;
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// handle.pin().flush();
+    /// ```
+    pub fn flush(&self) {
+        if let Some(local) = self.local() {
+            local.collect();
+        }
+    }
+
+    fn local(&self) -> Option<&Local> {
+        // SAFETY: The node is kept alive while the participant has guards.
+        unsafe { self.local.as_ref() }
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Some(local) = self.local() {
+            local.unpin();
+        }
+    }
+}
+
+impl fmt::Debug for Guard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Guard").finish()
+    }
+}
+
+/// Return a dummy guard that does not pin anything.
+///
+/// Deferred functions and objects are destroyed immediately through this guard. This is useful
+/// to access a data structure that is not shared, for example in its `Drop` implementation.
+///
+/// # Safety
+///
+/// Loaded pointers must not be dereferenced unless no other thread can concurrently destroy the
+/// objects they point to.
+///
+/// # Example
+///
+/// ```
+/// use portable_atomic::Ordering::Relaxed;
+/// use portable_atomic_util::epoch::{self, Atomic};
+///
+/// let a = Atomic::new(1);
+/// // SAFETY: `a` is not shared.
+/// unsafe {
+///     let guard = epoch::unprotected();
+///     let p = a.load(Relaxed, guard);
+///     assert_eq!(*p.deref(), 1);
+///     guard.defer_destroy(p);
+/// }
+/// ```
+#[must_use]
+pub unsafe fn unprotected() -> &'static Guard {
+    struct Unprotected(Guard);
+    // SAFETY: The unprotected guard has no participant and no state.
+    unsafe impl Sync for Unprotected {}
+    static UNPROTECTED: Unprotected = Unprotected(Guard { local: ptr::null() });
+    &UNPROTECTED.0
+}
+
+// Default collector
+
+#[cfg(feature = "std")]
+fn with_handle<R>(f: impl FnOnce(&LocalHandle) -> R) -> R {
+    std::thread_local! {
+        static HANDLE: LocalHandle = default_collector().register();
+    }
+    let mut f = Some(f);
+    HANDLE
+        .try_with(|handle| match f.take() {
+            Some(f) => f(handle),
+            None => unreachable!(),
+        })
+        .unwrap_or_else(|_| {
+            // The thread-local handle has been destroyed; use a temporary one.
+            match f.take() {
+                Some(f) => f(&default_collector().register()),
+                None => unreachable!(),
+            }
+        })
+}
+
+/// Return the default collector, which is used by [`pin`].
+///
+/// # Example
+///
+/// ```
+/// use portable_atomic_util::epoch;
+///
+/// let handle = epoch::default_collector().register();
+/// ```
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[must_use]
+pub fn default_collector() -> &'static Collector {
+    static COLLECTOR: crate::OnceLock<Collector> = crate::OnceLock::new();
+    COLLECTOR.get_or_init(Collector::new)
+}
+
+/// Pin the current thread to the [default collector](default_collector).
+///
+/// # Example
+///
+/// ```
+/// use portable_atomic_util::epoch;
+///
+/// let guard = epoch::pin();
+/// assert!(epoch::is_pinned());
+/// drop(guard);
+/// assert!(!epoch::is_pinned());
+/// ```
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[must_use]
+pub fn pin() -> Guard {
+    with_handle(LocalHandle::pin)
+}
+
+/// Return `true` if the current thread is pinned to the [default collector](default_collector).
+///
+/// # Example
+///
+/// ```
+/// use portable_atomic_util::epoch;
+///
+/// assert!(!epoch::is_pinned());
+/// ```
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[must_use]
+pub fn is_pinned() -> bool {
+    with_handle(LocalHandle::is_pinned)
+}
+
+// Pointers
+
+/// A pointer type that can be stored in an [`Atomic`]: [`Owned`] or [`Shared`].
+pub trait Pointer<T> {
+    /// Convert the pointer into a raw pointer, transferring its ownership if any.
+    fn into_ptr(self) -> *mut T;
+
+    /// Convert a raw pointer created by [`into_ptr`](Self::into_ptr) back into the pointer.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been returned by `into_ptr` of the same pointer type, and must not be
+    /// converted back more than once.
+    unsafe fn from_ptr(ptr: *mut T) -> Self;
+}
+
+/// An atomic pointer that can be safely shared between threads.
+///
+/// The pointed-to object is only accessed through [`Shared`] pointers bound to a [`Guard`].
+/// Dropping an `Atomic` does not drop the object; use [`into_owned`](Self::into_owned) or
+/// [`Guard::defer_destroy`].
+pub struct Atomic<T> {
+    ptr: AtomicPtr<T>,
+    _marker: PhantomData<Box<T>>,
+}
+
+// SAFETY: The object is shared between threads, so `T` must be `Send + Sync`.
+unsafe impl<T: Send + Sync> Send for Atomic<T> {}
+// SAFETY: See above.
+unsafe impl<T: Send + Sync> Sync for Atomic<T> {}
+
+/// An error returned from [`Atomic::compare_exchange`] and [`Atomic::compare_exchange_weak`].
+#[allow(clippy::exhaustive_structs)]
+pub struct CompareExchangeError<'g, T, P: Pointer<T>> {
+    /// The value in the atomic pointer at the time of the failed operation.
+    pub current: Shared<'g, T>,
+    /// The new value, which the operation failed to store.
+    pub new: P,
+}
+
+impl<T, P: Pointer<T> + fmt::Debug> fmt::Debug for CompareExchangeError<'_, T, P> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CompareExchangeError")
+            .field("current", &self.current)
+            .field("new", &self.new)
+            .finish()
+    }
+}
+
+impl<T> Atomic<T> {
+    /// Create a null atomic pointer.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::Ordering::Relaxed;
+    /// use portable_atomic_util::epoch::{self, Atomic};
+    ///
+    /// let a = Atomic::<i32>::null();
+    /// // SAFETY: The pointer is not dereferenced.
+    /// assert!(a.load(Relaxed, unsafe { epoch::unprotected() }).is_null());
+    /// ```
+    #[must_use]
+    pub const fn null() -> Self {
+        Self { ptr: AtomicPtr::new(ptr::null_mut()), _marker: PhantomData }
+    }
+
+    /// Allocate `value` on the heap and create an atomic pointer to it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Atomic;
+    ///
+    /// let a = Atomic::new(1);
+    /// # drop(unsafe { a.into_owned() });
+    /// ```
+    pub fn new(value: T) -> Self {
+        Self::from(Owned::new(value))
+    }
+
+    /// Load the pointer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ord` is [`Release`](Ordering::Release) or [`AcqRel`](Ordering::AcqRel).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::Ordering::Acquire;
+    /// use portable_atomic_util::epoch::{Atomic, Collector};
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// let a = Atomic::new(1);
+    /// let guard = handle.pin();
+    /// // SAFETY: The object is protected by `guard`.
+    /// assert_eq!(unsafe { a.load(Acquire, &guard).as_ref() }, Some(&1));
+    /// # drop(guard);
+    /// # drop(unsafe { a.into_owned() });
+    /// ```
+    pub fn load<'g>(&self, ord: Ordering, _guard: &'g Guard) -> Shared<'g, T> {
+        Shared::from_raw(self.ptr.load(ord))
+    }
+
+    /// Store a pointer.
+    ///
+    /// The previous object is not dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ord` is [`Acquire`](Ordering::Acquire) or [`AcqRel`](Ordering::AcqRel).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::Ordering::Release;
+    /// use portable_atomic_util::epoch::{Atomic, Owned};
+    ///
+    /// let a = Atomic::null();
+    /// a.store(Owned::new(1), Release);
+    /// # drop(unsafe { a.into_owned() });
+    /// ```
+    pub fn store<P: Pointer<T>>(&self, new: P, ord: Ordering) {
+        self.ptr.store(new.into_ptr(), ord);
+    }
+
+    /// Store a pointer and return the previous one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::Ordering::AcqRel;
+    /// use portable_atomic_util::epoch::{Atomic, Collector, Owned};
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// let a = Atomic::new(1);
+    /// let guard = handle.pin();
+    /// let old = a.swap(Owned::new(2), AcqRel, &guard);
+    /// // SAFETY: `old` was unlinked by the swap.
+    /// unsafe {
+    ///     assert_eq!(*old.deref(), 1);
+    ///     guard.defer_destroy(old);
+    /// }
+    /// # drop(guard);
+    /// # drop(unsafe { a.into_owned() });
+    /// ```
+    pub fn swap<'g, P: Pointer<T>>(
+        &self,
+        new: P,
+        ord: Ordering,
+        _guard: &'g Guard,
+    ) -> Shared<'g, T> {
+        Shared::from_raw(self.ptr.swap(new.into_ptr(), ord))
+    }
+
+    /// Store `new` if the current pointer is `current`.
+    ///
+    /// # Errors
+    ///
+    /// Returns the current pointer and `new` if the current pointer is not `current`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `failure` is [`Release`](Ordering::Release) or [`AcqRel`](Ordering::AcqRel).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::Ordering::{AcqRel, Acquire};
+    /// use portable_atomic_util::epoch::{Atomic, Collector, Owned, Shared};
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// let a = Atomic::new(1);
+    /// let guard = handle.pin();
+    /// let cur = a.load(Acquire, &guard);
+    /// assert!(a.compare_exchange(Shared::null(), Owned::new(2), AcqRel, Acquire, &guard).is_err());
+    /// let old = a.compare_exchange(cur, Owned::new(3), AcqRel, Acquire, &guard).unwrap();
+    /// // SAFETY: `old` was unlinked by the exchange.
+    /// unsafe { guard.defer_destroy(old) };
+    /// # drop(guard);
+    /// # drop(unsafe { a.into_owned() });
+    /// ```
+    pub fn compare_exchange<'g, P: Pointer<T>>(
+        &self,
+        current: Shared<'_, T>,
+        new: P,
+        success: Ordering,
+        failure: Ordering,
+        _guard: &'g Guard,
+    ) -> Result<Shared<'g, T>, CompareExchangeError<'g, T, P>> {
+        let new = new.into_ptr();
+        match self.ptr.compare_exchange(current.as_raw() as *mut T, new, success, failure) {
+            Ok(prev) => Ok(Shared::from_raw(prev)),
+            Err(current) => Err(CompareExchangeError {
+                current: Shared::from_raw(current),
+                // SAFETY: `new` was created by `into_ptr` above and was not stored.
+                new: unsafe { P::from_ptr(new) },
+            }),
+        }
+    }
+
+    /// Store `new` if the current pointer is `current`.
+    ///
+    /// Unlike [`compare_exchange`](Self::compare_exchange), this can fail spuriously.
+    ///
+    /// # Errors
+    ///
+    /// Returns the current pointer and `new` if the current pointer is not `current`, or if the
+    /// operation failed spuriously.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `failure` is [`Release`](Ordering::Release) or [`AcqRel`](Ordering::AcqRel).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::Ordering::{AcqRel, Acquire};
+    /// use portable_atomic_util::epoch::{Atomic, Collector, Owned};
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// let a = Atomic::new(1);
+    /// let guard = handle.pin();
+    /// let mut new = Owned::new(2);
+    /// let mut cur = a.load(Acquire, &guard);
+    /// loop {
+    ///     match a.compare_exchange_weak(cur, new, AcqRel, Acquire, &guard) {
+    ///         Ok(old) => {
+    ///             // SAFETY: `old` was unlinked by the exchange.
+    ///             unsafe { guard.defer_destroy(old) };
+    ///             break;
+    ///         }
+    ///         Err(e) => {
+    ///             cur = e.current;
+    ///             new = e.new;
+    ///         }
+    ///     }
+    /// }
+    /// # drop(guard);
+    /// # drop(unsafe { a.into_owned() });
+    /// ```
+    pub fn compare_exchange_weak<'g, P: Pointer<T>>(
+        &self,
+        current: Shared<'_, T>,
+        new: P,
+        success: Ordering,
+        failure: Ordering,
+        _guard: &'g Guard,
+    ) -> Result<Shared<'g, T>, CompareExchangeError<'g, T, P>> {
+        let new = new.into_ptr();
+        match self.ptr.compare_exchange_weak(current.as_raw() as *mut T, new, success, failure) {
+            Ok(prev) => Ok(Shared::from_raw(prev)),
+            Err(current) => Err(CompareExchangeError {
+                current: Shared::from_raw(current),
+                // SAFETY: `new` was created by `into_ptr` above and was not stored.
+                new: unsafe { P::from_ptr(new) },
+            }),
+        }
+    }
+
+    /// Take ownership of the pointed-to object.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must not be null, and no other thread may access the object, or use the
+    /// object through a [`Shared`] pointer afterwards.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Atomic;
+    ///
+    /// let a = Atomic::new(1);
+    /// // SAFETY: We have the only reference to `a`.
+    /// assert_eq!(*unsafe { a.into_owned() }, 1);
+    /// ```
+    #[must_use]
+    pub unsafe fn into_owned(self) -> Owned<T> {
+        // SAFETY: The caller guarantees that the pointer is valid and not null.
+        unsafe { Owned::from_raw(self.ptr.into_inner()) }
+    }
+}
+
+impl<T> Default for Atomic<T> {
+    fn default() -> Self {
+        Self::null()
+    }
+}
+
+impl<T> From<Owned<T>> for Atomic<T> {
+    fn from(owned: Owned<T>) -> Self {
+        Self { ptr: AtomicPtr::new(owned.into_ptr()), _marker: PhantomData }
+    }
+}
+
+impl<T> From<Shared<'_, T>> for Atomic<T> {
+    fn from(shared: Shared<'_, T>) -> Self {
+        Self { ptr: AtomicPtr::new(shared.into_ptr()), _marker: PhantomData }
+    }
+}
+
+impl<T> fmt::Debug for Atomic<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Atomic").field(&self.ptr.load(Relaxed)).finish()
+    }
+}
+
+/// An owned heap-allocated object, which can be stored in an [`Atomic`].
+pub struct Owned<T> {
+    boxed: Box<T>,
+}
+
+impl<T> Owned<T> {
+    /// Allocate `value` on the heap.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Owned;
+    ///
+    /// let o = Owned::new(1);
+    /// assert_eq!(*o, 1);
+    /// ```
+    pub fn new(value: T) -> Self {
+        Self { boxed: Box::new(value) }
+    }
+
+    /// Create an `Owned` from a raw pointer.
+    ///
+    /// # Safety
+    ///
+    /// `raw` must have been created by [`Box::into_raw`] or by converting an `Owned` into a raw
+    /// pointer, and must be owned by the caller.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Owned;
+    ///
+    /// let raw = Box::into_raw(Box::new(1));
+    /// // SAFETY: `raw` was created by `Box::into_raw`.
+    /// let o = unsafe { Owned::from_raw(raw) };
+    /// assert_eq!(*o, 1);
+    /// ```
+    pub unsafe fn from_raw(raw: *mut T) -> Self {
+        // SAFETY: The caller guarantees that `raw` was created from a `Box`.
+        Self { boxed: unsafe { Box::from_raw(raw) } }
+    }
+
+    /// Convert into a [`Box`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Owned;
+    ///
+    /// assert_eq!(*Owned::new(1).into_box(), 1);
+    /// ```
+    #[must_use]
+    pub fn into_box(self) -> Box<T> {
+        self.boxed
+    }
+
+    /// Convert into a [`Shared`] pointer bound to `guard`.
+    ///
+    /// The object is leaked unless it is stored in an [`Atomic`] or destroyed with
+    /// [`Guard::defer_destroy`] or [`Shared::into_owned`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::Ordering::Release;
+    /// use portable_atomic_util::epoch::{Atomic, Collector, Owned};
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// let a = Atomic::null();
+    /// let guard = handle.pin();
+    /// let p = Owned::new(1).into_shared(&guard);
+    /// a.store(p, Release);
+    /// # drop(guard);
+    /// # drop(unsafe { a.into_owned() });
+    /// ```
+    #[must_use]
+    pub fn into_shared(self, _guard: &Guard) -> Shared<'_, T> {
+        Shared::from_raw(self.into_ptr())
+    }
+}
+
+impl<T> Pointer<T> for Owned<T> {
+    fn into_ptr(self) -> *mut T {
+        Box::into_raw(self.boxed)
+    }
+    unsafe fn from_ptr(ptr: *mut T) -> Self {
+        // SAFETY: The caller guarantees that `ptr` was created by `into_ptr`.
+        unsafe { Self::from_raw(ptr) }
+    }
+}
+
+impl<T> Deref for Owned<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &self.boxed
+    }
+}
+
+impl<T> DerefMut for Owned<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.boxed
+    }
+}
+
+impl<T> Borrow<T> for Owned<T> {
+    fn borrow(&self) -> &T {
+        &self.boxed
+    }
+}
+
+impl<T> BorrowMut<T> for Owned<T> {
+    fn borrow_mut(&mut self) -> &mut T {
+        &mut self.boxed
+    }
+}
+
+impl<T> From<T> for Owned<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T> From<Box<T>> for Owned<T> {
+    fn from(boxed: Box<T>) -> Self {
+        Self { boxed }
+    }
+}
+
+impl<T: Clone> Clone for Owned<T> {
+    fn clone(&self) -> Self {
+        Self::new((*self.boxed).clone())
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Owned<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Owned").field(&self.boxed).finish()
+    }
+}
+
+/// A pointer to an object protected by a [`Guard`].
+///
+/// The lifetime `'g` is the lifetime of the guard, so the pointer cannot be used after the
+/// participant unpins.
+pub struct Shared<'g, T> {
+    ptr: *const T,
+    _marker: PhantomData<(&'g (), *const T)>,
+}
+
+impl<'g, T> Shared<'g, T> {
+    fn from_raw(ptr: *const T) -> Self {
+        Self { ptr, _marker: PhantomData }
+    }
+
+    /// Return a null pointer.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Shared;
+    ///
+    /// assert!(Shared::<i32>::null().is_null());
+    /// ```
+    #[must_use]
+    pub const fn null() -> Self {
+        Self { ptr: ptr::null(), _marker: PhantomData }
+    }
+
+    /// Return `true` if the pointer is null.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Shared;
+    ///
+    /// assert!(Shared::<i32>::null().is_null());
+    /// ```
+    #[must_use]
+    pub fn is_null(&self) -> bool {
+        self.ptr.is_null()
+    }
+
+    /// Return the raw pointer.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Shared;
+    ///
+    /// assert!(Shared::<i32>::null().as_raw().is_null());
+    /// ```
+    #[must_use]
+    pub fn as_raw(&self) -> *const T {
+        self.ptr
+    }
+
+    /// Dereference the pointer.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must not be null, and the object must not have been destroyed, which is the
+    /// case if it was loaded from an [`Atomic`] with a guard of a participant, and only
+    /// destroyed with [`Guard::defer_destroy`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::Ordering::Acquire;
+    /// use portable_atomic_util::epoch::{Atomic, Collector};
+    ///
+    /// let collector = Collector::new();
+    /// let handle = collector.register();
+    /// let a = Atomic::new(1);
+    /// let guard = handle.pin();
+    /// // SAFETY: The pointer is not null and is protected by `guard`.
+    /// assert_eq!(unsafe { *a.load(Acquire, &guard).deref() }, 1);
+    /// # drop(guard);
+    /// # drop(unsafe { a.into_owned() });
+    /// ```
+    #[must_use]
+    pub unsafe fn deref(&self) -> &'g T {
+        // SAFETY: The caller guarantees that the pointer is valid.
+        unsafe { &*self.ptr }
+    }
+
+    /// Convert the pointer to a reference, or `None` if it is null.
+    ///
+    /// # Safety
+    ///
+    /// The object, if any, must not have been destroyed; see [`deref`](Self::deref).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::epoch::Shared;
+    ///
+    /// // SAFETY: The pointer is null.
+    /// assert!(unsafe { Shared::<i32>::null().as_ref() }.is_none());
+    /// ```
+    #[must_use]
+    pub unsafe fn as_ref(&self) -> Option<&'g T> {
+        // SAFETY: The caller guarantees that the pointer is valid if it is not null.
+        unsafe { self.ptr.as_ref() }
+    }
+
+    /// Take ownership of the pointed-to object.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must not be null, must have been created from an [`Owned`], and no other
+    /// thread may access the object afterwards.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::Ordering::Relaxed;
+    /// use portable_atomic_util::epoch::{self, Atomic};
+    ///
+    /// let a = Atomic::new(1);
+    /// // SAFETY: `a` is not shared.
+    /// let o = unsafe { a.load(Relaxed, epoch::unprotected()).into_owned() };
+    /// assert_eq!(*o, 1);
+    /// ```
+    #[must_use]
+    pub unsafe fn into_owned(self) -> Owned<T> {
+        // SAFETY: The caller guarantees that the pointer was created from an `Owned`.
+        unsafe { Owned::from_raw(self.ptr as *mut T) }
+    }
+}
+
+impl<T> Pointer<T> for Shared<'_, T> {
+    fn into_ptr(self) -> *mut T {
+        self.ptr as *mut T
+    }
+    unsafe fn from_ptr(ptr: *mut T) -> Self {
+        Self::from_raw(ptr)
+    }
+}
+
+impl<T> Clone for Shared<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Shared<'_, T> {}
+
+impl<T> PartialEq for Shared<'_, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl<T> Eq for Shared<'_, T> {}
+
+impl<T> Default for Shared<'_, T> {
+    fn default() -> Self {
+        Self::null()
+    }
+}
+
+impl<T> fmt::Debug for Shared<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Shared").field(&self.ptr).finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::DropCounter;
+
+    use portable_atomic::Ordering::AcqRel;
+    use std::thread;
+
+    // Pin and flush until `drops` reaches `expected`, or panic if it does not.
+    //
+    // Exited threads hand their garbage to the collector from thread-local destructors, which
+    // may still be running after `thread::scope` returns, so yield between attempts.
+    fn flush_until(handle: &LocalHandle, drops: &AtomicUsize, expected: usize) {
+        for _ in 0..10_000 {
+            if drops.load(Relaxed) == expected {
+                return;
+            }
+            handle.pin().flush();
+            thread::yield_now();
+        }
+        assert_eq!(drops.load(Relaxed), expected);
+    }
+
+    #[test]
+    fn concurrent_swap() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        const THREADS: usize = 4;
+        const N: usize = 10_000;
+        let collector = Collector::new();
+        let a = Atomic::new(DropCounter(&DROPS));
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    let handle = collector.register();
+                    for _ in 0..N {
+                        let guard = handle.pin();
+                        let old = a.swap(Owned::new(DropCounter(&DROPS)), AcqRel, &guard);
+                        // SAFETY: `old` is not null and protected by `guard`, so it has not been
+                        // destroyed yet.
+                        let _ = unsafe { old.deref() }.0.load(Relaxed);
+                        // SAFETY: `old` was unlinked by the swap and is destroyed only once.
+                        unsafe { guard.defer_destroy(old) };
+                    }
+                });
+            }
+        });
+        // Garbage is destroyed while the collector is alive, including the garbage that the
+        // finished threads left behind.
+        let handle = collector.register();
+        flush_until(&handle, &DROPS, THREADS * N);
+        // SAFETY: We have the only reference to `a`.
+        drop(unsafe { a.into_owned() });
+        assert_eq!(DROPS.load(Relaxed), THREADS * N + 1);
+    }
+
+    #[test]
+    fn pinned_participant_blocks_reclamation() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let collector = Collector::new();
+        let pinned = collector.register();
+        let guard = pinned.pin();
+        let handle = collector.register();
+        {
+            let guard = handle.pin();
+            let old = Owned::new(DropCounter(&DROPS)).into_shared(&guard);
+            // SAFETY: `old` was never shared and is destroyed only once.
+            unsafe { guard.defer_destroy(old) };
+        }
+        for _ in 0..10 {
+            handle.pin().flush();
+        }
+        // `pinned` may still see the object.
+        assert_eq!(DROPS.load(Relaxed), 0);
+        drop(guard);
+        flush_until(&handle, &DROPS, 1);
+    }
+
+    #[test]
+    fn drop_collector_destroys_garbage() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let collector = Collector::new();
+        let handle = collector.register();
+        let guard = handle.pin();
+        for _ in 0..10 {
+            guard.defer(|| drop(DropCounter(&DROPS)));
+        }
+        drop(guard);
+        drop(collector);
+        // The handle keeps the collector alive.
+        assert_eq!(DROPS.load(Relaxed), 0);
+        drop(handle);
+        assert_eq!(DROPS.load(Relaxed), 10);
+    }
+
+    #[test]
+    fn default_collector_is_per_thread() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let guard = pin();
+                        assert!(is_pinned());
+                        guard.defer(|| drop(DropCounter(&DROPS)));
+                    }
+                    assert!(!is_pinned());
+                });
+            }
+        });
+        let handle = default_collector().register();
+        flush_until(&handle, &DROPS, 4000);
+    }
+}
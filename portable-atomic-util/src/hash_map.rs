@@ -0,0 +1,817 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A fixed-capacity lock-free hash map with `u64` keys and values.
+//!
+//! See [`AtomicHashMap`] for details.
+
+use core::fmt;
+
+// Keys reserved to mark empty slots, removed entries, and (with split slots) slots claimed by an
+// insertion that has not stored its value yet.
+const EMPTY: u64 = u64::MAX;
+const TOMBSTONE: u64 = u64::MAX - 1;
+const BUSY: u64 = u64::MAX - 2;
+
+#[derive(Clone, Copy)]
+enum Op {
+    Insert(u64),
+    Add(u64),
+}
+
+// The result of looking at a single slot while probing.
+enum Probe<T> {
+    // The slot holds (or now holds) the key.
+    Found(T),
+    // The slot is empty, so the key is not in the map.
+    Empty,
+    // The slot holds another key or a tombstone; continue probing.
+    Next,
+}
+
+cfg_no_atomic_128! {
+    // A slot with the key and value in separate atomics. This is used if `AtomicU128` does not
+    // exist.
+    mod split {
+        use super::{Op, Probe, BUSY, EMPTY, TOMBSTONE};
+        use portable_atomic::{
+            AtomicU64,
+            Ordering::{AcqRel, Acquire, Relaxed, Release},
+        };
+
+        pub(super) const IS_COMBINED: bool = false;
+
+        pub(super) struct Slot {
+            key: AtomicU64,
+            value: AtomicU64,
+        }
+
+        impl Slot {
+            // Only used to initialize the array of slots.
+            #[allow(clippy::declare_interior_mutable_const)]
+            pub(super) const NEW: Self =
+                Self { key: AtomicU64::new(EMPTY), value: AtomicU64::new(0) };
+
+            pub(super) fn load(&self) -> (u64, u64) {
+                (self.key.load(Acquire), self.value.load(Acquire))
+            }
+
+            // Load the key, waiting for a concurrent insertion that has claimed this slot to
+            // publish its key.
+            fn load_key(&self) -> u64 {
+                loop {
+                    match self.key.load(Acquire) {
+                        BUSY => portable_atomic::hint::spin_loop(),
+                        key => return key,
+                    }
+                }
+            }
+
+            pub(super) fn get(&self, key: u64) -> Probe<u64> {
+                match self.load_key() {
+                    k if k == key => Probe::Found(self.value.load(Acquire)),
+                    EMPTY => Probe::Empty,
+                    _ => Probe::Next,
+                }
+            }
+
+            pub(super) fn update(&self, key: u64, op: Op) -> Probe<Option<u64>> {
+                let mut current = self.load_key();
+                loop {
+                    if current == key {
+                        return Probe::Found(Some(self.apply(op)));
+                    }
+                    if current != EMPTY {
+                        return Probe::Next;
+                    }
+                    // Claim the slot with `BUSY`, so that no other thread uses the key before its
+                    // value is stored, and then publish the key.
+                    match self.key.compare_exchange(EMPTY, BUSY, Acquire, Relaxed) {
+                        Ok(_) => {
+                            let value = match op {
+                                Op::Insert(new) => new,
+                                Op::Add(delta) => delta,
+                            };
+                            self.value.store(value, Relaxed);
+                            self.key.store(key, Release);
+                            return Probe::Found(None);
+                        }
+                        Err(_) => current = self.load_key(),
+                    }
+                }
+            }
+
+            fn apply(&self, op: Op) -> u64 {
+                match op {
+                    Op::Insert(new) => self.value.swap(new, AcqRel),
+                    Op::Add(delta) => self.value.fetch_add(delta, AcqRel),
+                }
+            }
+
+            pub(super) fn remove(&self, key: u64) -> Probe<u64> {
+                let mut current = self.load_key();
+                loop {
+                    if current == EMPTY {
+                        return Probe::Empty;
+                    }
+                    if current != key {
+                        return Probe::Next;
+                    }
+                    match self.key.compare_exchange(key, TOMBSTONE, AcqRel, Acquire) {
+                        // An update that found the key before it was removed may still apply to
+                        // the value after this; that update is lost.
+                        Ok(_) => return Probe::Found(self.value.swap(0, AcqRel)),
+                        Err(k) => current = k,
+                    }
+                }
+            }
+
+            pub(super) fn clear(&mut self) {
+                *self = Self::NEW;
+            }
+        }
+    }
+    use self::split::{Slot, IS_COMBINED};
+}
+
+cfg_has_atomic_128! {
+    // A slot with the key in the high half and the value in the low half of an `AtomicU128`, so
+    // that every operation is a single atomic update of the whole entry.
+    mod combined {
+        use super::{Op, Probe, EMPTY, TOMBSTONE};
+        use portable_atomic::{
+            AtomicU128,
+            Ordering::{AcqRel, Acquire},
+        };
+
+        pub(super) const IS_COMBINED: bool = true;
+
+        impl Op {
+            fn apply(self, value: u64) -> u64 {
+                match self {
+                    Op::Insert(new) => new,
+                    Op::Add(delta) => value.wrapping_add(delta),
+                }
+            }
+        }
+
+        pub(super) struct Slot {
+            entry: AtomicU128,
+        }
+
+        impl Slot {
+            // Only used to initialize the array of slots.
+            #[allow(clippy::declare_interior_mutable_const)]
+            pub(super) const NEW: Self = Self { entry: AtomicU128::new(Self::pack(EMPTY, 0)) };
+
+            const fn pack(key: u64, value: u64) -> u128 {
+                ((key as u128) << 64) | value as u128
+            }
+
+            #[allow(clippy::cast_possible_truncation)]
+            fn unpack(entry: u128) -> (u64, u64) {
+                ((entry >> 64) as u64, entry as u64)
+            }
+
+            pub(super) fn load(&self) -> (u64, u64) {
+                Self::unpack(self.entry.load(Acquire))
+            }
+
+            pub(super) fn get(&self, key: u64) -> Probe<u64> {
+                match self.load() {
+                    (k, value) if k == key => Probe::Found(value),
+                    (EMPTY, _) => Probe::Empty,
+                    _ => Probe::Next,
+                }
+            }
+
+            pub(super) fn update(&self, key: u64, op: Op) -> Probe<Option<u64>> {
+                let mut current = self.entry.load(Acquire);
+                loop {
+                    let (k, value) = Self::unpack(current);
+                    let (new, prev) = if k == key {
+                        (Self::pack(key, op.apply(value)), Some(value))
+                    } else if k == EMPTY {
+                        (Self::pack(key, op.apply(0)), None)
+                    } else {
+                        return Probe::Next;
+                    };
+                    match self.entry.compare_exchange_weak(current, new, AcqRel, Acquire) {
+                        Ok(_) => return Probe::Found(prev),
+                        Err(c) => current = c,
+                    }
+                }
+            }
+
+            pub(super) fn remove(&self, key: u64) -> Probe<u64> {
+                let mut current = self.entry.load(Acquire);
+                loop {
+                    let (k, value) = Self::unpack(current);
+                    if k == EMPTY {
+                        return Probe::Empty;
+                    }
+                    if k != key {
+                        return Probe::Next;
+                    }
+                    let new = Self::pack(TOMBSTONE, 0);
+                    match self.entry.compare_exchange_weak(current, new, AcqRel, Acquire) {
+                        Ok(_) => return Probe::Found(value),
+                        Err(c) => current = c,
+                    }
+                }
+            }
+
+            pub(super) fn clear(&mut self) {
+                *self = Self::NEW;
+            }
+        }
+    }
+    use self::combined::{Slot, IS_COMBINED};
+}
+
+/// An error returned from [`AtomicHashMap::insert`] and [`AtomicHashMap::fetch_add`] when the
+/// key is not in the map and there is no empty slot left for it.
+#[allow(clippy::exhaustive_structs)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FullError;
+
+impl fmt::Display for FullError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("inserting into a full hash map")
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FullError {}
+
+/// A fixed-capacity lock-free hash map from `u64` keys to `u64` values.
+///
+/// The map has `N` slots stored inline, so it never allocates and can be placed in a `static`.
+/// Slots are probed linearly from the hash of the key.
+///
+/// [`remove`](Self::remove) replaces the entry with a tombstone, and tombstones are not reused
+/// until the map is [cleared](Self::clear). This means at most `N` distinct keys can be inserted
+/// between clears, regardless of how many are removed.
+///
+/// The keys `u64::MAX - 2`, `u64::MAX - 1`, and `u64::MAX` are reserved.
+///
+/// Where portable-atomic provides `AtomicU128` (on every target when the `fallback` feature is
+/// enabled), each slot is an `AtomicU128` holding both the key and the value, and every operation
+/// is linearizable. Otherwise, each slot is a pair of [`AtomicU64`](portable_atomic::AtomicU64)s:
+/// an operation that reaches a slot while a key is being inserted into it waits until the value
+/// is stored, and an update racing with the removal of the same key may be lost. This module is
+/// only available where portable-atomic provides `AtomicU64`, which is every target when the
+/// `fallback` feature is enabled.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::hash_map::AtomicHashMap;
+///
+/// static REQUESTS: AtomicHashMap<64> = AtomicHashMap::new();
+///
+/// REQUESTS.fetch_add(7, 1).unwrap();
+/// REQUESTS.fetch_add(7, 1).unwrap();
+/// REQUESTS.fetch_add(42, 5).unwrap();
+/// assert_eq!(REQUESTS.get(7), Some(2));
+/// assert_eq!(REQUESTS.get(42), Some(5));
+/// assert_eq!(REQUESTS.get(1), None);
+/// ```
+pub struct AtomicHashMap<const N: usize> {
+    slots: [Slot; N],
+}
+
+impl<const N: usize> AtomicHashMap<N> {
+    /// Create a new empty map.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::AtomicHashMap;
+    ///
+    /// static MAP: AtomicHashMap<16> = AtomicHashMap::new();
+    /// assert!(MAP.is_empty());
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { slots: [Slot::NEW; N] }
+    }
+
+    /// Return the number of slots.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::AtomicHashMap;
+    ///
+    /// let map = AtomicHashMap::<16>::new();
+    /// assert_eq!(map.capacity(), 16);
+    /// ```
+    #[allow(clippy::unused_self)]
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Return `true` if each slot holds the key and the value in a single `AtomicU128`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::AtomicHashMap;
+    ///
+    /// let is_combined = AtomicHashMap::<16>::is_combined();
+    /// ```
+    #[must_use]
+    pub const fn is_combined() -> bool {
+        IS_COMBINED
+    }
+
+    /// Return the value of `key`, or `None` if it is not in the map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is `u64::MAX - 2`, `u64::MAX - 1`, or `u64::MAX`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::AtomicHashMap;
+    ///
+    /// let map = AtomicHashMap::<16>::new();
+    /// map.insert(1, 10).unwrap();
+    /// assert_eq!(map.get(1), Some(10));
+    /// assert_eq!(map.get(2), None);
+    /// ```
+    #[must_use]
+    pub fn get(&self, key: u64) -> Option<u64> {
+        for slot in self.probe(key) {
+            match slot.get(key) {
+                Probe::Found(value) => return Some(value),
+                Probe::Empty => return None,
+                Probe::Next => {}
+            }
+        }
+        None
+    }
+
+    /// Return `true` if `key` is in the map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is `u64::MAX - 2`, `u64::MAX - 1`, or `u64::MAX`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::AtomicHashMap;
+    ///
+    /// let map = AtomicHashMap::<16>::new();
+    /// map.insert(1, 10).unwrap();
+    /// assert!(map.contains_key(1));
+    /// assert!(!map.contains_key(2));
+    /// ```
+    #[must_use]
+    pub fn contains_key(&self, key: u64) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Set the value of `key` to `value`, and return the previous value.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FullError`] if `key` is not in the map and there is no empty slot left.
+    ///
+    /// Slots of removed keys are not reused until the map is [cleared](Self::clear), so this can
+    /// fail even if the map holds fewer than `N` entries: at most `N` distinct keys can be
+    /// inserted between clears.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is `u64::MAX - 2`, `u64::MAX - 1`, or `u64::MAX`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::{AtomicHashMap, FullError};
+    ///
+    /// let map = AtomicHashMap::<1>::new();
+    /// assert_eq!(map.insert(1, 10), Ok(None));
+    /// assert_eq!(map.insert(1, 20), Ok(Some(10)));
+    /// assert_eq!(map.insert(2, 30), Err(FullError));
+    /// ```
+    pub fn insert(&self, key: u64, value: u64) -> Result<Option<u64>, FullError> {
+        self.update(key, Op::Insert(value))
+    }
+
+    /// Add `delta` to the value of `key` (wrapping around on overflow), and return the previous
+    /// value.
+    ///
+    /// If `key` is not in the map, it is inserted with the value `delta` and 0 is returned.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`FullError`] if `key` is not in the map and there is no empty slot left. As with
+    /// [`insert`](Self::insert), slots of removed keys are not reused until the map is
+    /// [cleared](Self::clear).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is `u64::MAX - 2`, `u64::MAX - 1`, or `u64::MAX`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::AtomicHashMap;
+    ///
+    /// let map = AtomicHashMap::<16>::new();
+    /// assert_eq!(map.fetch_add(1, 5), Ok(0));
+    /// assert_eq!(map.fetch_add(1, 5), Ok(5));
+    /// assert_eq!(map.get(1), Some(10));
+    /// ```
+    pub fn fetch_add(&self, key: u64, delta: u64) -> Result<u64, FullError> {
+        self.update(key, Op::Add(delta)).map(|prev| prev.unwrap_or(0))
+    }
+
+    /// Remove `key` from the map, and return its value.
+    ///
+    /// The slot of the key becomes a tombstone, which is not reused until the map is
+    /// [cleared](Self::clear). Removing a key does not free up room for other keys: once `N`
+    /// distinct keys have been inserted, inserting a new key fails until the map is cleared.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `key` is `u64::MAX - 2`, `u64::MAX - 1`, or `u64::MAX`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::AtomicHashMap;
+    ///
+    /// let map = AtomicHashMap::<16>::new();
+    /// map.insert(1, 10).unwrap();
+    /// assert_eq!(map.remove(1), Some(10));
+    /// assert_eq!(map.remove(1), None);
+    /// ```
+    pub fn remove(&self, key: u64) -> Option<u64> {
+        for slot in self.probe(key) {
+            match slot.remove(key) {
+                Probe::Found(value) => return Some(value),
+                Probe::Empty => return None,
+                Probe::Next => {}
+            }
+        }
+        None
+    }
+
+    /// Return the number of entries in the map.
+    ///
+    /// This scans all slots, and the result may be out of date if the map is modified
+    /// concurrently.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::AtomicHashMap;
+    ///
+    /// let map = AtomicHashMap::<16>::new();
+    /// map.insert(1, 10).unwrap();
+    /// map.insert(2, 20).unwrap();
+    /// assert_eq!(map.len(), 2);
+    /// ```
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Return `true` if the map has no entries.
+    ///
+    /// This scans all slots, and the result may be out of date if the map is modified
+    /// concurrently.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::AtomicHashMap;
+    ///
+    /// let map = AtomicHashMap::<16>::new();
+    /// assert!(map.is_empty());
+    /// ```
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.iter().next().is_none()
+    }
+
+    /// Return an iterator over the entries of the map, as `(key, value)` pairs.
+    ///
+    /// Each entry is read when the iterator reaches its slot, so this is not a snapshot of the
+    /// whole map if it is modified concurrently.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::AtomicHashMap;
+    ///
+    /// let map = AtomicHashMap::<16>::new();
+    /// map.insert(1, 10).unwrap();
+    /// map.insert(2, 20).unwrap();
+    /// let mut entries: Vec<_> = map.iter().collect();
+    /// entries.sort_unstable();
+    /// assert_eq!(entries, [(1, 10), (2, 20)]);
+    /// ```
+    pub fn iter(&self) -> Iter<'_> {
+        Iter { slots: self.slots.iter() }
+    }
+
+    /// Remove all entries and tombstones.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hash_map::AtomicHashMap;
+    ///
+    /// let mut map = AtomicHashMap::<16>::new();
+    /// map.insert(1, 10).unwrap();
+    /// map.remove(1);
+    /// map.clear();
+    /// assert!(map.is_empty());
+    /// ```
+    pub fn clear(&mut self) {
+        for slot in &mut self.slots {
+            slot.clear();
+        }
+    }
+
+    fn update(&self, key: u64, op: Op) -> Result<Option<u64>, FullError> {
+        for slot in self.probe(key) {
+            match slot.update(key, op) {
+                Probe::Found(prev) => return Ok(prev),
+                Probe::Empty | Probe::Next => {}
+            }
+        }
+        Err(FullError)
+    }
+
+    // Return the slots in probing order for `key`.
+    fn probe(&self, key: u64) -> impl Iterator<Item = &Slot> {
+        assert!(key < BUSY, "the keys u64::MAX - 2 to u64::MAX are reserved");
+        let start = if N == 0 { 0 } else { hash_index(key, N) };
+        let (tail, head) = self.slots.split_at(start);
+        head.iter().chain(tail)
+    }
+}
+
+// The 64-bit finalizer of MurmurHash3.
+#[allow(clippy::cast_possible_truncation)]
+fn hash_index(key: u64, n: usize) -> usize {
+    let mut h = key;
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^= h >> 33;
+    (h % n as u64) as usize
+}
+
+impl<const N: usize> Default for AtomicHashMap<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<const N: usize> fmt::Debug for AtomicHashMap<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, const N: usize> IntoIterator for &'a AtomicHashMap<N> {
+    type Item = (u64, u64);
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Iter<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over the entries of an [`AtomicHashMap`], returned by [`AtomicHashMap::iter`].
+pub struct Iter<'a> {
+    slots: core::slice::Iter<'a, Slot>,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = (u64, u64);
+
+    fn next(&mut self) -> Option<(u64, u64)> {
+        for slot in &mut self.slots {
+            let (key, value) = slot.load();
+            if key < BUSY {
+                return Some((key, value));
+            }
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (0, Some(self.slots.len()))
+    }
+}
+
+impl fmt::Debug for Iter<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Iter").finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::{collections::HashMap, sync::Barrier, thread, vec::Vec};
+
+    #[derive(Clone, Copy, Debug)]
+    enum TestOp {
+        Insert(u8, u64),
+        FetchAdd(u8, u64),
+        Remove(u8),
+        Get(u8),
+    }
+
+    impl quickcheck::Arbitrary for TestOp {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            // Use a small key space so that keys are often reused.
+            let key = u8::arbitrary(g) % 16;
+            match u8::arbitrary(g) % 4 {
+                0 => TestOp::Insert(key, u64::arbitrary(g)),
+                1 => TestOp::FetchAdd(key, u64::arbitrary(g)),
+                2 => TestOp::Remove(key),
+                _ => TestOp::Get(key),
+            }
+        }
+    }
+
+    ::quickcheck::quickcheck! {
+        fn quickcheck_sequential(ops: Vec<TestOp>) -> bool {
+            const N: usize = 8;
+            let mut map = AtomicHashMap::<N>::new();
+            let mut model = HashMap::new();
+            // The number of slots holding a key or a tombstone.
+            let mut used = 0;
+            for op in ops {
+                match op {
+                    TestOp::Insert(key, value) => {
+                        let key = u64::from(key);
+                        let res = map.insert(key, value);
+                        if model.contains_key(&key) || used < N {
+                            if !model.contains_key(&key) {
+                                used += 1;
+                            }
+                            assert_eq!(res, Ok(model.insert(key, value)));
+                        } else {
+                            assert_eq!(res, Err(FullError));
+                        }
+                    }
+                    TestOp::FetchAdd(key, delta) => {
+                        let key = u64::from(key);
+                        let res = map.fetch_add(key, delta);
+                        if model.contains_key(&key) || used < N {
+                            if !model.contains_key(&key) {
+                                used += 1;
+                            }
+                            let value = model.entry(key).or_insert(0);
+                            assert_eq!(res, Ok(*value));
+                            *value = value.wrapping_add(delta);
+                        } else {
+                            assert_eq!(res, Err(FullError));
+                        }
+                    }
+                    TestOp::Remove(key) => {
+                        let key = u64::from(key);
+                        assert_eq!(map.remove(key), model.remove(&key));
+                    }
+                    TestOp::Get(key) => {
+                        let key = u64::from(key);
+                        assert_eq!(map.get(key), model.get(&key).copied());
+                    }
+                }
+                assert_eq!(map.len(), model.len());
+                if used == N && model.len() < N / 2 {
+                    map.clear();
+                    model.clear();
+                    used = 0;
+                }
+            }
+            let mut entries: Vec<_> = map.iter().collect();
+            entries.sort_unstable();
+            let mut expected: Vec<_> = model.into_iter().collect();
+            expected.sort_unstable();
+            entries == expected
+        }
+    }
+
+    #[test]
+    fn concurrent_fetch_add() {
+        const THREADS: usize = 4;
+        const N: u64 = 10_000;
+        const KEYS: u64 = 16;
+        let map = AtomicHashMap::<32>::new();
+        let barrier = Barrier::new(THREADS);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    barrier.wait();
+                    for i in 0..N {
+                        map.fetch_add(i % KEYS, 1).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(map.len() as u64, KEYS);
+        for key in 0..KEYS {
+            assert_eq!(map.get(key), Some(THREADS as u64 * N / KEYS));
+        }
+    }
+
+    #[test]
+    fn concurrent_insert_remove() {
+        const THREADS: usize = 4;
+        const KEYS: u64 = 64;
+        let map = AtomicHashMap::<256>::new();
+        let barrier = Barrier::new(THREADS);
+        thread::scope(|s| {
+            for t in 0..THREADS as u64 {
+                let (map, barrier) = (&map, &barrier);
+                s.spawn(move || {
+                    barrier.wait();
+                    // Each thread owns its keys, so every operation sees its own writes.
+                    for key in (t * KEYS)..(t + 1) * KEYS {
+                        assert_eq!(map.insert(key, key), Ok(None));
+                        assert_eq!(map.fetch_add(key, 1), Ok(key));
+                        assert_eq!(map.get(key), Some(key + 1));
+                        if key % 2 == 0 {
+                            assert_eq!(map.remove(key), Some(key + 1));
+                            assert_eq!(map.get(key), None);
+                        }
+                    }
+                });
+            }
+        });
+        let mut entries: Vec<_> = map.iter().collect();
+        entries.sort_unstable();
+        let expected: Vec<_> =
+            (0..THREADS as u64 * KEYS).filter(|k| k % 2 == 1).map(|k| (k, k + 1)).collect();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn concurrent_insert_same_key() {
+        const THREADS: usize = 4;
+        for key in 0..100 {
+            let map = AtomicHashMap::<4>::new();
+            let barrier = Barrier::new(THREADS);
+            let inserted = thread::scope(|s| {
+                let handles: Vec<_> = (0..THREADS)
+                    .map(|t| {
+                        let (map, barrier) = (&map, &barrier);
+                        s.spawn(move || {
+                            barrier.wait();
+                            map.insert(key, t as u64).unwrap().is_none()
+                        })
+                    })
+                    .collect();
+                handles.into_iter().map(|h| h.join().unwrap()).filter(|&inserted| inserted).count()
+            });
+            // Exactly one thread inserts the key, and the key takes a single slot.
+            assert_eq!(inserted, 1);
+            assert_eq!(map.len(), 1);
+        }
+    }
+
+    #[test]
+    fn concurrent_insert_get() {
+        const READERS: usize = 3;
+        for key in 0..100 {
+            let map = AtomicHashMap::<4>::new();
+            let barrier = Barrier::new(READERS + 1);
+            thread::scope(|s| {
+                for _ in 0..READERS {
+                    s.spawn(|| {
+                        barrier.wait();
+                        // A reader never sees the key before its value.
+                        loop {
+                            match map.get(key) {
+                                Some(value) => {
+                                    assert_eq!(value, key + 1);
+                                    break;
+                                }
+                                None => thread::yield_now(),
+                            }
+                        }
+                    });
+                }
+                barrier.wait();
+                map.fetch_add(key, key + 1).unwrap();
+            });
+        }
+    }
+}
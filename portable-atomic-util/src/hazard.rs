@@ -0,0 +1,552 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Hazard-pointer memory reclamation.
+//!
+//! Before a thread dereferences a shared node, it publishes the node's address in a hazard slot
+//! with [`HazardDomain::protect`]. A node that has been unlinked is [retired](HazardDomain::retire)
+//! instead of freed, and a retired node is only freed once no hazard slot points to it.
+//!
+//! Unlike [epoch-based reclamation](crate::epoch), a stalled reader only keeps the nodes it
+//! protects alive, not all garbage retired after it was pinned. In exchange, each protection
+//! costs a store and a [`fence(SeqCst)`](portable_atomic::fence).
+//!
+//! Retired nodes are freed in batches: each domain scans the hazard slots once the number of
+//! retired nodes exceeds a threshold proportional to the number of slots, so the cost of a scan
+//! is amortized over the retirements.
+//!
+//! See Maged M. Michael, "Hazard Pointers: Safe Memory Reclamation for Lock-Free Objects" for the
+//! algorithm.
+//!
+//! # Examples
+//!
+//! ```
+//! use portable_atomic::{AtomicPtr, Ordering::AcqRel};
+//! use portable_atomic_util::hazard::HazardDomain;
+//!
+//! unsafe fn free(ptr: *mut i32) {
+//!     // SAFETY: The pointer was created by `Box::into_raw`.
+//!     drop(unsafe { Box::from_raw(ptr) });
+//! }
+//!
+//! static DOMAIN: HazardDomain = HazardDomain::new();
+//! let value = AtomicPtr::new(Box::into_raw(Box::new(1)));
+//!
+//! let guard = DOMAIN.protect(&value);
+//! // SAFETY: The pointer is protected, and objects are only freed through `DOMAIN`.
+//! assert_eq!(unsafe { guard.as_ref() }, Some(&1));
+//!
+//! let old = value.swap(Box::into_raw(Box::new(2)), AcqRel);
+//! // SAFETY: `old` was unlinked by the swap and is only retired once.
+//! unsafe { DOMAIN.retire(old, free) };
+//! // `old` is still protected by `guard`.
+//! assert_eq!(unsafe { guard.as_ref() }, Some(&1));
+//! drop(guard);
+//! DOMAIN.reclaim();
+//! # unsafe { free(value.into_inner()) };
+//! ```
+
+use portable_atomic::{
+    fence, AtomicBool, AtomicPtr, AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release, SeqCst},
+};
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{fmt, marker::PhantomData, ptr};
+
+// Scan when the number of retired nodes reaches
+// `max(RETIRED_THRESHOLD, RETIRED_PER_SLOT * number of slots)`.
+const RETIRED_THRESHOLD: usize = 64;
+const RETIRED_PER_SLOT: usize = 2;
+
+struct Slot {
+    // The protected pointer, or null.
+    ptr: AtomicPtr<()>,
+    // Whether a guard owns this slot.
+    active: AtomicBool,
+    next: *mut Slot,
+}
+
+#[repr(C)]
+struct Retired {
+    ptr: *mut (),
+    next: *mut Retired,
+    reclaim: unsafe fn(*mut Retired),
+}
+
+// A retired node with its deleter. `header` must be the first field, so that a pointer to the
+// header is also a pointer to the node.
+#[repr(C)]
+struct RetiredNode<T> {
+    header: Retired,
+    deleter: unsafe fn(*mut T),
+}
+
+impl<T> RetiredNode<T> {
+    unsafe fn reclaim(retired: *mut Retired) {
+        // SAFETY: The caller guarantees that `retired` was created by `HazardDomain::retire`
+        // with the same `T`.
+        let node = unsafe { Box::from_raw(retired as *mut Self) };
+        // SAFETY: The caller of `retire` guarantees that calling the deleter is safe once the
+        // pointer is no longer protected.
+        unsafe { (node.deleter)(node.header.ptr as *mut T) }
+    }
+}
+
+/// A hazard-pointer domain: a set of hazard slots and the objects retired in it.
+///
+/// Objects protected with [`protect`](Self::protect) must only be freed with
+/// [`retire`](Self::retire) on the same domain. A domain can be created in a `static` with the
+/// `const` [`new`](Self::new).
+///
+/// Hazard slots are allocated on demand and reused by later guards; they are freed when the
+/// domain is dropped, together with all remaining retired objects.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic::AtomicPtr;
+/// use portable_atomic_util::hazard::HazardDomain;
+///
+/// let domain = HazardDomain::new();
+/// let value = AtomicPtr::new(Box::into_raw(Box::new(1)));
+/// let guard = domain.protect(&value);
+/// // SAFETY: The pointer is protected, and objects are only freed through `domain`.
+/// assert_eq!(unsafe { guard.as_ref() }, Some(&1));
+/// # drop(guard);
+/// # drop(unsafe { Box::from_raw(value.into_inner()) });
+/// ```
+pub struct HazardDomain {
+    slots: AtomicPtr<Slot>,
+    slot_count: AtomicUsize,
+    retired: AtomicPtr<Retired>,
+    retired_count: AtomicUsize,
+}
+
+impl HazardDomain {
+    /// Create a new domain with no hazard slots.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hazard::HazardDomain;
+    ///
+    /// static DOMAIN: HazardDomain = HazardDomain::new();
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            slots: AtomicPtr::new(ptr::null_mut()),
+            slot_count: AtomicUsize::new(0),
+            retired: AtomicPtr::new(ptr::null_mut()),
+            retired_count: AtomicUsize::new(0),
+        }
+    }
+
+    /// Load the pointer in `src` and protect it from being freed until the returned guard is
+    /// dropped.
+    ///
+    /// The returned pointer is protected only if it was not retired before it was loaded; this is
+    /// the case if objects are retired only after they have been unlinked from `src`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::AtomicPtr;
+    /// use portable_atomic_util::hazard::HazardDomain;
+    ///
+    /// let domain = HazardDomain::new();
+    /// let value = AtomicPtr::new(Box::into_raw(Box::new(1)));
+    /// let guard = domain.protect(&value);
+    /// assert_eq!(guard.as_ptr(), value.load(portable_atomic::Ordering::Relaxed));
+    /// # drop(guard);
+    /// # drop(unsafe { Box::from_raw(value.into_inner()) });
+    /// ```
+    pub fn protect<T>(&self, src: &AtomicPtr<T>) -> HazardGuard<'_, T> {
+        let slot = self.acquire_slot();
+        let mut ptr = src.load(Relaxed);
+        loop {
+            slot.ptr.store(ptr as *mut (), Relaxed);
+            // Make the hazard visible before validating it. Pairs with the fence in `reclaim`:
+            // either the reclaiming thread sees the hazard, or we see that `src` has changed.
+            fence(SeqCst);
+            let current = src.load(Acquire);
+            if current == ptr {
+                return HazardGuard { slot, ptr, _marker: PhantomData };
+            }
+            ptr = current;
+        }
+    }
+
+    /// Retire `ptr`, calling `deleter` with it once no hazard slot of this domain protects it.
+    ///
+    /// Retiring a null pointer does nothing.
+    ///
+    /// # Safety
+    ///
+    /// - `ptr` must have been unlinked from every [`AtomicPtr`] that can be
+    ///   [protected](Self::protect) with this domain, so that no new guard can protect it.
+    /// - `ptr` must not be retired more than once.
+    /// - Calling `deleter` with `ptr` must be safe on any thread, at any time until the domain is
+    ///   dropped.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::{AtomicPtr, Ordering::AcqRel};
+    /// use portable_atomic_util::hazard::HazardDomain;
+    ///
+    /// unsafe fn free(ptr: *mut i32) {
+    ///     // SAFETY: The pointer was created by `Box::into_raw`.
+    ///     drop(unsafe { Box::from_raw(ptr) });
+    /// }
+    ///
+    /// let domain = HazardDomain::new();
+    /// let value = AtomicPtr::new(Box::into_raw(Box::new(1)));
+    /// let old = value.swap(std::ptr::null_mut(), AcqRel);
+    /// // SAFETY: `old` was unlinked by the swap and is only retired once.
+    /// unsafe { domain.retire(old, free) };
+    /// ```
+    pub unsafe fn retire<T>(&self, ptr: *mut T, deleter: unsafe fn(*mut T)) {
+        if ptr.is_null() {
+            return;
+        }
+        let node = Box::into_raw(Box::new(RetiredNode {
+            header: Retired {
+                ptr: ptr as *mut (),
+                next: ptr::null_mut(),
+                reclaim: RetiredNode::<T>::reclaim,
+            },
+            deleter,
+        }));
+        // Count before pushing, so that `reclaim` never subtracts a node that was not counted.
+        let count = self.retired_count.fetch_add(1, Relaxed) + 1;
+        self.push_retired(node as *mut Retired, node as *mut Retired);
+        if count >= self.threshold() {
+            self.reclaim();
+        }
+    }
+
+    /// Free the retired objects that are not protected.
+    ///
+    /// This is called automatically once enough objects have been retired, so calling this is not
+    /// required.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::hazard::HazardDomain;
+    ///
+    /// let domain = HazardDomain::new();
+    /// domain.reclaim();
+    /// ```
+    pub fn reclaim(&self) {
+        if self.retired.load(Relaxed).is_null() {
+            return;
+        }
+        // Take the whole list, so that no other thread accesses the nodes.
+        let mut retired = self.retired.swap(ptr::null_mut(), Acquire);
+        // Pairs with the fence in `protect`.
+        fence(SeqCst);
+
+        let mut hazards = Vec::new();
+        let mut slot = self.slots.load(Acquire);
+        while !slot.is_null() {
+            // SAFETY: Slots are not freed while the domain is alive.
+            let s = unsafe { &*slot };
+            let ptr = s.ptr.load(Relaxed);
+            if !ptr.is_null() {
+                hazards.push(ptr);
+            }
+            slot = s.next;
+        }
+        hazards.sort_unstable();
+
+        let mut kept_head: *mut Retired = ptr::null_mut();
+        let mut kept_tail: *mut Retired = ptr::null_mut();
+        let mut reclaimed = 0;
+        while !retired.is_null() {
+            let node = retired;
+            // SAFETY: We took the node out of the list, and it was created by `retire`.
+            unsafe {
+                retired = (*node).next;
+                if hazards.binary_search(&(*node).ptr).is_ok() {
+                    (*node).next = kept_head;
+                    if kept_head.is_null() {
+                        kept_tail = node;
+                    }
+                    kept_head = node;
+                } else {
+                    ((*node).reclaim)(node);
+                    reclaimed += 1;
+                }
+            }
+        }
+        self.retired_count.fetch_sub(reclaimed, Relaxed);
+        if !kept_head.is_null() {
+            self.push_retired(kept_head, kept_tail);
+        }
+    }
+
+    fn threshold(&self) -> usize {
+        let slots = self.slot_count.load(Relaxed);
+        RETIRED_THRESHOLD.max(slots.saturating_mul(RETIRED_PER_SLOT))
+    }
+
+    // Push the list from `head` to `tail` onto the retired list.
+    fn push_retired(&self, head: *mut Retired, tail: *mut Retired) {
+        let mut current = self.retired.load(Relaxed);
+        loop {
+            // SAFETY: The list is not shared until the CAS succeeds.
+            unsafe { (*tail).next = current }
+            match self.retired.compare_exchange_weak(current, head, Release, Relaxed) {
+                Ok(_) => return,
+                Err(c) => current = c,
+            }
+        }
+    }
+
+    fn acquire_slot(&self) -> &Slot {
+        let mut slot = self.slots.load(Acquire);
+        while !slot.is_null() {
+            // SAFETY: Slots are not freed while the domain is alive.
+            let s = unsafe { &*slot };
+            if !s.active.load(Relaxed)
+                && s.active.compare_exchange(false, true, Acquire, Relaxed).is_ok()
+            {
+                return s;
+            }
+            slot = s.next;
+        }
+
+        let slot = Box::into_raw(Box::new(Slot {
+            ptr: AtomicPtr::new(ptr::null_mut()),
+            active: AtomicBool::new(true),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.slots.load(Relaxed);
+        loop {
+            // SAFETY: `slot` is not shared until the CAS succeeds.
+            unsafe { (*slot).next = head }
+            match self.slots.compare_exchange_weak(head, slot, Release, Relaxed) {
+                Ok(_) => break,
+                Err(h) => head = h,
+            }
+        }
+        self.slot_count.fetch_add(1, Relaxed);
+        // SAFETY: Slots are not freed while the domain is alive.
+        unsafe { &*slot }
+    }
+}
+
+impl Default for HazardDomain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for HazardDomain {
+    fn drop(&mut self) {
+        // No guards are alive, since they borrow the domain.
+        // SAFETY: We have exclusive access, and the nodes were created by `retire` and
+        // `acquire_slot`.
+        unsafe {
+            let mut retired = *self.retired.get_mut();
+            while !retired.is_null() {
+                let node = retired;
+                retired = (*node).next;
+                ((*node).reclaim)(node);
+            }
+            let mut slot = *self.slots.get_mut();
+            while !slot.is_null() {
+                let s = Box::from_raw(slot);
+                slot = s.next;
+            }
+        }
+    }
+}
+
+impl fmt::Debug for HazardDomain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HazardDomain")
+            .field("slots", &self.slot_count.load(Relaxed))
+            .field("retired", &self.retired_count.load(Relaxed))
+            .finish()
+    }
+}
+
+/// A guard that protects a pointer from being freed, returned by [`HazardDomain::protect`].
+///
+/// The hazard slot is cleared and released for reuse when the guard is dropped.
+#[must_use]
+pub struct HazardGuard<'d, T> {
+    slot: &'d Slot,
+    ptr: *mut T,
+    _marker: PhantomData<*mut T>,
+}
+
+impl<T> HazardGuard<'_, T> {
+    /// Return the protected pointer.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::AtomicPtr;
+    /// use portable_atomic_util::hazard::HazardDomain;
+    ///
+    /// let domain = HazardDomain::new();
+    /// let value = AtomicPtr::<i32>::new(std::ptr::null_mut());
+    /// assert!(domain.protect(&value).as_ptr().is_null());
+    /// ```
+    #[must_use]
+    pub fn as_ptr(&self) -> *mut T {
+        self.ptr
+    }
+
+    /// Return a reference to the protected object, or `None` if the pointer is null.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must have pointed to a valid object when it was loaded, and objects must only
+    /// be freed by [retiring](HazardDomain::retire) them in the domain of this guard.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::AtomicPtr;
+    /// use portable_atomic_util::hazard::HazardDomain;
+    ///
+    /// let domain = HazardDomain::new();
+    /// let value = AtomicPtr::new(Box::into_raw(Box::new(1)));
+    /// let guard = domain.protect(&value);
+    /// // SAFETY: The pointer is protected, and objects are only freed through `domain`.
+    /// assert_eq!(unsafe { guard.as_ref() }, Some(&1));
+    /// # drop(guard);
+    /// # drop(unsafe { Box::from_raw(value.into_inner()) });
+    /// ```
+    #[must_use]
+    pub unsafe fn as_ref(&self) -> Option<&T> {
+        // SAFETY: The caller guarantees that the object is valid, and it is not freed while the
+        // hazard slot protects it.
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T> Drop for HazardGuard<'_, T> {
+    fn drop(&mut self) {
+        self.slot.ptr.store(ptr::null_mut(), Release);
+        self.slot.active.store(false, Release);
+    }
+}
+
+impl<T> fmt::Debug for HazardGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("HazardGuard").field(&self.ptr).finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::DropCounter;
+
+    use portable_atomic::Ordering::AcqRel;
+    use std::thread;
+
+    // An object that poisons its value when dropped, and counts how many times it was dropped.
+    struct Node {
+        value: usize,
+        _drops: DropCounter<'static>,
+    }
+    impl Drop for Node {
+        fn drop(&mut self) {
+            self.value = usize::MAX;
+        }
+    }
+
+    fn new(value: usize, drops: &'static AtomicUsize) -> *mut Node {
+        Box::into_raw(Box::new(Node { value, _drops: DropCounter(drops) }))
+    }
+
+    unsafe fn free(ptr: *mut Node) {
+        // SAFETY: The pointer was created by `new`.
+        drop(unsafe { Box::from_raw(ptr) });
+    }
+
+    #[test]
+    fn concurrent_protect_retire() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        const THREADS: usize = 2;
+        const N: usize = 10_000;
+        let domain = HazardDomain::new();
+        let value = AtomicPtr::new(new(0, &DROPS));
+        let done = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for i in 1..=N {
+                        let old = value.swap(new(i, &DROPS), AcqRel);
+                        // SAFETY: `old` was unlinked by the swap and is only retired once.
+                        unsafe { domain.retire(old, free) };
+                    }
+                    done.fetch_add(1, Release);
+                });
+                s.spawn(|| {
+                    while done.load(Acquire) != THREADS {
+                        let guard = domain.protect(&value);
+                        // SAFETY: The pointer is protected, and objects are only freed through
+                        // `domain`.
+                        let v = unsafe { guard.as_ref() }.unwrap().value;
+                        assert!(v <= N);
+                        thread::yield_now();
+                        // Still protected after other threads ran.
+                        // SAFETY: See above.
+                        assert_eq!(unsafe { guard.as_ref() }.unwrap().value, v);
+                    }
+                });
+            }
+        });
+        // Retired objects are freed while the domain is alive.
+        domain.reclaim();
+        assert_eq!(DROPS.load(Relaxed), THREADS * N);
+        // SAFETY: The object is no longer shared.
+        unsafe { free(value.into_inner()) };
+        assert_eq!(DROPS.load(Relaxed), THREADS * N + 1);
+    }
+
+    #[test]
+    fn protected_object_is_not_freed() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let domain = HazardDomain::new();
+        let value = AtomicPtr::new(new(1, &DROPS));
+        let guard = domain.protect(&value);
+        let old = value.swap(ptr::null_mut(), AcqRel);
+        // SAFETY: `old` was unlinked by the swap and is only retired once.
+        unsafe { domain.retire(old, free) };
+        // Retiring more objects triggers automatic scans, which must skip the protected object.
+        for i in 0..RETIRED_THRESHOLD * 2 {
+            // SAFETY: The object was never shared.
+            unsafe { domain.retire(new(i, &DROPS), free) };
+        }
+        domain.reclaim();
+        assert_eq!(DROPS.load(Relaxed), RETIRED_THRESHOLD * 2);
+        // SAFETY: The pointer is protected, and objects are only freed through `domain`.
+        assert_eq!(unsafe { guard.as_ref() }.unwrap().value, 1);
+        drop(guard);
+        domain.reclaim();
+        assert_eq!(DROPS.load(Relaxed), RETIRED_THRESHOLD * 2 + 1);
+    }
+
+    #[test]
+    fn drop_domain_frees_retired() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        let domain = HazardDomain::new();
+        for i in 0..10 {
+            // SAFETY: The object was never shared.
+            unsafe { domain.retire(new(i, &DROPS), free) };
+        }
+        assert_eq!(DROPS.load(Relaxed), 0);
+        drop(domain);
+        assert_eq!(DROPS.load(Relaxed), 10);
+    }
+}
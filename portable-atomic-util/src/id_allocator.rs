@@ -0,0 +1,530 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A lock-free bitmap allocator for small integer IDs.
+//!
+//! See [`IdAllocator`] for details.
+
+use portable_atomic::{
+    AtomicUsize,
+    Ordering::{AcqRel, Acquire, Relaxed},
+};
+
+use core::{fmt, mem};
+
+const WORD_BITS: usize = mem::size_of::<usize>() * 8;
+
+/// Return the number of words an [`IdAllocator`] needs to hold `bits` IDs.
+///
+/// The array of words of an [`IdAllocator`] cannot be sized by the number of IDs directly, since
+/// that requires arithmetic on const generic parameters, which is not stable. Use this to compute
+/// the const parameter from the number of IDs instead.
+///
+/// # Example
+///
+/// ```
+/// use portable_atomic_util::id_allocator::{self, IdAllocator};
+///
+/// static IDS: IdAllocator<{ id_allocator::words(100) }> = IdAllocator::with_capacity(100);
+/// assert_eq!(IDS.capacity(), 100);
+/// ```
+#[must_use]
+pub const fn words(bits: usize) -> usize {
+    (bits + WORD_BITS - 1) / WORD_BITS
+}
+
+// Return the mask of the `len` bits starting at bit `lo` of a word.
+fn mask(lo: usize, len: usize) -> usize {
+    if len == WORD_BITS {
+        !0
+    } else {
+        ((1 << len) - 1) << lo
+    }
+}
+
+// Return the `(word, mask)` segments of the bits `start..start + n`.
+fn segments(start: usize, n: usize) -> impl Iterator<Item = (usize, usize)> {
+    let end = start + n;
+    let mut bit = start;
+    core::iter::from_fn(move || {
+        if bit >= end {
+            return None;
+        }
+        let word = bit / WORD_BITS;
+        let lo = bit % WORD_BITS;
+        let len = (WORD_BITS - lo).min(end - bit);
+        bit += len;
+        Some((word, mask(lo, len)))
+    })
+}
+
+/// A lock-free allocator of IDs in `0..capacity`, backed by a bitmap of `WORDS` words.
+///
+/// The const parameter is the number of `usize` words, not the number of IDs. Stable Rust cannot
+/// size the bitmap from a number of IDs given as a const parameter, so to allocate `BITS` IDs, use
+/// `IdAllocator<{ id_allocator::words(BITS) }>` (see [`words`]) created with
+/// [`with_capacity(BITS)`](Self::with_capacity), for example through a type alias:
+///
+/// ```
+/// use portable_atomic_util::id_allocator::{self, IdAllocator};
+///
+/// const BITS: usize = 100;
+/// type Ids = IdAllocator<{ id_allocator::words(BITS) }>;
+///
+/// static IDS: Ids = Ids::with_capacity(BITS);
+/// assert_eq!(IDS.capacity(), 100);
+/// ```
+///
+/// [`new`](Self::new) uses all `WORDS * usize::BITS` IDs of the bitmap.
+///
+/// Each ID is a bit in an array of [`AtomicUsize`] words. [`claim`](Self::claim) finds the first
+/// zero bit of a word and sets it with [`bit_set`](AtomicUsize::bit_set), and
+/// [`release`](Self::release) clears it. Claims start at a hinted word, which moves past words
+/// that become full, so that concurrent claims spread over the bitmap instead of all contending on
+/// the first word.
+///
+/// The allocator is stored inline and can be placed in a `static`. It only uses atomic
+/// read-modify-write operations on `AtomicUsize`, so it also works on single-core targets without
+/// native atomic CAS with portable-atomic's `critical-section` feature (or
+/// `unsafe-assume-single-core` cfg), for example to allocate slots from interrupt handlers.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::id_allocator::{self, IdAllocator};
+///
+/// static CONNECTIONS: IdAllocator<{ id_allocator::words(256) }> = IdAllocator::with_capacity(256);
+///
+/// let a = CONNECTIONS.claim().unwrap();
+/// let b = CONNECTIONS.claim().unwrap();
+/// assert_ne!(a, b);
+/// assert!(CONNECTIONS.is_claimed(a));
+/// CONNECTIONS.release(a);
+/// assert!(!CONNECTIONS.is_claimed(a));
+///
+/// // Claim 4 contiguous IDs, for example for a chain of DMA descriptors.
+/// let first = CONNECTIONS.claim_contiguous(4).unwrap();
+/// CONNECTIONS.release_contiguous(first, 4);
+/// ```
+pub struct IdAllocator<const WORDS: usize> {
+    words: [AtomicUsize; WORDS],
+    capacity: usize,
+    // The word to start searching from.
+    hint: AtomicUsize,
+}
+
+impl<const WORDS: usize> IdAllocator<WORDS> {
+    #[allow(clippy::declare_interior_mutable_const)] // only used to initialize the array of words
+    const WORD: AtomicUsize = AtomicUsize::new(0);
+
+    /// Create a new allocator of `WORDS * usize::BITS` IDs, none of which are claimed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::id_allocator::IdAllocator;
+    ///
+    /// let ids = IdAllocator::<2>::new();
+    /// assert_eq!(ids.capacity(), 2 * usize::BITS as usize);
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { words: [Self::WORD; WORDS], capacity: WORDS * WORD_BITS, hint: AtomicUsize::new(0) }
+    }
+
+    /// Create a new allocator of `capacity` IDs, none of which are claimed.
+    ///
+    /// If `capacity` is greater than `WORDS * usize::BITS`, the capacity is
+    /// `WORDS * usize::BITS`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::id_allocator::{self, IdAllocator};
+    ///
+    /// let ids = IdAllocator::<{ id_allocator::words(3) }>::with_capacity(3);
+    /// assert_eq!(ids.capacity(), 3);
+    /// for _ in 0..3 {
+    ///     ids.claim().unwrap();
+    /// }
+    /// assert!(ids.claim().is_none());
+    /// ```
+    #[must_use]
+    pub const fn with_capacity(capacity: usize) -> Self {
+        let mut this = Self::new();
+        if capacity < this.capacity {
+            this.capacity = capacity;
+        }
+        // Mark the IDs past the capacity as claimed, so that they are never handed out.
+        let mut i = 0;
+        while i < WORDS {
+            let base = i * WORD_BITS;
+            let used = if base >= capacity {
+                !0
+            } else if capacity - base >= WORD_BITS {
+                0
+            } else {
+                !0 << (capacity - base)
+            };
+            this.words[i] = AtomicUsize::new(used);
+            i += 1;
+        }
+        this
+    }
+
+    /// Return the number of IDs.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::id_allocator::IdAllocator;
+    ///
+    /// let ids = IdAllocator::<1>::with_capacity(10);
+    /// assert_eq!(ids.capacity(), 10);
+    /// ```
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Claim an unclaimed ID, or return `None` if all IDs are claimed.
+    ///
+    /// The search starts at the hinted word and takes the first zero bit of the first word that
+    /// has one, so the returned ID is not necessarily the lowest unclaimed ID.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::id_allocator::IdAllocator;
+    ///
+    /// let ids = IdAllocator::<1>::with_capacity(2);
+    /// let a = ids.claim().unwrap();
+    /// let b = ids.claim().unwrap();
+    /// assert_ne!(a, b);
+    /// assert!(ids.claim().is_none());
+    /// ```
+    pub fn claim(&self) -> Option<usize> {
+        if WORDS == 0 {
+            return None;
+        }
+        let start = self.hint.load(Relaxed) % WORDS;
+        for i in 0..WORDS {
+            let index = (start + i) % WORDS;
+            let word = &self.words[index];
+            let mut current = word.load(Relaxed);
+            while current != !0 {
+                let bit = (!current).trailing_zeros();
+                if !word.bit_set(bit, AcqRel) {
+                    if current | (1 << bit) == !0 {
+                        // This word is now full; start the next search at the next word.
+                        self.hint.store((index + 1) % WORDS, Relaxed);
+                    } else if i != 0 {
+                        self.hint.store(index, Relaxed);
+                    }
+                    return Some(index * WORD_BITS + bit as usize);
+                }
+                current = word.load(Relaxed);
+            }
+        }
+        None
+    }
+
+    /// Claim `n` contiguous unclaimed IDs, and return the first of them, or return `None` if there
+    /// are no `n` contiguous unclaimed IDs.
+    ///
+    /// The search starts at ID 0 and returns the lowest run that could be claimed. A run that
+    /// spans several words is claimed one word at a time, and the words already claimed are
+    /// released again if a later word turns out to be taken, so concurrent claims may briefly
+    /// see those IDs as claimed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n` is 0.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::id_allocator::IdAllocator;
+    ///
+    /// let ids = IdAllocator::<2>::new();
+    /// let first = ids.claim_contiguous(100).unwrap();
+    /// assert!((first..first + 100).all(|id| ids.is_claimed(id)));
+    /// ```
+    pub fn claim_contiguous(&self, n: usize) -> Option<usize> {
+        assert!(n != 0, "cannot claim 0 IDs");
+        let mut start = 0;
+        while n <= self.capacity && start <= self.capacity - n {
+            // Find a run that looks free, then try to claim it.
+            match self.find_claimed(start, n) {
+                Some(claimed) => start = claimed + 1,
+                None => match self.try_claim_range(start, n) {
+                    Ok(()) => return Some(start),
+                    Err(claimed) => start = claimed + 1,
+                },
+            }
+        }
+        None
+    }
+
+    /// Release a claimed ID.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not less than the capacity or is not claimed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::id_allocator::IdAllocator;
+    ///
+    /// let ids = IdAllocator::<1>::new();
+    /// let id = ids.claim().unwrap();
+    /// ids.release(id);
+    /// assert!(!ids.is_claimed(id));
+    /// ```
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn release(&self, id: usize) {
+        assert!(id < self.capacity, "ID out of range");
+        let was_claimed = self.words[id / WORD_BITS].bit_clear((id % WORD_BITS) as u32, AcqRel);
+        assert!(was_claimed, "releasing an unclaimed ID");
+    }
+
+    /// Release `n` contiguous claimed IDs starting at `start`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any of the IDs is not less than the capacity or is not claimed. In that case,
+    /// none of the IDs are released.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::id_allocator::IdAllocator;
+    ///
+    /// let ids = IdAllocator::<2>::new();
+    /// let first = ids.claim_contiguous(70).unwrap();
+    /// ids.release_contiguous(first, 70);
+    /// assert!(!ids.is_claimed(first));
+    /// ```
+    pub fn release_contiguous(&self, start: usize, n: usize) {
+        assert!(start <= self.capacity && n <= self.capacity - start, "ID range out of range");
+        // Check all IDs before clearing any of them, so that a failed release leaves the claims of
+        // the caller intact.
+        for (index, mask) in segments(start, n) {
+            let claimed = self.words[index].load(Relaxed) & mask;
+            assert!(claimed == mask, "releasing an unclaimed ID");
+        }
+        for (index, mask) in segments(start, n) {
+            self.words[index].fetch_and(!mask, AcqRel);
+        }
+    }
+
+    /// Return `true` if `id` is claimed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `id` is not less than the capacity.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::id_allocator::IdAllocator;
+    ///
+    /// let ids = IdAllocator::<1>::new();
+    /// let id = ids.claim().unwrap();
+    /// assert!(ids.is_claimed(id));
+    /// ```
+    #[must_use]
+    pub fn is_claimed(&self, id: usize) -> bool {
+        assert!(id < self.capacity, "ID out of range");
+        self.words[id / WORD_BITS].load(Acquire) & (1 << (id % WORD_BITS)) != 0
+    }
+
+    // Return the first claimed ID in `start..start + n`.
+    fn find_claimed(&self, start: usize, n: usize) -> Option<usize> {
+        for (index, mask) in segments(start, n) {
+            let claimed = self.words[index].load(Relaxed) & mask;
+            if claimed != 0 {
+                return Some(index * WORD_BITS + claimed.trailing_zeros() as usize);
+            }
+        }
+        None
+    }
+
+    // Claim the IDs `start..start + n`, or return the first ID found to be claimed.
+    fn try_claim_range(&self, start: usize, n: usize) -> Result<(), usize> {
+        for (i, (index, mask)) in segments(start, n).enumerate() {
+            let word = &self.words[index];
+            let mut current = word.load(Relaxed);
+            loop {
+                let claimed = current & mask;
+                if claimed != 0 {
+                    // Release the words claimed so far.
+                    for (index, mask) in segments(start, n).take(i) {
+                        self.words[index].fetch_and(!mask, Relaxed);
+                    }
+                    return Err(index * WORD_BITS + claimed.trailing_zeros() as usize);
+                }
+                match word.compare_exchange_weak(current, current | mask, AcqRel, Relaxed) {
+                    Ok(_) => break,
+                    Err(c) => current = c,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<const WORDS: usize> Default for IdAllocator<WORDS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<const WORDS: usize> fmt::Debug for IdAllocator<WORDS> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdAllocator").field("capacity", &self.capacity).finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use portable_atomic::AtomicBool;
+    use std::{collections::BTreeSet, panic, sync::Barrier, thread, vec::Vec};
+
+    #[derive(Clone, Copy, Debug)]
+    enum Op {
+        Claim,
+        Release(u8),
+        ClaimContiguous(u8),
+    }
+
+    impl quickcheck::Arbitrary for Op {
+        fn arbitrary(g: &mut quickcheck::Gen) -> Self {
+            match u8::arbitrary(g) % 3 {
+                0 => Op::Claim,
+                1 => Op::Release(u8::arbitrary(g)),
+                _ => Op::ClaimContiguous(u8::arbitrary(g) % 80 + 1),
+            }
+        }
+    }
+
+    ::quickcheck::quickcheck! {
+        fn quickcheck_sequential(capacity: u8, ops: Vec<Op>) -> bool {
+            let capacity = usize::from(capacity % 130);
+            let ids = IdAllocator::<3>::with_capacity(capacity);
+            let mut model = BTreeSet::new();
+            for op in ops {
+                match op {
+                    Op::Claim => match ids.claim() {
+                        Some(id) => {
+                            assert!(id < capacity);
+                            assert!(model.insert(id));
+                        }
+                        None => assert_eq!(model.len(), capacity),
+                    },
+                    Op::Release(i) => {
+                        if !model.is_empty() {
+                            let id = *model.iter().nth(usize::from(i) % model.len()).unwrap();
+                            ids.release(id);
+                            model.remove(&id);
+                        }
+                    }
+                    Op::ClaimContiguous(n) => {
+                        let n = usize::from(n);
+                        // The lowest run of `n` unclaimed IDs.
+                        let expected = (0..capacity.saturating_sub(n - 1))
+                            .find(|&start| (start..start + n).all(|id| !model.contains(&id)));
+                        assert_eq!(ids.claim_contiguous(n), expected);
+                        if let Some(start) = expected {
+                            model.extend(start..start + n);
+                        }
+                    }
+                }
+                assert!((0..capacity).all(|id| ids.is_claimed(id) == model.contains(&id)));
+            }
+            true
+        }
+    }
+
+    #[test]
+    fn concurrent_claim_release() {
+        const THREADS: usize = 4;
+        const N: usize = 10_000;
+        const CAPACITY: usize = 100;
+        let ids = IdAllocator::<{ words(CAPACITY) }>::with_capacity(CAPACITY);
+        let owned: Vec<_> = (0..CAPACITY).map(|_| AtomicBool::new(false)).collect();
+        let barrier = Barrier::new(THREADS);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    barrier.wait();
+                    let mut held = Vec::new();
+                    for i in 0..N {
+                        if let Some(id) = ids.claim() {
+                            // An ID is never handed out twice.
+                            assert!(!owned[id].swap(true, Relaxed));
+                            held.push(id);
+                        }
+                        if i % 3 == 0 || held.len() > CAPACITY / THREADS {
+                            for id in held.drain(..) {
+                                owned[id].store(false, Relaxed);
+                                ids.release(id);
+                            }
+                        }
+                    }
+                    for id in held {
+                        owned[id].store(false, Relaxed);
+                        ids.release(id);
+                    }
+                });
+            }
+        });
+        assert!((0..CAPACITY).all(|id| !ids.is_claimed(id)));
+    }
+
+    #[test]
+    fn concurrent_claim_contiguous() {
+        const THREADS: usize = 4;
+        const N: usize = 1000;
+        let ids = IdAllocator::<4>::new();
+        let owned: Vec<_> = (0..ids.capacity()).map(|_| AtomicBool::new(false)).collect();
+        let barrier = Barrier::new(THREADS);
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let (ids, owned, barrier) = (&ids, &owned, &barrier);
+                s.spawn(move || {
+                    barrier.wait();
+                    for i in 0..N {
+                        // Runs of various lengths, some of which span several words.
+                        let n = (t * 31 + i * 7) % 70 + 1;
+                        if let Some(start) = ids.claim_contiguous(n) {
+                            for owned in &owned[start..start + n] {
+                                assert!(!owned.swap(true, Relaxed));
+                            }
+                            thread::yield_now();
+                            for owned in &owned[start..start + n] {
+                                owned.store(false, Relaxed);
+                            }
+                            ids.release_contiguous(start, n);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(ids.claim_contiguous(ids.capacity()), Some(0));
+    }
+
+    #[test]
+    fn release_contiguous_unclaimed() {
+        let ids = IdAllocator::<2>::new();
+        let start = ids.claim_contiguous(100).unwrap();
+        ids.release(start + 80);
+        let res = panic::catch_unwind(|| ids.release_contiguous(start, 100));
+        assert!(res.is_err());
+        // The IDs that were claimed are still claimed.
+        assert!((start..start + 100).all(|id| ids.is_claimed(id) == (id != start + 80)));
+    }
+}
@@ -0,0 +1,610 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Software multi-word compare-and-swap (k-CAS).
+//!
+//! [`kcas`] atomically replaces the values of several [`KCasWord`]s if all of them hold their
+//! expected values, and [`read`] returns a consistent snapshot of several words. Both are
+//! lock-free: a thread that finds an operation in progress helps it complete instead of waiting
+//! for it.
+//!
+//! Operations are described by heap-allocated descriptors that other threads may still be reading
+//! after the operation completes, so descriptors are freed through a [`Reclaim`] strategy. The
+//! strategy is a type parameter of [`KCasWord`], so all operations on a word use the same one.
+//! With the `std` feature, [`DefaultReclaim`] uses [epoch-based reclamation](crate::epoch).
+//!
+//! The two low bits of each word are used to mark descriptors, so values must be multiples of 4,
+//! such as pointers to types aligned to at least 4 bytes, or integers shifted left by 2.
+//!
+//! See Timothy L. Harris, Keir Fraser, and Ian A. Pratt, "A Practical Multi-Word
+//! Compare-and-Swap Operation" for the algorithm.
+//!
+//! # Examples
+//!
+//! ```
+//! use portable_atomic_util::kcas::{self, DefaultReclaim, KCasWord};
+//!
+//! let a = KCasWord::<DefaultReclaim>::new(4);
+//! let b = KCasWord::<DefaultReclaim>::new(8);
+//!
+//! // SAFETY: `a` and `b` outlive all threads that may access them.
+//! unsafe {
+//!     assert!(kcas::kcas(&[(&a, 4, 12), (&b, 8, 16)]));
+//!     assert!(!kcas::kcas(&[(&a, 12, 0), (&b, 8, 0)]));
+//!     assert_eq!(kcas::read(&[&a, &b]), [12, 16]);
+//! }
+//! ```
+
+// A k-CAS first installs its descriptor in each word, in address order, with RDCSS (a double-
+// compare single-swap that only installs the descriptor if the word holds the expected value and
+// the operation is still undecided). If every word held its expected value, the operation
+// succeeds, otherwise it fails. Either way, the descriptor is then replaced by the new or the
+// expected value in each word. Any thread that finds a descriptor in a word helps the operation
+// before retrying.
+//
+// RDCSS descriptors are unique per attempt, so a delayed helper cannot complete an RDCSS that was
+// already completed. They hold a reference to the k-CAS descriptor, because an RDCSS can still be
+// installed (and then reverted) by a delayed helper after the k-CAS has finished. Before retiring
+// its descriptor, the thread that started a k-CAS makes sure that no word refers to it anymore;
+// any RDCSS installed after that sees the decided status and reverts.
+
+use portable_atomic::{AtomicUsize, Ordering::SeqCst};
+
+use crate::Arc;
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{fmt, marker::PhantomData, mem::ManuallyDrop};
+
+const TAG: usize = 0b11;
+const RDCSS_TAG: usize = 0b01;
+const KCAS_TAG: usize = 0b10;
+
+const UNDECIDED: usize = 0;
+const SUCCEEDED: usize = 1;
+const FAILED: usize = 2;
+
+/// A memory reclamation strategy for k-CAS descriptors.
+///
+/// # Safety
+///
+/// A pointer passed to [`retire`](Self::retire) must not be passed to the deleter until every
+/// guard returned by [`pin`](Self::pin) that existed at the time of the call has been dropped.
+///
+/// # Examples
+///
+/// Epoch-based reclamation with a collector that is private to the data structure:
+///
+/// ```
+/// use portable_atomic_util::{epoch, kcas::Reclaim};
+/// use std::sync::OnceLock;
+///
+/// struct MyReclaim;
+///
+/// fn collector() -> &'static epoch::Collector {
+///     static COLLECTOR: OnceLock<epoch::Collector> = OnceLock::new();
+///     COLLECTOR.get_or_init(epoch::Collector::new)
+/// }
+///
+/// thread_local! {
+///     static HANDLE: epoch::LocalHandle = collector().register();
+/// }
+///
+/// // SAFETY: Deferred functions run once all participants pinned at the time have unpinned.
+/// unsafe impl Reclaim for MyReclaim {
+///     type Guard = epoch::Guard;
+///
+///     fn pin() -> epoch::Guard {
+///         HANDLE.with(epoch::LocalHandle::pin)
+///     }
+///
+///     unsafe fn retire(guard: &epoch::Guard, ptr: *mut (), deleter: unsafe fn(*mut ())) {
+///         // SAFETY: The collector is never dropped, and the caller guarantees that `ptr` can be
+///         // passed to `deleter` on any thread.
+///         unsafe { guard.defer_unchecked(move || deleter(ptr)) }
+///     }
+/// }
+/// ```
+pub unsafe trait Reclaim {
+    /// A guard that keeps retired descriptors alive while it exists.
+    type Guard;
+
+    /// Protect the descriptors that the current thread may load until the guard is dropped.
+    fn pin() -> Self::Guard;
+
+    /// Call `deleter(ptr)` once all guards that exist at the time of the call have been dropped.
+    ///
+    /// # Safety
+    ///
+    /// `guard` must have been returned by [`pin`](Self::pin), and it must be safe to call
+    /// `deleter(ptr)` on any thread once.
+    unsafe fn retire(guard: &Self::Guard, ptr: *mut (), deleter: unsafe fn(*mut ()));
+}
+
+/// The default reclamation strategy, which defers freeing descriptors through the
+/// [default epoch collector](crate::epoch::default_collector).
+#[cfg(feature = "std")]
+#[cfg_attr(docsrs, doc(cfg(feature = "std")))]
+#[allow(clippy::exhaustive_structs)]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultReclaim;
+
+// SAFETY: Deferred functions are called once all participants pinned at the time have unpinned.
+#[cfg(feature = "std")]
+unsafe impl Reclaim for DefaultReclaim {
+    type Guard = crate::epoch::Guard;
+
+    fn pin() -> Self::Guard {
+        crate::epoch::pin()
+    }
+
+    unsafe fn retire(guard: &Self::Guard, ptr: *mut (), deleter: unsafe fn(*mut ())) {
+        // SAFETY: The default collector is never dropped, and the caller guarantees that `ptr`
+        // can be passed to `deleter` on any thread.
+        unsafe { guard.defer_unchecked(move || deleter(ptr)) }
+    }
+}
+
+/// A word that can be updated by [`kcas`], with descriptors reclaimed by `R`.
+pub struct KCasWord<R> {
+    value: AtomicUsize,
+    _reclaim: PhantomData<fn() -> R>,
+}
+
+impl<R> KCasWord<R> {
+    /// Create a new word holding `value`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `value` is not a multiple of 4.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::kcas::{DefaultReclaim, KCasWord};
+    ///
+    /// let word = KCasWord::<DefaultReclaim>::new(4);
+    /// assert_eq!(word.load(), 4);
+    /// ```
+    #[must_use]
+    pub fn new(value: usize) -> Self {
+        assert_untagged(value);
+        Self { value: AtomicUsize::new(value), _reclaim: PhantomData }
+    }
+}
+
+impl<R: Reclaim> KCasWord<R> {
+    /// Load the value of the word, helping the operation in progress on it, if any.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::kcas::{self, DefaultReclaim, KCasWord};
+    ///
+    /// let word = KCasWord::<DefaultReclaim>::new(0);
+    /// // SAFETY: `word` outlives all threads that may access it.
+    /// unsafe { kcas::kcas(&[(&word, 0, 4)]) };
+    /// assert_eq!(word.load(), 4);
+    /// ```
+    #[must_use]
+    pub fn load(&self) -> usize {
+        let guard = R::pin();
+        load::<R>(&self.value, &guard)
+    }
+}
+
+impl<R: Reclaim> fmt::Debug for KCasWord<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("KCasWord").field(&self.load()).finish()
+    }
+}
+
+/// Atomically replace the value of each word with the new value if all of them hold their
+/// expected values.
+///
+/// Each entry is `(word, expected, new)`. Return `true` if the words were updated. If `entries`
+/// is empty, this returns `true`.
+///
+/// # Panics
+///
+/// Panics if a word appears in `entries` more than once, or if a value is not a multiple of 4.
+///
+/// # Safety
+///
+/// Other threads may still help this operation, and write to the words, after this returns. The
+/// words must not be deallocated or reused until every guard returned by `R::pin` that exists
+/// when this returns has been dropped, for example by freeing them through `R::retire`.
+///
+/// # Example
+///
+/// ```
+/// use portable_atomic_util::kcas::{self, DefaultReclaim, KCasWord};
+///
+/// let a = KCasWord::<DefaultReclaim>::new(0);
+/// let b = KCasWord::<DefaultReclaim>::new(0);
+/// let c = KCasWord::<DefaultReclaim>::new(0);
+/// // SAFETY: The words outlive all threads that may access them.
+/// unsafe {
+///     assert!(kcas::kcas(&[(&a, 0, 4), (&b, 0, 8), (&c, 0, 12)]));
+///     assert!(!kcas::kcas(&[(&a, 4, 0), (&c, 0, 0)]));
+/// }
+/// assert_eq!((a.load(), b.load(), c.load()), (4, 8, 12));
+/// ```
+pub unsafe fn kcas<R: Reclaim>(entries: &[(&KCasWord<R>, usize, usize)]) -> bool {
+    let mut sorted: Vec<Entry> = entries
+        .iter()
+        .map(|&(word, expected, new)| {
+            assert_untagged(expected);
+            assert_untagged(new);
+            Entry { word: &word.value, expected, new }
+        })
+        .collect();
+    sorted.sort_unstable_by_key(|entry| entry.word as usize);
+    assert!(
+        sorted.windows(2).all(|w| w[0].word != w[1].word),
+        "a word appears more than once in a k-CAS"
+    );
+
+    let guard = R::pin();
+    if let [entry] = &sorted[..] {
+        // SAFETY: The caller guarantees that the word is valid.
+        let word = unsafe { &*entry.word };
+        return loop {
+            if load::<R>(word, &guard) != entry.expected {
+                break false;
+            }
+            if word.compare_exchange(entry.expected, entry.new, SeqCst, SeqCst).is_ok() {
+                break true;
+            }
+        };
+    }
+
+    let desc = Arc::into_raw(Arc::new(Descriptor {
+        status: AtomicUsize::new(UNDECIDED),
+        entries: sorted,
+    }));
+    // SAFETY: The descriptor is alive until it is retired below, and the caller guarantees that
+    // the words are valid.
+    unsafe {
+        let succeeded = help::<R>(desc, &guard);
+        finish(desc);
+        R::retire(&guard, desc as *mut (), drop_descriptor);
+        succeeded
+    }
+}
+
+/// Read the values of the words as of a single point in time.
+///
+/// This performs a k-CAS that replaces each value with itself, so it is as expensive as a
+/// [`kcas`] of the same words.
+///
+/// # Panics
+///
+/// Panics if a word appears in `words` more than once.
+///
+/// # Safety
+///
+/// The same as for [`kcas`].
+///
+/// # Example
+///
+/// ```
+/// use portable_atomic_util::kcas::{self, DefaultReclaim, KCasWord};
+///
+/// let a = KCasWord::<DefaultReclaim>::new(4);
+/// let b = KCasWord::<DefaultReclaim>::new(8);
+/// // SAFETY: The words outlive all threads that may access them.
+/// assert_eq!(unsafe { kcas::read(&[&a, &b]) }, [4, 8]);
+/// ```
+#[must_use]
+pub unsafe fn read<R: Reclaim>(words: &[&KCasWord<R>]) -> Vec<usize> {
+    loop {
+        let values: Vec<usize> = words.iter().map(|word| word.load()).collect();
+        if words.len() <= 1 {
+            return values;
+        }
+        let entries: Vec<_> =
+            words.iter().zip(&values).map(|(&word, &value)| (word, value, value)).collect();
+        // SAFETY: The caller guarantees that the words are valid.
+        if unsafe { kcas(&entries) } {
+            return values;
+        }
+    }
+}
+
+fn assert_untagged(value: usize) {
+    assert!(value & TAG == 0, "k-CAS values must be multiples of 4");
+}
+
+struct Entry {
+    word: *const AtomicUsize,
+    expected: usize,
+    new: usize,
+}
+
+// A k-CAS descriptor, shared through `Arc`.
+#[repr(align(4))]
+struct Descriptor {
+    status: AtomicUsize,
+    // Sorted by address.
+    entries: Vec<Entry>,
+}
+
+// An RDCSS descriptor that installs `kcas` in the `index`th word.
+#[repr(align(4))]
+struct Rdcss {
+    kcas: Arc<Descriptor>,
+    index: usize,
+}
+
+unsafe fn drop_descriptor(ptr: *mut ()) {
+    // SAFETY: The pointer was created by `Arc::into_raw`.
+    drop(unsafe { Arc::from_raw(ptr as *const Descriptor) });
+}
+
+unsafe fn drop_rdcss(ptr: *mut ()) {
+    // SAFETY: The pointer was created by `Box::into_raw`.
+    drop(unsafe { Box::from_raw(ptr as *mut Rdcss) });
+}
+
+// Load the value of `word`, helping the operations in progress on it.
+fn load<R: Reclaim>(word: &AtomicUsize, guard: &R::Guard) -> usize {
+    loop {
+        let value = word.load(SeqCst);
+        match value & TAG {
+            0 => return value,
+            // SAFETY: Descriptors loaded from a word are protected by `guard`, and the words they
+            // refer to are valid, as guaranteed by the callers of `kcas`.
+            RDCSS_TAG => unsafe { complete((value & !TAG) as *const Rdcss) },
+            // SAFETY: See above.
+            _ => unsafe {
+                help::<R>((value & !TAG) as *const Descriptor, guard);
+            },
+        }
+    }
+}
+
+// Drive the k-CAS to completion, and return `true` if it succeeded.
+//
+// The descriptor and the words it refers to must be valid.
+unsafe fn help<R: Reclaim>(desc: *const Descriptor, guard: &R::Guard) -> bool {
+    // SAFETY: The caller guarantees that the descriptor is valid.
+    let d = unsafe { &*desc };
+    let tagged = desc as usize | KCAS_TAG;
+    if d.status.load(SeqCst) == UNDECIDED {
+        let mut status = SUCCEEDED;
+        'entries: for index in 0..d.entries.len() {
+            loop {
+                // SAFETY: The caller guarantees that the descriptor and the words are valid.
+                let value = unsafe { rdcss::<R>(desc, index, guard) };
+                if value == tagged {
+                    // Already installed by another thread.
+                    break;
+                }
+                if value & TAG == KCAS_TAG {
+                    // SAFETY: Descriptors loaded from a word are protected by `guard`.
+                    unsafe { help::<R>((value & !TAG) as *const Descriptor, guard) };
+                    continue;
+                }
+                if value != d.entries[index].expected {
+                    status = FAILED;
+                    break 'entries;
+                }
+                break;
+            }
+        }
+        let _ = d.status.compare_exchange(UNDECIDED, status, SeqCst, SeqCst);
+    }
+    let succeeded = d.status.load(SeqCst) == SUCCEEDED;
+    for entry in &d.entries {
+        let value = if succeeded { entry.new } else { entry.expected };
+        // SAFETY: The caller guarantees that the words are valid.
+        let _ = unsafe { &*entry.word }.compare_exchange(tagged, value, SeqCst, SeqCst);
+    }
+    succeeded
+}
+
+// Install the k-CAS descriptor in the `index`th word if the word holds the expected value and the
+// k-CAS is undecided. Return the value found in the word, which is not an RDCSS descriptor.
+unsafe fn rdcss<R: Reclaim>(desc: *const Descriptor, index: usize, guard: &R::Guard) -> usize {
+    // SAFETY: The caller guarantees that the descriptor and the words are valid.
+    let entry = unsafe { &(&*desc).entries[index] };
+    // SAFETY: See above.
+    let word = unsafe { &*entry.word };
+    let mut rdcss: *mut Rdcss = core::ptr::null_mut();
+    loop {
+        let value = word.load(SeqCst);
+        if value & TAG == RDCSS_TAG {
+            // SAFETY: Descriptors loaded from a word are protected by `guard`.
+            unsafe { complete((value & !TAG) as *const Rdcss) };
+            continue;
+        }
+        if value != entry.expected {
+            if !rdcss.is_null() {
+                // SAFETY: The RDCSS descriptor was never installed.
+                unsafe { drop_rdcss(rdcss as *mut ()) };
+            }
+            return value;
+        }
+        if rdcss.is_null() {
+            // SAFETY: The k-CAS descriptor is valid, so it has a strong reference. Cloning an
+            // `Arc` created from the raw pointer adds a reference that is owned by the RDCSS.
+            let kcas = unsafe { Arc::clone(&ManuallyDrop::new(Arc::from_raw(desc))) };
+            rdcss = Box::into_raw(Box::new(Rdcss { kcas, index }));
+        }
+        if word.compare_exchange(value, rdcss as usize | RDCSS_TAG, SeqCst, SeqCst).is_ok() {
+            // SAFETY: The RDCSS descriptor was installed by this thread, and is removed from the
+            // word once it is completed, so it can be retired.
+            unsafe {
+                complete(rdcss);
+                R::retire(guard, rdcss as *mut (), drop_rdcss);
+            }
+            return value;
+        }
+    }
+}
+
+// Replace the RDCSS descriptor with the k-CAS descriptor if the k-CAS is undecided, or with the
+// expected value otherwise.
+//
+// The RDCSS descriptor must be valid.
+unsafe fn complete(rdcss: *const Rdcss) {
+    // SAFETY: The caller guarantees that the descriptor is valid, and it keeps the k-CAS
+    // descriptor alive.
+    let r = unsafe { &*rdcss };
+    let entry = &r.kcas.entries[r.index];
+    let value = if r.kcas.status.load(SeqCst) == UNDECIDED {
+        Arc::as_ptr(&r.kcas) as usize | KCAS_TAG
+    } else {
+        entry.expected
+    };
+    // SAFETY: The words of a valid k-CAS descriptor are valid.
+    let word = unsafe { &*entry.word };
+    let _ = word.compare_exchange(rdcss as usize | RDCSS_TAG, value, SeqCst, SeqCst);
+}
+
+// Remove all references to the decided k-CAS from its words, so that the k-CAS descriptor can be
+// retired.
+unsafe fn finish(desc: *const Descriptor) {
+    // SAFETY: The caller guarantees that the descriptor and the words are valid.
+    let d = unsafe { &*desc };
+    let succeeded = d.status.load(SeqCst) == SUCCEEDED;
+    let tagged = desc as usize | KCAS_TAG;
+    for entry in &d.entries {
+        // SAFETY: See above.
+        let word = unsafe { &*entry.word };
+        loop {
+            let value = word.load(SeqCst);
+            if value == tagged {
+                let new = if succeeded { entry.new } else { entry.expected };
+                if word.compare_exchange(value, new, SeqCst, SeqCst).is_ok() {
+                    break;
+                }
+            } else if value & TAG == RDCSS_TAG {
+                let rdcss = (value & !TAG) as *const Rdcss;
+                // SAFETY: Descriptors loaded from a word are protected by the caller's guard.
+                if unsafe { Arc::as_ptr(&(*rdcss).kcas) } != desc {
+                    break;
+                }
+                // SAFETY: See above. The k-CAS is decided, so this reverts the word.
+                unsafe { complete(rdcss) };
+            } else {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::epoch::{self, Collector, LocalHandle};
+    use std::thread;
+
+    // Counts the descriptors that were retired and freed, using a private collector so that other
+    // tests do not delay reclamation.
+    struct CountingReclaim;
+
+    static RETIRED: AtomicUsize = AtomicUsize::new(0);
+    static FREED: AtomicUsize = AtomicUsize::new(0);
+
+    fn collector() -> &'static Collector {
+        static COLLECTOR: crate::OnceLock<Collector> = crate::OnceLock::new();
+        COLLECTOR.get_or_init(Collector::new)
+    }
+
+    std::thread_local! {
+        static HANDLE: LocalHandle = collector().register();
+    }
+
+    // SAFETY: Deferred functions run once all participants pinned at the time have unpinned.
+    unsafe impl Reclaim for CountingReclaim {
+        type Guard = epoch::Guard;
+
+        fn pin() -> epoch::Guard {
+            HANDLE.with(LocalHandle::pin)
+        }
+
+        unsafe fn retire(guard: &epoch::Guard, ptr: *mut (), deleter: unsafe fn(*mut ())) {
+            RETIRED.fetch_add(1, SeqCst);
+            // SAFETY: The collector is never dropped, and the caller guarantees that `ptr` can be
+            // passed to `deleter` on any thread.
+            unsafe {
+                guard.defer_unchecked(move || {
+                    deleter(ptr);
+                    FREED.fetch_add(1, SeqCst);
+                });
+            }
+        }
+    }
+
+    type Word = KCasWord<CountingReclaim>;
+
+    #[test]
+    fn concurrent_transfers() {
+        const THREADS: usize = 4;
+        const N: usize = 2000;
+        const WORDS: usize = 3;
+        const TOTAL: usize = WORDS * 100 * 4;
+        let words: [Word; WORDS] = [Word::new(400), Word::new(400), Word::new(400)];
+        // The number of successful transfers, times 4.
+        let count = Word::new(0);
+        let successes: usize = thread::scope(|s| {
+            let handles: std::vec::Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let (words, count) = (&words, &count);
+                    s.spawn(move || {
+                        let mut successes = 0;
+                        for i in 0..N {
+                            let (from, to) = ((t + i) % WORDS, (t + i + 1) % WORDS);
+                            let (a, b, c) = (words[from].load(), words[to].load(), count.load());
+                            if a == 0 {
+                                continue;
+                            }
+                            // SAFETY: The words outlive all threads, and are dropped only after
+                            // the collector has freed all descriptors.
+                            let ok = unsafe {
+                                kcas(&[
+                                    (&words[from], a, a - 4),
+                                    (&words[to], b, b + 4),
+                                    (count, c, c + 4),
+                                ])
+                            };
+                            if ok {
+                                successes += 1;
+                            }
+                            // A snapshot always has the same total.
+                            // SAFETY: See above.
+                            let values = unsafe { read(&[&words[0], &words[1], &words[2]]) };
+                            assert_eq!(values.iter().sum::<usize>(), TOTAL);
+                        }
+                        successes
+                    })
+                })
+                .collect();
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+        assert_eq!(words.iter().map(Word::load).sum::<usize>(), TOTAL);
+        assert_eq!(count.load(), successes * 4);
+
+        // Every retired descriptor is eventually freed. Exited threads hand their garbage to the
+        // collector from thread-local destructors, which may still be running, so yield between
+        // attempts.
+        for _ in 0..10_000 {
+            if FREED.load(SeqCst) == RETIRED.load(SeqCst) {
+                break;
+            }
+            HANDLE.with(|handle| handle.pin().flush());
+            thread::yield_now();
+        }
+        assert!(RETIRED.load(SeqCst) >= successes);
+        assert_eq!(FREED.load(SeqCst), RETIRED.load(SeqCst));
+    }
+
+    #[test]
+    #[should_panic = "a word appears more than once in a k-CAS"]
+    fn duplicate_word() {
+        let a = Word::new(0);
+        // SAFETY: `a` outlives all threads that may access it.
+        unsafe { kcas(&[(&a, 0, 4), (&a, 0, 8)]) };
+    }
+}
@@ -0,0 +1,596 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A left-right concurrency primitive for read-mostly data.
+//!
+//! A left-right keeps two copies of a value. Readers read one copy, and the single writer
+//! modifies the other. Modifications are recorded as operations in an oplog, and
+//! [`publish`](WriteHandle::publish) applies them to the writer's copy and swaps the copies, so
+//! that readers see all operations published so far. The operations are then replayed on the
+//! other copy once all readers have left it.
+//!
+//! Entering and leaving a read is a store, a [`fence(SeqCst)`](portable_atomic::fence) and a
+//! load on an epoch counter owned by the [`ReadHandle`], so readers are wait-free and never
+//! contend with each other or with the writer. In exchange, the data is stored twice, every
+//! operation is applied twice, and the writer may have to wait for readers.
+//!
+//! See Pedro Ramalhete and Andreia Correia, "Left-Right: A Concurrency Control Technique with
+//! Wait-Free Population Oblivious Reads" for the algorithm.
+//!
+//! # Examples
+//!
+//! ```
+//! use portable_atomic_util::left_right::{self, Absorb};
+//! use std::{collections::HashMap, thread};
+//!
+//! enum Op {
+//!     Insert(u32, &'static str),
+//!     Remove(u32),
+//! }
+//!
+//! #[derive(Clone, Default)]
+//! struct Table(HashMap<u32, &'static str>);
+//!
+//! impl Absorb<Op> for Table {
+//!     fn absorb(&mut self, op: &Op) {
+//!         match *op {
+//!             Op::Insert(k, v) => {
+//!                 self.0.insert(k, v);
+//!             }
+//!             Op::Remove(k) => {
+//!                 self.0.remove(&k);
+//!             }
+//!         }
+//!     }
+//! }
+//!
+//! let (mut writer, reader) = left_right::new(Table::default());
+//! writer.append(Op::Insert(1, "one"));
+//! writer.append(Op::Insert(2, "two"));
+//! // Operations are not visible to readers until they are published.
+//! assert!(reader.read().0.is_empty());
+//! writer.publish();
+//!
+//! let handle = thread::spawn(move || reader.read().0.get(&1).copied());
+//! assert_eq!(handle.join().unwrap(), Some("one"));
+//!
+//! writer.append(Op::Remove(1));
+//! writer.publish();
+//! assert_eq!(writer.reader().read().0.len(), 1);
+//! ```
+
+// Each reader has an epoch counter, which it increments when it enters and leaves a read, so the
+// counter is odd while the reader may be reading a copy. A reader stores the new (odd) epoch,
+// issues a SeqCst fence, and then loads the index of the copy to read. The writer swaps the index,
+// issues a SeqCst fence, and later loads the epochs: if a reader loaded the old index, its fence
+// precedes the writer's fence, so the writer sees its odd epoch. The writer records the epochs it
+// sees after each swap, and before modifying the copy that is no longer loaded, it waits for the
+// readers whose recorded epoch was odd to change their epoch.
+
+use portable_atomic::{
+    fence, AtomicBool, AtomicPtr, AtomicUsize,
+    Ordering::{AcqRel, Acquire, Relaxed, Release, SeqCst},
+};
+
+use crate::{utils::Backoff, Arc};
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{cell::Cell, cell::UnsafeCell, fmt, ops::Deref, ptr};
+
+/// A value that can absorb the operations of a left-right oplog.
+///
+/// Each operation is applied to both copies of the value, at different times, so
+/// [`absorb`](Self::absorb) must be deterministic: applying the same sequence of operations to two
+/// equal values must produce two equal values.
+pub trait Absorb<O> {
+    /// Apply `op` to `self`.
+    fn absorb(&mut self, op: &O);
+}
+
+struct ReaderSlot {
+    // Odd while the reader is in a read.
+    epoch: AtomicUsize,
+    // The epoch when the writer last swapped the copies. Only accessed by the writer.
+    swapped_at: AtomicUsize,
+    // Whether a `ReadHandle` owns this slot.
+    active: AtomicBool,
+    next: *mut ReaderSlot,
+}
+
+struct Inner<T> {
+    copies: [UnsafeCell<T>; 2],
+    // The index of the copy that readers read.
+    read_index: AtomicUsize,
+    readers: AtomicPtr<ReaderSlot>,
+}
+
+// Send is implicitly implemented.
+// SAFETY: Readers only get shared references to the copy they read, and the writer only modifies
+// the other copy after all readers have left it.
+unsafe impl<T: Send + Sync> Sync for Inner<T> {}
+
+impl<T> Inner<T> {
+    fn acquire_slot(&self) -> *const ReaderSlot {
+        let mut slot = self.readers.load(Acquire);
+        while !slot.is_null() {
+            // SAFETY: Slots are not freed while `Inner` is alive.
+            let s = unsafe { &*slot };
+            if !s.active.load(Relaxed)
+                && s.active.compare_exchange(false, true, Acquire, Relaxed).is_ok()
+            {
+                return s;
+            }
+            slot = s.next;
+        }
+
+        let slot = Box::into_raw(Box::new(ReaderSlot {
+            epoch: AtomicUsize::new(0),
+            swapped_at: AtomicUsize::new(0),
+            active: AtomicBool::new(true),
+            next: ptr::null_mut(),
+        }));
+        let mut head = self.readers.load(Relaxed);
+        loop {
+            // SAFETY: `slot` is not shared until the CAS succeeds.
+            unsafe { (*slot).next = head }
+            match self.readers.compare_exchange_weak(head, slot, Release, Relaxed) {
+                Ok(_) => return slot,
+                Err(h) => head = h,
+            }
+        }
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        let mut slot = *self.readers.get_mut();
+        while !slot.is_null() {
+            // SAFETY: We have exclusive access, and the slots were created by `acquire_slot`.
+            let s = unsafe { Box::from_raw(slot) };
+            slot = s.next;
+        }
+    }
+}
+
+/// Create a new left-right holding two clones of `value`.
+///
+/// Returns the only [`WriteHandle`] and a first [`ReadHandle`]. More read handles can be created
+/// by cloning a read handle or with [`WriteHandle::reader`]. The value is dropped once all
+/// handles are dropped.
+///
+/// # Example
+///
+/// ```
+/// use portable_atomic_util::left_right::{self, Absorb};
+///
+/// #[derive(Clone)]
+/// struct Counter(u64);
+///
+/// impl Absorb<u64> for Counter {
+///     fn absorb(&mut self, op: &u64) {
+///         self.0 += op;
+///     }
+/// }
+///
+/// let (mut writer, reader) = left_right::new(Counter(0));
+/// writer.append(2);
+/// writer.publish();
+/// assert_eq!(reader.read().0, 2);
+/// ```
+pub fn new<T, O>(value: T) -> (WriteHandle<T, O>, ReadHandle<T>)
+where
+    T: Absorb<O> + Clone,
+{
+    let inner = Arc::new(Inner {
+        copies: [UnsafeCell::new(value.clone()), UnsafeCell::new(value)],
+        read_index: AtomicUsize::new(0),
+        readers: AtomicPtr::new(ptr::null_mut()),
+    });
+    let reader = ReadHandle::new(Arc::clone(&inner));
+    (WriteHandle { inner, oplog: Vec::new(), applied: 0 }, reader)
+}
+
+/// The write handle of a left-right, created by [`new`].
+///
+/// There is only one write handle for each left-right.
+pub struct WriteHandle<T, O> {
+    inner: Arc<Inner<T>>,
+    oplog: Vec<O>,
+    // The number of operations at the start of `oplog` that have been applied to the copy
+    // readers read, but not yet to the other copy.
+    applied: usize,
+}
+
+impl<T: Absorb<O>, O> WriteHandle<T, O> {
+    /// Append `op` to the oplog.
+    ///
+    /// The operation becomes visible to readers at the next [`publish`](Self::publish).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::left_right::{self, Absorb};
+    ///
+    /// #[derive(Clone)]
+    /// struct Log(Vec<u8>);
+    ///
+    /// impl Absorb<u8> for Log {
+    ///     fn absorb(&mut self, op: &u8) {
+    ///         self.0.push(*op);
+    ///     }
+    /// }
+    ///
+    /// let (mut writer, reader) = left_right::new(Log(vec![]));
+    /// writer.append(1);
+    /// assert!(reader.read().0.is_empty());
+    /// writer.publish();
+    /// assert_eq!(reader.read().0, [1]);
+    /// ```
+    pub fn append(&mut self, op: O) {
+        self.oplog.push(op);
+    }
+
+    /// Make all appended operations visible to readers.
+    ///
+    /// This first waits until no reader is reading the writer's copy, that is, until every reader
+    /// that was in a read at the end of the previous `publish` has left it. It then
+    /// applies the operations published last time and the newly appended operations to that copy,
+    /// and swaps the copies.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::left_right::{self, Absorb};
+    ///
+    /// #[derive(Clone)]
+    /// struct Value(i32);
+    ///
+    /// impl Absorb<i32> for Value {
+    ///     fn absorb(&mut self, op: &i32) {
+    ///         self.0 = *op;
+    ///     }
+    /// }
+    ///
+    /// let (mut writer, reader) = left_right::new(Value(0));
+    /// writer.append(1);
+    /// writer.publish();
+    /// writer.append(2);
+    /// writer.publish();
+    /// assert_eq!(reader.read().0, 2);
+    /// ```
+    pub fn publish(&mut self) {
+        self.wait_for_readers();
+
+        let read_index = self.inner.read_index.load(Relaxed);
+        // SAFETY: Readers only read the copy at `read_index`, and no reader is still reading the
+        // other copy (see `wait_for_readers`).
+        let copy = unsafe { &mut *self.inner.copies[read_index ^ 1].get() };
+        for op in &self.oplog {
+            copy.absorb(op);
+        }
+        // The operations that were applied to the other copy in the previous `publish` are now
+        // applied to both.
+        self.oplog.drain(..self.applied);
+        self.applied = self.oplog.len();
+
+        // Release ordering makes the modifications visible to readers that load the new index.
+        self.inner.read_index.swap(read_index ^ 1, AcqRel);
+        fence(SeqCst);
+        self.for_each_reader(|slot| slot.swapped_at.store(slot.epoch.load(Relaxed), Relaxed));
+    }
+
+    /// Return the number of operations that have been appended but not yet published.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::left_right::{self, Absorb};
+    ///
+    /// #[derive(Clone)]
+    /// struct Value(i32);
+    ///
+    /// impl Absorb<i32> for Value {
+    ///     fn absorb(&mut self, op: &i32) {
+    ///         self.0 = *op;
+    ///     }
+    /// }
+    ///
+    /// let (mut writer, _reader) = left_right::new(Value(0));
+    /// writer.append(1);
+    /// assert_eq!(writer.pending(), 1);
+    /// writer.publish();
+    /// assert_eq!(writer.pending(), 0);
+    /// ```
+    #[must_use]
+    pub fn pending(&self) -> usize {
+        self.oplog.len() - self.applied
+    }
+
+    // Wait until no reader is reading the copy that readers no longer load, that is, until every
+    // reader that was in a read at the last swap has left it.
+    fn wait_for_readers(&self) {
+        self.for_each_reader(|slot| {
+            let epoch = slot.swapped_at.load(Relaxed);
+            if epoch % 2 == 1 {
+                let mut backoff = Backoff::new();
+                // Acquire ordering synchronizes with the store in `ReadGuard::drop`, so the reads
+                // of the copy happen before it is modified.
+                while slot.epoch.load(Acquire) == epoch {
+                    backoff.snooze();
+                }
+            }
+        });
+    }
+
+    fn for_each_reader(&self, mut f: impl FnMut(&ReaderSlot)) {
+        let mut slot = self.inner.readers.load(Acquire);
+        while !slot.is_null() {
+            // SAFETY: Slots are not freed while `Inner` is alive.
+            let s = unsafe { &*slot };
+            f(s);
+            slot = s.next;
+        }
+    }
+}
+
+impl<T, O> WriteHandle<T, O> {
+    /// Create a new read handle for this left-right.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::left_right::{self, Absorb};
+    ///
+    /// #[derive(Clone)]
+    /// struct Value(i32);
+    ///
+    /// impl Absorb<i32> for Value {
+    ///     fn absorb(&mut self, op: &i32) {
+    ///         self.0 = *op;
+    ///     }
+    /// }
+    ///
+    /// let (mut writer, _reader) = left_right::new(Value(0));
+    /// writer.append(1);
+    /// writer.publish();
+    /// assert_eq!(writer.reader().read().0, 1);
+    /// ```
+    #[must_use]
+    pub fn reader(&self) -> ReadHandle<T> {
+        ReadHandle::new(Arc::clone(&self.inner))
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<T, O> fmt::Debug for WriteHandle<T, O> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteHandle").field("pending", &(self.oplog.len() - self.applied)).finish()
+    }
+}
+
+/// A read handle of a left-right.
+///
+/// Each read handle has its own epoch counter, so a read handle cannot be shared between threads,
+/// but it can be cloned and sent to another thread.
+pub struct ReadHandle<T> {
+    inner: Arc<Inner<T>>,
+    slot: *const ReaderSlot,
+    // The number of live guards of this handle, and the index of the copy they read.
+    depth: Cell<usize>,
+    index: Cell<usize>,
+}
+
+// SAFETY: `slot` is owned by this handle, and readers on different threads only share `&T`.
+unsafe impl<T: Send + Sync> Send for ReadHandle<T> {}
+
+impl<T> ReadHandle<T> {
+    fn new(inner: Arc<Inner<T>>) -> Self {
+        let slot = inner.acquire_slot();
+        Self { inner, slot, depth: Cell::new(0), index: Cell::new(0) }
+    }
+
+    /// Enter a read and return a guard that dereferences to the most recently published copy.
+    ///
+    /// This is wait-free. The writer cannot modify the copy while the guard is alive, so guards
+    /// should not be held for long. Nested reads on the same handle read the same copy.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::left_right::{self, Absorb};
+    ///
+    /// #[derive(Clone)]
+    /// struct Value(i32);
+    ///
+    /// impl Absorb<i32> for Value {
+    ///     fn absorb(&mut self, op: &i32) {
+    ///         self.0 = *op;
+    ///     }
+    /// }
+    ///
+    /// let (mut writer, reader) = left_right::new(Value(0));
+    /// let guard = reader.read();
+    /// writer.append(1);
+    /// writer.publish();
+    /// // The guard still reads the copy from before the publish.
+    /// assert_eq!(guard.0, 0);
+    /// drop(guard);
+    /// assert_eq!(reader.read().0, 1);
+    /// ```
+    pub fn read(&self) -> ReadGuard<'_, T> {
+        let depth = self.depth.get();
+        if depth == 0 {
+            let slot = self.slot();
+            // Only this handle modifies the epoch, so this doesn't need to be an RMW.
+            let epoch = slot.epoch.load(Relaxed);
+            slot.epoch.store(epoch.wrapping_add(1), Relaxed);
+            fence(SeqCst);
+            self.index.set(self.inner.read_index.load(Acquire));
+        }
+        self.depth.set(depth + 1);
+        ReadGuard { handle: self }
+    }
+
+    fn slot(&self) -> &ReaderSlot {
+        // SAFETY: Slots are not freed while `Inner` is alive, and `self.inner` keeps it alive.
+        unsafe { &*self.slot }
+    }
+}
+
+impl<T> Clone for ReadHandle<T> {
+    fn clone(&self) -> Self {
+        Self::new(Arc::clone(&self.inner))
+    }
+}
+
+impl<T> Drop for ReadHandle<T> {
+    fn drop(&mut self) {
+        // No guards are alive, since they borrow the handle, so the epoch is even.
+        self.slot().active.store(false, Release);
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<T> fmt::Debug for ReadHandle<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadHandle").field("reading", &(self.depth.get() != 0)).finish()
+    }
+}
+
+/// A guard returned by [`ReadHandle::read`] that dereferences to the copy being read.
+///
+/// The read ends when the last guard of the handle is dropped.
+#[must_use = "if unused the read will immediately end"]
+pub struct ReadGuard<'a, T> {
+    handle: &'a ReadHandle<T>,
+}
+
+impl<T> Deref for ReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The writer does not modify the copy at `index` while the epoch of this handle
+        // is odd, which it is until the last guard is dropped.
+        unsafe { &*self.handle.inner.copies[self.handle.index.get()].get() }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for ReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> Drop for ReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let depth = self.handle.depth.get() - 1;
+        self.handle.depth.set(depth);
+        if depth == 0 {
+            let slot = self.handle.slot();
+            let epoch = slot.epoch.load(Relaxed);
+            // Release ordering makes the reads of the copy happen before the writer modifies it.
+            slot.epoch.store(epoch.wrapping_add(1), Release);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::DropCounter;
+
+    use crate::left_right;
+    use std::{thread, vec};
+
+    #[derive(Clone)]
+    struct Log(Vec<usize>);
+
+    impl Absorb<usize> for Log {
+        fn absorb(&mut self, op: &usize) {
+            self.0.push(*op);
+        }
+    }
+
+    // Check that the log is `0..len`, which every published state is.
+    fn check_log(log: &Log) -> usize {
+        assert!(log.0.iter().enumerate().all(|(i, &v)| i == v));
+        log.0.len()
+    }
+
+    #[test]
+    fn concurrent_read_publish() {
+        const READERS: usize = 3;
+        const N: usize = 10_000;
+        let (mut writer, reader) = left_right::new::<Log, usize>(Log(vec![]));
+        thread::scope(|s| {
+            for _ in 0..READERS {
+                let reader = reader.clone();
+                s.spawn(move || {
+                    let mut last = 0;
+                    while last != N {
+                        let guard = reader.read();
+                        let len = check_log(&guard);
+                        // Published operations never disappear.
+                        assert!(len >= last);
+                        last = len;
+                        // A nested read reads the same copy.
+                        assert_eq!(check_log(&reader.read()), len);
+                        drop(guard);
+                        thread::yield_now();
+                    }
+                });
+            }
+            // Readers that come and go, so that slots are reused while the writer publishes.
+            let base = reader.clone();
+            s.spawn(move || {
+                let mut len = 0;
+                while len != N {
+                    let reader = base.clone();
+                    len = check_log(&reader.read());
+                }
+            });
+            for i in 0..N {
+                writer.append(i);
+                if i % 7 == 0 || i == N - 1 {
+                    writer.publish();
+                }
+            }
+        });
+        // Both copies end up with every operation.
+        writer.publish();
+        assert_eq!(writer.pending(), 0);
+        assert_eq!(check_log(&reader.read()), N);
+        writer.publish();
+        assert_eq!(check_log(&reader.read()), N);
+    }
+
+    impl Absorb<DropCounter<'_>> for DropCounter<'_> {
+        fn absorb(&mut self, _op: &DropCounter<'_>) {}
+    }
+
+    #[test]
+    fn drop_value_and_ops() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        static OP_DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        let (mut writer, reader) = left_right::new(DropCounter(&DROPS));
+        // `new` clones the value and moves the original in, so nothing is dropped.
+        assert_eq!(DROPS.load(Relaxed), 0);
+        for _ in 0..10 {
+            writer.append(DropCounter(&OP_DROPS));
+        }
+        writer.publish();
+        writer.append(DropCounter(&OP_DROPS));
+        writer.publish();
+        // The first 10 operations have been applied to both copies.
+        assert_eq!(OP_DROPS.load(Relaxed), 10);
+        drop(writer);
+        assert_eq!(OP_DROPS.load(Relaxed), 11);
+        // The copies are dropped with the last handle.
+        let reader2 = reader.clone();
+        drop(reader);
+        assert_eq!(DROPS.load(Relaxed), 0);
+        drop(reader2);
+        assert_eq!(DROPS.load(Relaxed), 2);
+    }
+}
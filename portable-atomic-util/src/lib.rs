@@ -4,7 +4,30 @@
 <!-- tidy:crate-doc:start -->
 Synchronization primitives built with [portable-atomic].
 
-- Provide `Arc`. (optional, requires the `std` or `alloc` feature)
+- Provide `Arc`, `UniqueArc`, and `ArcLite`. (optional, requires the `std` or `alloc` feature)
+- Provide `ArcPool`, a statically sized pool of reference counted values that does not require a global allocator.
+- Provide `SpinMutex` and `TicketMutex`, spin-based mutexes. (`lock_api` integration is optional, requires the `lock_api` feature)
+- Provide `RwSpinLock`, a spin-based reader-writer lock with upgradeable reads. (`lock_api` integration is optional, requires the `lock_api` feature)
+- Provide `McsLock` and `ClhLock`, fair queue-based spin locks.
+- Provide `OnceLock` and `LazyLock`, blocking once cells, and `OnceNonZeroUsize`, `OnceBool`, `OnceRef`, and `OnceBox`, race-based once cells. (`OnceBox` requires the `std` or `alloc` feature)
+- Provide `spsc::Queue`, a bounded single-producer single-consumer queue that only requires atomic loads and stores. (available without the `require-cas` feature)
+- Provide `ArrayQueue` and `StaticArrayQueue`, bounded multi-producer multi-consumer queues. (`ArrayQueue` requires the `std` or `alloc` feature)
+- Provide `mpsc::Queue` and `mpsc::IntrusiveQueue`, unbounded multi-producer single-consumer queues. (requires the `std` or `alloc` feature)
+- Provide `channel`, oneshot, bounded, and unbounded channels with blocking (requires the `std` feature) and async receive. (requires the `std` or `alloc` feature)
+- Provide `AtomicWaker` and `Notify`, async task notification primitives whose waking side never blocks.
+- Provide `deque`, a Chase-Lev work-stealing deque. (requires the `std` or `alloc` feature)
+- Provide `epoch`, epoch-based memory reclamation for lock-free data structures. (requires the `std` or `alloc` feature)
+- Provide `hazard`, hazard-pointer memory reclamation for lock-free data structures. (requires the `std` or `alloc` feature)
+- Provide `stack`, an ABA-safe lock-free Treiber stack and intrusive free list using double-width CAS. (`TreiberStack` requires the `std` or `alloc` feature)
+- Provide `hash_map::AtomicHashMap`, a fixed-capacity lock-free hash map with `u64` keys and values.
+- Provide `ShardedCounter`, a counter split into cache-padded stripes for contended statistics.
+- Provide `id_allocator::IdAllocator`, a lock-free bitmap allocator for small integer IDs.
+- Provide `SeqLock`, a sequence lock that gives readers torn-read-free copies of a padding-free `Copy` value without locking.
+- Provide `AtomicRefCell`, a thread-safe `RefCell` whose conflicting borrows fail instead of blocking.
+- Provide `triple_buffer::TripleBuffer`, a wait-free triple buffer that hands the latest value from a producer to a consumer.
+- Provide `left_right`, a left-right concurrency primitive with wait-free readers for read-mostly data. (requires the `std` or `alloc` feature)
+- Provide `Barrier`, `Latch`, `WaitGroup`, and a counting `Semaphore`. They can be used in `static`s, block with thread parking when the `std` feature is enabled, and spin otherwise.
+- Provide `kcas`, a lock-free software multi-word compare-and-swap over `KCasWord`s, with a pluggable reclamation strategy for its descriptors. (requires the `std` or `alloc` feature)
 <!-- - Provide generic `Atomic<T>` type. (optional, requires the `generic` feature) -->
 
 See [#1] for other primitives being considered for addition to this crate.
@@ -23,13 +46,37 @@ See [#1] for other primitives being considered for addition to this crate.
   Use `alloc`.
 
   Note:
+  - This implicitly enables the `require-cas` feature.
   - The MSRV when this feature is enabled and the `std` feature is *not* enabled is Rust 1.36 that `alloc` crate stabilized.
 
+- **`require-cas`**<br>
+  Provide the primitives that require atomic CAS, and enable portable-atomic's [`require-cas`] feature.
+
+  Note:
+  - This feature is enabled by default.
+  - Only `spsc` is available when this feature is disabled. This allows using `spsc` on targets without atomic CAS (such as thumbv6m) without providing CAS via portable-atomic's `critical-section` or `unsafe-assume-single-core` options.
+
+- **`fallback`**<br>
+  Enable portable-atomic's [`fallback`] feature, so that `stack` and `hash_map` can use portable-atomic's 128-bit and 64-bit atomic types on targets and CPUs that do not support them natively. portable-atomic uses native instructions where they are available, including via run-time detection (such as `cmpxchg16b` on x86_64).
+
+  Note:
+  - This feature is enabled by default.
+  - When this feature is disabled, `stack` uses a spin lock on targets without native double-width atomics, and `hash_map` uses a pair of 64-bit atomics per slot on targets without native 128-bit atomics and is not available on targets without native 64-bit atomics.
+
+- **`lock_api`**<br>
+  Implement [`lock_api`]'s raw lock traits for the raw locks provided by this crate, such as `RawSpinMutex`.
+
+  Note:
+  - This enables the `lock_api` dependency, which has a higher MSRV than this crate.
+
 <!-- TODO: https://github.com/taiki-e/portable-atomic/issues/1
 - **`generic`**<br>
   Provides generic `Atomic<T>` type.
 -->
 
+[`fallback`]: https://github.com/taiki-e/portable-atomic#optional-features-fallback
+[`lock_api`]: https://docs.rs/lock_api
+[`require-cas`]: https://github.com/taiki-e/portable-atomic#optional-features-require-cas
 [portable-atomic]: https://github.com/taiki-e/portable-atomic
 [#1]: https://github.com/taiki-e/portable-atomic/issues/1
 
@@ -89,9 +136,144 @@ See [#1] for other primitives being considered for addition to this crate.
 extern crate alloc;
 #[cfg(all(feature = "std", portable_atomic_no_alloc))]
 extern crate std as alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+#[macro_use]
+mod utils;
+#[cfg(all(test, feature = "std"))]
+mod test_util;
 
 #[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
 #[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
 mod arc;
 #[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
-pub use arc::{Arc, Weak};
+pub use arc::{Arc, UniqueArc, Weak};
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+mod arc_lite;
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+pub use arc_lite::ArcLite;
+
+#[cfg(all(not(portable_atomic_no_min_const_generics), feature = "require-cas"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod pool;
+#[cfg(all(not(portable_atomic_no_min_const_generics), feature = "require-cas"))]
+pub use pool::{ArcPool, PoolArc, PoolWeak};
+
+#[cfg(feature = "require-cas")]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod mutex;
+#[cfg(feature = "require-cas")]
+pub use mutex::{
+    RawSpinMutex, RawTicketMutex, SpinMutex, SpinMutexGuard, TicketMutex, TicketMutexGuard,
+};
+#[cfg(feature = "require-cas")]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod rwlock;
+#[cfg(feature = "require-cas")]
+pub use rwlock::{
+    RawRwSpinLock, RwSpinLock, RwSpinLockReadGuard, RwSpinLockUpgradeableReadGuard,
+    RwSpinLockWriteGuard,
+};
+#[cfg(feature = "require-cas")]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod queue_lock;
+#[cfg(feature = "require-cas")]
+pub use queue_lock::{ClhLock, McsLock};
+#[cfg(feature = "require-cas")]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod once;
+#[cfg(feature = "require-cas")]
+pub use once::{LazyLock, OnceLock};
+#[cfg(feature = "require-cas")]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod race;
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+pub use race::OnceBox;
+#[cfg(feature = "require-cas")]
+pub use race::{OnceBool, OnceNonZeroUsize, OnceRef};
+
+#[cfg(not(portable_atomic_no_min_const_generics))]
+pub mod spsc;
+#[cfg(all(not(portable_atomic_no_min_const_generics), feature = "require-cas"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod array_queue;
+#[cfg(all(
+    not(portable_atomic_no_min_const_generics),
+    any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"),
+))]
+pub use array_queue::ArrayQueue;
+#[cfg(all(not(portable_atomic_no_min_const_generics), feature = "require-cas"))]
+pub use array_queue::StaticArrayQueue;
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub mod mpsc;
+#[cfg(all(
+    not(portable_atomic_no_min_const_generics),
+    any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"),
+))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub mod channel;
+#[cfg(all(
+    not(portable_atomic_no_maybe_uninit),
+    any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"),
+))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub mod deque;
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub mod epoch;
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub mod hazard;
+#[cfg(feature = "require-cas")]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+pub mod stack;
+#[cfg(all(not(portable_atomic_no_min_const_generics), feature = "require-cas"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod counter;
+#[cfg(all(not(portable_atomic_no_min_const_generics), feature = "require-cas"))]
+pub use counter::ShardedCounter;
+cfg_has_atomic_64! {
+    #[cfg(all(not(portable_atomic_no_min_const_generics), feature = "require-cas"))]
+    #[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+    pub mod hash_map;
+}
+#[cfg(all(not(portable_atomic_no_min_const_generics), feature = "require-cas"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+pub mod id_allocator;
+#[cfg(all(not(portable_atomic_no_maybe_uninit), feature = "require-cas"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod seq_lock;
+#[cfg(all(not(portable_atomic_no_maybe_uninit), feature = "require-cas"))]
+pub use seq_lock::{NoPadding, SeqLock};
+#[cfg(feature = "require-cas")]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod atomic_ref_cell;
+#[cfg(feature = "require-cas")]
+pub use atomic_ref_cell::{AtomicRef, AtomicRefCell, AtomicRefMut, BorrowError, BorrowMutError};
+#[cfg(feature = "require-cas")]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+pub mod triple_buffer;
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub mod left_right;
+#[cfg(feature = "require-cas")]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod sync;
+#[cfg(feature = "require-cas")]
+pub use sync::{Barrier, BarrierWaitResult, Latch, Semaphore, WaitGroup};
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub mod kcas;
+#[cfg(all(not(portable_atomic_no_futures_api), feature = "require-cas"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod waker;
+#[cfg(all(not(portable_atomic_no_futures_api), feature = "require-cas"))]
+pub use waker::AtomicWaker;
+#[cfg(all(not(portable_atomic_no_futures_api), feature = "require-cas"))]
+#[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+mod notify;
+#[cfg(all(not(portable_atomic_no_futures_api), feature = "require-cas"))]
+pub use notify::{Notified, Notify};
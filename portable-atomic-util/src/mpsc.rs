@@ -0,0 +1,727 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Unbounded multi-producer single-consumer queues.
+//!
+//! Both queues are Dmitry Vyukov's MPSC queue: pushing is wait-free (a single
+//! [`AtomicPtr::swap`](portable_atomic::AtomicPtr::swap) followed by a store), and popping is
+//! lock-free but can observe a producer that has swapped itself in but not linked itself yet. In
+//! that case [`PopResult::Inconsistent`] is returned and the caller should back off and retry.
+//!
+//! - [`Queue`] allocates a node for each element.
+//! - [`IntrusiveQueue`] links reference-counted [`Arc`] nodes through an embedded [`Link`], so
+//!   pushing does not allocate. This is useful for task queues of executors and actor mailboxes.
+//!
+//! See <https://www.1024cores.net/home/lock-free-algorithms/queues/intrusive-mpsc-node-based-queue>
+//! for the algorithm.
+
+use portable_atomic::{
+    AtomicBool, AtomicPtr,
+    Ordering::{AcqRel, Acquire, Relaxed, Release},
+};
+
+use crate::Arc;
+
+use alloc::boxed::Box;
+use core::{fmt, marker::PhantomData, ptr};
+
+/// The result of popping from a [`Queue`] or an [`IntrusiveQueue`].
+#[allow(clippy::exhaustive_enums)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PopResult<T> {
+    /// An element was popped.
+    Data(T),
+    /// The queue is empty.
+    Empty,
+    /// A producer is in the middle of pushing an element, so the queue is neither empty nor
+    /// ready to pop. The caller should back off and try again.
+    Inconsistent,
+}
+
+// Queue
+
+struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    value: Option<T>,
+}
+
+impl<T> Node<T> {
+    fn new(value: Option<T>) -> *mut Self {
+        Box::into_raw(Box::new(Self { next: AtomicPtr::new(ptr::null_mut()), value }))
+    }
+}
+
+/// An unbounded multi-producer single-consumer queue.
+///
+/// Each push allocates a node, and [`push`](Self::push) is wait-free.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::mpsc::{PopResult, Queue};
+/// use std::{sync::Arc, thread};
+///
+/// let q = Arc::new(Queue::new());
+/// let producers: Vec<_> = (0..4)
+///     .map(|i| {
+///         let q = Arc::clone(&q);
+///         thread::spawn(move || {
+///             for j in 0..100 {
+///                 q.push(i * 100 + j);
+///             }
+///         })
+///     })
+///     .collect();
+///
+/// let mut sum = 0;
+/// let mut count = 0;
+/// while count < 400 {
+///     // SAFETY: This is the only thread that pops.
+///     match unsafe { q.pop() } {
+///         PopResult::Data(v) => {
+///             sum += v;
+///             count += 1;
+///         }
+///         PopResult::Empty | PopResult::Inconsistent => thread::yield_now(),
+///     }
+/// }
+/// for t in producers {
+///     t.join().unwrap();
+/// }
+/// assert_eq!(sum, (0..400).sum());
+/// ```
+pub struct Queue<T> {
+    // The most recently pushed node.
+    head: AtomicPtr<Node<T>>,
+    // The node before the oldest element, whose value has already been taken. Only written by
+    // the consumer.
+    tail: AtomicPtr<Node<T>>,
+}
+
+// SAFETY: Elements are moved between threads, so `T` must be `Send`.
+unsafe impl<T: Send> Send for Queue<T> {}
+// SAFETY: Nodes are only freed by the consumer, as required by the safety contract of `pop`.
+unsafe impl<T: Send> Sync for Queue<T> {}
+
+impl<T> Queue<T> {
+    /// Create a new empty queue.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::mpsc::Queue;
+    ///
+    /// let q = Queue::<i32>::new();
+    /// assert!(q.is_empty());
+    /// ```
+    #[must_use]
+    pub fn new() -> Self {
+        let stub = Node::new(None);
+        Self { head: AtomicPtr::new(stub), tail: AtomicPtr::new(stub) }
+    }
+
+    /// Push an element to the queue.
+    ///
+    /// This is wait-free.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::mpsc::{PopResult, Queue};
+    ///
+    /// let q = Queue::new();
+    /// q.push(1);
+    /// // SAFETY: This is the only thread that pops.
+    /// assert_eq!(unsafe { q.pop() }, PopResult::Data(1));
+    /// ```
+    pub fn push(&self, value: T) {
+        let node = Node::new(Some(value));
+        let prev = self.head.swap(node, AcqRel);
+        // SAFETY: `prev` is not freed until the consumer has moved past it, which requires this
+        // link.
+        unsafe { (*prev).next.store(node, Release) }
+    }
+
+    /// Pop an element from the queue.
+    ///
+    /// # Safety
+    ///
+    /// Only one thread may call this method at a time.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::mpsc::{PopResult, Queue};
+    ///
+    /// let q = Queue::new();
+    /// // SAFETY: This is the only thread that pops.
+    /// unsafe {
+    ///     assert_eq!(q.pop(), PopResult::Empty);
+    ///     q.push(1);
+    ///     q.push(2);
+    ///     assert_eq!(q.pop(), PopResult::Data(1));
+    ///     assert_eq!(q.pop(), PopResult::Data(2));
+    ///     assert_eq!(q.pop(), PopResult::Empty);
+    /// }
+    /// ```
+    pub unsafe fn pop(&self) -> PopResult<T> {
+        // SAFETY: The caller guarantees that we are the only consumer, and `tail` always points
+        // to a valid node.
+        unsafe {
+            let tail = self.tail.load(Relaxed);
+            let next = (*tail).next.load(Acquire);
+            if next.is_null() {
+                return if self.head.load(Acquire) == tail {
+                    PopResult::Empty
+                } else {
+                    PopResult::Inconsistent
+                };
+            }
+            self.tail.store(next, Release);
+            debug_assert!((*tail).value.is_none());
+            let value = (*next).value.take();
+            drop(Box::from_raw(tail));
+            match value {
+                Some(value) => PopResult::Data(value),
+                None => unreachable!(),
+            }
+        }
+    }
+
+    /// Return `true` if the queue is empty.
+    ///
+    /// A concurrent push may not be observed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::mpsc::Queue;
+    ///
+    /// let q = Queue::new();
+    /// assert!(q.is_empty());
+    /// q.push(1);
+    /// assert!(!q.is_empty());
+    /// ```
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        // `head` and `tail` point to the same node only when there are no elements.
+        self.head.load(Acquire) == self.tail.load(Acquire)
+    }
+}
+
+impl<T> Default for Queue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for Queue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Queue").finish()
+    }
+}
+
+impl<T> Drop for Queue<T> {
+    fn drop(&mut self) {
+        // SAFETY: We have exclusive access, so we are the only consumer and there are no
+        // producers, so the queue cannot be inconsistent.
+        unsafe {
+            while let PopResult::Data(_) = self.pop() {}
+            drop(Box::from_raw(*self.tail.get_mut()));
+        }
+    }
+}
+
+// IntrusiveQueue
+
+/// The link embedded in the elements of an [`IntrusiveQueue`].
+///
+/// See [`Linked`] for how to use it.
+pub struct Link {
+    next: AtomicPtr<Link>,
+    // The `Arc<T>` owning this link, converted by `Arc::into_raw`. Null for the stub link.
+    owner: AtomicPtr<()>,
+    queued: AtomicBool,
+}
+
+impl Link {
+    /// Create a new link that is not in any queue.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::mpsc::Link;
+    ///
+    /// static LINK: Link = Link::new();
+    /// assert!(!LINK.is_queued());
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            next: AtomicPtr::new(ptr::null_mut()),
+            owner: AtomicPtr::new(ptr::null_mut()),
+            queued: AtomicBool::new(false),
+        }
+    }
+
+    /// Return `true` if the element owning this link is currently in a queue.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use portable_atomic_util::{mpsc::{IntrusiveQueue, Link, Linked}, Arc};
+    /// # struct Task(Link);
+    /// # // SAFETY: `link` always returns the same link, which is only used by the queue.
+    /// # unsafe impl Linked for Task {
+    /// #     fn link(&self) -> &Link {
+    /// #         &self.0
+    /// #     }
+    /// # }
+    /// let q = IntrusiveQueue::new();
+    /// let task = Arc::new(Task(Link::new()));
+    /// assert!(!task.0.is_queued());
+    /// q.push(Arc::clone(&task)).unwrap_or_else(|_| unreachable!());
+    /// assert!(task.0.is_queued());
+    /// ```
+    #[must_use]
+    pub fn is_queued(&self) -> bool {
+        self.queued.load(Acquire)
+    }
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Link").field("queued", &self.is_queued()).finish()
+    }
+}
+
+/// A type that can be linked into an [`IntrusiveQueue`].
+///
+/// # Safety
+///
+/// `link` must always return a reference to the same [`Link`], and that link must not be used
+/// by anything else than [`IntrusiveQueue`].
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::mpsc::{Link, Linked};
+///
+/// struct Task {
+///     link: Link,
+///     id: u32,
+/// }
+///
+/// // SAFETY: `link` always returns the same link, which is only used by the queue.
+/// unsafe impl Linked for Task {
+///     fn link(&self) -> &Link {
+///         &self.link
+///     }
+/// }
+/// ```
+pub unsafe trait Linked {
+    /// Return the link embedded in this element.
+    fn link(&self) -> &Link;
+}
+
+/// An unbounded intrusive multi-producer single-consumer queue of [`Arc`]s.
+///
+/// The elements embed a [`Link`] (see [`Linked`]), so pushing does not allocate and is
+/// wait-free. An element can be in at most one queue at a time; pushing an element that is
+/// already queued fails. This makes it easy to implement "wake by re-queueing" schemes, where a
+/// task that is woken several times before it is run is only queued once.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::{
+///     mpsc::{IntrusiveQueue, Link, Linked, PopResult},
+///     Arc,
+/// };
+///
+/// struct Task {
+///     link: Link,
+///     id: u32,
+/// }
+///
+/// // SAFETY: `link` always returns the same link, which is only used by the queue.
+/// unsafe impl Linked for Task {
+///     fn link(&self) -> &Link {
+///         &self.link
+///     }
+/// }
+///
+/// let q = IntrusiveQueue::new();
+/// let task = Arc::new(Task { link: Link::new(), id: 1 });
+/// assert!(q.push(Arc::clone(&task)).is_ok());
+/// // The task is already queued.
+/// assert!(q.push(Arc::clone(&task)).is_err());
+///
+/// // SAFETY: This is the only thread that pops.
+/// match unsafe { q.pop() } {
+///     PopResult::Data(t) => assert_eq!(t.id, 1),
+///     _ => unreachable!(),
+/// }
+/// assert!(q.push(task).is_ok());
+/// ```
+pub struct IntrusiveQueue<T: Linked> {
+    // The most recently pushed link.
+    head: AtomicPtr<Link>,
+    // The oldest link, or the stub. Only written by the consumer.
+    tail: AtomicPtr<Link>,
+    // The stub link, which keeps the queue non-empty so that producers never need to update
+    // `tail`. Boxed so that its address does not change when the queue is moved.
+    stub: Box<Link>,
+    _marker: PhantomData<Arc<T>>,
+}
+
+// SAFETY: The queue owns `Arc<T>`s, which are `Send` and `Sync` if `T` is `Send` and `Sync`.
+unsafe impl<T: Linked + Send + Sync> Send for IntrusiveQueue<T> {}
+// SAFETY: Elements are only taken out by the consumer, as required by the safety contract of
+// `pop`.
+unsafe impl<T: Linked + Send + Sync> Sync for IntrusiveQueue<T> {}
+
+impl<T: Linked> IntrusiveQueue<T> {
+    /// Create a new empty queue.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::mpsc::{IntrusiveQueue, Link, Linked};
+    ///
+    /// struct Task(Link);
+    /// // SAFETY: `link` always returns the same link, which is only used by the queue.
+    /// unsafe impl Linked for Task {
+    ///     fn link(&self) -> &Link {
+    ///         &self.0
+    ///     }
+    /// }
+    ///
+    /// let q = IntrusiveQueue::<Task>::new();
+    /// ```
+    #[must_use]
+    pub fn new() -> Self {
+        let stub = Box::new(Link::new());
+        let stub_ptr = &*stub as *const Link;
+        Self {
+            head: AtomicPtr::new(stub_ptr as *mut Link),
+            tail: AtomicPtr::new(stub_ptr as *mut Link),
+            stub,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Push an element to the queue.
+    ///
+    /// This is wait-free.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(node)` if the element is already in a queue.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use portable_atomic_util::{mpsc::{IntrusiveQueue, Link, Linked}, Arc};
+    /// # struct Task(Link);
+    /// # // SAFETY: `link` always returns the same link, which is only used by the queue.
+    /// # unsafe impl Linked for Task {
+    /// #     fn link(&self) -> &Link {
+    /// #         &self.0
+    /// #     }
+    /// # }
+    /// let q = IntrusiveQueue::new();
+    /// let task = Arc::new(Task(Link::new()));
+    /// assert!(q.push(Arc::clone(&task)).is_ok());
+    /// assert!(q.push(task).is_err());
+    /// ```
+    pub fn push(&self, node: Arc<T>) -> Result<(), Arc<T>> {
+        if node.link().queued.swap(true, AcqRel) {
+            return Err(node);
+        }
+        let link = node.link() as *const Link;
+        // SAFETY: We set `queued`, so no other queue uses this link until it is popped.
+        unsafe {
+            (*link).owner.store(Arc::into_raw(node) as *mut (), Relaxed);
+            self.push_link(link);
+        }
+        Ok(())
+    }
+
+    unsafe fn push_link(&self, link: *const Link) {
+        // SAFETY: The caller guarantees that `link` is valid and not in the queue.
+        unsafe {
+            (*link).next.store(ptr::null_mut(), Relaxed);
+            let prev = self.head.swap(link as *mut Link, AcqRel);
+            // `prev` is kept alive by the queue until the consumer has moved past it, which
+            // requires this link.
+            (*prev).next.store(link as *mut Link, Release);
+        }
+    }
+
+    /// Pop an element from the queue.
+    ///
+    /// # Safety
+    ///
+    /// Only one thread may call this method at a time.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use portable_atomic_util::{mpsc::{IntrusiveQueue, Link, Linked}, Arc};
+    /// # struct Task(Link);
+    /// # // SAFETY: `link` always returns the same link, which is only used by the queue.
+    /// # unsafe impl Linked for Task {
+    /// #     fn link(&self) -> &Link {
+    /// #         &self.0
+    /// #     }
+    /// # }
+    /// use portable_atomic_util::mpsc::PopResult;
+    ///
+    /// let q = IntrusiveQueue::new();
+    /// let a = Arc::new(Task(Link::new()));
+    /// let b = Arc::new(Task(Link::new()));
+    /// assert!(q.push(Arc::clone(&a)).is_ok());
+    /// assert!(q.push(Arc::clone(&b)).is_ok());
+    /// // SAFETY: This is the only thread that pops.
+    /// unsafe {
+    ///     assert!(matches!(q.pop(), PopResult::Data(t) if Arc::ptr_eq(&t, &a)));
+    ///     assert!(matches!(q.pop(), PopResult::Data(t) if Arc::ptr_eq(&t, &b)));
+    ///     assert!(matches!(q.pop(), PopResult::Empty));
+    /// }
+    /// ```
+    pub unsafe fn pop(&self) -> PopResult<Arc<T>> {
+        let stub = &*self.stub as *const Link;
+        // SAFETY: The caller guarantees that we are the only consumer. All links in the queue are
+        // kept alive by the `Arc`s owned by the queue, or are the stub.
+        unsafe {
+            let mut tail = self.tail.load(Relaxed) as *const Link;
+            let mut next = (*tail).next.load(Acquire) as *const Link;
+
+            if tail == stub {
+                if next.is_null() {
+                    return PopResult::Empty;
+                }
+                self.tail.store(next as *mut Link, Release);
+                tail = next;
+                next = (*next).next.load(Acquire);
+            }
+
+            if next.is_null() {
+                if !ptr::eq(tail, self.head.load(Acquire)) {
+                    return PopResult::Inconsistent;
+                }
+                // `tail` is the last element: push the stub behind it so that it can be popped.
+                self.push_link(stub);
+                next = (*tail).next.load(Acquire);
+                if next.is_null() {
+                    return PopResult::Inconsistent;
+                }
+            }
+
+            self.tail.store(next as *mut Link, Release);
+            let owner = (*tail).owner.load(Relaxed) as *const T;
+            // `tail` is no longer referenced by the queue, so it can be pushed again.
+            (*tail).queued.store(false, Release);
+            PopResult::Data(Arc::from_raw(owner))
+        }
+    }
+
+    /// Return `true` if the queue is empty.
+    ///
+    /// A concurrent push may not be observed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use portable_atomic_util::{mpsc::{IntrusiveQueue, Link, Linked}, Arc};
+    /// # struct Task(Link);
+    /// # // SAFETY: `link` always returns the same link, which is only used by the queue.
+    /// # unsafe impl Linked for Task {
+    /// #     fn link(&self) -> &Link {
+    /// #         &self.0
+    /// #     }
+    /// # }
+    /// let q = IntrusiveQueue::new();
+    /// assert!(q.is_empty());
+    /// assert!(q.push(Arc::new(Task(Link::new()))).is_ok());
+    /// assert!(!q.is_empty());
+    /// ```
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        // Elements are only left when the consumer has not moved past them yet.
+        let stub = &*self.stub as *const Link as *mut Link;
+        self.tail.load(Acquire) == stub && self.head.load(Acquire) == stub
+    }
+}
+
+impl<T: Linked> Default for IntrusiveQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: Linked> fmt::Debug for IntrusiveQueue<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IntrusiveQueue").finish()
+    }
+}
+
+impl<T: Linked> Drop for IntrusiveQueue<T> {
+    fn drop(&mut self) {
+        // SAFETY: We have exclusive access, so we are the only consumer and there are no
+        // producers, so the queue cannot be inconsistent.
+        unsafe { while let PopResult::Data(_) = self.pop() {} }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::DropCounter;
+
+    use std::{sync::Barrier, thread, vec::Vec};
+
+    use portable_atomic::AtomicUsize;
+
+    // Pop until `n` elements have been popped, retrying on `Inconsistent`.
+    fn pop_n<T>(n: usize, mut pop: impl FnMut() -> PopResult<T>) -> Vec<T> {
+        let mut popped = Vec::with_capacity(n);
+        while popped.len() < n {
+            match pop() {
+                PopResult::Data(v) => popped.push(v),
+                PopResult::Empty | PopResult::Inconsistent => thread::yield_now(),
+            }
+        }
+        popped
+    }
+
+    ::quickcheck::quickcheck! {
+        fn quickcheck_sequential(values: Vec<u8>) -> bool {
+            let q = Queue::new();
+            for &v in &values {
+                q.push(v);
+            }
+            // SAFETY: This is the only thread that pops.
+            let popped = pop_n(values.len(), || unsafe { q.pop() });
+            // SAFETY: See above.
+            popped == values && q.is_empty() && unsafe { q.pop() } == PopResult::Empty
+        }
+        fn quickcheck_concurrent(values: Vec<u32>) -> bool {
+            const THREADS: usize = 4;
+            let q = Queue::new();
+            let barrier = Barrier::new(THREADS + 1);
+            let popped = thread::scope(|s| {
+                for chunk in 0..THREADS {
+                    let (q, barrier, values) = (&q, &barrier, &values);
+                    s.spawn(move || {
+                        barrier.wait();
+                        for &v in values.iter().skip(chunk).step_by(THREADS) {
+                            q.push((chunk, v));
+                        }
+                    });
+                }
+                barrier.wait();
+                // SAFETY: This is the only thread that pops.
+                pop_n(values.len(), || unsafe { q.pop() })
+            });
+            // Elements pushed by the same producer are popped in order.
+            (0..THREADS).all(|chunk| {
+                let from_chunk: Vec<_> =
+                    popped.iter().filter(|&&(c, _)| c == chunk).map(|&(_, v)| v).collect();
+                let expected: Vec<_> = values.iter().skip(chunk).step_by(THREADS).copied().collect();
+                from_chunk == expected
+            })
+        }
+    }
+
+    #[test]
+    fn drop_elements() {
+        const THREADS: usize = 4;
+        const N: usize = 1000;
+        let count = AtomicUsize::new(0);
+        let q = Queue::new();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..N {
+                        q.push(DropCounter(&count));
+                    }
+                });
+            }
+            // SAFETY: This is the only thread that pops.
+            drop(pop_n(THREADS * N / 2, || unsafe { q.pop() }));
+        });
+        assert_eq!(count.load(Relaxed), THREADS * N / 2);
+        drop(q);
+        assert_eq!(count.load(Relaxed), THREADS * N);
+    }
+
+    struct Task {
+        link: Link,
+        runs: AtomicUsize,
+    }
+
+    // SAFETY: `link` always returns the same link, which is only used by the queue.
+    unsafe impl Linked for Task {
+        fn link(&self) -> &Link {
+            &self.link
+        }
+    }
+
+    #[test]
+    fn intrusive_concurrent() {
+        const THREADS: usize = 4;
+        const TASKS: usize = 8;
+        const N: usize = 1000;
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|_| Arc::new(Task { link: Link::new(), runs: AtomicUsize::new(0) }))
+            .collect();
+        let q = IntrusiveQueue::new();
+        let done = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for i in 0..N {
+                        // Pushing a task that is already queued fails, and the task is dropped.
+                        drop(q.push(Arc::clone(&tasks[i % TASKS])));
+                    }
+                    done.fetch_add(1, Release);
+                });
+            }
+            loop {
+                let finished = done.load(Acquire) == THREADS;
+                // SAFETY: This is the only thread that pops.
+                match unsafe { q.pop() } {
+                    PopResult::Data(task) => {
+                        assert!(!task.link.is_queued());
+                        task.runs.fetch_add(1, Relaxed);
+                    }
+                    PopResult::Empty if finished => break,
+                    PopResult::Empty | PopResult::Inconsistent => thread::yield_now(),
+                }
+            }
+        });
+        assert!(q.is_empty());
+        for task in &tasks {
+            assert!(task.runs.load(Relaxed) > 0);
+            // No reference was leaked by the queue.
+            assert_eq!(Arc::strong_count(task), 1);
+        }
+
+        // Dropping the queue drops the references to the queued tasks.
+        let q = IntrusiveQueue::new();
+        for task in &tasks {
+            assert!(q.push(Arc::clone(task)).is_ok());
+        }
+        assert_eq!(Arc::strong_count(&tasks[0]), 2);
+        drop(q);
+        assert!(tasks.iter().all(|task| Arc::strong_count(task) == 1 && !task.link.is_queued()));
+    }
+}
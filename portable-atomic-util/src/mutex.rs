@@ -0,0 +1,544 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Spin-based mutual exclusion locks.
+//!
+//! These never block the thread in the OS sense: waiting threads spin with exponential backoff,
+//! and yield to the OS scheduler once the backoff limit is reached if the `std` feature is enabled.
+
+use portable_atomic::{
+    AtomicBool, AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release},
+};
+
+use crate::utils::Backoff;
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+/// A raw test-and-test-and-set spin lock.
+///
+/// This is the lock used by [`SpinMutex`]. When the `lock_api` feature is enabled, this implements
+/// [`lock_api::RawMutex`].
+pub struct RawSpinMutex {
+    locked: AtomicBool,
+}
+
+impl RawSpinMutex {
+    /// Create a new unlocked lock.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { locked: AtomicBool::new(false) }
+    }
+
+    /// Acquire the lock, spinning until it is available.
+    pub fn lock(&self) {
+        let mut backoff = Backoff::new();
+        loop {
+            if self.locked.compare_exchange_weak(false, true, Acquire, Relaxed).is_ok() {
+                return;
+            }
+            // Wait until the lock looks unlocked before retrying the compare-exchange,
+            // to avoid invalidating the cache line on every iteration.
+            while self.is_locked() {
+                backoff.snooze();
+            }
+        }
+    }
+
+    /// Attempt to acquire the lock without spinning.
+    #[must_use]
+    pub fn try_lock(&self) -> bool {
+        self.locked.compare_exchange(false, true, Acquire, Relaxed).is_ok()
+    }
+
+    /// Release the lock.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the current context.
+    pub unsafe fn unlock(&self) {
+        self.locked.store(false, Release);
+    }
+
+    /// Return `true` if the lock is currently held.
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Relaxed)
+    }
+}
+
+impl Default for RawSpinMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for RawSpinMutex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawSpinMutex").field("locked", &self.is_locked()).finish()
+    }
+}
+
+/// A raw fair ticket lock.
+///
+/// This is the lock used by [`TicketMutex`]. When the `lock_api` feature is enabled, this
+/// implements [`lock_api::RawMutex`] and [`lock_api::RawMutexFair`].
+pub struct RawTicketMutex {
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+}
+
+impl RawTicketMutex {
+    /// Create a new unlocked lock.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { next_ticket: AtomicUsize::new(0), now_serving: AtomicUsize::new(0) }
+    }
+
+    /// Acquire the lock, spinning until it is our turn.
+    pub fn lock(&self) {
+        let ticket = self.next_ticket.fetch_add(1, Relaxed);
+        let mut backoff = Backoff::new();
+        while self.now_serving.load(Acquire) != ticket {
+            backoff.snooze();
+        }
+    }
+
+    /// Attempt to acquire the lock without spinning.
+    #[must_use]
+    pub fn try_lock(&self) -> bool {
+        let ticket = self.now_serving.load(Relaxed);
+        self.next_ticket.compare_exchange(ticket, ticket.wrapping_add(1), Acquire, Relaxed).is_ok()
+    }
+
+    /// Release the lock, handing it to the next waiter in line.
+    ///
+    /// # Safety
+    ///
+    /// The lock must be held by the current context.
+    pub unsafe fn unlock(&self) {
+        // Only the holder of the lock modifies `now_serving`, so this doesn't need to be an RMW.
+        let ticket = self.now_serving.load(Relaxed);
+        self.now_serving.store(ticket.wrapping_add(1), Release);
+    }
+
+    /// Return `true` if the lock is currently held.
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.next_ticket.load(Relaxed) != self.now_serving.load(Relaxed)
+    }
+}
+
+impl Default for RawTicketMutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for RawTicketMutex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawTicketMutex").field("locked", &self.is_locked()).finish()
+    }
+}
+
+#[cfg(feature = "lock_api")]
+// SAFETY: `lock` and `try_lock` acquire the lock with `Acquire` ordering, and `unlock` releases it
+// with `Release` ordering.
+unsafe impl lock_api::RawMutex for RawSpinMutex {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self::new();
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        Self::lock(self);
+    }
+
+    fn try_lock(&self) -> bool {
+        Self::try_lock(self)
+    }
+
+    unsafe fn unlock(&self) {
+        // SAFETY: the caller must uphold the safety contract.
+        unsafe { Self::unlock(self) }
+    }
+
+    fn is_locked(&self) -> bool {
+        Self::is_locked(self)
+    }
+}
+
+#[cfg(feature = "lock_api")]
+// SAFETY: `lock` and `try_lock` acquire the lock with `Acquire` ordering, and `unlock` releases it
+// with `Release` ordering.
+unsafe impl lock_api::RawMutex for RawTicketMutex {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self::new();
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock(&self) {
+        Self::lock(self);
+    }
+
+    fn try_lock(&self) -> bool {
+        Self::try_lock(self)
+    }
+
+    unsafe fn unlock(&self) {
+        // SAFETY: the caller must uphold the safety contract.
+        unsafe { Self::unlock(self) }
+    }
+
+    fn is_locked(&self) -> bool {
+        Self::is_locked(self)
+    }
+}
+
+#[cfg(feature = "lock_api")]
+// SAFETY: `unlock` always hands the lock to the next waiter in line, which is already fair.
+unsafe impl lock_api::RawMutexFair for RawTicketMutex {
+    unsafe fn unlock_fair(&self) {
+        // SAFETY: the caller must uphold the safety contract.
+        unsafe { Self::unlock(self) }
+    }
+}
+
+macro_rules! mutex {
+    (
+        $(#[$attr:meta])*
+        $mutex:ident, $guard:ident, $raw:ident
+    ) => {
+        $(#[$attr])*
+        pub struct $mutex<T: ?Sized> {
+            raw: $raw,
+            data: UnsafeCell<T>,
+        }
+
+        // SAFETY: The lock provides mutual exclusion, so the data is only accessed from one thread
+        // at a time.
+        unsafe impl<T: ?Sized + Send> Send for $mutex<T> {}
+        // SAFETY: See above.
+        unsafe impl<T: ?Sized + Send> Sync for $mutex<T> {}
+
+        doc_comment! {
+            concat!("An RAII guard returned by [`", stringify!($mutex), "::lock`] and [`",
+                stringify!($mutex), "::try_lock`].
+
+The lock is released when this guard is dropped."),
+            #[must_use = "if unused the lock will immediately unlock"]
+            pub struct $guard<'a, T: ?Sized> {
+                mutex: &'a $mutex<T>,
+                // The guard gives out `&mut T`, so it should be `Sync` only if `T` is `Sync`.
+                _marker: PhantomData<&'a mut T>,
+            }
+        }
+
+        impl<T> $mutex<T> {
+            doc_comment! {
+                concat!("Create a new unlocked [`", stringify!($mutex), "`].
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($mutex), ";
+
+static M: ", stringify!($mutex), "<u32> = ", stringify!($mutex), "::new(0);
+```"),
+                pub const fn new(value: T) -> Self {
+                    Self { raw: $raw::new(), data: UnsafeCell::new(value) }
+                }
+            }
+
+            doc_comment! {
+                concat!("Consume this mutex and return the underlying data.
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($mutex), ";
+
+let m = ", stringify!($mutex), "::new(5);
+assert_eq!(m.into_inner(), 5);
+```"),
+                pub fn into_inner(self) -> T {
+                    self.data.into_inner()
+                }
+            }
+        }
+
+        impl<T: ?Sized> $mutex<T> {
+            doc_comment! {
+                concat!("Acquire the lock, spinning until it is available.
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($mutex), ";
+use std::{sync::Arc, thread};
+
+let m = Arc::new(", stringify!($mutex), "::new(0));
+let threads: Vec<_> = (0..4)
+    .map(|_| {
+        let m = Arc::clone(&m);
+        thread::spawn(move || {
+            for _ in 0..100 {
+                *m.lock() += 1;
+            }
+        })
+    })
+    .collect();
+for t in threads {
+    t.join().unwrap();
+}
+assert_eq!(*m.lock(), 400);
+```"),
+                pub fn lock(&self) -> $guard<'_, T> {
+                    self.raw.lock();
+                    $guard { mutex: self, _marker: PhantomData }
+                }
+            }
+
+            doc_comment! {
+                concat!("Attempt to acquire the lock without spinning.
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($mutex), ";
+
+let m = ", stringify!($mutex), "::new(5);
+let guard = m.lock();
+assert!(m.try_lock().is_none());
+drop(guard);
+assert!(m.try_lock().is_some());
+```"),
+                pub fn try_lock(&self) -> Option<$guard<'_, T>> {
+                    if self.raw.try_lock() {
+                        Some($guard { mutex: self, _marker: PhantomData })
+                    } else {
+                        None
+                    }
+                }
+            }
+
+            /// Return `true` if the lock is currently held.
+            ///
+            /// This is only a hint: the state may change immediately after this returns.
+            #[must_use]
+            pub fn is_locked(&self) -> bool {
+                self.raw.is_locked()
+            }
+
+            doc_comment! {
+                concat!("Get a mutable reference to the underlying data.
+
+Since this call borrows the mutex mutably, no actual locking needs to take place.
+
+# Example
+
+```
+use portable_atomic_util::", stringify!($mutex), ";
+
+let mut m = ", stringify!($mutex), "::new(5);
+*m.get_mut() += 1;
+assert_eq!(*m.lock(), 6);
+```"),
+                pub fn get_mut(&mut self) -> &mut T {
+                    // SAFETY: We have exclusive access to the mutex.
+                    unsafe { &mut *self.data.get() }
+                }
+            }
+        }
+
+        impl<T: Default> Default for $mutex<T> {
+            fn default() -> Self {
+                Self::new(T::default())
+            }
+        }
+
+        impl<T> From<T> for $mutex<T> {
+            fn from(value: T) -> Self {
+                Self::new(value)
+            }
+        }
+
+        impl<T: ?Sized + fmt::Debug> fmt::Debug for $mutex<T> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                let mut d = f.debug_struct(stringify!($mutex));
+                match self.try_lock() {
+                    Some(guard) => d.field("data", &&*guard),
+                    None => d.field("data", &format_args!("<locked>")),
+                };
+                d.finish()
+            }
+        }
+
+        impl<T: ?Sized> Deref for $guard<'_, T> {
+            type Target = T;
+
+            fn deref(&self) -> &T {
+                // SAFETY: The lock is held, so we have exclusive access to the data.
+                unsafe { &*self.mutex.data.get() }
+            }
+        }
+
+        impl<T: ?Sized> DerefMut for $guard<'_, T> {
+            fn deref_mut(&mut self) -> &mut T {
+                // SAFETY: The lock is held, so we have exclusive access to the data.
+                unsafe { &mut *self.mutex.data.get() }
+            }
+        }
+
+        impl<T: ?Sized + fmt::Debug> fmt::Debug for $guard<'_, T> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Debug::fmt(&**self, f)
+            }
+        }
+
+        impl<T: ?Sized + fmt::Display> fmt::Display for $guard<'_, T> {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fmt::Display::fmt(&**self, f)
+            }
+        }
+
+        impl<T: ?Sized> Drop for $guard<'_, T> {
+            fn drop(&mut self) {
+                // SAFETY: The lock is held by this guard.
+                unsafe { self.mutex.raw.unlock() }
+            }
+        }
+    };
+}
+
+mutex! {
+    /// A mutual exclusion lock based on a test-and-test-and-set spin lock.
+    ///
+    /// This is cheap to acquire when uncontended, but is not fair: a thread that has just released
+    /// the lock may immediately reacquire it. See [`TicketMutex`] for a fair lock.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use portable_atomic_util::SpinMutex;
+    ///
+    /// static COUNTER: SpinMutex<u32> = SpinMutex::new(0);
+    ///
+    /// *COUNTER.lock() += 1;
+    /// assert_eq!(*COUNTER.lock(), 1);
+    /// ```
+    SpinMutex, SpinMutexGuard, RawSpinMutex
+}
+
+mutex! {
+    /// A fair mutual exclusion lock based on a ticket lock.
+    ///
+    /// Threads acquire the lock in the order in which they started waiting for it.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use portable_atomic_util::TicketMutex;
+    ///
+    /// static COUNTER: TicketMutex<u32> = TicketMutex::new(0);
+    ///
+    /// *COUNTER.lock() += 1;
+    /// assert_eq!(*COUNTER.lock(), 1);
+    /// ```
+    TicketMutex, TicketMutexGuard, RawTicketMutex
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::{thread, vec::Vec};
+
+    const THREADS: usize = 4;
+    const ITERS: usize = 1000;
+
+    // Checks that the lock is never held by two threads at the same time, and that writes made
+    // under the lock are visible to the next holder.
+    macro_rules! counter_test {
+        ($name:ident, $mutex:ident) => {
+            #[test]
+            fn $name() {
+                let mutex = $mutex::new(0);
+                let inside = AtomicBool::new(false);
+                thread::scope(|s| {
+                    for _ in 0..THREADS {
+                        s.spawn(|| {
+                            for i in 0..ITERS {
+                                let mut guard = if i % 2 == 0 {
+                                    mutex.lock()
+                                } else {
+                                    loop {
+                                        if let Some(guard) = mutex.try_lock() {
+                                            break guard;
+                                        }
+                                        thread::yield_now();
+                                    }
+                                };
+                                assert!(!inside.swap(true, Relaxed));
+                                *guard += 1;
+                                inside.store(false, Relaxed);
+                            }
+                        });
+                    }
+                });
+                assert!(!mutex.is_locked());
+                assert_eq!(mutex.into_inner(), THREADS * ITERS);
+            }
+        };
+    }
+
+    counter_test!(spin_mutex_counter, SpinMutex);
+    counter_test!(ticket_mutex_counter, TicketMutex);
+
+    #[test]
+    fn ticket_mutex_fifo() {
+        const WAITERS: usize = 8;
+        let mutex = TicketMutex::new(Vec::new());
+        let guard = mutex.lock();
+        thread::scope(|s| {
+            for i in 0..WAITERS {
+                let mutex = &mutex;
+                s.spawn(move || mutex.lock().push(i));
+                // Wait until this thread has taken its ticket before starting the next one, so
+                // the tickets are handed out in spawn order.
+                while mutex.raw.next_ticket.load(Relaxed) != i + 2 {
+                    thread::yield_now();
+                }
+            }
+            drop(guard);
+        });
+        assert_eq!(mutex.into_inner(), (0..WAITERS).collect::<Vec<_>>());
+    }
+
+    #[cfg(feature = "lock_api")]
+    #[test]
+    fn lock_api_counter() {
+        fn counter<R: lock_api::RawMutex + Sync>(unlock: fn(lock_api::MutexGuard<'_, R, usize>)) {
+            let mutex = lock_api::Mutex::<R, usize>::new(0);
+            thread::scope(|s| {
+                for _ in 0..THREADS {
+                    s.spawn(|| {
+                        for _ in 0..ITERS {
+                            let mut guard = mutex.lock();
+                            *guard += 1;
+                            unlock(guard);
+                        }
+                    });
+                }
+            });
+            assert_eq!(mutex.into_inner(), THREADS * ITERS);
+        }
+        counter::<RawSpinMutex>(|guard| drop(guard));
+        counter::<RawTicketMutex>(|guard| drop(guard));
+        counter::<RawTicketMutex>(|guard| lock_api::MutexGuard::unlock_fair(guard));
+    }
+}
@@ -0,0 +1,689 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+// Async task notification.
+//
+// The API is based on tokio's Notify:
+// https://github.com/tokio-rs/tokio/blob/tokio-1.35.0/tokio/src/sync/notify.rs
+//
+// Waiting `Notified` futures are linked into an intrusive doubly linked list, which is protected
+// by the `LOCKED` bit of `state`. Futures are not polled from interrupt handlers, so pollers
+// simply spin until they get the lock. Notifiers, however, never wait for the lock: they record
+// the notification in `state` (`PENDING_ALL`, or the count of pending `notify_one` calls), and
+// whoever holds or next takes the lock applies it before unlocking. This makes `notify_one` and
+// `notify_waiters` safe to call from interrupt handlers.
+//
+// Wakers are woken after the lock is released, in batches of `WAKE_BATCH`.
+
+use portable_atomic::{
+    AtomicUsize,
+    Ordering::{AcqRel, Acquire, Release},
+};
+
+use crate::utils::Backoff;
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    future::Future,
+    marker::PhantomPinned,
+    pin::Pin,
+    ptr,
+    task::{Context, Poll, Waker},
+};
+
+const LOCKED: usize = 1;
+// A `notify_one` call found no waiter; the next waiter completes immediately.
+const PERMIT: usize = 2;
+// A `notify_waiters` call has not been applied to the waiter list yet.
+const PENDING_ALL: usize = 4;
+// The count of `notify_one` calls that have not been applied yet is stored in the remaining bits.
+const PENDING_ONE: usize = 8;
+const PENDING_MASK: usize = !(LOCKED | PERMIT);
+
+const WAKE_BATCH: usize = 8;
+
+/// Notify a task of an event.
+///
+/// `Notify` provides a basic mechanism to notify a single task, or all waiting tasks, of an
+/// event. It does not carry any data. Tasks wait by awaiting the future returned by
+/// [`notified`](Self::notified).
+///
+/// - [`notify_one`](Self::notify_one) wakes one waiting task. If no task is waiting, a permit is
+///   stored, and the next call to `notified().await` completes immediately. At most one permit
+///   is stored.
+/// - [`notify_waiters`](Self::notify_waiters) wakes all tasks waiting at the time of the call,
+///   including [`Notified`] futures that have been created but not polled yet. It does not store
+///   a permit.
+///
+/// This only uses the atomic operations provided by portable-atomic, and does not require an
+/// allocator, so it also works on targets without native atomic CAS when portable-atomic's
+/// `critical-section` feature (or `unsafe-assume-single-core` cfg) is enabled. The notifying
+/// methods never block, so they can be called from interrupt handlers.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::Notify;
+/// use std::{sync::Arc, thread};
+/// # fn block_on<F: std::future::Future>(f: F) -> F::Output {
+/// #     use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+/// #     static VTABLE: RawWakerVTable = RawWakerVTable::new(|p| RawWaker::new(p, &VTABLE), |_| {}, |_| {}, |_| {});
+/// #     let waker = unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) };
+/// #     let mut f = Box::pin(f);
+/// #     loop {
+/// #         if let Poll::Ready(v) = f.as_mut().poll(&mut Context::from_waker(&waker)) {
+/// #             return v;
+/// #         }
+/// #         thread::yield_now();
+/// #     }
+/// # }
+///
+/// let notify = Arc::new(Notify::new());
+/// let notify2 = Arc::clone(&notify);
+/// let t = thread::spawn(move || notify2.notify_one());
+/// block_on(notify.notified());
+/// t.join().unwrap();
+/// ```
+pub struct Notify {
+    state: AtomicUsize,
+    // The number of `notify_waiters` calls, which `Notified` compares with the value at creation.
+    generation: AtomicUsize,
+    // Guarded by `LOCKED`.
+    waiters: UnsafeCell<List>,
+}
+
+// SAFETY: `waiters` is only accessed while holding the lock, and the wakers in it are `Send`.
+unsafe impl Send for Notify {}
+// SAFETY: See above.
+unsafe impl Sync for Notify {}
+
+struct List {
+    head: *mut Waiter,
+    tail: *mut Waiter,
+}
+
+// The node of a waiting `Notified`, guarded by the lock of its `Notify`.
+struct Waiter {
+    prev: *mut Waiter,
+    next: *mut Waiter,
+    waker: Option<Waker>,
+    // The generation when the `Notified` was created.
+    generation: usize,
+    notified: Option<Notification>,
+    _pinned: PhantomPinned,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Notification {
+    One,
+    All,
+}
+
+impl List {
+    // SAFETY: `waiter` must be valid and not in the list.
+    unsafe fn push_back(&mut self, waiter: *mut Waiter) {
+        // SAFETY: The caller guarantees that `waiter` is valid, and nodes in the list are valid.
+        unsafe {
+            (*waiter).prev = self.tail;
+            (*waiter).next = ptr::null_mut();
+            if self.tail.is_null() {
+                self.head = waiter;
+            } else {
+                (*self.tail).next = waiter;
+            }
+            self.tail = waiter;
+        }
+    }
+
+    // SAFETY: `waiter` must be in the list.
+    unsafe fn remove(&mut self, waiter: *mut Waiter) {
+        // SAFETY: The caller guarantees that `waiter` is in the list, and nodes in the list are
+        // valid.
+        unsafe {
+            let prev = (*waiter).prev;
+            let next = (*waiter).next;
+            if prev.is_null() {
+                self.head = next;
+            } else {
+                (*prev).next = next;
+            }
+            if next.is_null() {
+                self.tail = prev;
+            } else {
+                (*next).prev = prev;
+            }
+            (*waiter).prev = ptr::null_mut();
+            (*waiter).next = ptr::null_mut();
+        }
+    }
+}
+
+struct WakeList {
+    wakers: [Option<Waker>; WAKE_BATCH],
+    len: usize,
+}
+
+impl WakeList {
+    fn new() -> Self {
+        Self { wakers: [None, None, None, None, None, None, None, None], len: 0 }
+    }
+    fn is_full(&self) -> bool {
+        self.len == WAKE_BATCH
+    }
+    fn push(&mut self, waker: Option<Waker>) {
+        debug_assert!(!self.is_full());
+        if let Some(waker) = waker {
+            self.wakers[self.len] = Some(waker);
+            self.len += 1;
+        }
+    }
+    fn wake_all(&mut self) {
+        for waker in &mut self.wakers[..self.len] {
+            if let Some(waker) = waker.take() {
+                waker.wake();
+            }
+        }
+        self.len = 0;
+    }
+}
+
+impl Notify {
+    /// Create a new `Notify` with no stored permit.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Notify;
+    ///
+    /// static NOTIFY: Notify = Notify::new();
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+            waiters: UnsafeCell::new(List { head: ptr::null_mut(), tail: ptr::null_mut() }),
+        }
+    }
+
+    /// Wait for a notification.
+    ///
+    /// The returned future completes when [`notify_one`](Self::notify_one) selects it (or a
+    /// permit was stored before it was first polled), or when
+    /// [`notify_waiters`](Self::notify_waiters) is called after it was created.
+    ///
+    /// If the future is dropped after being selected by `notify_one` but before completing, the
+    /// notification is passed to another waiter.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Notify;
+    /// # fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    /// #     use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    /// #     static VTABLE: RawWakerVTable = RawWakerVTable::new(|p| RawWaker::new(p, &VTABLE), |_| {}, |_| {}, |_| {});
+    /// #     let waker = unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) };
+    /// #     let mut f = Box::pin(f);
+    /// #     loop {
+    /// #         if let Poll::Ready(v) = f.as_mut().poll(&mut Context::from_waker(&waker)) {
+    /// #             return v;
+    /// #         }
+    /// #     }
+    /// # }
+    ///
+    /// let notify = Notify::new();
+    /// let notified = notify.notified();
+    /// notify.notify_waiters();
+    /// block_on(notified);
+    /// ```
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            state: NotifiedState::Init,
+            waiter: UnsafeCell::new(Waiter {
+                prev: ptr::null_mut(),
+                next: ptr::null_mut(),
+                waker: None,
+                generation: self.generation.load(Acquire),
+                notified: None,
+                _pinned: PhantomPinned,
+            }),
+        }
+    }
+
+    /// Notify one waiting task, or store a permit if no task is waiting.
+    ///
+    /// Waiting tasks are notified in the order in which they started waiting.
+    ///
+    /// This never blocks, so it can be called from an interrupt handler.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Notify;
+    /// # fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    /// #     use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    /// #     static VTABLE: RawWakerVTable = RawWakerVTable::new(|p| RawWaker::new(p, &VTABLE), |_| {}, |_| {}, |_| {});
+    /// #     let waker = unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) };
+    /// #     let mut f = Box::pin(f);
+    /// #     loop {
+    /// #         if let Poll::Ready(v) = f.as_mut().poll(&mut Context::from_waker(&waker)) {
+    /// #             return v;
+    /// #         }
+    /// #     }
+    /// # }
+    ///
+    /// let notify = Notify::new();
+    /// // No task is waiting, so a permit is stored.
+    /// notify.notify_one();
+    /// // The stored permit is consumed.
+    /// block_on(notify.notified());
+    /// ```
+    pub fn notify_one(&self) {
+        self.state.fetch_add(PENDING_ONE, AcqRel);
+        self.try_apply_pending();
+    }
+
+    /// Notify all tasks that are currently waiting.
+    ///
+    /// This includes [`Notified`] futures that have been created but not polled yet, but not
+    /// futures created after this call. No permit is stored.
+    ///
+    /// This never blocks, so it can be called from an interrupt handler.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Notify;
+    /// # fn block_on<F: std::future::Future>(f: F) -> F::Output {
+    /// #     use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    /// #     static VTABLE: RawWakerVTable = RawWakerVTable::new(|p| RawWaker::new(p, &VTABLE), |_| {}, |_| {}, |_| {});
+    /// #     let waker = unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) };
+    /// #     let mut f = Box::pin(f);
+    /// #     loop {
+    /// #         if let Poll::Ready(v) = f.as_mut().poll(&mut Context::from_waker(&waker)) {
+    /// #             return v;
+    /// #         }
+    /// #     }
+    /// # }
+    ///
+    /// let notify = Notify::new();
+    /// let a = notify.notified();
+    /// let b = notify.notified();
+    /// notify.notify_waiters();
+    /// block_on(a);
+    /// block_on(b);
+    /// ```
+    pub fn notify_waiters(&self) {
+        self.generation.fetch_add(1, AcqRel);
+        self.state.fetch_or(PENDING_ALL, AcqRel);
+        self.try_apply_pending();
+    }
+
+    // Apply pending notifications if nobody holds the lock. Otherwise, the lock holder applies
+    // them before unlocking.
+    fn try_apply_pending(&self) {
+        let mut state = self.state.load(Acquire);
+        loop {
+            if state & LOCKED != 0 || state & PENDING_MASK == 0 {
+                return;
+            }
+            match self.state.compare_exchange_weak(state, state | LOCKED, Acquire, Acquire) {
+                Ok(_) => break,
+                Err(s) => state = s,
+            }
+        }
+        self.unlock(&mut WakeList::new());
+    }
+
+    fn lock(&self) {
+        let mut backoff = Backoff::new();
+        let mut state = self.state.load(Acquire);
+        loop {
+            if state & LOCKED == 0 {
+                match self.state.compare_exchange_weak(state, state | LOCKED, Acquire, Acquire) {
+                    Ok(_) => return,
+                    Err(s) => state = s,
+                }
+            } else {
+                backoff.snooze();
+                state = self.state.load(Acquire);
+            }
+        }
+    }
+
+    // Apply pending notifications, release the lock, and wake the woken tasks. If there are too
+    // many tasks to wake at once, this takes the lock again (if nobody else took it) to continue.
+    fn unlock(&self, wakers: &mut WakeList) {
+        loop {
+            let mut state = self.state.load(Acquire);
+            loop {
+                if state & PENDING_MASK != 0 && !wakers.is_full() {
+                    // Take the pending notifications and apply them.
+                    match self.state.compare_exchange_weak(
+                        state,
+                        state & !PENDING_MASK,
+                        Acquire,
+                        Acquire,
+                    ) {
+                        Ok(_) => {
+                            // SAFETY: We hold the lock.
+                            unsafe { self.apply(state, wakers) }
+                            state = self.state.load(Acquire);
+                        }
+                        Err(s) => state = s,
+                    }
+                    continue;
+                }
+                match self.state.compare_exchange_weak(state, state & !LOCKED, Release, Acquire) {
+                    Ok(_) => break,
+                    Err(s) => state = s,
+                }
+            }
+            wakers.wake_all();
+
+            // Notifications that did not fit in the batch are still pending. Continue if nobody
+            // else took the lock to apply them.
+            let mut state = self.state.load(Acquire);
+            loop {
+                if state & LOCKED != 0 || state & PENDING_MASK == 0 {
+                    return;
+                }
+                match self.state.compare_exchange_weak(state, state | LOCKED, Acquire, Acquire) {
+                    Ok(_) => break,
+                    Err(s) => state = s,
+                }
+            }
+        }
+    }
+
+    // Apply the pending notifications taken from `pending`. Notifications that do not fit in
+    // `wakers` are put back.
+    //
+    // SAFETY: The caller must hold the lock.
+    unsafe fn apply(&self, pending: usize, wakers: &mut WakeList) {
+        // SAFETY: The caller guarantees that we hold the lock.
+        let waiters = unsafe { &mut *self.waiters.get() };
+        let generation = self.generation.load(Acquire);
+
+        if pending & PENDING_ALL != 0 {
+            let mut waiter = waiters.head;
+            while !waiter.is_null() {
+                if wakers.is_full() {
+                    self.state.fetch_or(PENDING_ALL, AcqRel);
+                    break;
+                }
+                // SAFETY: Nodes in the list are valid while we hold the lock.
+                unsafe {
+                    let next = (*waiter).next;
+                    if (*waiter).generation != generation {
+                        waiters.remove(waiter);
+                        (*waiter).notified = Some(Notification::All);
+                        wakers.push((*waiter).waker.take());
+                    }
+                    waiter = next;
+                }
+            }
+        }
+
+        let mut ones = pending / PENDING_ONE;
+        while ones != 0 {
+            if wakers.is_full() {
+                self.state.fetch_add(ones * PENDING_ONE, AcqRel);
+                return;
+            }
+            // Select the oldest waiter that is not going to be notified by a pending
+            // `notify_waiters` anyway.
+            let mut waiter = waiters.head;
+            // SAFETY: Nodes in the list are valid while we hold the lock.
+            unsafe {
+                while !waiter.is_null() && (*waiter).generation != generation {
+                    waiter = (*waiter).next;
+                }
+                if waiter.is_null() {
+                    // Nobody is waiting; the remaining notifications collapse into one permit.
+                    self.state.fetch_or(PERMIT, AcqRel);
+                    return;
+                }
+                waiters.remove(waiter);
+                (*waiter).notified = Some(Notification::One);
+                wakers.push((*waiter).waker.take());
+            }
+            ones -= 1;
+        }
+    }
+
+    fn take_permit(&self) -> bool {
+        self.state.fetch_and(!PERMIT, AcqRel) & PERMIT != 0
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Notify {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Notify").finish()
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum NotifiedState {
+    Init,
+    Waiting,
+    Done,
+}
+
+/// A future returned by [`Notify::notified`].
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    state: NotifiedState,
+    // Linked into `notify.waiters` while `state` is `Waiting`. Guarded by the lock of `notify`.
+    waiter: UnsafeCell<Waiter>,
+}
+
+// SAFETY: `waiter` is only accessed while holding the lock of `notify`, which is `Sync`.
+unsafe impl Send for Notified<'_> {}
+// SAFETY: `Notified` has no methods that take `&self`.
+unsafe impl Sync for Notified<'_> {}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // SAFETY: We never move `waiter` out of the pinned future.
+        let this = unsafe { self.get_unchecked_mut() };
+        let notify = this.notify;
+        let waiter = this.waiter.get();
+        match this.state {
+            NotifiedState::Done => return Poll::Ready(()),
+            NotifiedState::Init => {
+                // SAFETY: `waiter` is not in the list yet, so only we access it.
+                if notify.generation.load(Acquire) != unsafe { (*waiter).generation }
+                    || notify.take_permit()
+                {
+                    this.state = NotifiedState::Done;
+                    return Poll::Ready(());
+                }
+            }
+            NotifiedState::Waiting => {}
+        }
+
+        let mut wakers = WakeList::new();
+        notify.lock();
+        // SAFETY: We hold the lock. The waiter is pinned, so it stays valid while in the list,
+        // and `Drop` removes it from the list.
+        let ready = unsafe {
+            let waiters = &mut *notify.waiters.get();
+            if this.state == NotifiedState::Init {
+                // Apply pending notifications first, so that a pending `notify_one` is not lost.
+                let state = notify.state.fetch_and(!PENDING_MASK, Acquire);
+                notify.apply(state & PENDING_MASK, &mut wakers);
+                if notify.take_permit() || notify.generation.load(Acquire) != (*waiter).generation {
+                    true
+                } else {
+                    (*waiter).waker = Some(cx.waker().clone());
+                    waiters.push_back(waiter);
+                    this.state = NotifiedState::Waiting;
+                    false
+                }
+            } else if (*waiter).notified.is_some() {
+                true
+            } else if notify.generation.load(Acquire) != (*waiter).generation {
+                // A `notify_waiters` call has not been applied yet.
+                waiters.remove(waiter);
+                true
+            } else {
+                match &(*waiter).waker {
+                    Some(w) if w.will_wake(cx.waker()) => {}
+                    _ => (*waiter).waker = Some(cx.waker().clone()),
+                }
+                false
+            }
+        };
+        notify.unlock(&mut wakers);
+        if ready {
+            this.state = NotifiedState::Done;
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if self.state != NotifiedState::Waiting {
+            return;
+        }
+        let notify = self.notify;
+        let waiter = self.waiter.get();
+        notify.lock();
+        // SAFETY: We hold the lock, and `waiter` is in the list unless it has been notified.
+        unsafe {
+            match (*waiter).notified {
+                None => (*notify.waiters.get()).remove(waiter),
+                // We were selected by `notify_one` but will not complete: pass the notification
+                // on. It is applied when we unlock.
+                Some(Notification::One) => {
+                    notify.state.fetch_add(PENDING_ONE, AcqRel);
+                }
+                Some(Notification::All) => {}
+            }
+        }
+        notify.unlock(&mut WakeList::new());
+    }
+}
+
+impl fmt::Debug for Notified<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Notified").finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::block_on;
+
+    use std::{boxed::Box, pin::pin, thread, vec::Vec};
+
+    use portable_atomic::Ordering::Relaxed;
+
+    #[test]
+    fn notify_one_concurrent() {
+        // Each `notify_one` hands out a token; a lost notification hangs the test.
+        const THREADS: usize = 4;
+        const N: usize = 1000;
+        let notify = Notify::new();
+        let tokens = AtomicUsize::new(0);
+        let take = || tokens.fetch_update(AcqRel, Acquire, |t| t.checked_sub(1)).is_ok();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..N {
+                        loop {
+                            let notified = notify.notified();
+                            if take() {
+                                // Notifications of tokens added while no task was waiting
+                                // collapse into one permit, so pass the remaining ones on.
+                                if tokens.load(Acquire) != 0 {
+                                    notify.notify_one();
+                                }
+                                break;
+                            }
+                            block_on(notified);
+                        }
+                    }
+                });
+            }
+            for _ in 0..THREADS * N {
+                tokens.fetch_add(1, Release);
+                notify.notify_one();
+            }
+        });
+        assert_eq!(tokens.load(Relaxed), 0);
+    }
+
+    #[test]
+    fn notify_waiters_concurrent() {
+        const THREADS: usize = 4;
+        for _ in 0..100 {
+            let notify = Notify::new();
+            let ready = AtomicUsize::new(0);
+            thread::scope(|s| {
+                let threads: Vec<_> = (0..THREADS)
+                    .map(|_| {
+                        s.spawn(|| {
+                            let notified = notify.notified();
+                            ready.fetch_add(1, Release);
+                            block_on(notified);
+                        })
+                    })
+                    .collect();
+                while ready.load(Acquire) < THREADS {
+                    thread::yield_now();
+                }
+                // All futures were created before this call, whether they were polled or not.
+                notify.notify_waiters();
+                for t in threads {
+                    t.join().unwrap();
+                }
+            });
+            // No permit is stored.
+            let mut notified = pin!(notify.notified());
+            let waker = crate::waker::current_thread_waker();
+            assert!(notified.as_mut().poll(&mut Context::from_waker(&waker)).is_pending());
+        }
+    }
+
+    #[test]
+    fn drop_notified() {
+        let notify = Notify::new();
+        let waker = crate::waker::current_thread_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        // A waiting future that is dropped is removed from the list, and `notify_one` stores a
+        // permit instead.
+        {
+            let mut notified = pin!(notify.notified());
+            assert!(notified.as_mut().poll(&mut cx).is_pending());
+        }
+        notify.notify_one();
+        block_on(notify.notified());
+
+        // A future that was selected by `notify_one` but dropped passes the notification on.
+        let mut a = Box::pin(notify.notified());
+        let mut b = Box::pin(notify.notified());
+        assert!(a.as_mut().poll(&mut cx).is_pending());
+        assert!(b.as_mut().poll(&mut cx).is_pending());
+        notify.notify_one();
+        drop(a);
+        assert!(b.as_mut().poll(&mut cx).is_ready());
+    }
+}
@@ -0,0 +1,594 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+// Blocking once cells.
+//
+// Based on std::sync::OnceLock and std's queue-based Once implementation:
+// https://github.com/rust-lang/rust/blob/1.75.0/library/std/src/sys_common/once/queue.rs
+//
+// The state is stored in a single `AtomicPtr<u8>`: the low two bits are one of `INCOMPLETE`,
+// `RUNNING`, and `COMPLETE`. With the `std` feature, while the state is `RUNNING`, the remaining
+// bits point to a linked list of threads waiting for the initialization to finish, which are
+// unparked when it finishes. Without the `std` feature, waiting threads spin instead.
+
+use portable_atomic::{
+    AtomicPtr,
+    Ordering::{AcqRel, Acquire},
+};
+
+use crate::utils::strict;
+
+use core::{
+    cell::{Cell, UnsafeCell},
+    fmt,
+    ops::Deref,
+};
+
+const INCOMPLETE: usize = 0;
+const RUNNING: usize = 1;
+const COMPLETE: usize = 2;
+const STATE_MASK: usize = 3;
+
+#[inline]
+fn state_of(state: *mut u8) -> usize {
+    state as usize & STATE_MASK
+}
+
+/// A thread-safe cell which can be written to only once.
+///
+/// Unlike [`OnceBox`](crate::OnceBox) and the other race-based cells, only one thread runs the
+/// initialization function; other threads trying to initialize the cell at the same time wait
+/// until it finishes. With the `std` feature, waiting threads are parked; otherwise they spin.
+///
+/// If the initialization function panics, the cell stays empty and another thread can try to
+/// initialize it.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::OnceLock;
+///
+/// static GREETING: OnceLock<String> = OnceLock::new();
+///
+/// let greeting = GREETING.get_or_init(|| "hello".to_owned());
+/// assert_eq!(greeting, "hello");
+/// assert_eq!(GREETING.get().map(String::as_str), Some("hello"));
+/// ```
+pub struct OnceLock<T> {
+    state: AtomicPtr<u8>,
+    value: UnsafeCell<Option<T>>,
+}
+
+// SAFETY: `&OnceLock<T>` can be used to send a `T` to another thread (by initializing it there)
+// and to share `&T` between threads.
+unsafe impl<T: Send + Sync> Sync for OnceLock<T> {}
+
+impl<T> OnceLock<T> {
+    /// Create a new empty cell.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceLock;
+    ///
+    /// static CELL: OnceLock<u32> = OnceLock::new();
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { state: AtomicPtr::new(strict::invalid(INCOMPLETE)), value: UnsafeCell::new(None) }
+    }
+
+    /// Get a reference to the underlying value, or `None` if the cell is empty or being
+    /// initialized.
+    ///
+    /// This never blocks.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceLock;
+    ///
+    /// let cell = OnceLock::new();
+    /// assert!(cell.get().is_none());
+    /// cell.set(1).unwrap();
+    /// assert_eq!(cell.get(), Some(&1));
+    /// ```
+    #[must_use]
+    pub fn get(&self) -> Option<&T> {
+        if state_of(self.state.load(Acquire)) == COMPLETE {
+            // SAFETY: The value has been initialized and is never modified through `&self` again.
+            unsafe { (*self.value.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+
+    /// Get a mutable reference to the underlying value, or `None` if the cell is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceLock;
+    ///
+    /// let mut cell = OnceLock::new();
+    /// cell.set(1).unwrap();
+    /// *cell.get_mut().unwrap() += 1;
+    /// assert_eq!(cell.get(), Some(&2));
+    /// ```
+    pub fn get_mut(&mut self) -> Option<&mut T> {
+        // SAFETY: We have exclusive access to the cell.
+        unsafe { (*self.value.get()).as_mut() }
+    }
+
+    /// Set the contents of this cell to `value`, blocking if another thread is initializing it.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(value)` if the cell was already initialized.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceLock;
+    ///
+    /// let cell = OnceLock::new();
+    /// assert_eq!(cell.set(1), Ok(()));
+    /// assert_eq!(cell.set(2), Err(2));
+    /// assert_eq!(cell.get(), Some(&1));
+    /// ```
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.get_or_init(|| match value.take() {
+            Some(value) => value,
+            None => unreachable!(),
+        });
+        match value {
+            None => Ok(()),
+            Some(value) => Err(value),
+        }
+    }
+
+    /// Get the contents of the cell, initializing it with `f` if the cell was empty.
+    ///
+    /// Only one thread runs `f`; other threads calling this method at the same time block until
+    /// the initialization finishes. Calling this method from `f` on the same cell deadlocks.
+    ///
+    /// If `f` panics, the panic is propagated to the caller and the cell stays empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceLock;
+    /// use std::thread;
+    ///
+    /// static CELL: OnceLock<usize> = OnceLock::new();
+    ///
+    /// let threads: Vec<_> = (0..4)
+    ///     .map(|i| thread::spawn(move || *CELL.get_or_init(|| i)))
+    ///     .collect();
+    /// let values: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+    /// assert!(values.iter().all(|&v| v == values[0]));
+    /// ```
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> T,
+    {
+        enum Void {}
+        match self.get_or_try_init(|| Ok::<T, Void>(f())) {
+            Ok(value) => value,
+            Err(void) => match void {},
+        }
+    }
+
+    /// Get the contents of the cell, initializing it with `f` if the cell was empty.
+    ///
+    /// If `f` fails, the error is returned and the cell stays empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error returned by `f`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceLock;
+    ///
+    /// let cell = OnceLock::new();
+    /// assert_eq!(cell.get_or_try_init(|| Err(())), Err(()));
+    /// assert!(cell.get().is_none());
+    /// assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(1)), Ok(&1));
+    /// ```
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+        self.initialize(f)?;
+        match self.get() {
+            Some(value) => Ok(value),
+            None => unreachable!(),
+        }
+    }
+
+    /// Consume the cell and return the underlying value, or `None` if the cell was empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceLock;
+    ///
+    /// let cell = OnceLock::new();
+    /// cell.set("hello".to_owned()).unwrap();
+    /// assert_eq!(cell.into_inner(), Some("hello".to_owned()));
+    /// ```
+    pub fn into_inner(self) -> Option<T> {
+        self.value.into_inner()
+    }
+
+    /// Take the value out of the cell, leaving it empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceLock;
+    ///
+    /// let mut cell = OnceLock::new();
+    /// cell.set(1).unwrap();
+    /// assert_eq!(cell.take(), Some(1));
+    /// assert!(cell.get().is_none());
+    /// ```
+    pub fn take(&mut self) -> Option<T> {
+        self.state = AtomicPtr::new(strict::invalid(INCOMPLETE));
+        // SAFETY: We have exclusive access to the cell.
+        unsafe { (*self.value.get()).take() }
+    }
+
+    #[cold]
+    fn initialize<F, E>(&self, f: F) -> Result<(), E>
+    where
+        F: FnOnce() -> Result<T, E>,
+    {
+        let mut f = Some(f);
+        let mut state = self.state.load(Acquire);
+        loop {
+            match state_of(state) {
+                COMPLETE => return Ok(()),
+                INCOMPLETE => {
+                    if let Err(new) = self.state.compare_exchange_weak(
+                        state,
+                        strict::invalid(RUNNING),
+                        Acquire,
+                        Acquire,
+                    ) {
+                        state = new;
+                        continue;
+                    }
+                    // If `f` fails or panics, reset the state to `INCOMPLETE` and wake up the
+                    // waiting threads so that one of them can try again.
+                    let mut finish = Finish { state: &self.state, new_state: INCOMPLETE };
+                    let value = (f.take().unwrap())()?;
+                    // SAFETY: We are the only thread that can access the value while `RUNNING`.
+                    unsafe { *self.value.get() = Some(value) }
+                    finish.new_state = COMPLETE;
+                    return Ok(());
+                }
+                _ => {
+                    wait(&self.state, state);
+                    state = self.state.load(Acquire);
+                }
+            }
+        }
+    }
+}
+
+impl<T> Default for OnceLock<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> From<T> for OnceLock<T> {
+    fn from(value: T) -> Self {
+        Self {
+            state: AtomicPtr::new(strict::invalid(COMPLETE)),
+            value: UnsafeCell::new(Some(value)),
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_tuple("OnceLock");
+        match self.get() {
+            Some(value) => d.field(value),
+            None => d.field(&format_args!("<uninit>")),
+        };
+        d.finish()
+    }
+}
+
+/// Sets the state and wakes up the waiting threads when dropped, including on panic.
+struct Finish<'a> {
+    state: &'a AtomicPtr<u8>,
+    new_state: usize,
+}
+
+impl Drop for Finish<'_> {
+    #[allow(clippy::cast_ptr_alignment)] // The queue pointers were created from `&Waiter`.
+    fn drop(&mut self) {
+        let queue = self.state.swap(strict::invalid(self.new_state), AcqRel);
+        debug_assert_eq!(state_of(queue), RUNNING);
+        #[cfg(feature = "std")]
+        {
+            let mut queue = strict::map_addr(queue, |addr| addr & !STATE_MASK) as *const Waiter;
+            while !queue.is_null() {
+                // SAFETY: The waiter stays alive until `signaled` is set, and we must not touch it
+                // after that.
+                unsafe {
+                    let next = (*queue).next.get();
+                    let thread = (*queue).thread.take().unwrap();
+                    (*queue).signaled.store(true, portable_atomic::Ordering::Release);
+                    thread.unpark();
+                    queue = next;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+#[repr(align(4))] // The low two bits of the pointer are used for the state.
+struct Waiter {
+    thread: Cell<Option<std::thread::Thread>>,
+    signaled: portable_atomic::AtomicBool,
+    next: Cell<*const Waiter>,
+}
+
+#[cfg(feature = "std")]
+#[cold]
+#[allow(clippy::cast_ptr_alignment)] // The queue pointers were created from `&Waiter`.
+fn wait(state_ptr: &AtomicPtr<u8>, mut state: *mut u8) {
+    let node = Waiter {
+        thread: Cell::new(Some(std::thread::current())),
+        signaled: portable_atomic::AtomicBool::new(false),
+        next: Cell::new(core::ptr::null()),
+    };
+    let me = &node as *const Waiter as *mut u8;
+    loop {
+        if state_of(state) != RUNNING {
+            return;
+        }
+        node.next.set(strict::map_addr(state, |addr| addr & !STATE_MASK) as *const Waiter);
+        match state_ptr.compare_exchange_weak(
+            state,
+            strict::map_addr(me, |addr| addr | RUNNING),
+            portable_atomic::Ordering::Release,
+            Acquire,
+        ) {
+            Ok(_) => break,
+            Err(new) => state = new,
+        }
+    }
+    // Spurious wakeups are possible, so check `signaled`.
+    while !node.signaled.load(Acquire) {
+        std::thread::park();
+    }
+}
+
+#[cfg(not(feature = "std"))]
+#[cold]
+fn wait(state_ptr: &AtomicPtr<u8>, _state: *mut u8) {
+    let mut backoff = crate::utils::Backoff::new();
+    while state_of(state_ptr.load(Acquire)) == RUNNING {
+        backoff.snooze();
+    }
+}
+
+/// A value which is initialized on the first access.
+///
+/// This is a thread-safe lazily initialized value built on [`OnceLock`], so threads accessing it
+/// while it is being initialized block until the initialization finishes.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::LazyLock;
+/// use std::collections::HashMap;
+///
+/// static PRIMES: LazyLock<HashMap<u32, bool>> = LazyLock::new(|| {
+///     let mut m = HashMap::new();
+///     m.insert(2, true);
+///     m.insert(4, false);
+///     m
+/// });
+///
+/// assert_eq!(PRIMES.get(&2), Some(&true));
+/// ```
+pub struct LazyLock<T, F = fn() -> T> {
+    cell: OnceLock<T>,
+    init: Cell<Option<F>>,
+}
+
+// SAFETY: `init` is only accessed by the thread initializing `cell`, which may be different from
+// the thread that created the `LazyLock`, so `F` must be `Send`.
+unsafe impl<T: Send + Sync, F: Send> Sync for LazyLock<T, F> {}
+
+impl<T, F> LazyLock<T, F> {
+    /// Create a new lazy value with the given initialization function.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::LazyLock;
+    ///
+    /// static VALUE: LazyLock<u32> = LazyLock::new(|| 1 + 1);
+    /// assert_eq!(*VALUE, 2);
+    /// ```
+    pub const fn new(f: F) -> Self {
+        Self { cell: OnceLock::new(), init: Cell::new(Some(f)) }
+    }
+}
+
+impl<T, F: FnOnce() -> T> LazyLock<T, F> {
+    /// Force the evaluation of this lazy value and return a reference to the result.
+    ///
+    /// This is equivalent to the `Deref` impl, but is explicit.
+    ///
+    /// # Panics
+    ///
+    /// Panics if a previous initialization panicked.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::LazyLock;
+    ///
+    /// let lazy = LazyLock::new(|| 92);
+    /// assert_eq!(LazyLock::force(&lazy), &92);
+    /// ```
+    pub fn force(this: &Self) -> &T {
+        this.cell.get_or_init(|| match this.init.take() {
+            Some(f) => f(),
+            None => panic!("LazyLock instance has previously been poisoned"),
+        })
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for LazyLock<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Self::force(self)
+    }
+}
+
+impl<T: Default> Default for LazyLock<T> {
+    fn default() -> Self {
+        Self::new(T::default)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for LazyLock<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_tuple("LazyLock");
+        match self.cell.get() {
+            Some(value) => d.field(value),
+            None => d.field(&format_args!("<uninit>")),
+        };
+        d.finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use portable_atomic::{AtomicUsize, Ordering::Relaxed};
+    use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
+        thread,
+        vec::Vec,
+    };
+
+    const THREADS: usize = 4;
+
+    // Spins until another thread is parked in the waiter queue of `cell`.
+    fn wait_for_waiter<T>(cell: &OnceLock<T>) {
+        while cell.state.load(Acquire) as usize & !STATE_MASK == 0 {
+            thread::yield_now();
+        }
+    }
+
+    #[test]
+    fn concurrent_get_or_init() {
+        let cell = OnceLock::new();
+        let inits = AtomicUsize::new(0);
+        let values: Vec<usize> = thread::scope(|s| {
+            let threads: Vec<_> = (0..THREADS)
+                .map(|i| {
+                    let (cell, inits) = (&cell, &inits);
+                    s.spawn(move || {
+                        *cell.get_or_init(|| {
+                            inits.fetch_add(1, Relaxed);
+                            // Keep the cell `RUNNING` until another thread waits for it.
+                            wait_for_waiter(cell);
+                            i
+                        })
+                    })
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert_eq!(inits.load(Relaxed), 1);
+        assert!(values.iter().all(|&v| v == values[0]));
+        assert_eq!(cell.get(), Some(&values[0]));
+    }
+
+    #[test]
+    fn panicking_init_retry() {
+        let cell = OnceLock::new();
+        let inits = AtomicUsize::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                let res = catch_unwind(AssertUnwindSafe(|| {
+                    cell.get_or_init(|| {
+                        wait_for_waiter(&cell);
+                        panic!("init failed")
+                    })
+                }));
+                assert!(res.is_err());
+            });
+            // The waiters must be woken up when the first initialization panics, and exactly one
+            // of them must then initialize the cell.
+            for i in 0..THREADS {
+                let (cell, inits) = (&cell, &inits);
+                s.spawn(move || {
+                    let value = *cell.get_or_init(|| {
+                        inits.fetch_add(1, Relaxed);
+                        i
+                    });
+                    assert_eq!(cell.get(), Some(&value));
+                });
+            }
+        });
+        assert_eq!(inits.load(Relaxed), 1);
+        assert!(cell.get().is_some());
+    }
+
+    #[test]
+    fn get_or_try_init_error_resets() {
+        let cell = OnceLock::new();
+        assert_eq!(cell.get_or_try_init(|| Err(1)), Err(1));
+        assert!(cell.get().is_none());
+        assert_eq!(cell.set(2), Ok(()));
+        assert_eq!(cell.set(3), Err(3));
+        assert_eq!(cell.into_inner(), Some(2));
+    }
+
+    #[test]
+    fn lazy_lock_poisoning() {
+        let lazy: LazyLock<u32> = LazyLock::new(|| panic!("init failed"));
+        let payload = catch_unwind(AssertUnwindSafe(|| *lazy)).unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"init failed"));
+        // The initialization function was consumed by the first attempt.
+        let payload = catch_unwind(AssertUnwindSafe(|| *lazy)).unwrap_err();
+        assert_eq!(
+            payload.downcast_ref::<&str>(),
+            Some(&"LazyLock instance has previously been poisoned")
+        );
+        assert!(lazy.cell.get().is_none());
+    }
+
+    #[test]
+    fn lazy_lock_concurrent() {
+        let inits = AtomicUsize::new(0);
+        let lazy = LazyLock::new(|| inits.fetch_add(1, Relaxed) + 10);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| assert_eq!(*lazy, 10));
+            }
+        });
+        assert_eq!(inits.load(Relaxed), 1);
+    }
+}
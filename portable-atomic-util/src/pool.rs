@@ -0,0 +1,671 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A statically sized pool of reference counted values.
+//!
+//! This does not require a global allocator, so it can be used on targets where the `alloc`
+//! feature is not available.
+//!
+//! Free slots are kept in a lock-free free list (Treiber stack) of slot indices. To avoid the ABA
+//! problem, the head of the list is packed into a single `AtomicUsize` together with a tag that is
+//! incremented on every update: the lower half of the bits holds the index and the upper half
+//! holds the tag.
+
+use portable_atomic::{
+    AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release},
+};
+
+use crate::utils::{abort, MAX_REFCOUNT};
+
+use core::{cell::UnsafeCell, fmt, marker::PhantomData, mem, ops::Deref, ptr};
+
+#[allow(clippy::cast_possible_truncation)]
+const INDEX_BITS: u32 = mem::size_of::<usize>() as u32 * 8 / 2;
+const INDEX_MASK: usize = (1 << INDEX_BITS) - 1;
+/// The index representing the end of the free list.
+const NIL: usize = INDEX_MASK;
+
+/// The free list of an [`ArcPool`], which does not depend on the capacity of the pool.
+struct FreeList {
+    /// The tagged index of the first free slot, or `NIL` if there are no recycled slots.
+    head: AtomicUsize,
+
+    /// The number of slots that have been handed out at least once.
+    ///
+    /// Slots at or above this index have never been used, so they are not in the free list.
+    /// This allows the pool to be constructed in a `const` context without linking all slots.
+    bump: AtomicUsize,
+}
+
+/// A slot in an [`ArcPool`].
+struct Slot<T> {
+    /// The current strong reference count.
+    ///
+    /// As long as this is greater than zero, the `value` is initialized.
+    strong: AtomicUsize,
+
+    /// The weak reference count.
+    ///
+    /// This includes an intrinsic weak reference held by the strong pointers.
+    /// The slot should be returned to the pool when this reaches zero.
+    weak: AtomicUsize,
+
+    /// The index of the next free slot while this slot is in the free list.
+    next: AtomicUsize,
+
+    /// The free list this slot belongs to, and the index of this slot.
+    ///
+    /// This is written when the slot is taken out of the pool, while it is exclusively owned.
+    owner: UnsafeCell<(*const FreeList, usize)>,
+
+    /// The value that is being reference counted.
+    value: UnsafeCell<mem::MaybeUninit<T>>,
+}
+
+impl<T> Slot<T> {
+    #[allow(clippy::declare_interior_mutable_const)] // only used to initialize the array of slots
+    const NEW: Self = Self {
+        strong: AtomicUsize::new(0),
+        weak: AtomicUsize::new(0),
+        next: AtomicUsize::new(NIL),
+        owner: UnsafeCell::new((ptr::null(), 0)),
+        value: UnsafeCell::new(mem::MaybeUninit::uninit()),
+    };
+
+    fn value(&self) -> &T {
+        // SAFETY: This is only called while the strong count is greater than zero, so the value
+        // is initialized and only shared references to it exist.
+        unsafe { &*(*self.value.get()).as_ptr() }
+    }
+
+    /// Return this slot to the pool it was taken from.
+    ///
+    /// # Safety
+    ///
+    /// The weak count must have reached zero, and the value must have already been dropped or
+    /// moved out.
+    unsafe fn release(&self) {
+        // SAFETY: The slot is exclusively owned now, and `owner` was written when the slot was
+        // taken out of the pool. The free list lives as long as the pool, which is `'static`.
+        let (free, index) = unsafe { *self.owner.get() };
+        // SAFETY: See above.
+        let free = unsafe { &*free };
+
+        let mut head = free.head.load(Relaxed);
+        loop {
+            self.next.store(head & INDEX_MASK, Relaxed);
+            let new = ((head >> INDEX_BITS).wrapping_add(1) << INDEX_BITS) | index;
+            match free.head.compare_exchange_weak(head, new, Release, Relaxed) {
+                Ok(_) => return,
+                Err(x) => head = x,
+            }
+        }
+    }
+}
+
+/// A statically sized pool of values that are handed out as reference counted [`PoolArc`]s.
+///
+/// This is intended to be placed in a `static`, and does not require a global allocator. When the
+/// last [`PoolArc`] and [`PoolWeak`] pointing to a slot are dropped, the slot is returned to the
+/// pool and can be reused.
+///
+/// The capacity `N` must be less than `2^(usize::BITS / 2)`, i.e., less than 255 on 16-bit
+/// targets and less than 65535 on 32-bit targets. Larger capacities are rejected at compile time.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::{ArcPool, PoolArc};
+///
+/// static POOL: ArcPool<[u8; 4], 2> = ArcPool::new();
+///
+/// let a = POOL.alloc([1, 2, 3, 4]).unwrap();
+/// let b = PoolArc::clone(&a);
+/// let c = POOL.alloc([5, 6, 7, 8]).unwrap();
+///
+/// // The pool is full.
+/// assert!(POOL.alloc([0; 4]).is_err());
+///
+/// // Dropping all references returns the slot to the pool.
+/// drop(a);
+/// drop(b);
+/// let d = POOL.alloc([0; 4]).unwrap();
+/// assert_eq!(*c, [5, 6, 7, 8]);
+/// assert_eq!(*d, [0; 4]);
+/// ```
+pub struct ArcPool<T, const N: usize> {
+    free: FreeList,
+    slots: [Slot<T>; N],
+}
+
+// SAFETY: Values are moved into the pool and dropped from arbitrary threads, but the pool itself
+// only gives out access to them through `PoolArc`, which has the appropriate bounds.
+unsafe impl<T: Send, const N: usize> Sync for ArcPool<T, N> {}
+// SAFETY: See above.
+unsafe impl<T: Send, const N: usize> Send for ArcPool<T, N> {}
+
+impl<T, const N: usize> fmt::Debug for ArcPool<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ArcPool").field("capacity", &N).finish()
+    }
+}
+
+impl<T, const N: usize> ArcPool<T, N> {
+    // Evaluating this fails to compile if `N` does not fit in the index bits of the free list.
+    const CAPACITY_CHECK: () = [()][(N >= NIL) as usize];
+
+    /// Create a new empty pool.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcPool;
+    ///
+    /// static POOL: ArcPool<u32, 16> = ArcPool::new();
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        #[allow(clippy::let_unit_value)]
+        let () = Self::CAPACITY_CHECK;
+        Self {
+            free: FreeList { head: AtomicUsize::new(NIL), bump: AtomicUsize::new(0) },
+            slots: [Slot::NEW; N],
+        }
+    }
+
+    /// Return the number of slots in the pool.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcPool;
+    ///
+    /// static POOL: ArcPool<u32, 16> = ArcPool::new();
+    /// assert_eq!(POOL.capacity(), 16);
+    /// ```
+    #[allow(clippy::unused_self)]
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Move a value into a free slot of the pool and return a [`PoolArc`] pointing to it.
+    ///
+    /// Returns the value back if there are no free slots.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ArcPool;
+    ///
+    /// static POOL: ArcPool<u32, 1> = ArcPool::new();
+    ///
+    /// let five = POOL.alloc(5).unwrap();
+    /// assert_eq!(POOL.alloc(6).unwrap_err(), 6);
+    /// ```
+    pub fn alloc(&'static self, value: T) -> Result<PoolArc<T>, T> {
+        let index = match self.pop() {
+            Some(index) => index,
+            None => return Err(value),
+        };
+        let slot = &self.slots[index];
+
+        // SAFETY: The slot has been taken out of the pool, so it is exclusively owned until the
+        // reference counts are set below.
+        unsafe {
+            *slot.owner.get() = (&self.free, index);
+            *slot.value.get() = mem::MaybeUninit::new(value);
+        }
+        // The slot is not shared with other threads until the returned `PoolArc` is, which
+        // provides the necessary synchronization. There are no weak pointers to this slot since
+        // its weak count was zero when it was returned to the pool.
+        slot.weak.store(1, Relaxed);
+        slot.strong.store(1, Relaxed);
+
+        Ok(PoolArc { slot, _marker: PhantomData })
+    }
+
+    /// Take a slot index out of the pool.
+    fn pop(&self) -> Option<usize> {
+        let mut head = self.free.head.load(Acquire);
+        loop {
+            let index = head & INDEX_MASK;
+            if index == NIL {
+                break;
+            }
+            // The slot may be concurrently taken and returned by other threads, in which case the
+            // value read here is stale, but the tag of the head has changed and the CAS fails.
+            let next = self.slots[index].next.load(Relaxed);
+            let new = ((head >> INDEX_BITS).wrapping_add(1) << INDEX_BITS) | next;
+            match self.free.head.compare_exchange_weak(head, new, Acquire, Acquire) {
+                Ok(_) => return Some(index),
+                Err(x) => head = x,
+            }
+        }
+
+        // The free list is empty, so try to take a slot that has never been used.
+        self.free.bump.fetch_update(Relaxed, Relaxed, |n| if n < N { Some(n + 1) } else { None }).ok()
+    }
+}
+
+impl<T, const N: usize> Default for ArcPool<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A thread-safe, strongly reference counted pointer to a value in an [`ArcPool`].
+///
+/// This is similar to [`Arc`](crate::Arc), but the value is stored in a slot of a statically
+/// sized pool instead of the heap.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::{ArcPool, PoolArc};
+/// use std::thread;
+///
+/// static POOL: ArcPool<u32, 4> = ArcPool::new();
+///
+/// let five = POOL.alloc(5).unwrap();
+///
+/// for _ in 0..10 {
+///     let five = PoolArc::clone(&five);
+///     thread::spawn(move || {
+///         assert_eq!(*five, 5);
+///     });
+/// }
+/// # if cfg!(miri) { std::thread::sleep(std::time::Duration::from_millis(500)); } // wait for background threads closed: https://github.com/rust-lang/miri/issues/1371
+/// ```
+pub struct PoolArc<T: 'static> {
+    slot: &'static Slot<T>,
+
+    _marker: PhantomData<T>,
+}
+
+/// A weakly reference counted pointer to a value in an [`ArcPool`].
+///
+/// The slot is not returned to the pool while any `PoolWeak` pointing to it exists.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::{ArcPool, PoolArc};
+///
+/// static POOL: ArcPool<u32, 4> = ArcPool::new();
+///
+/// let five = POOL.alloc(5).unwrap();
+/// let weak_five = PoolArc::downgrade(&five);
+/// assert_eq!(*weak_five.upgrade().unwrap(), 5);
+///
+/// drop(five);
+/// assert!(weak_five.upgrade().is_none());
+/// ```
+pub struct PoolWeak<T: 'static> {
+    slot: &'static Slot<T>,
+}
+
+// SAFETY: This value is accessible from many threads, it has to be Sync.
+unsafe impl<T: Send + Sync> Send for PoolArc<T> {}
+// SAFETY: This value is accessible from many threads, it has to be Sync.
+unsafe impl<T: Send + Sync> Sync for PoolArc<T> {}
+// SAFETY: This value is accessible from many threads, it has to be Sync.
+unsafe impl<T: Send + Sync> Send for PoolWeak<T> {}
+// SAFETY: This value is accessible from many threads, it has to be Sync.
+unsafe impl<T: Send + Sync> Sync for PoolWeak<T> {}
+
+impl<T: fmt::Debug> fmt::Debug for PoolArc<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T> fmt::Debug for PoolWeak<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PoolWeak")
+    }
+}
+
+impl<T> Clone for PoolArc<T> {
+    fn clone(&self) -> Self {
+        // Bump the refcount.
+        let old_size = self.slot.strong.fetch_add(1, Relaxed);
+
+        // Abort if the refcount overflowed.
+        if old_size > MAX_REFCOUNT {
+            abort();
+        }
+
+        Self { slot: self.slot, _marker: PhantomData }
+    }
+}
+
+impl<T> PoolArc<T> {
+    /// Get a [`PoolWeak`] reference from this `PoolArc`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{ArcPool, PoolArc};
+    ///
+    /// static POOL: ArcPool<u32, 1> = ArcPool::new();
+    ///
+    /// let five = POOL.alloc(5).unwrap();
+    /// let weak_five = PoolArc::downgrade(&five);
+    ///
+    /// assert!(weak_five.upgrade().is_some());
+    /// ```
+    #[must_use]
+    pub fn downgrade(this: &Self) -> PoolWeak<T> {
+        let mut cur = this.slot.weak.load(Relaxed);
+
+        loop {
+            // The weak counter may be "locked" by `get_mut`, so spin and reload if it is.
+            if cur == usize::MAX {
+                portable_atomic::hint::spin_loop();
+                cur = this.slot.weak.load(Relaxed);
+                continue;
+            }
+
+            // Abort if the refcount would overflow.
+            if cur > MAX_REFCOUNT {
+                abort();
+            }
+
+            // Try to increment the weak counter.
+            match this.slot.weak.compare_exchange_weak(cur, cur + 1, Acquire, Relaxed) {
+                Ok(_) => return PoolWeak { slot: this.slot },
+                Err(x) => cur = x,
+            }
+        }
+    }
+
+    /// Get the number of strong pointers to this slot.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{ArcPool, PoolArc};
+    ///
+    /// static POOL: ArcPool<u32, 1> = ArcPool::new();
+    ///
+    /// let five = POOL.alloc(5).unwrap();
+    /// let five2 = PoolArc::clone(&five);
+    ///
+    /// assert_eq!(PoolArc::strong_count(&five), 2);
+    /// ```
+    #[must_use]
+    pub fn strong_count(this: &Self) -> usize {
+        this.slot.strong.load(Acquire)
+    }
+
+    /// Get the number of weak pointers to this slot.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{ArcPool, PoolArc};
+    ///
+    /// static POOL: ArcPool<u32, 1> = ArcPool::new();
+    ///
+    /// let five = POOL.alloc(5).unwrap();
+    /// let weak_five = PoolArc::downgrade(&five);
+    ///
+    /// assert_eq!(PoolArc::weak_count(&five), 1);
+    /// ```
+    #[must_use]
+    pub fn weak_count(this: &Self) -> usize {
+        match this.slot.weak.load(Acquire) {
+            usize::MAX => 0,
+            cnt => cnt - 1,
+        }
+    }
+
+    /// Tell if two `PoolArc`s point to the same slot.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{ArcPool, PoolArc};
+    ///
+    /// static POOL: ArcPool<u32, 2> = ArcPool::new();
+    ///
+    /// let five = POOL.alloc(5).unwrap();
+    /// let five2 = PoolArc::clone(&five);
+    /// let other_five = POOL.alloc(5).unwrap();
+    ///
+    /// assert!(PoolArc::ptr_eq(&five, &five2));
+    /// assert!(!PoolArc::ptr_eq(&five, &other_five));
+    /// ```
+    #[must_use]
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        ptr::eq(this.slot, other.slot)
+    }
+
+    /// Get a mutable pointer to the inner value if there are no other strong or weak references.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{ArcPool, PoolArc};
+    ///
+    /// static POOL: ArcPool<u32, 1> = ArcPool::new();
+    ///
+    /// let mut five = POOL.alloc(5).unwrap();
+    /// *PoolArc::get_mut(&mut five).unwrap() += 1;
+    /// assert_eq!(*five, 6);
+    ///
+    /// let five2 = PoolArc::clone(&five);
+    /// assert!(PoolArc::get_mut(&mut five).is_none());
+    /// ```
+    #[must_use]
+    pub fn get_mut(this: &mut Self) -> Option<&mut T> {
+        // Checking the weak and strong counts with two separate loads is not enough: another
+        // strong pointer may downgrade and then be dropped between the loads. "Lock" the weak
+        // counter so it can't be increased if we turn out to be the only strong reference, as
+        // `Arc::is_unique` does.
+        if this.slot.weak.compare_exchange(1, usize::MAX, Acquire, Relaxed).is_ok() {
+            // There are no outside weak references, so we can check the strong count.
+            let strong = this.slot.strong.load(Acquire);
+
+            // Restore the former weak count.
+            this.slot.weak.store(1, Release);
+
+            if strong == 1 {
+                // SAFETY: This is the only strong pointer and there are no weak pointers, and new
+                // ones can only be created from this pointer, which we borrow mutably.
+                return unsafe { Some(&mut *(*this.slot.value.get()).as_mut_ptr()) };
+            }
+        }
+        None
+    }
+
+    /// Unwrap and try to get the inner value.
+    ///
+    /// The slot is returned to the pool once there are no more weak references to it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{ArcPool, PoolArc};
+    ///
+    /// static POOL: ArcPool<u32, 1> = ArcPool::new();
+    ///
+    /// let five = POOL.alloc(5).unwrap();
+    /// assert_eq!(PoolArc::try_unwrap(five).unwrap(), 5);
+    ///
+    /// let five = POOL.alloc(5).unwrap();
+    /// let five2 = PoolArc::clone(&five);
+    /// assert!(PoolArc::try_unwrap(five).is_err());
+    /// ```
+    pub fn try_unwrap(this: Self) -> Result<T, Self> {
+        // Try to decrement the strong count.
+        if this.slot.strong.compare_exchange(1, 0, Relaxed, Relaxed).is_err() {
+            return Err(this);
+        }
+
+        // Otherwise, we can safely unwrap the value.
+        acquire!(this.slot.strong);
+
+        let slot = this.slot;
+        mem::forget(this);
+
+        // SAFETY: The strong count is zero and we hold the only reference to the value.
+        let value = unsafe { ptr::read((*slot.value.get()).as_ptr()) };
+
+        // Drop the intrinsic weak reference to release the slot.
+        drop(PoolWeak { slot });
+
+        Ok(value)
+    }
+}
+
+impl<T> Deref for PoolArc<T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.slot.value()
+    }
+}
+
+impl<T> AsRef<T> for PoolArc<T> {
+    fn as_ref(&self) -> &T {
+        self.slot.value()
+    }
+}
+
+impl<T> Drop for PoolArc<T> {
+    fn drop(&mut self) {
+        // Decrement the strong refcount.
+        if self.slot.strong.fetch_sub(1, Release) != 1 {
+            return;
+        }
+
+        // Ensure we're synchronized with other threads.
+        acquire!(self.slot.strong);
+
+        // SAFETY: Since the strong count is zero, we have exclusive access to the inner value.
+        unsafe { ptr::drop_in_place((*self.slot.value.get()).as_mut_ptr()) }
+
+        // Drop the intrinsic weak reference to release the slot.
+        drop(PoolWeak { slot: self.slot });
+    }
+}
+
+impl<T> Clone for PoolWeak<T> {
+    fn clone(&self) -> Self {
+        // Bump the weak refcount.
+        let old_size = self.slot.weak.fetch_add(1, Relaxed);
+
+        // Abort if the refcount overflowed.
+        if old_size > MAX_REFCOUNT {
+            abort();
+        }
+
+        Self { slot: self.slot }
+    }
+}
+
+impl<T> PoolWeak<T> {
+    /// Try to upgrade this `PoolWeak` pointer to a strong pointer.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{ArcPool, PoolArc};
+    ///
+    /// static POOL: ArcPool<u32, 1> = ArcPool::new();
+    ///
+    /// let five = POOL.alloc(5).unwrap();
+    ///
+    /// let weak = PoolArc::downgrade(&five);
+    /// assert!(weak.upgrade().is_some());
+    /// ```
+    #[allow(clippy::missing_panics_doc)]
+    #[must_use]
+    pub fn upgrade(&self) -> Option<PoolArc<T>> {
+        let mut strong = self.slot.strong.load(Relaxed);
+
+        loop {
+            // If the strong count is zero, the value has been dropped.
+            if strong == 0 {
+                return None;
+            }
+
+            // If the strong count is greater than the maximum, panic.
+            // Panic instead of abort is okay because we didn't increment the strong counter yet.
+            assert!(strong <= MAX_REFCOUNT, "PoolArc counter overflow");
+
+            // Try to increment the strong count.
+            match self.slot.strong.compare_exchange_weak(strong, strong + 1, Acquire, Relaxed) {
+                Ok(_) => return Some(PoolArc { slot: self.slot, _marker: PhantomData }),
+                Err(x) => strong = x,
+            }
+        }
+    }
+
+    /// Get the number of strong pointers to this slot.
+    #[must_use]
+    pub fn strong_count(&self) -> usize {
+        self.slot.strong.load(Acquire)
+    }
+}
+
+impl<T> Drop for PoolWeak<T> {
+    fn drop(&mut self) {
+        if self.slot.weak.fetch_sub(1, Release) == 1 {
+            acquire!(self.slot.weak);
+
+            // SAFETY: The weak count is zero, so the value has been dropped and nothing else
+            // refers to this slot.
+            unsafe { self.slot.release() }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::SpinMutex;
+    use std::thread;
+
+    #[test]
+    fn get_mut_races_with_downgrade() {
+        static POOL: ArcPool<u32, 1> = ArcPool::new();
+        const ROUNDS: usize = 10_000;
+
+        let handoff = SpinMutex::new(None::<PoolArc<u32>>);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..ROUNDS {
+                    let b = loop {
+                        if let Some(b) = handoff.lock().take() {
+                            break b;
+                        }
+                        thread::yield_now();
+                    };
+                    let weak = PoolArc::downgrade(&b);
+                    drop(b);
+                    // `get_mut` must not succeed while `weak` exists, so the value is unchanged.
+                    assert_eq!(*weak.upgrade().unwrap(), 0);
+                }
+            });
+            for _ in 0..ROUNDS {
+                let mut a = POOL.alloc(0).unwrap();
+                *handoff.lock() = Some(PoolArc::clone(&a));
+                loop {
+                    if let Some(v) = PoolArc::get_mut(&mut a) {
+                        *v += 1;
+                        break;
+                    }
+                    thread::yield_now();
+                }
+                // No other strong or weak pointers exist once `get_mut` succeeds, and no new ones
+                // can be created from the other thread after that.
+                assert_eq!(PoolArc::weak_count(&a), 0);
+                assert_eq!(PoolArc::strong_count(&a), 1);
+            }
+        });
+    }
+}
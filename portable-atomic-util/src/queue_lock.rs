@@ -0,0 +1,549 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! Queue-based spin locks.
+//!
+//! Unlike test-and-set or ticket locks, where every waiter spins on the lock word itself, each
+//! waiter in a queue lock spins on its own cache line, so handing the lock over only invalidates
+//! the cache line of the next waiter.
+//!
+//! The queue nodes live on the stack of the waiting thread. Because a node must stay in place
+//! until its successor no longer refers to it, the locks are only accessible through closures
+//! (a guard that could be leaked with `mem::forget` would allow the node to be freed while
+//! still linked into the queue).
+
+use portable_atomic::{
+    AtomicBool, AtomicPtr, AtomicUsize,
+    Ordering::{AcqRel, Acquire, Relaxed, Release},
+};
+
+use crate::utils::{Backoff, CachePadded};
+
+use core::{cell::UnsafeCell, fmt, ptr};
+
+/// Releases the lock when dropped, including on panic in the critical section.
+struct Unlock<F: FnMut()>(F);
+
+impl<F: FnMut()> Drop for Unlock<F> {
+    fn drop(&mut self) {
+        (self.0)();
+    }
+}
+
+struct McsNode {
+    locked: AtomicBool,
+    next: AtomicPtr<CachePadded<McsNode>>,
+}
+
+/// A fair MCS queue lock.
+///
+/// Waiters are queued in FIFO order, and each waiter spins on a flag in its own cache-padded
+/// queue node, which lives on the waiter's stack. Enqueueing is a single `swap` of the tail
+/// pointer.
+///
+/// See also [`ClhLock`], a variant where each waiter spins on its predecessor's node instead.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::McsLock;
+/// use std::thread;
+///
+/// static COUNTER: McsLock<usize> = McsLock::new(0);
+///
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         thread::spawn(|| {
+///             for _ in 0..100 {
+///                 COUNTER.with_lock(|counter| *counter += 1);
+///             }
+///         })
+///     })
+///     .collect();
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+/// assert_eq!(COUNTER.with_lock(|counter| *counter), 400);
+/// ```
+pub struct McsLock<T: ?Sized> {
+    tail: AtomicPtr<CachePadded<McsNode>>,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: `McsLock` provides the same guarantees as std's `Mutex`.
+unsafe impl<T: ?Sized + Send> Send for McsLock<T> {}
+// SAFETY: `McsLock` provides the same guarantees as std's `Mutex`.
+unsafe impl<T: ?Sized + Send> Sync for McsLock<T> {}
+
+impl<T> McsLock<T> {
+    /// Create a new unlocked [`McsLock`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::McsLock;
+    ///
+    /// static LOCK: McsLock<u32> = McsLock::new(0);
+    /// ```
+    pub const fn new(value: T) -> Self {
+        Self { tail: AtomicPtr::new(ptr::null_mut()), data: UnsafeCell::new(value) }
+    }
+
+    /// Consume this lock and return the underlying data.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::McsLock;
+    ///
+    /// let lock = McsLock::new(5);
+    /// assert_eq!(lock.into_inner(), 5);
+    /// ```
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> McsLock<T> {
+    /// Acquire the lock, spinning until it is available, and call `f` with the protected data.
+    ///
+    /// The lock is released when `f` returns or panics.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::McsLock;
+    ///
+    /// let lock = McsLock::new(vec![1]);
+    /// let len = lock.with_lock(|v| {
+    ///     v.push(2);
+    ///     v.len()
+    /// });
+    /// assert_eq!(len, 2);
+    /// ```
+    pub fn with_lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        let node = CachePadded::new(McsNode {
+            locked: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut()),
+        });
+        let node_ptr = &node as *const CachePadded<McsNode> as *mut CachePadded<McsNode>;
+
+        let prev = self.tail.swap(node_ptr, AcqRel);
+        if !prev.is_null() {
+            // SAFETY: `prev` is still linked into the queue, so its owner is waiting for us to
+            // set its `next` pointer (see `unlock`) and keeps it alive until then.
+            unsafe { (&*prev).next.store(node_ptr, Release) }
+            let mut backoff = Backoff::new();
+            while node.locked.load(Acquire) {
+                backoff.snooze();
+            }
+        }
+
+        let _unlock = Unlock(|| self.unlock(&node));
+        // SAFETY: We hold the lock.
+        f(unsafe { &mut *self.data.get() })
+    }
+
+    /// Attempt to acquire the lock without spinning, and call `f` with the protected data on
+    /// success.
+    ///
+    /// Returns `None` if the lock is already held.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::McsLock;
+    ///
+    /// let lock = McsLock::new(5);
+    /// lock.with_lock(|_| assert!(lock.try_with_lock(|_| ()).is_none()));
+    /// assert_eq!(lock.try_with_lock(|x| *x), Some(5));
+    /// ```
+    pub fn try_with_lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Option<R> {
+        let node = CachePadded::new(McsNode {
+            locked: AtomicBool::new(true),
+            next: AtomicPtr::new(ptr::null_mut()),
+        });
+        let node_ptr = &node as *const CachePadded<McsNode> as *mut CachePadded<McsNode>;
+
+        if self.tail.compare_exchange(ptr::null_mut(), node_ptr, Acquire, Relaxed).is_err() {
+            return None;
+        }
+
+        let _unlock = Unlock(|| self.unlock(&node));
+        // SAFETY: We hold the lock.
+        Some(f(unsafe { &mut *self.data.get() }))
+    }
+
+    fn unlock(&self, node: &CachePadded<McsNode>) {
+        let node_ptr = node as *const CachePadded<McsNode> as *mut CachePadded<McsNode>;
+        let mut next = node.next.load(Acquire);
+        if next.is_null() {
+            if self.tail.compare_exchange(node_ptr, ptr::null_mut(), Release, Relaxed).is_ok() {
+                return;
+            }
+            // A successor has swapped itself into the tail, but has not linked itself yet.
+            let mut backoff = Backoff::new();
+            loop {
+                next = node.next.load(Acquire);
+                if !next.is_null() {
+                    break;
+                }
+                backoff.snooze();
+            }
+        }
+        // SAFETY: The successor is spinning on its `locked` flag and keeps its node alive until
+        // this store. We must not touch `next` after it.
+        unsafe { (&*next).locked.store(false, Release) }
+    }
+
+    /// Return `true` if the lock is currently held.
+    ///
+    /// This is only a hint: the state may change immediately after this returns.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::McsLock;
+    ///
+    /// let lock = McsLock::new(5);
+    /// assert!(!lock.is_locked());
+    /// lock.with_lock(|_| assert!(lock.is_locked()));
+    /// ```
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Relaxed).is_null()
+    }
+
+    /// Get a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the lock mutably, no actual locking needs to take place.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::McsLock;
+    ///
+    /// let mut lock = McsLock::new(5);
+    /// *lock.get_mut() += 1;
+    /// assert_eq!(lock.into_inner(), 6);
+    /// ```
+    pub fn get_mut(&mut self) -> &mut T {
+        // SAFETY: We have exclusive access to the lock.
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: Default> Default for McsLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for McsLock<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for McsLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("McsLock");
+        if self
+            .try_with_lock(|data| {
+                d.field("data", &&*data);
+            })
+            .is_none()
+        {
+            d.field("data", &format_args!("<locked>"));
+        }
+        d.finish()
+    }
+}
+
+// The node is still locked or the lock is still held by its owner.
+const CLH_LOCKED: usize = 0;
+// The owner released the lock, and waits for its successor to acknowledge it.
+const CLH_RELEASED: usize = 1;
+// The successor acquired the lock and no longer refers to the node.
+const CLH_ACKED: usize = 2;
+
+/// A fair CLH queue lock.
+///
+/// Waiters are queued in FIFO order, and each waiter spins on the cache-padded node of its
+/// predecessor. Enqueueing is a single `swap` of the tail pointer.
+///
+/// Since the queue nodes live on the stack of their owners rather than being recycled between
+/// threads, a thread releasing the lock waits until its successor has observed the release
+/// before its node goes out of scope. This wait is short because the successor is actively
+/// spinning on the node, but it does mean that [`McsLock`] is usually the better choice when the
+/// lock holder must not be delayed by a preempted waiter.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::ClhLock;
+/// use std::thread;
+///
+/// static COUNTER: ClhLock<usize> = ClhLock::new(0);
+///
+/// let threads: Vec<_> = (0..4)
+///     .map(|_| {
+///         thread::spawn(|| {
+///             for _ in 0..100 {
+///                 COUNTER.with_lock(|counter| *counter += 1);
+///             }
+///         })
+///     })
+///     .collect();
+/// for t in threads {
+///     t.join().unwrap();
+/// }
+/// assert_eq!(COUNTER.with_lock(|counter| *counter), 400);
+/// ```
+pub struct ClhLock<T: ?Sized> {
+    tail: AtomicPtr<CachePadded<AtomicUsize>>,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: `ClhLock` provides the same guarantees as std's `Mutex`.
+unsafe impl<T: ?Sized + Send> Send for ClhLock<T> {}
+// SAFETY: `ClhLock` provides the same guarantees as std's `Mutex`.
+unsafe impl<T: ?Sized + Send> Sync for ClhLock<T> {}
+
+impl<T> ClhLock<T> {
+    /// Create a new unlocked [`ClhLock`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ClhLock;
+    ///
+    /// static LOCK: ClhLock<u32> = ClhLock::new(0);
+    /// ```
+    pub const fn new(value: T) -> Self {
+        Self { tail: AtomicPtr::new(ptr::null_mut()), data: UnsafeCell::new(value) }
+    }
+
+    /// Consume this lock and return the underlying data.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ClhLock;
+    ///
+    /// let lock = ClhLock::new(5);
+    /// assert_eq!(lock.into_inner(), 5);
+    /// ```
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> ClhLock<T> {
+    /// Acquire the lock, spinning until it is available, and call `f` with the protected data.
+    ///
+    /// The lock is released when `f` returns or panics.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ClhLock;
+    ///
+    /// let lock = ClhLock::new(vec![1]);
+    /// let len = lock.with_lock(|v| {
+    ///     v.push(2);
+    ///     v.len()
+    /// });
+    /// assert_eq!(len, 2);
+    /// ```
+    pub fn with_lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+        let node = CachePadded::new(AtomicUsize::new(CLH_LOCKED));
+        let node_ptr = &node as *const CachePadded<AtomicUsize> as *mut CachePadded<AtomicUsize>;
+
+        let prev = self.tail.swap(node_ptr, AcqRel);
+        if !prev.is_null() {
+            let mut backoff = Backoff::new();
+            // SAFETY: The owner of `prev` keeps it alive until we acknowledge the release.
+            while unsafe { &*prev }.load(Acquire) != CLH_RELEASED {
+                backoff.snooze();
+            }
+            // SAFETY: See above. We must not touch `prev` after this store.
+            unsafe { &*prev }.store(CLH_ACKED, Release);
+        }
+
+        let _unlock = Unlock(|| self.unlock(&node));
+        // SAFETY: We hold the lock.
+        f(unsafe { &mut *self.data.get() })
+    }
+
+    /// Attempt to acquire the lock without spinning, and call `f` with the protected data on
+    /// success.
+    ///
+    /// Returns `None` if the lock is already held.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ClhLock;
+    ///
+    /// let lock = ClhLock::new(5);
+    /// lock.with_lock(|_| assert!(lock.try_with_lock(|_| ()).is_none()));
+    /// assert_eq!(lock.try_with_lock(|x| *x), Some(5));
+    /// ```
+    pub fn try_with_lock<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Option<R> {
+        let node = CachePadded::new(AtomicUsize::new(CLH_LOCKED));
+        let node_ptr = &node as *const CachePadded<AtomicUsize> as *mut CachePadded<AtomicUsize>;
+
+        if self.tail.compare_exchange(ptr::null_mut(), node_ptr, Acquire, Relaxed).is_err() {
+            return None;
+        }
+
+        let _unlock = Unlock(|| self.unlock(&node));
+        // SAFETY: We hold the lock.
+        Some(f(unsafe { &mut *self.data.get() }))
+    }
+
+    fn unlock(&self, node: &CachePadded<AtomicUsize>) {
+        let node_ptr = node as *const CachePadded<AtomicUsize> as *mut CachePadded<AtomicUsize>;
+        if self.tail.compare_exchange(node_ptr, ptr::null_mut(), Release, Relaxed).is_ok() {
+            // No successor has seen our node.
+            return;
+        }
+        node.store(CLH_RELEASED, Release);
+        // Wait for the successor to stop referring to our node before it goes out of scope.
+        let mut backoff = Backoff::new();
+        while node.load(Acquire) != CLH_ACKED {
+            backoff.snooze();
+        }
+    }
+
+    /// Return `true` if the lock is currently held.
+    ///
+    /// This is only a hint: the state may change immediately after this returns.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ClhLock;
+    ///
+    /// let lock = ClhLock::new(5);
+    /// assert!(!lock.is_locked());
+    /// lock.with_lock(|_| assert!(lock.is_locked()));
+    /// ```
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        !self.tail.load(Relaxed).is_null()
+    }
+
+    /// Get a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the lock mutably, no actual locking needs to take place.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::ClhLock;
+    ///
+    /// let mut lock = ClhLock::new(5);
+    /// *lock.get_mut() += 1;
+    /// assert_eq!(lock.into_inner(), 6);
+    /// ```
+    pub fn get_mut(&mut self) -> &mut T {
+        // SAFETY: We have exclusive access to the lock.
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: Default> Default for ClhLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for ClhLock<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for ClhLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("ClhLock");
+        if self
+            .try_with_lock(|data| {
+                d.field("data", &&*data);
+            })
+            .is_none()
+        {
+            d.field("data", &format_args!("<locked>"));
+        }
+        d.finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::{
+        panic::{catch_unwind, AssertUnwindSafe},
+        thread,
+    };
+
+    const THREADS: usize = 4;
+    const ITERS: usize = 1000;
+
+    macro_rules! tests {
+        ($mod:ident, $lock:ident) => {
+            mod $mod {
+                use super::*;
+
+                // Checks that the lock is never held by two threads at the same time, and that
+                // writes made under the lock are visible to the next holder.
+                #[test]
+                fn concurrent_counter() {
+                    let lock = $lock::new(0);
+                    let inside = AtomicBool::new(false);
+                    let critical_section = |counter: &mut usize| {
+                        assert!(!inside.swap(true, Relaxed));
+                        *counter += 1;
+                        inside.store(false, Relaxed);
+                    };
+                    thread::scope(|s| {
+                        for _ in 0..THREADS {
+                            s.spawn(|| {
+                                for i in 0..ITERS {
+                                    if i % 2 == 0 {
+                                        lock.with_lock(critical_section);
+                                    } else {
+                                        while lock.try_with_lock(critical_section).is_none() {
+                                            thread::yield_now();
+                                        }
+                                    }
+                                }
+                            });
+                        }
+                    });
+                    assert!(!lock.is_locked());
+                    assert_eq!(lock.into_inner(), THREADS * ITERS);
+                }
+
+                #[test]
+                fn panic_releases_lock() {
+                    let lock = $lock::new(0);
+                    let res = catch_unwind(AssertUnwindSafe(|| {
+                        lock.with_lock(|counter| {
+                            *counter += 1;
+                            panic!("test");
+                        })
+                    }));
+                    assert!(res.is_err());
+                    assert!(!lock.is_locked());
+                    assert_eq!(lock.try_with_lock(|counter| *counter), Some(1));
+                }
+            }
+        };
+    }
+
+    tests!(mcs, McsLock);
+    tests!(clh, ClhLock);
+}
@@ -0,0 +1,690 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+// Race-based once cells.
+//
+// If several threads try to initialize the cell concurrently, all of them may run the
+// initialization function, but only one of the results is stored and all threads observe the
+// same value. This only requires CAS on pointer-sized atomics, unlike `OnceLock`, which must be
+// able to make threads wait for the initializing thread.
+//
+// Based on once_cell's race module: https://github.com/matklad/once_cell/blob/v1.19.0/src/race.rs
+
+use portable_atomic::{
+    AtomicPtr, AtomicUsize,
+    Ordering::{AcqRel, Acquire},
+};
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+use alloc::boxed::Box;
+use core::{cell::UnsafeCell, fmt, marker::PhantomData, num::NonZeroUsize, ptr};
+
+/// A thread-safe cell which can be written to only once, storing a [`NonZeroUsize`].
+///
+/// If several threads initialize the cell concurrently, they may all run the initialization
+/// function, but only one value is stored.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::OnceNonZeroUsize;
+/// use std::num::NonZeroUsize;
+///
+/// static PAGE_SIZE: OnceNonZeroUsize = OnceNonZeroUsize::new();
+///
+/// let size = PAGE_SIZE.get_or_init(|| NonZeroUsize::new(4096).unwrap());
+/// assert_eq!(size.get(), 4096);
+/// ```
+#[derive(Default)]
+pub struct OnceNonZeroUsize {
+    inner: AtomicUsize,
+}
+
+impl OnceNonZeroUsize {
+    /// Create a new empty cell.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { inner: AtomicUsize::new(0) }
+    }
+
+    /// Get the underlying value, or `None` if the cell is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceNonZeroUsize;
+    ///
+    /// let cell = OnceNonZeroUsize::new();
+    /// assert!(cell.get().is_none());
+    /// ```
+    #[must_use]
+    pub fn get(&self) -> Option<NonZeroUsize> {
+        NonZeroUsize::new(self.inner.load(Acquire))
+    }
+
+    /// Set the contents of this cell to `value`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(value)` if the cell was already initialized.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceNonZeroUsize;
+    /// use std::num::NonZeroUsize;
+    ///
+    /// let cell = OnceNonZeroUsize::new();
+    /// let one = NonZeroUsize::new(1).unwrap();
+    /// let two = NonZeroUsize::new(2).unwrap();
+    /// assert_eq!(cell.set(one), Ok(()));
+    /// assert_eq!(cell.set(two), Err(two));
+    /// assert_eq!(cell.get(), Some(one));
+    /// ```
+    pub fn set(&self, value: NonZeroUsize) -> Result<(), NonZeroUsize> {
+        match self.inner.compare_exchange(0, value.get(), AcqRel, Acquire) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(value),
+        }
+    }
+
+    /// Get the contents of the cell, initializing it with `f` if the cell was empty.
+    ///
+    /// If several threads call this concurrently, `f` may be called several times, but only one
+    /// result is stored and returned to all callers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceNonZeroUsize;
+    /// use std::num::NonZeroUsize;
+    ///
+    /// let cell = OnceNonZeroUsize::new();
+    /// let v = cell.get_or_init(|| NonZeroUsize::new(1).unwrap());
+    /// assert_eq!(v.get(), 1);
+    /// let v = cell.get_or_init(|| NonZeroUsize::new(2).unwrap());
+    /// assert_eq!(v.get(), 1);
+    /// ```
+    pub fn get_or_init<F>(&self, f: F) -> NonZeroUsize
+    where
+        F: FnOnce() -> NonZeroUsize,
+    {
+        enum Void {}
+        match self.get_or_try_init(|| Ok::<NonZeroUsize, Void>(f())) {
+            Ok(value) => value,
+            Err(void) => match void {},
+        }
+    }
+
+    /// Get the contents of the cell, initializing it with `f` if the cell was empty.
+    ///
+    /// If `f` fails, the error is returned and the cell stays empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error returned by `f`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceNonZeroUsize;
+    /// use std::num::NonZeroUsize;
+    ///
+    /// let cell = OnceNonZeroUsize::new();
+    /// assert_eq!(cell.get_or_try_init(|| Err(())), Err(()));
+    /// assert!(cell.get().is_none());
+    /// let v = cell.get_or_try_init(|| NonZeroUsize::new(1).ok_or(()));
+    /// assert_eq!(v, Ok(NonZeroUsize::new(1).unwrap()));
+    /// ```
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<NonZeroUsize, E>
+    where
+        F: FnOnce() -> Result<NonZeroUsize, E>,
+    {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+        let value = f()?;
+        Ok(match self.inner.compare_exchange(0, value.get(), AcqRel, Acquire) {
+            Ok(_) => value,
+            // SAFETY: Only non-zero values are stored in the cell.
+            Err(old) => unsafe { NonZeroUsize::new_unchecked(old) },
+        })
+    }
+}
+
+impl fmt::Debug for OnceNonZeroUsize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OnceNonZeroUsize").field(&self.get()).finish()
+    }
+}
+
+/// A thread-safe cell which can be written to only once, storing a `bool`.
+///
+/// If several threads initialize the cell concurrently, they may all run the initialization
+/// function, but only one value is stored.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::OnceBool;
+///
+/// static HAS_FEATURE: OnceBool = OnceBool::new();
+///
+/// assert!(HAS_FEATURE.get_or_init(|| true));
+/// assert_eq!(HAS_FEATURE.get(), Some(true));
+/// ```
+#[derive(Default)]
+pub struct OnceBool {
+    inner: OnceNonZeroUsize,
+}
+
+impl OnceBool {
+    /// Create a new empty cell.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { inner: OnceNonZeroUsize::new() }
+    }
+
+    /// Get the underlying value, or `None` if the cell is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceBool;
+    ///
+    /// let cell = OnceBool::new();
+    /// assert!(cell.get().is_none());
+    /// ```
+    #[must_use]
+    pub fn get(&self) -> Option<bool> {
+        self.inner.get().map(Self::from_usize)
+    }
+
+    /// Set the contents of this cell to `value`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(value)` if the cell was already initialized.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceBool;
+    ///
+    /// let cell = OnceBool::new();
+    /// assert_eq!(cell.set(false), Ok(()));
+    /// assert_eq!(cell.set(true), Err(true));
+    /// assert_eq!(cell.get(), Some(false));
+    /// ```
+    pub fn set(&self, value: bool) -> Result<(), bool> {
+        self.inner.set(Self::to_usize(value)).map_err(|_| value)
+    }
+
+    /// Get the contents of the cell, initializing it with `f` if the cell was empty.
+    ///
+    /// If several threads call this concurrently, `f` may be called several times, but only one
+    /// result is stored and returned to all callers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceBool;
+    ///
+    /// let cell = OnceBool::new();
+    /// assert!(!cell.get_or_init(|| false));
+    /// assert!(!cell.get_or_init(|| true));
+    /// ```
+    pub fn get_or_init<F>(&self, f: F) -> bool
+    where
+        F: FnOnce() -> bool,
+    {
+        Self::from_usize(self.inner.get_or_init(|| Self::to_usize(f())))
+    }
+
+    /// Get the contents of the cell, initializing it with `f` if the cell was empty.
+    ///
+    /// If `f` fails, the error is returned and the cell stays empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error returned by `f`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceBool;
+    ///
+    /// let cell = OnceBool::new();
+    /// assert_eq!(cell.get_or_try_init(|| Err(())), Err(()));
+    /// assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(true)), Ok(true));
+    /// ```
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<bool, E>
+    where
+        F: FnOnce() -> Result<bool, E>,
+    {
+        self.inner.get_or_try_init(|| f().map(Self::to_usize)).map(Self::from_usize)
+    }
+
+    #[inline]
+    fn from_usize(value: NonZeroUsize) -> bool {
+        value.get() == 1
+    }
+
+    #[inline]
+    fn to_usize(value: bool) -> NonZeroUsize {
+        // SAFETY: Both 1 and 2 are non-zero.
+        unsafe { NonZeroUsize::new_unchecked(if value { 1 } else { 2 }) }
+    }
+}
+
+impl fmt::Debug for OnceBool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OnceBool").field(&self.get()).finish()
+    }
+}
+
+/// A thread-safe cell which can be written to only once, storing a shared reference.
+///
+/// If several threads initialize the cell concurrently, they may all run the initialization
+/// function, but only one reference is stored.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::OnceRef;
+///
+/// static NAME: OnceRef<'static, &str> = OnceRef::new();
+///
+/// assert_eq!(*NAME.get_or_init(|| &"portable-atomic"), "portable-atomic");
+/// ```
+pub struct OnceRef<'a, T> {
+    inner: AtomicPtr<T>,
+    // Invariant over `'a` to prevent storing a reference with a shorter lifetime through a
+    // covariant subtype of this cell.
+    _marker: PhantomData<UnsafeCell<&'a T>>,
+}
+
+// SAFETY: The cell only ever hands out `&'a T`, which can be shared between threads if `T: Sync`.
+unsafe impl<T: Sync> Sync for OnceRef<'_, T> {}
+
+impl<'a, T> OnceRef<'a, T> {
+    /// Create a new empty cell.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { inner: AtomicPtr::new(ptr::null_mut()), _marker: PhantomData }
+    }
+
+    /// Get the stored reference, or `None` if the cell is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceRef;
+    ///
+    /// let cell = OnceRef::<u8>::new();
+    /// assert!(cell.get().is_none());
+    /// ```
+    #[must_use]
+    pub fn get(&self) -> Option<&'a T> {
+        let ptr = self.inner.load(Acquire);
+        // SAFETY: A non-null pointer was created from a `&'a T`.
+        unsafe { ptr.as_ref() }
+    }
+
+    /// Set the contents of this cell to `value`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(value)` if the cell was already initialized.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceRef;
+    ///
+    /// let cell = OnceRef::new();
+    /// assert_eq!(cell.set(&1), Ok(()));
+    /// assert_eq!(cell.set(&2), Err(&2));
+    /// assert_eq!(cell.get(), Some(&1));
+    /// ```
+    pub fn set(&self, value: &'a T) -> Result<(), &'a T> {
+        let ptr = value as *const T as *mut T;
+        match self.inner.compare_exchange(ptr::null_mut(), ptr, AcqRel, Acquire) {
+            Ok(_) => Ok(()),
+            Err(_) => Err(value),
+        }
+    }
+
+    /// Get the contents of the cell, initializing it with `f` if the cell was empty.
+    ///
+    /// If several threads call this concurrently, `f` may be called several times, but only one
+    /// result is stored and returned to all callers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceRef;
+    ///
+    /// let cell = OnceRef::new();
+    /// assert_eq!(cell.get_or_init(|| &1), &1);
+    /// assert_eq!(cell.get_or_init(|| &2), &1);
+    /// ```
+    pub fn get_or_init<F>(&self, f: F) -> &'a T
+    where
+        F: FnOnce() -> &'a T,
+    {
+        enum Void {}
+        match self.get_or_try_init(|| Ok::<&'a T, Void>(f())) {
+            Ok(value) => value,
+            Err(void) => match void {},
+        }
+    }
+
+    /// Get the contents of the cell, initializing it with `f` if the cell was empty.
+    ///
+    /// If `f` fails, the error is returned and the cell stays empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error returned by `f`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceRef;
+    ///
+    /// let cell = OnceRef::new();
+    /// assert_eq!(cell.get_or_try_init(|| Err(())), Err(()));
+    /// assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(&1)), Ok(&1));
+    /// ```
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&'a T, E>
+    where
+        F: FnOnce() -> Result<&'a T, E>,
+    {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+        let value = f()?;
+        let ptr = value as *const T as *mut T;
+        Ok(match self.inner.compare_exchange(ptr::null_mut(), ptr, AcqRel, Acquire) {
+            Ok(_) => value,
+            // SAFETY: A non-null pointer was created from a `&'a T`.
+            Err(old) => unsafe { &*old },
+        })
+    }
+}
+
+impl<T> Default for OnceRef<'_, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for OnceRef<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OnceRef").field(&self.get()).finish()
+    }
+}
+
+/// A thread-safe cell which can be written to only once, storing a heap-allocated value.
+///
+/// If several threads initialize the cell concurrently, they may all run the initialization
+/// function, but only one value is stored; the other values are dropped.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::OnceBox;
+///
+/// static CONFIG: OnceBox<String> = OnceBox::new();
+///
+/// let config = CONFIG.get_or_init(|| Box::new("verbose".to_owned()));
+/// assert_eq!(config, "verbose");
+/// ```
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub struct OnceBox<T> {
+    inner: AtomicPtr<T>,
+    _marker: PhantomData<Option<Box<T>>>,
+}
+
+// SAFETY: The value may be dropped by a different thread than the one that initialized it, so `T`
+// must be `Send` in addition to `Sync`.
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+unsafe impl<T: Sync + Send> Sync for OnceBox<T> {}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+impl<T> OnceBox<T> {
+    /// Create a new empty cell.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { inner: AtomicPtr::new(ptr::null_mut()), _marker: PhantomData }
+    }
+
+    /// Get a reference to the underlying value, or `None` if the cell is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceBox;
+    ///
+    /// let cell = OnceBox::<u8>::new();
+    /// assert!(cell.get().is_none());
+    /// ```
+    #[must_use]
+    pub fn get(&self) -> Option<&T> {
+        let ptr = self.inner.load(Acquire);
+        // SAFETY: A non-null pointer was created by `Box::into_raw` and is only freed on drop.
+        unsafe { ptr.as_ref() }
+    }
+
+    /// Set the contents of this cell to `value`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(value)` if the cell was already initialized.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceBox;
+    ///
+    /// let cell = OnceBox::new();
+    /// assert!(cell.set(Box::new(1)).is_ok());
+    /// assert_eq!(cell.set(Box::new(2)), Err(Box::new(2)));
+    /// assert_eq!(cell.get(), Some(&1));
+    /// ```
+    pub fn set(&self, value: Box<T>) -> Result<(), Box<T>> {
+        let ptr = Box::into_raw(value);
+        match self.inner.compare_exchange(ptr::null_mut(), ptr, AcqRel, Acquire) {
+            Ok(_) => Ok(()),
+            // SAFETY: `ptr` was created by `Box::into_raw` above and was not stored.
+            Err(_) => Err(unsafe { Box::from_raw(ptr) }),
+        }
+    }
+
+    /// Get the contents of the cell, initializing it with `f` if the cell was empty.
+    ///
+    /// If several threads call this concurrently, `f` may be called several times, but only one
+    /// result is stored and returned to all callers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceBox;
+    ///
+    /// let cell = OnceBox::new();
+    /// assert_eq!(cell.get_or_init(|| Box::new(1)), &1);
+    /// assert_eq!(cell.get_or_init(|| Box::new(2)), &1);
+    /// ```
+    pub fn get_or_init<F>(&self, f: F) -> &T
+    where
+        F: FnOnce() -> Box<T>,
+    {
+        enum Void {}
+        match self.get_or_try_init(|| Ok::<Box<T>, Void>(f())) {
+            Ok(value) => value,
+            Err(void) => match void {},
+        }
+    }
+
+    /// Get the contents of the cell, initializing it with `f` if the cell was empty.
+    ///
+    /// If `f` fails, the error is returned and the cell stays empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error returned by `f`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::OnceBox;
+    ///
+    /// let cell = OnceBox::new();
+    /// assert_eq!(cell.get_or_try_init(|| Err(())), Err(()));
+    /// assert_eq!(cell.get_or_try_init(|| Ok::<_, ()>(Box::new(1))), Ok(&1));
+    /// ```
+    pub fn get_or_try_init<F, E>(&self, f: F) -> Result<&T, E>
+    where
+        F: FnOnce() -> Result<Box<T>, E>,
+    {
+        if let Some(value) = self.get() {
+            return Ok(value);
+        }
+        let ptr = Box::into_raw(f()?);
+        let ptr = match self.inner.compare_exchange(ptr::null_mut(), ptr, AcqRel, Acquire) {
+            Ok(_) => ptr,
+            Err(old) => {
+                // SAFETY: `ptr` was created by `Box::into_raw` above and was not stored.
+                drop(unsafe { Box::from_raw(ptr) });
+                old
+            }
+        };
+        // SAFETY: `ptr` is the non-null pointer stored in the cell.
+        Ok(unsafe { &*ptr })
+    }
+}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+impl<T> Default for OnceBox<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+impl<T: fmt::Debug> fmt::Debug for OnceBox<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("OnceBox").field(&self.get()).finish()
+    }
+}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+impl<T> Drop for OnceBox<T> {
+    fn drop(&mut self) {
+        let ptr = self.inner.load(Acquire);
+        if !ptr.is_null() {
+            // SAFETY: A non-null pointer was created by `Box::into_raw`, and we have exclusive
+            // access.
+            drop(unsafe { Box::from_raw(ptr) });
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::DropCounter;
+
+    use portable_atomic::Ordering::Relaxed;
+    use std::{thread, vec::Vec};
+
+    const THREADS: usize = 4;
+
+    #[test]
+    fn once_box_race() {
+        let created = AtomicUsize::new(0);
+        let drops = AtomicUsize::new(0);
+        let cell = OnceBox::new();
+        let wins = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for i in 0..THREADS {
+                let (cell, created, drops, wins) = (&cell, &created, &drops, &wins);
+                s.spawn(move || {
+                    let value = if i % 2 == 0 {
+                        created.fetch_add(1, Relaxed);
+                        if cell.set(Box::new((i, DropCounter(drops)))).is_ok() {
+                            wins.fetch_add(1, Relaxed);
+                        }
+                        cell.get().unwrap().0
+                    } else {
+                        cell.get_or_init(|| {
+                            created.fetch_add(1, Relaxed);
+                            Box::new((i, DropCounter(drops)))
+                        })
+                        .0
+                    };
+                    assert_eq!(cell.get().unwrap().0, value);
+                });
+            }
+        });
+        let value = cell.get().unwrap().0;
+        // `set` succeeds at most once, and only if its value is the one that was stored.
+        assert_eq!(wins.load(Relaxed), (value % 2 == 0) as usize);
+        // Every value that lost the race was dropped, and the stored one is dropped with the cell.
+        assert_eq!(drops.load(Relaxed), created.load(Relaxed) - 1);
+        drop(cell);
+        assert_eq!(drops.load(Relaxed), created.load(Relaxed));
+    }
+
+    #[test]
+    fn once_non_zero_usize_race() {
+        let cell = OnceNonZeroUsize::new();
+        let values: Vec<_> = thread::scope(|s| {
+            let threads: Vec<_> = (1..=THREADS)
+                .map(|i| {
+                    let cell = &cell;
+                    s.spawn(move || cell.get_or_init(|| NonZeroUsize::new(i).unwrap()))
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert!(values.iter().all(|&v| v == values[0]));
+        assert_eq!(cell.get(), Some(values[0]));
+        assert_eq!(cell.set(values[0]), Err(values[0]));
+    }
+
+    #[test]
+    fn once_bool_race() {
+        let cell = OnceBool::new();
+        let values: Vec<_> = thread::scope(|s| {
+            let threads: Vec<_> = (0..THREADS)
+                .map(|i| {
+                    let cell = &cell;
+                    s.spawn(move || cell.get_or_init(|| i % 2 == 0))
+                })
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).collect()
+        });
+        assert!(values.iter().all(|&v| v == values[0]));
+        assert_eq!(cell.get(), Some(values[0]));
+    }
+
+    #[test]
+    fn once_ref_race() {
+        let targets: Vec<usize> = (0..THREADS).collect();
+        let cell = OnceRef::new();
+        thread::scope(|s| {
+            for target in &targets {
+                let cell = &cell;
+                s.spawn(move || {
+                    let value = cell.get_or_init(|| target);
+                    assert!(ptr::eq(cell.get().unwrap(), value));
+                });
+            }
+        });
+        let value = cell.get().unwrap();
+        assert!(targets.iter().any(|t| ptr::eq(t, value)));
+    }
+}
@@ -0,0 +1,1039 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A spin-based reader-writer lock with upgradeable reads.
+//!
+//! The whole lock state is stored in a single `AtomicUsize`:
+//!
+//! - bit 0 (`WRITER`): a writer holds the lock.
+//! - bit 1 (`UPGRADEABLE`): an upgradeable reader holds the lock.
+//! - bit 2 (`WRITER_PENDING`): a writer (or an upgrading reader) is waiting. New readers back off
+//!   while this is set, so writers are not starved by a continuous stream of readers.
+//! - the remaining bits: the number of readers, in units of `READER`.
+
+use portable_atomic::{
+    AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release},
+};
+
+use crate::utils::Backoff;
+
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    mem,
+    ops::{Deref, DerefMut},
+};
+
+const WRITER: usize = 1;
+const UPGRADEABLE: usize = 1 << 1;
+const WRITER_PENDING: usize = 1 << 2;
+const READER: usize = 1 << 3;
+
+/// A raw spin-based reader-writer lock with writer preference.
+///
+/// This is the lock used by [`RwSpinLock`]. When the `lock_api` feature is enabled, this
+/// implements [`lock_api::RawRwLock`], [`lock_api::RawRwLockDowngrade`],
+/// [`lock_api::RawRwLockUpgrade`], and [`lock_api::RawRwLockUpgradeDowngrade`].
+pub struct RawRwSpinLock {
+    state: AtomicUsize,
+}
+
+impl RawRwSpinLock {
+    /// Create a new unlocked lock.
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { state: AtomicUsize::new(0) }
+    }
+
+    /// Acquire a shared lock, spinning until it is available.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of readers overflows.
+    pub fn lock_shared(&self) {
+        let mut backoff = Backoff::new();
+        while !self.try_lock_shared() {
+            backoff.snooze();
+        }
+    }
+
+    /// Attempt to acquire a shared lock without spinning.
+    ///
+    /// This fails if a writer holds the lock or is waiting for it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of readers overflows.
+    #[must_use]
+    pub fn try_lock_shared(&self) -> bool {
+        let mut state = self.state.load(Relaxed);
+        loop {
+            if state & (WRITER | WRITER_PENDING) != 0 {
+                return false;
+            }
+            let new = state.checked_add(READER).expect("RwSpinLock reader count overflow");
+            match self.state.compare_exchange_weak(state, new, Acquire, Relaxed) {
+                Ok(_) => return true,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    /// Release a shared lock.
+    ///
+    /// # Safety
+    ///
+    /// A shared lock must be held by the current context.
+    pub unsafe fn unlock_shared(&self) {
+        self.state.fetch_sub(READER, Release);
+    }
+
+    /// Acquire an exclusive lock, spinning until it is available.
+    pub fn lock_exclusive(&self) {
+        let mut backoff = Backoff::new();
+        loop {
+            let state = self.state.load(Relaxed);
+            if state & !WRITER_PENDING == 0 {
+                // Clear `WRITER_PENDING` as well: other waiting writers will set it again.
+                if self.state.compare_exchange_weak(state, WRITER, Acquire, Relaxed).is_ok() {
+                    return;
+                }
+            } else if state & WRITER_PENDING == 0 {
+                self.state.fetch_or(WRITER_PENDING, Relaxed);
+            }
+            backoff.snooze();
+        }
+    }
+
+    /// Attempt to acquire an exclusive lock without spinning.
+    #[must_use]
+    pub fn try_lock_exclusive(&self) -> bool {
+        let state = self.state.load(Relaxed);
+        state & !WRITER_PENDING == 0
+            && self.state.compare_exchange(state, WRITER, Acquire, Relaxed).is_ok()
+    }
+
+    /// Release an exclusive lock.
+    ///
+    /// # Safety
+    ///
+    /// An exclusive lock must be held by the current context.
+    pub unsafe fn unlock_exclusive(&self) {
+        self.state.fetch_and(!WRITER, Release);
+    }
+
+    /// Acquire an upgradeable lock, spinning until it is available.
+    ///
+    /// An upgradeable lock can be held together with shared locks, but not with an exclusive lock
+    /// or another upgradeable lock.
+    pub fn lock_upgradeable(&self) {
+        let mut backoff = Backoff::new();
+        while !self.try_lock_upgradeable() {
+            backoff.snooze();
+        }
+    }
+
+    /// Attempt to acquire an upgradeable lock without spinning.
+    #[must_use]
+    pub fn try_lock_upgradeable(&self) -> bool {
+        let mut state = self.state.load(Relaxed);
+        loop {
+            if state & (WRITER | UPGRADEABLE | WRITER_PENDING) != 0 {
+                return false;
+            }
+            match self.state.compare_exchange_weak(state, state | UPGRADEABLE, Acquire, Relaxed) {
+                Ok(_) => return true,
+                Err(x) => state = x,
+            }
+        }
+    }
+
+    /// Release an upgradeable lock.
+    ///
+    /// # Safety
+    ///
+    /// An upgradeable lock must be held by the current context.
+    pub unsafe fn unlock_upgradeable(&self) {
+        self.state.fetch_sub(UPGRADEABLE, Release);
+    }
+
+    /// Upgrade an upgradeable lock to an exclusive lock, spinning until all readers are gone.
+    ///
+    /// # Safety
+    ///
+    /// An upgradeable lock must be held by the current context.
+    pub unsafe fn upgrade(&self) {
+        let mut backoff = Backoff::new();
+        loop {
+            let state = self.state.load(Relaxed);
+            if state & !(UPGRADEABLE | WRITER_PENDING) == 0 {
+                if self.state.compare_exchange_weak(state, WRITER, Acquire, Relaxed).is_ok() {
+                    return;
+                }
+            } else if state & WRITER_PENDING == 0 {
+                // Prevent new readers from entering so that the existing readers can drain.
+                self.state.fetch_or(WRITER_PENDING, Relaxed);
+            }
+            backoff.snooze();
+        }
+    }
+
+    /// Attempt to upgrade an upgradeable lock to an exclusive lock without spinning.
+    ///
+    /// # Safety
+    ///
+    /// An upgradeable lock must be held by the current context.
+    #[must_use]
+    pub unsafe fn try_upgrade(&self) -> bool {
+        let state = self.state.load(Relaxed);
+        state & !(UPGRADEABLE | WRITER_PENDING) == 0
+            && self.state.compare_exchange(state, WRITER, Acquire, Relaxed).is_ok()
+    }
+
+    /// Atomically downgrade an exclusive lock to a shared lock.
+    ///
+    /// # Safety
+    ///
+    /// An exclusive lock must be held by the current context.
+    pub unsafe fn downgrade(&self) {
+        // The `WRITER` bit is set, so this clears it and adds a reader without carrying into the
+        // other bits.
+        self.state.fetch_add(READER - WRITER, Release);
+    }
+
+    /// Atomically downgrade an exclusive lock to an upgradeable lock.
+    ///
+    /// # Safety
+    ///
+    /// An exclusive lock must be held by the current context.
+    pub unsafe fn downgrade_to_upgradeable(&self) {
+        // The `WRITER` bit is set and the `UPGRADEABLE` bit is not.
+        self.state.fetch_add(UPGRADEABLE - WRITER, Release);
+    }
+
+    /// Atomically downgrade an upgradeable lock to a shared lock.
+    ///
+    /// # Safety
+    ///
+    /// An upgradeable lock must be held by the current context.
+    pub unsafe fn downgrade_upgradeable(&self) {
+        // The `UPGRADEABLE` bit is set, so this clears it and adds a reader.
+        self.state.fetch_add(READER - UPGRADEABLE, Release);
+    }
+
+    /// Return `true` if the lock is held in any mode.
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.state.load(Relaxed) & !WRITER_PENDING != 0
+    }
+
+    /// Return `true` if the lock is held exclusively.
+    #[must_use]
+    pub fn is_locked_exclusive(&self) -> bool {
+        self.state.load(Relaxed) & WRITER != 0
+    }
+
+    /// Return the number of shared locks currently held, not including an upgradeable lock.
+    #[must_use]
+    pub fn reader_count(&self) -> usize {
+        self.state.load(Relaxed) / READER
+    }
+}
+
+impl Default for RawRwSpinLock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for RawRwSpinLock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state.load(Relaxed);
+        f.debug_struct("RawRwSpinLock")
+            .field("writer", &(state & WRITER != 0))
+            .field("upgradeable", &(state & UPGRADEABLE != 0))
+            .field("readers", &(state / READER))
+            .finish()
+    }
+}
+
+#[cfg(feature = "lock_api")]
+// SAFETY: Locking acquires the lock with `Acquire` ordering, and unlocking releases it with
+// `Release` ordering. An exclusive lock is never held together with any other lock.
+unsafe impl lock_api::RawRwLock for RawRwSpinLock {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const INIT: Self = Self::new();
+
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock_shared(&self) {
+        Self::lock_shared(self);
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        Self::try_lock_shared(self)
+    }
+
+    unsafe fn unlock_shared(&self) {
+        // SAFETY: the caller must uphold the safety contract.
+        unsafe { Self::unlock_shared(self) }
+    }
+
+    fn lock_exclusive(&self) {
+        Self::lock_exclusive(self);
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        Self::try_lock_exclusive(self)
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        // SAFETY: the caller must uphold the safety contract.
+        unsafe { Self::unlock_exclusive(self) }
+    }
+
+    fn is_locked(&self) -> bool {
+        Self::is_locked(self)
+    }
+
+    fn is_locked_exclusive(&self) -> bool {
+        Self::is_locked_exclusive(self)
+    }
+}
+
+#[cfg(feature = "lock_api")]
+// SAFETY: Downgrading atomically converts the exclusive lock into a shared lock.
+unsafe impl lock_api::RawRwLockDowngrade for RawRwSpinLock {
+    unsafe fn downgrade(&self) {
+        // SAFETY: the caller must uphold the safety contract.
+        unsafe { Self::downgrade(self) }
+    }
+}
+
+#[cfg(feature = "lock_api")]
+// SAFETY: An upgradeable lock is never held together with an exclusive lock or another
+// upgradeable lock, and upgrading waits for all shared locks to be released.
+unsafe impl lock_api::RawRwLockUpgrade for RawRwSpinLock {
+    fn lock_upgradable(&self) {
+        Self::lock_upgradeable(self);
+    }
+
+    fn try_lock_upgradable(&self) -> bool {
+        Self::try_lock_upgradeable(self)
+    }
+
+    unsafe fn unlock_upgradable(&self) {
+        // SAFETY: the caller must uphold the safety contract.
+        unsafe { Self::unlock_upgradeable(self) }
+    }
+
+    unsafe fn upgrade(&self) {
+        // SAFETY: the caller must uphold the safety contract.
+        unsafe { Self::upgrade(self) }
+    }
+
+    unsafe fn try_upgrade(&self) -> bool {
+        // SAFETY: the caller must uphold the safety contract.
+        unsafe { Self::try_upgrade(self) }
+    }
+}
+
+#[cfg(feature = "lock_api")]
+// SAFETY: Downgrading atomically converts the lock into the weaker lock.
+unsafe impl lock_api::RawRwLockUpgradeDowngrade for RawRwSpinLock {
+    unsafe fn downgrade_upgradable(&self) {
+        // SAFETY: the caller must uphold the safety contract.
+        unsafe { Self::downgrade_upgradeable(self) }
+    }
+
+    unsafe fn downgrade_to_upgradable(&self) {
+        // SAFETY: the caller must uphold the safety contract.
+        unsafe { Self::downgrade_to_upgradeable(self) }
+    }
+}
+
+/// A spin-based reader-writer lock with upgradeable reads.
+///
+/// This allows a number of readers or at most one writer at any point in time. In addition, one
+/// upgradeable reader can hold the lock together with normal readers, and later atomically upgrade
+/// to a writer without letting another writer in between.
+///
+/// Writers are preferred: once a writer is waiting, new readers wait until it has acquired and
+/// released the lock.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::RwSpinLock;
+///
+/// static TABLE: RwSpinLock<[u32; 4]> = RwSpinLock::new([0; 4]);
+///
+/// {
+///     let r1 = TABLE.read();
+///     let r2 = TABLE.read();
+///     assert_eq!(r1[0], r2[0]);
+/// }
+///
+/// TABLE.write()[0] = 1;
+/// assert_eq!(TABLE.read()[0], 1);
+/// ```
+pub struct RwSpinLock<T: ?Sized> {
+    raw: RawRwSpinLock,
+    data: UnsafeCell<T>,
+}
+
+// SAFETY: The lock provides the same guarantees as std's `RwLock`.
+unsafe impl<T: ?Sized + Send> Send for RwSpinLock<T> {}
+// SAFETY: Readers on multiple threads can access `&T` at the same time, so `T` must be `Sync`.
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwSpinLock<T> {}
+
+/// An RAII guard for shared read access, returned by [`RwSpinLock::read`].
+///
+/// The lock is released when this guard is dropped.
+#[must_use = "if unused the lock will immediately unlock"]
+pub struct RwSpinLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+    _marker: PhantomData<&'a T>,
+}
+
+/// An RAII guard for exclusive write access, returned by [`RwSpinLock::write`].
+///
+/// The lock is released when this guard is dropped.
+#[must_use = "if unused the lock will immediately unlock"]
+pub struct RwSpinLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+    _marker: PhantomData<&'a mut T>,
+}
+
+/// An RAII guard for upgradeable read access, returned by [`RwSpinLock::upgradeable_read`].
+///
+/// The lock is released when this guard is dropped.
+#[must_use = "if unused the lock will immediately unlock"]
+pub struct RwSpinLockUpgradeableReadGuard<'a, T: ?Sized> {
+    lock: &'a RwSpinLock<T>,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<T> RwSpinLock<T> {
+    /// Create a new unlocked [`RwSpinLock`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::RwSpinLock;
+    ///
+    /// static LOCK: RwSpinLock<u32> = RwSpinLock::new(0);
+    /// ```
+    pub const fn new(value: T) -> Self {
+        Self { raw: RawRwSpinLock::new(), data: UnsafeCell::new(value) }
+    }
+
+    /// Consume this lock and return the underlying data.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::RwSpinLock;
+    ///
+    /// let lock = RwSpinLock::new(5);
+    /// assert_eq!(lock.into_inner(), 5);
+    /// ```
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized> RwSpinLock<T> {
+    /// Acquire shared read access, spinning until it is available.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of readers overflows.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::RwSpinLock;
+    ///
+    /// let lock = RwSpinLock::new(5);
+    /// let r1 = lock.read();
+    /// let r2 = lock.read();
+    /// assert_eq!(*r1 + *r2, 10);
+    /// ```
+    pub fn read(&self) -> RwSpinLockReadGuard<'_, T> {
+        self.raw.lock_shared();
+        RwSpinLockReadGuard { lock: self, _marker: PhantomData }
+    }
+
+    /// Attempt to acquire shared read access without spinning.
+    ///
+    /// This fails if a writer holds the lock or is waiting for it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the number of readers overflows.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::RwSpinLock;
+    ///
+    /// let lock = RwSpinLock::new(5);
+    /// let w = lock.write();
+    /// assert!(lock.try_read().is_none());
+    /// drop(w);
+    /// assert!(lock.try_read().is_some());
+    /// ```
+    pub fn try_read(&self) -> Option<RwSpinLockReadGuard<'_, T>> {
+        if self.raw.try_lock_shared() {
+            Some(RwSpinLockReadGuard { lock: self, _marker: PhantomData })
+        } else {
+            None
+        }
+    }
+
+    /// Acquire exclusive write access, spinning until it is available.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::RwSpinLock;
+    /// use std::{sync::Arc, thread};
+    ///
+    /// let lock = Arc::new(RwSpinLock::new(0));
+    /// let threads: Vec<_> = (0..4)
+    ///     .map(|_| {
+    ///         let lock = Arc::clone(&lock);
+    ///         thread::spawn(move || {
+    ///             for _ in 0..100 {
+    ///                 *lock.write() += 1;
+    ///                 let _ = *lock.read();
+    ///             }
+    ///         })
+    ///     })
+    ///     .collect();
+    /// for t in threads {
+    ///     t.join().unwrap();
+    /// }
+    /// assert_eq!(*lock.read(), 400);
+    /// ```
+    pub fn write(&self) -> RwSpinLockWriteGuard<'_, T> {
+        self.raw.lock_exclusive();
+        RwSpinLockWriteGuard { lock: self, _marker: PhantomData }
+    }
+
+    /// Attempt to acquire exclusive write access without spinning.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::RwSpinLock;
+    ///
+    /// let lock = RwSpinLock::new(5);
+    /// let r = lock.read();
+    /// assert!(lock.try_write().is_none());
+    /// drop(r);
+    /// assert!(lock.try_write().is_some());
+    /// ```
+    pub fn try_write(&self) -> Option<RwSpinLockWriteGuard<'_, T>> {
+        if self.raw.try_lock_exclusive() {
+            Some(RwSpinLockWriteGuard { lock: self, _marker: PhantomData })
+        } else {
+            None
+        }
+    }
+
+    /// Acquire upgradeable read access, spinning until it is available.
+    ///
+    /// Only one upgradeable reader can hold the lock at a time, but it can coexist with normal
+    /// readers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{RwSpinLock, RwSpinLockUpgradeableReadGuard};
+    ///
+    /// let lock = RwSpinLock::new(5);
+    /// let u = lock.upgradeable_read();
+    /// let r = lock.read();
+    /// assert_eq!(*u, *r);
+    /// drop(r);
+    ///
+    /// let mut w = RwSpinLockUpgradeableReadGuard::upgrade(u);
+    /// *w += 1;
+    /// drop(w);
+    /// assert_eq!(*lock.read(), 6);
+    /// ```
+    pub fn upgradeable_read(&self) -> RwSpinLockUpgradeableReadGuard<'_, T> {
+        self.raw.lock_upgradeable();
+        RwSpinLockUpgradeableReadGuard { lock: self, _marker: PhantomData }
+    }
+
+    /// Attempt to acquire upgradeable read access without spinning.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::RwSpinLock;
+    ///
+    /// let lock = RwSpinLock::new(5);
+    /// let u = lock.upgradeable_read();
+    /// assert!(lock.try_upgradeable_read().is_none());
+    /// drop(u);
+    /// assert!(lock.try_upgradeable_read().is_some());
+    /// ```
+    pub fn try_upgradeable_read(&self) -> Option<RwSpinLockUpgradeableReadGuard<'_, T>> {
+        if self.raw.try_lock_upgradeable() {
+            Some(RwSpinLockUpgradeableReadGuard { lock: self, _marker: PhantomData })
+        } else {
+            None
+        }
+    }
+
+    /// Return `true` if the lock is currently held in any mode.
+    ///
+    /// This is only a hint: the state may change immediately after this returns.
+    #[must_use]
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Return the number of readers currently holding the lock, not including an upgradeable
+    /// reader.
+    ///
+    /// This is only a hint: the state may change immediately after this returns.
+    #[must_use]
+    pub fn reader_count(&self) -> usize {
+        self.raw.reader_count()
+    }
+
+    /// Get a mutable reference to the underlying data.
+    ///
+    /// Since this call borrows the lock mutably, no actual locking needs to take place.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::RwSpinLock;
+    ///
+    /// let mut lock = RwSpinLock::new(5);
+    /// *lock.get_mut() += 1;
+    /// assert_eq!(*lock.read(), 6);
+    /// ```
+    pub fn get_mut(&mut self) -> &mut T {
+        // SAFETY: We have exclusive access to the lock.
+        unsafe { &mut *self.data.get() }
+    }
+}
+
+impl<T: Default> Default for RwSpinLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T> From<T> for RwSpinLock<T> {
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwSpinLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut d = f.debug_struct("RwSpinLock");
+        match self.try_read() {
+            Some(guard) => d.field("data", &&*guard),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
+impl<'a, T: ?Sized> RwSpinLockWriteGuard<'a, T> {
+    /// Atomically downgrade this write guard to a read guard, without allowing any writers to
+    /// take the lock in between.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{RwSpinLock, RwSpinLockWriteGuard};
+    ///
+    /// let lock = RwSpinLock::new(5);
+    /// let mut w = lock.write();
+    /// *w += 1;
+    /// let r = RwSpinLockWriteGuard::downgrade(w);
+    /// assert_eq!(*r, 6);
+    /// assert!(lock.try_read().is_some());
+    /// ```
+    pub fn downgrade(this: Self) -> RwSpinLockReadGuard<'a, T> {
+        let lock = this.lock;
+        mem::forget(this);
+        // SAFETY: The exclusive lock was held by the guard we just forgot.
+        unsafe { lock.raw.downgrade() }
+        RwSpinLockReadGuard { lock, _marker: PhantomData }
+    }
+
+    /// Atomically downgrade this write guard to an upgradeable read guard, without allowing any
+    /// writers to take the lock in between.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{RwSpinLock, RwSpinLockWriteGuard};
+    ///
+    /// let lock = RwSpinLock::new(5);
+    /// let w = lock.write();
+    /// let u = RwSpinLockWriteGuard::downgrade_to_upgradeable(w);
+    /// assert!(lock.try_read().is_some());
+    /// assert!(lock.try_upgradeable_read().is_none());
+    /// ```
+    pub fn downgrade_to_upgradeable(this: Self) -> RwSpinLockUpgradeableReadGuard<'a, T> {
+        let lock = this.lock;
+        mem::forget(this);
+        // SAFETY: The exclusive lock was held by the guard we just forgot.
+        unsafe { lock.raw.downgrade_to_upgradeable() }
+        RwSpinLockUpgradeableReadGuard { lock, _marker: PhantomData }
+    }
+}
+
+impl<'a, T: ?Sized> RwSpinLockUpgradeableReadGuard<'a, T> {
+    /// Upgrade this guard to a write guard, spinning until all other readers release the lock.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{RwSpinLock, RwSpinLockUpgradeableReadGuard};
+    ///
+    /// let lock = RwSpinLock::new(5);
+    /// let u = lock.upgradeable_read();
+    /// let mut w = RwSpinLockUpgradeableReadGuard::upgrade(u);
+    /// *w += 1;
+    /// assert_eq!(*w, 6);
+    /// ```
+    pub fn upgrade(this: Self) -> RwSpinLockWriteGuard<'a, T> {
+        let lock = this.lock;
+        mem::forget(this);
+        // SAFETY: The upgradeable lock was held by the guard we just forgot.
+        unsafe { lock.raw.upgrade() }
+        RwSpinLockWriteGuard { lock, _marker: PhantomData }
+    }
+
+    /// Attempt to upgrade this guard to a write guard without spinning.
+    ///
+    /// Returns the guard back if there are other readers.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{RwSpinLock, RwSpinLockUpgradeableReadGuard};
+    ///
+    /// let lock = RwSpinLock::new(5);
+    /// let u = lock.upgradeable_read();
+    /// let r = lock.read();
+    /// let u = RwSpinLockUpgradeableReadGuard::try_upgrade(u).unwrap_err();
+    /// drop(r);
+    /// assert!(RwSpinLockUpgradeableReadGuard::try_upgrade(u).is_ok());
+    /// ```
+    pub fn try_upgrade(this: Self) -> Result<RwSpinLockWriteGuard<'a, T>, Self> {
+        // SAFETY: The upgradeable lock is held by this guard.
+        if unsafe { this.lock.raw.try_upgrade() } {
+            let lock = this.lock;
+            mem::forget(this);
+            Ok(RwSpinLockWriteGuard { lock, _marker: PhantomData })
+        } else {
+            Err(this)
+        }
+    }
+
+    /// Atomically downgrade this guard to a normal read guard, allowing another upgradeable
+    /// reader or a writer to take the lock after the remaining readers are gone.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::{RwSpinLock, RwSpinLockUpgradeableReadGuard};
+    ///
+    /// let lock = RwSpinLock::new(5);
+    /// let u = lock.upgradeable_read();
+    /// let r = RwSpinLockUpgradeableReadGuard::downgrade(u);
+    /// assert!(lock.try_upgradeable_read().is_some());
+    /// assert_eq!(*r, 5);
+    /// ```
+    pub fn downgrade(this: Self) -> RwSpinLockReadGuard<'a, T> {
+        let lock = this.lock;
+        mem::forget(this);
+        // SAFETY: The upgradeable lock was held by the guard we just forgot.
+        unsafe { lock.raw.downgrade_upgradeable() }
+        RwSpinLockReadGuard { lock, _marker: PhantomData }
+    }
+}
+
+impl<T: ?Sized> Deref for RwSpinLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: A shared lock is held, so no one can mutate the data.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Deref for RwSpinLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: The exclusive lock is held, so we have exclusive access to the data.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwSpinLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: The exclusive lock is held, so we have exclusive access to the data.
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Deref for RwSpinLockUpgradeableReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: An upgradeable lock is held, so no one can mutate the data.
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: A shared lock is held by this guard.
+        unsafe { self.lock.raw.unlock_shared() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: The exclusive lock is held by this guard.
+        unsafe { self.lock.raw.unlock_exclusive() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwSpinLockUpgradeableReadGuard<'_, T> {
+    fn drop(&mut self) {
+        // SAFETY: The upgradeable lock is held by this guard.
+        unsafe { self.lock.raw.unlock_upgradeable() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwSpinLockReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwSpinLockWriteGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for RwSpinLockUpgradeableReadGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    const ITERS: usize = 500;
+
+    // Counts the threads in each critical section, so that the test can check which sections
+    // overlap.
+    #[derive(Default)]
+    struct Holders {
+        readers: AtomicUsize,
+        upgradeable: AtomicUsize,
+        writers: AtomicUsize,
+    }
+
+    impl Holders {
+        fn read(&self) {
+            self.readers.fetch_add(1, Relaxed);
+            assert_eq!(self.writers.load(Relaxed), 0);
+        }
+        fn unread(&self) {
+            self.readers.fetch_sub(1, Relaxed);
+        }
+        fn upgradeable(&self) {
+            assert_eq!(self.upgradeable.fetch_add(1, Relaxed), 0);
+            assert_eq!(self.writers.load(Relaxed), 0);
+        }
+        fn unupgradeable(&self) {
+            self.upgradeable.fetch_sub(1, Relaxed);
+        }
+        fn write(&self) {
+            assert_eq!(self.writers.fetch_add(1, Relaxed), 0);
+            assert_eq!(self.readers.load(Relaxed), 0);
+            assert_eq!(self.upgradeable.load(Relaxed), 0);
+        }
+        fn unwrite(&self) {
+            self.writers.fetch_sub(1, Relaxed);
+        }
+    }
+
+    #[test]
+    fn concurrent_exclusion() {
+        // Writers keep both halves equal, so a reader that sees them differ raced with a writer.
+        let lock = RwSpinLock::new((0, 0));
+        let holders = Holders::default();
+        thread::scope(|s| {
+            // Readers.
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..ITERS {
+                        let guard = lock.read();
+                        holders.read();
+                        assert_eq!(guard.0, guard.1);
+                        holders.unread();
+                    }
+                });
+            }
+            // Writers that downgrade to a reader. No other writer can get in between.
+            s.spawn(|| {
+                for _ in 0..ITERS {
+                    let mut guard = lock.write();
+                    holders.write();
+                    guard.0 += 1;
+                    guard.1 += 1;
+                    let value = *guard;
+                    holders.unwrite();
+                    let guard = RwSpinLockWriteGuard::downgrade(guard);
+                    holders.read();
+                    assert_eq!(*guard, value);
+                    holders.unread();
+                }
+            });
+            // Upgradeable readers that upgrade, then downgrade back to an upgradeable reader.
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..ITERS {
+                        let guard = lock.upgradeable_read();
+                        holders.upgradeable();
+                        let value = *guard;
+                        holders.unupgradeable();
+                        let mut guard = RwSpinLockUpgradeableReadGuard::upgrade(guard);
+                        holders.write();
+                        // Upgrading does not let another writer in.
+                        assert_eq!(*guard, value);
+                        guard.0 += 1;
+                        guard.1 += 1;
+                        let value = *guard;
+                        holders.unwrite();
+                        let guard = RwSpinLockWriteGuard::downgrade_to_upgradeable(guard);
+                        holders.upgradeable();
+                        assert_eq!(*guard, value);
+                        holders.unupgradeable();
+                    }
+                });
+            }
+        });
+        assert!(!lock.is_locked());
+        assert_eq!(lock.into_inner(), (3 * ITERS, 3 * ITERS));
+    }
+
+    #[test]
+    fn writer_preference() {
+        let lock = RwSpinLock::new(0);
+        let guard = lock.read();
+        thread::scope(|s| {
+            s.spawn(|| *lock.write() += 1);
+            // Wait until the writer has announced itself.
+            while lock.raw.state.load(Relaxed) & WRITER_PENDING == 0 {
+                thread::yield_now();
+            }
+            // New readers and upgradeable readers must wait for the writer, but the existing
+            // reader is unaffected.
+            assert!(lock.try_read().is_none());
+            assert!(lock.try_upgradeable_read().is_none());
+            assert_eq!(*guard, 0);
+            assert_eq!(lock.reader_count(), 1);
+            drop(guard);
+        });
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn try_upgrade_with_readers() {
+        let lock = RwSpinLock::new(0);
+        let reader = lock.read();
+        let upgradeable = lock.upgradeable_read();
+        let upgradeable = RwSpinLockUpgradeableReadGuard::try_upgrade(upgradeable).unwrap_err();
+        assert!(lock.try_write().is_none());
+        drop(reader);
+        let mut writer = RwSpinLockUpgradeableReadGuard::try_upgrade(upgradeable).unwrap();
+        *writer += 1;
+        assert!(lock.try_read().is_none());
+        drop(writer);
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[cfg(feature = "lock_api")]
+    #[test]
+    fn lock_api_exclusion() {
+        use lock_api::{RwLockUpgradableReadGuard, RwLockWriteGuard};
+
+        let lock = lock_api::RwLock::<RawRwSpinLock, _>::new((0, 0));
+        let holders = Holders::default();
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..ITERS {
+                    let guard = lock.read();
+                    holders.read();
+                    assert_eq!(guard.0, guard.1);
+                    holders.unread();
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..ITERS {
+                    let mut guard = lock.write();
+                    holders.write();
+                    guard.0 += 1;
+                    guard.1 += 1;
+                    let value = *guard;
+                    holders.unwrite();
+                    let guard = RwLockWriteGuard::downgrade(guard);
+                    holders.read();
+                    assert_eq!(*guard, value);
+                    holders.unread();
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..ITERS {
+                    let guard = lock.upgradable_read();
+                    holders.upgradeable();
+                    let value = *guard;
+                    holders.unupgradeable();
+                    let mut guard = RwLockUpgradableReadGuard::upgrade(guard);
+                    holders.write();
+                    assert_eq!(*guard, value);
+                    guard.0 += 1;
+                    guard.1 += 1;
+                    let value = *guard;
+                    holders.unwrite();
+                    let guard = RwLockWriteGuard::downgrade_to_upgradable(guard);
+                    holders.upgradeable();
+                    assert_eq!(*guard, value);
+                    holders.unupgradeable();
+                    let guard = RwLockUpgradableReadGuard::downgrade(guard);
+                    holders.read();
+                    assert_eq!(*guard, value);
+                    holders.unread();
+                }
+            });
+        });
+        assert_eq!(lock.into_inner(), (2 * ITERS, 2 * ITERS));
+    }
+}
@@ -0,0 +1,569 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+// The lock is the stamped lock of portable-atomic's fallback implementation (src/imp/fallback),
+// which is adapted from https://github.com/crossbeam-rs/crossbeam/blob/crossbeam-utils-0.8.7/crossbeam-utils/src/atomic/seq_lock.rs.
+//
+// Like the fallback implementation, the value is copied in and out with atomic loads and stores of
+// word-sized chunks, instead of non-atomic reads that race with the writer. See the comments in
+// `SeqLock::load` for details. Since the chunks are integers, the value must not contain
+// uninitialized bytes, which `NoPadding` guarantees.
+
+use portable_atomic::{AtomicU8, AtomicUsize, Ordering::Relaxed};
+
+use crate::utils::Backoff;
+
+use core::{cell::UnsafeCell, fmt, mem, mem::MaybeUninit, ptr};
+
+use self::stamp::Stamp;
+
+const WORD: usize = mem::size_of::<usize>();
+
+/// A marker trait for `Copy` types whose values have no padding or otherwise uninitialized bytes.
+///
+/// [`SeqLock`] copies its value with atomic loads and stores of integers, and reading an
+/// uninitialized byte as an integer is undefined behavior, so only values of these types can be
+/// read and written.
+///
+/// This is implemented for integers, floating-point numbers, `bool`, `char`, and arrays of these
+/// types (up to length 32, and some larger powers of two).
+///
+/// # Safety
+///
+/// Every byte of every value of the type must be initialized: the type must have no padding
+/// (for example between fields of different sizes, or at the end of a struct), no
+/// [`MaybeUninit`] fields, and no unions or enums whose variants differ in size. The type also
+/// must not contain pointers or references, since they lose their provenance when copied as
+/// integers.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::{NoPadding, SeqLock};
+///
+/// #[derive(Clone, Copy)]
+/// #[repr(C)]
+/// struct Point {
+///     x: u32,
+///     y: u32,
+/// }
+///
+/// // SAFETY: `Point` is `repr(C)` with two `u32` fields, so it has no padding.
+/// unsafe impl NoPadding for Point {}
+///
+/// let lock = SeqLock::new(Point { x: 1, y: 2 });
+/// assert_eq!(lock.read().y, 2);
+/// ```
+pub unsafe trait NoPadding: Copy {}
+
+macro_rules! impl_no_padding {
+    ($($ty:ty),*) => {$(
+        // SAFETY: primitive integers, floats, `bool`, and `char` have no uninitialized bytes.
+        unsafe impl NoPadding for $ty {}
+    )*};
+}
+impl_no_padding!(
+    u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char
+);
+
+macro_rules! impl_no_padding_array {
+    ($($n:expr),*) => {$(
+        // SAFETY: arrays have no padding between elements, and the elements have no
+        // uninitialized bytes.
+        unsafe impl<T: NoPadding> NoPadding for [T; $n] {}
+    )*};
+}
+impl_no_padding_array!(
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24, 25,
+    26, 27, 28, 29, 30, 31, 32, 64, 128, 256, 512, 1024
+);
+
+/// A sequence lock, which gives readers torn-read-free copies of a `Copy` value without taking a
+/// lock.
+///
+/// Readers copy the value optimistically and retry if a write happened concurrently, so they never
+/// block the writer and never write to shared memory. This is a good fit for small values that are
+/// read much more often than they are written, such as market-data snapshots or sensor frames.
+/// Writers are serialized with a spin lock.
+///
+/// Readers are not lock-free: [`read`](Self::read) spins while a write is in progress, so a
+/// writer that is preempted (or interrupted) in the middle of a write delays readers until it
+/// resumes, and a continuous stream of writes can starve them. [`try_read`](Self::try_read) never
+/// waits.
+///
+/// The value is copied as integers, so `T` must implement [`NoPadding`].
+///
+/// On targets whose pointer width is less than 64 bits, the stamp is made of two words, so that it
+/// does not wrap around in practice.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::SeqLock;
+///
+/// #[derive(Clone, Copy)]
+/// #[repr(C)]
+/// struct Quote {
+///     bid: u64,
+///     ask: u64,
+/// }
+///
+/// // SAFETY: `Quote` is `repr(C)` with two `u64` fields, so it has no padding.
+/// unsafe impl portable_atomic_util::NoPadding for Quote {}
+///
+/// static QUOTE: SeqLock<Quote> = SeqLock::new(Quote { bid: 100, ask: 101 });
+///
+/// QUOTE.write(|quote| {
+///     quote.bid = 102;
+///     quote.ask = 103;
+/// });
+/// let quote = QUOTE.read();
+/// assert_eq!((quote.bid, quote.ask), (102, 103));
+/// ```
+#[repr(C)]
+pub struct SeqLock<T> {
+    // `value` is the first field of a `repr(C)` struct whose alignment is at least that of `usize`
+    // (because of `_align`), so it can be copied in `usize` chunks.
+    value: UnsafeCell<T>,
+    _align: [usize; 0],
+    stamp: Stamp,
+}
+
+// Send is implicitly implemented.
+// SAFETY: readers only get copies of the value, and any data races are prevented by the lock and
+// atomic operations.
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+impl<T> SeqLock<T> {
+    /// Create a new sequence lock holding `value`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::SeqLock;
+    ///
+    /// static FRAME: SeqLock<[u16; 4]> = SeqLock::new([0; 4]);
+    /// assert_eq!(FRAME.read(), [0; 4]);
+    /// ```
+    #[must_use]
+    pub const fn new(value: T) -> Self {
+        Self { value: UnsafeCell::new(value), _align: [], stamp: Stamp::new() }
+    }
+}
+
+impl<T: NoPadding> SeqLock<T> {
+    /// Return a copy of the value.
+    ///
+    /// This retries optimistic reads like [`try_read`](Self::try_read), with exponential
+    /// backoff, until one is not interrupted by a write. It never takes the write lock, so it
+    /// never blocks writers, but it spins while a write is in progress.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::SeqLock;
+    ///
+    /// let lock = SeqLock::new([1, 2]);
+    /// assert_eq!(lock.read(), [1, 2]);
+    /// ```
+    #[must_use]
+    pub fn read(&self) -> T {
+        let mut backoff = Backoff::new();
+        loop {
+            if let Some(value) = self.try_read() {
+                return value;
+            }
+            backoff.snooze();
+        }
+    }
+
+    /// Return a copy of the value, or `None` if a write was in progress or happened during the
+    /// read.
+    ///
+    /// This never waits and never writes to shared memory.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::SeqLock;
+    ///
+    /// let lock = SeqLock::new(5);
+    /// assert_eq!(lock.try_read(), Some(5));
+    /// ```
+    #[must_use]
+    pub fn try_read(&self) -> Option<T> {
+        let stamp = self.stamp.optimistic_read()?;
+        // SAFETY: the copy may be torn if a write happens concurrently, in which case
+        // `validate_read` fails and the copy is discarded without being assumed initialized.
+        let value = unsafe { self.load() };
+        if self.stamp.validate_read(stamp) {
+            // SAFETY: no write happened during the copy, so it is a valid value of `T`.
+            Some(unsafe { value.assume_init() })
+        } else {
+            None
+        }
+    }
+
+    /// Update the value with `f`, spinning until the write lock is available.
+    ///
+    /// `f` is called with a copy of the current value, and the updated copy is then stored back.
+    /// Readers see either the old value or the new value, never a mix of both. If `f` panics, the
+    /// value is not changed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::SeqLock;
+    ///
+    /// let lock = SeqLock::new([0_u32; 3]);
+    /// lock.write(|frame| frame[1] = 7);
+    /// assert_eq!(lock.read(), [0, 7, 0]);
+    /// ```
+    pub fn write<F>(&self, f: F)
+    where
+        F: FnOnce(&mut T),
+    {
+        let guard = self.stamp.write();
+        // SAFETY: we hold the write lock, so there are no concurrent writes.
+        let mut value = unsafe { self.load().assume_init() };
+        f(&mut value);
+        // SAFETY: we hold the write lock.
+        unsafe { self.store(&value) }
+        drop(guard);
+    }
+
+    /// Return a mutable reference to the value.
+    ///
+    /// Since this call borrows the lock mutably, no actual locking needs to take place.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::SeqLock;
+    ///
+    /// let mut lock = SeqLock::new(1);
+    /// *lock.get_mut() += 1;
+    /// assert_eq!(lock.read(), 2);
+    /// ```
+    pub fn get_mut(&mut self) -> &mut T {
+        // SAFETY: the mutable reference guarantees unique ownership.
+        // (UnsafeCell::get_mut requires Rust 1.50)
+        unsafe { &mut *self.value.get() }
+    }
+
+    /// Consume the lock and return the value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::SeqLock;
+    ///
+    /// let lock = SeqLock::new(3);
+    /// assert_eq!(lock.into_inner(), 3);
+    /// ```
+    #[must_use]
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+
+    // Copy the value out in chunks.
+    //
+    // # Safety
+    //
+    // There must be no concurrent calls to `store`, unless the result is discarded without being
+    // assumed initialized.
+    unsafe fn load(&self) -> MaybeUninit<T> {
+        let mut dst = MaybeUninit::<T>::uninit();
+        let src_words = self.value.get() as *const AtomicUsize;
+        let src_bytes = self.value.get() as *const AtomicU8;
+        let dst_ptr = dst.as_mut_ptr() as *mut u8;
+        let words = mem::size_of::<T>() / WORD;
+        // If non-atomic reads were used here, they would cause a data race with concurrent
+        // `store`s. See the comments in optimistic_read in src/imp/fallback/mod.rs of
+        // portable-atomic for why atomic loads of chunks are used instead.
+        //
+        // Reading bytes as integers requires them to be initialized, which `T: NoPadding`
+        // guarantees.
+        for i in 0..words {
+            // SAFETY:
+            // - `src_words` is aligned to `usize` (see the definition of `SeqLock`), and
+            //   `(i + 1) * WORD` is at most the size of `T`.
+            // - There is no writer that updates the value using atomic operations of different
+            //   granularity, and all writes are atomic.
+            unsafe {
+                let chunk = (*src_words.add(i)).load(Relaxed);
+                ptr::write_unaligned(dst_ptr.add(i * WORD) as *mut usize, chunk);
+            }
+        }
+        for i in words * WORD..mem::size_of::<T>() {
+            // SAFETY: `i` is less than the size of `T`, and see above for the rest.
+            unsafe { *dst_ptr.add(i) = (*src_bytes.add(i)).load(Relaxed) }
+        }
+        dst
+    }
+
+    // Copy `value` in, in chunks.
+    //
+    // # Safety
+    //
+    // The write lock must be held by the current context.
+    unsafe fn store(&self, value: &T) {
+        let src = value as *const T as *const u8;
+        let dst_words = self.value.get() as *const AtomicUsize;
+        let dst_bytes = self.value.get() as *const AtomicU8;
+        let words = mem::size_of::<T>() / WORD;
+        for i in 0..words {
+            // SAFETY: see `load`. The caller holds the write lock, so there are no concurrent
+            // stores.
+            unsafe {
+                let chunk = ptr::read_unaligned(src.add(i * WORD) as *const usize);
+                (*dst_words.add(i)).store(chunk, Relaxed);
+            }
+        }
+        for i in words * WORD..mem::size_of::<T>() {
+            // SAFETY: see above.
+            unsafe { (*dst_bytes.add(i)).store(*src.add(i), Relaxed) }
+        }
+    }
+}
+
+impl<T: NoPadding + Default> Default for SeqLock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: NoPadding + fmt::Debug> fmt::Debug for SeqLock<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SeqLock").field("value", &self.read()).finish()
+    }
+}
+
+// Use a "wide" stamp made of two words if the pointer width is less than 64 bits, to prevent it
+// from wrapping around. See src/imp/fallback/seq_lock_wide.rs of portable-atomic for details.
+#[cfg(target_pointer_width = "64")]
+mod stamp {
+    use portable_atomic::{
+        fence, AtomicUsize,
+        Ordering::{Acquire, Relaxed, Release},
+    };
+
+    use crate::utils::Backoff;
+
+    pub(super) struct Stamp {
+        // All bits except the least significant one hold the current stamp. When locked, the state
+        // equals 1 and doesn't contain a valid stamp.
+        state: AtomicUsize,
+    }
+
+    impl Stamp {
+        pub(super) const fn new() -> Self {
+            Self { state: AtomicUsize::new(0) }
+        }
+
+        // If not locked, returns the current stamp.
+        pub(super) fn optimistic_read(&self) -> Option<usize> {
+            let state = self.state.load(Acquire);
+            if state == 1 {
+                None
+            } else {
+                Some(state)
+            }
+        }
+
+        // Returns `true` if the current stamp is equal to `stamp`.
+        pub(super) fn validate_read(&self, stamp: usize) -> bool {
+            fence(Acquire);
+            self.state.load(Relaxed) == stamp
+        }
+
+        pub(super) fn write(&self) -> WriteGuard<'_> {
+            let mut backoff = Backoff::new();
+            loop {
+                let previous = self.state.swap(1, Acquire);
+                if previous != 1 {
+                    fence(Release);
+                    return WriteGuard { stamp: self, state: previous };
+                }
+                while self.state.load(Relaxed) == 1 {
+                    backoff.snooze();
+                }
+            }
+        }
+    }
+
+    // Releases the lock and increments the stamp when dropped.
+    #[must_use]
+    pub(super) struct WriteGuard<'a> {
+        stamp: &'a Stamp,
+        // The stamp before locking.
+        state: usize,
+    }
+
+    impl Drop for WriteGuard<'_> {
+        fn drop(&mut self) {
+            self.stamp.state.store(self.state.wrapping_add(2), Release);
+        }
+    }
+}
+#[cfg(not(target_pointer_width = "64"))]
+mod stamp {
+    use portable_atomic::{
+        fence, AtomicUsize,
+        Ordering::{Acquire, Relaxed, Release},
+    };
+
+    use crate::utils::Backoff;
+
+    pub(super) struct Stamp {
+        // The high bits of the current state.
+        state_hi: AtomicUsize,
+        // The low bits of the current state. All bits except the least significant one hold the
+        // current stamp. When locked, `state_lo` equals 1 and doesn't contain a valid stamp.
+        state_lo: AtomicUsize,
+    }
+
+    impl Stamp {
+        pub(super) const fn new() -> Self {
+            Self { state_hi: AtomicUsize::new(0), state_lo: AtomicUsize::new(0) }
+        }
+
+        // If not locked, returns the current stamp.
+        pub(super) fn optimistic_read(&self) -> Option<(usize, usize)> {
+            // The acquire loads synchronize with the release stores in `WriteGuard::drop`.
+            let state_hi = self.state_hi.load(Acquire);
+            let state_lo = self.state_lo.load(Acquire);
+            if state_lo == 1 {
+                None
+            } else {
+                Some((state_hi, state_lo))
+            }
+        }
+
+        // Returns `true` if the current stamp is equal to `stamp`.
+        pub(super) fn validate_read(&self, stamp: (usize, usize)) -> bool {
+            fence(Acquire);
+            // If `state_lo` wrapped around, the acquire ordering ensures we see the new value of
+            // `state_hi`.
+            let state_lo = self.state_lo.load(Acquire);
+            let state_hi = self.state_hi.load(Relaxed);
+            (state_hi, state_lo) == stamp
+        }
+
+        pub(super) fn write(&self) -> WriteGuard<'_> {
+            let mut backoff = Backoff::new();
+            loop {
+                let previous = self.state_lo.swap(1, Acquire);
+                if previous != 1 {
+                    fence(Release);
+                    return WriteGuard { stamp: self, state_lo: previous };
+                }
+                while self.state_lo.load(Relaxed) == 1 {
+                    backoff.snooze();
+                }
+            }
+        }
+    }
+
+    // Releases the lock and increments the stamp when dropped.
+    #[must_use]
+    pub(super) struct WriteGuard<'a> {
+        stamp: &'a Stamp,
+        // The low bits of the stamp before locking.
+        state_lo: usize,
+    }
+
+    impl Drop for WriteGuard<'_> {
+        fn drop(&mut self) {
+            let state_lo = self.state_lo.wrapping_add(2);
+            // Increase the high bits if the low bits wrap around.
+            if state_lo == 0 {
+                let state_hi = self.stamp.state_hi.load(Relaxed);
+                self.stamp.state_hi.store(state_hi.wrapping_add(1), Release);
+            }
+            self.stamp.state_lo.store(state_lo, Release);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use portable_atomic::{AtomicBool, Ordering::Relaxed};
+    use std::{panic, thread};
+
+    // Check that readers never see a torn value while writers update all elements together.
+    fn check_no_torn_reads<T>()
+    where
+        T: NoPadding + Default + Send + AsRef<[u8]> + AsMut<[u8]> + fmt::Debug,
+    {
+        const WRITERS: usize = 2;
+        const READERS: usize = 2;
+        const WRITES: usize = 10_000;
+        let lock = SeqLock::new(T::default());
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..READERS {
+                s.spawn(|| {
+                    while !done.load(Relaxed) {
+                        let value = lock.read();
+                        let value = value.as_ref();
+                        assert!(value.iter().all(|&v| v == value[0]), "torn read: {:?}", value);
+                        if let Some(value) = lock.try_read() {
+                            let value = value.as_ref();
+                            assert!(value.iter().all(|&v| v == value[0]));
+                        }
+                    }
+                });
+            }
+            let writers: std::vec::Vec<_> = (0..WRITERS)
+                .map(|_| {
+                    s.spawn(|| {
+                        for _ in 0..WRITES {
+                            lock.write(|value| {
+                                let value = value.as_mut();
+                                let next = value[0].wrapping_add(1);
+                                for v in value.iter_mut() {
+                                    *v = next;
+                                }
+                            });
+                        }
+                    })
+                })
+                .collect();
+            for writer in writers {
+                writer.join().unwrap();
+            }
+            done.store(true, Relaxed);
+        });
+        // Writers are serialized, so no write is lost.
+        #[allow(clippy::cast_possible_truncation)]
+        let expected = (WRITERS * WRITES) as u8;
+        assert!(lock.read().as_ref().iter().all(|&v| v == expected));
+    }
+
+    #[test]
+    fn no_torn_reads() {
+        // Sizes that are a multiple of the word size, smaller than a word, and with trailing
+        // bytes after the last whole word.
+        check_no_torn_reads::<[u8; 16]>();
+        check_no_torn_reads::<[u8; 3]>();
+        check_no_torn_reads::<[u8; 13]>();
+    }
+
+    #[test]
+    fn write_panic_keeps_value() {
+        let lock = SeqLock::new([1_u32, 2]);
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            lock.write(|value| {
+                value[0] = 10;
+                panic!();
+            });
+        }));
+        assert!(res.is_err());
+        assert_eq!(lock.read(), [1, 2]);
+        // The lock was released.
+        lock.write(|value| value[1] = 20);
+        assert_eq!(lock.try_read(), Some([1, 20]));
+    }
+}
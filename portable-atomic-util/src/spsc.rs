@@ -0,0 +1,616 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A bounded single-producer single-consumer queue.
+//!
+//! The queue only uses atomic loads and stores (no CAS), and the handles can be used from
+//! interrupt handlers. This module is available even if this crate's `require-cas` feature is
+//! disabled, so it can be used on targets without atomic CAS without providing CAS via
+//! portable-atomic's options (such as `critical-section`).
+//!
+//! # Examples
+//!
+//! ```
+//! use portable_atomic_util::spsc::Queue;
+//! use std::thread;
+//!
+//! let mut queue: Queue<u32, 4> = Queue::new();
+//! let (mut producer, mut consumer) = queue.split();
+//!
+//! thread::scope(|s| {
+//!     s.spawn(move || {
+//!         for i in 0..100 {
+//!             while producer.push(i).is_err() {}
+//!         }
+//!     });
+//!     let mut expected = 0;
+//!     while expected < 100 {
+//!         if let Some(v) = consumer.pop() {
+//!             assert_eq!(v, expected);
+//!             expected += 1;
+//!         }
+//!     }
+//! });
+//! ```
+//!
+//! To hand the producer and consumer to interrupt handlers or other `'static` contexts, use
+//! [`Queue::split_static`] on a queue in a `static` (requires the `require-cas` feature), or split
+//! a `&'static mut Queue`, for example one obtained from `Box::leak`.
+
+// Both indices run from 0 to 2 * N - 1, so that a full queue (len == N) and an empty queue
+// (len == 0) can be distinguished without wasting a slot. The slot for an index `i` is `i % N`.
+//
+// `head` is only written by the consumer and `tail` is only written by the producer. Each side
+// reads its own index with `Relaxed` ordering and the other side's index with `Acquire` ordering,
+// and publishes its index with `Release` ordering after reading or writing the slots.
+
+#[cfg(feature = "require-cas")]
+use portable_atomic::AtomicBool;
+use portable_atomic::{
+    AtomicUsize,
+    Ordering::{Acquire, Relaxed, Release},
+};
+
+use crate::utils::CachePadded;
+
+use core::{cell::UnsafeCell, fmt, marker::PhantomData, mem::MaybeUninit, ptr};
+
+/// A bounded single-producer single-consumer queue with capacity `N`.
+///
+/// Use [`split`](Self::split) to get the [`Producer`] and [`Consumer`] handles.
+pub struct Queue<T, const N: usize> {
+    head: CachePadded<AtomicUsize>,
+    tail: CachePadded<AtomicUsize>,
+    buffer: UnsafeCell<MaybeUninit<[T; N]>>,
+    // Whether `split_static` has returned the handles.
+    #[cfg(feature = "require-cas")]
+    split: AtomicBool,
+}
+
+/// The producer handle of a [`Queue`].
+pub struct Producer<'a, T, const N: usize> {
+    queue: &'a Queue<T, N>,
+    // Not `Sync`: only one thread can push at a time.
+    _marker: PhantomData<*const ()>,
+}
+
+/// The consumer handle of a [`Queue`].
+pub struct Consumer<'a, T, const N: usize> {
+    queue: &'a Queue<T, N>,
+    // Not `Sync`: only one thread can pop at a time.
+    _marker: PhantomData<*const ()>,
+}
+
+// SAFETY: A shared reference to the queue only allows reading the indices and splitting it with
+// `split_static`, which hands out the handles at most once. Values are only moved through the
+// handles, which require `T: Send` to be sent to another thread.
+unsafe impl<T: Send, const N: usize> Sync for Queue<T, N> {}
+// SAFETY: The producer only moves values of type `T` into the queue.
+unsafe impl<T: Send, const N: usize> Send for Producer<'_, T, N> {}
+// SAFETY: The consumer only moves values of type `T` out of the queue.
+unsafe impl<T: Send, const N: usize> Send for Consumer<'_, T, N> {}
+
+impl<T, const N: usize> Queue<T, N> {
+    /// Create a new empty queue.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::spsc::Queue;
+    ///
+    /// let queue: Queue<u8, 16> = Queue::new();
+    /// assert!(queue.is_empty());
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            head: CachePadded::new(AtomicUsize::new(0)),
+            tail: CachePadded::new(AtomicUsize::new(0)),
+            buffer: UnsafeCell::new(MaybeUninit::uninit()),
+            #[cfg(feature = "require-cas")]
+            split: AtomicBool::new(false),
+        }
+    }
+
+    /// Split the queue into its producer and consumer handles.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::spsc::Queue;
+    ///
+    /// let mut queue: Queue<u8, 2> = Queue::new();
+    /// let (mut producer, mut consumer) = queue.split();
+    /// producer.push(1).unwrap();
+    /// assert_eq!(consumer.pop(), Some(1));
+    /// ```
+    pub fn split(&mut self) -> (Producer<'_, T, N>, Consumer<'_, T, N>) {
+        (
+            Producer { queue: self, _marker: PhantomData },
+            Consumer { queue: self, _marker: PhantomData },
+        )
+    }
+
+    /// Split a `'static` queue into its producer and consumer handles.
+    ///
+    /// This returns the handles only on the first call and `None` on later calls, so that a queue
+    /// in a `static` can be split without `unsafe` code, and the handles can be moved into
+    /// interrupt handlers or threads.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::spsc::Queue;
+    ///
+    /// static QUEUE: Queue<u8, 4> = Queue::new();
+    ///
+    /// let (mut producer, mut consumer) = QUEUE.split_static().unwrap();
+    /// assert!(QUEUE.split_static().is_none());
+    /// std::thread::spawn(move || producer.push(1).unwrap()).join().unwrap();
+    /// assert_eq!(consumer.pop(), Some(1));
+    /// ```
+    #[cfg(feature = "require-cas")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "require-cas")))]
+    pub fn split_static(
+        &'static self,
+    ) -> Option<(Producer<'static, T, N>, Consumer<'static, T, N>)> {
+        if self.split.swap(true, Relaxed) {
+            return None;
+        }
+        Some((
+            Producer { queue: self, _marker: PhantomData },
+            Consumer { queue: self, _marker: PhantomData },
+        ))
+    }
+
+    /// Return the capacity of the queue.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::spsc::Queue;
+    ///
+    /// let queue: Queue<u8, 16> = Queue::new();
+    /// assert_eq!(queue.capacity(), 16);
+    /// ```
+    #[allow(clippy::unused_self)]
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Return the number of elements in the queue.
+    ///
+    /// If the producer or consumer is in use on another thread, the result is only approximate:
+    /// the two indices are loaded separately and may have changed in between.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::spsc::Queue;
+    ///
+    /// let mut queue: Queue<u8, 16> = Queue::new();
+    /// queue.split().0.push(1).unwrap();
+    /// assert_eq!(queue.len(), 1);
+    /// ```
+    #[must_use]
+    pub fn len(&self) -> usize {
+        let len = distance(self.head.load(Acquire), self.tail.load(Acquire), N);
+        // If the consumer popped and the producer pushed between the two loads, the indices may
+        // be more than `N` apart.
+        if len > N {
+            N
+        } else {
+            len
+        }
+    }
+
+    /// Return `true` if the queue is empty.
+    ///
+    /// Like [`len`](Self::len), this is only approximate while the handles are in use.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return `true` if the queue is full.
+    ///
+    /// Like [`len`](Self::len), this is only approximate while the handles are in use.
+    #[must_use]
+    pub fn is_full(&self) -> bool {
+        self.len() == N
+    }
+
+    #[inline]
+    fn slot(&self, index: usize) -> *mut T {
+        let index = if index >= N { index - N } else { index };
+        // SAFETY: `index < N`, so the pointer is within the buffer.
+        unsafe { (self.buffer.get() as *mut T).add(index) }
+    }
+}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for Queue<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Queue").field("len", &self.len()).field("capacity", &N).finish()
+    }
+}
+
+impl<T, const N: usize> Drop for Queue<T, N> {
+    fn drop(&mut self) {
+        let mut head = self.head.load(Relaxed);
+        let tail = self.tail.load(Relaxed);
+        while head != tail {
+            // SAFETY: Slots between head and tail are initialized, and we have exclusive access.
+            unsafe { ptr::drop_in_place(self.slot(head)) }
+            head = next(head, N);
+        }
+    }
+}
+
+impl<T, const N: usize> Producer<'_, T, N> {
+    /// Push a value into the queue.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(value)` if the queue is full.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::spsc::Queue;
+    ///
+    /// let mut queue: Queue<u8, 1> = Queue::new();
+    /// let (mut producer, _) = queue.split();
+    /// assert_eq!(producer.push(1), Ok(()));
+    /// assert_eq!(producer.push(2), Err(2));
+    /// ```
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let queue = self.queue;
+        let tail = queue.tail.load(Relaxed);
+        if distance(queue.head.load(Acquire), tail, N) == N {
+            return Err(value);
+        }
+        // SAFETY: The slot at `tail` is not visible to the consumer until we publish the new
+        // tail, and only the producer writes to it.
+        unsafe { queue.slot(tail).write(value) }
+        queue.tail.store(next(tail, N), Release);
+        Ok(())
+    }
+
+    /// Return the number of elements that can be pushed before the queue is full.
+    ///
+    /// Since only the producer can push, the returned number of elements can always be pushed.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::spsc::Queue;
+    ///
+    /// let mut queue: Queue<u8, 4> = Queue::new();
+    /// let (mut producer, _) = queue.split();
+    /// producer.push(1).unwrap();
+    /// assert_eq!(producer.free_len(), 3);
+    /// ```
+    #[must_use]
+    pub fn free_len(&self) -> usize {
+        N - distance(self.queue.head.load(Acquire), self.queue.tail.load(Relaxed), N)
+    }
+
+    /// Return `true` if the queue is full.
+    #[must_use]
+    pub fn is_full(&self) -> bool {
+        self.free_len() == 0
+    }
+
+    /// Return the capacity of the queue.
+    #[allow(clippy::unused_self)]
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T: Copy, const N: usize> Producer<'_, T, N> {
+    /// Push as many values from `values` as fit into the queue, and return the number of values
+    /// pushed.
+    ///
+    /// The values are copied with at most two `memcpy`s and published to the consumer at once.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::spsc::Queue;
+    ///
+    /// let mut queue: Queue<u8, 4> = Queue::new();
+    /// let (mut producer, mut consumer) = queue.split();
+    /// assert_eq!(producer.push_slice(&[1, 2, 3, 4, 5]), 4);
+    /// let mut buf = [0; 8];
+    /// assert_eq!(consumer.pop_slice(&mut buf), 4);
+    /// assert_eq!(buf[..4], [1, 2, 3, 4]);
+    /// ```
+    pub fn push_slice(&mut self, values: &[T]) -> usize {
+        let queue = self.queue;
+        let tail = queue.tail.load(Relaxed);
+        let free = N - distance(queue.head.load(Acquire), tail, N);
+        let count = if values.len() < free { values.len() } else { free };
+        let start = if tail >= N { tail - N } else { tail };
+        let first = if count < N - start { count } else { N - start };
+        // SAFETY: The `count` slots starting at `tail` are free and not visible to the consumer
+        // until we publish the new tail. `first` slots fit before the end of the buffer and the
+        // rest wrap around to its start.
+        unsafe {
+            ptr::copy_nonoverlapping(values.as_ptr(), queue.slot(start), first);
+            ptr::copy_nonoverlapping(values.as_ptr().add(first), queue.slot(0), count - first);
+        }
+        queue.tail.store(advance(tail, count, N), Release);
+        count
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for Producer<'_, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Producer").field("free_len", &self.free_len()).finish()
+    }
+}
+
+impl<T, const N: usize> Consumer<'_, T, N> {
+    /// Pop a value from the queue, or return `None` if the queue is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::spsc::Queue;
+    ///
+    /// let mut queue: Queue<u8, 4> = Queue::new();
+    /// let (mut producer, mut consumer) = queue.split();
+    /// assert_eq!(consumer.pop(), None);
+    /// producer.push(1).unwrap();
+    /// assert_eq!(consumer.pop(), Some(1));
+    /// ```
+    pub fn pop(&mut self) -> Option<T> {
+        let queue = self.queue;
+        let head = queue.head.load(Relaxed);
+        if head == queue.tail.load(Acquire) {
+            return None;
+        }
+        // SAFETY: The slot at `head` was initialized by the producer before it published the
+        // tail, and the producer does not reuse it until we publish the new head.
+        let value = unsafe { queue.slot(head).read() };
+        queue.head.store(next(head, N), Release);
+        Some(value)
+    }
+
+    /// Return a reference to the next value in the queue without removing it, or `None` if the
+    /// queue is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::spsc::Queue;
+    ///
+    /// let mut queue: Queue<u8, 4> = Queue::new();
+    /// let (mut producer, mut consumer) = queue.split();
+    /// producer.push(1).unwrap();
+    /// assert_eq!(consumer.peek(), Some(&1));
+    /// assert_eq!(consumer.pop(), Some(1));
+    /// ```
+    #[must_use]
+    pub fn peek(&self) -> Option<&T> {
+        let queue = self.queue;
+        let head = queue.head.load(Relaxed);
+        if head == queue.tail.load(Acquire) {
+            return None;
+        }
+        // SAFETY: The slot at `head` is initialized and is not modified until it is popped,
+        // which requires `&mut self`.
+        Some(unsafe { &*queue.slot(head) })
+    }
+
+    /// Return the number of elements in the queue.
+    ///
+    /// Since only the consumer can pop, the returned number of elements can always be popped.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        distance(self.queue.head.load(Relaxed), self.queue.tail.load(Acquire), N)
+    }
+
+    /// Return `true` if the queue is empty.
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the capacity of the queue.
+    #[allow(clippy::unused_self)]
+    #[must_use]
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T: Copy, const N: usize> Consumer<'_, T, N> {
+    /// Pop as many values as fit into `buf`, and return the number of values popped.
+    ///
+    /// The values are copied with at most two `memcpy`s and released to the producer at once.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::spsc::Queue;
+    ///
+    /// let mut queue: Queue<u8, 4> = Queue::new();
+    /// let (mut producer, mut consumer) = queue.split();
+    /// producer.push_slice(&[1, 2, 3]);
+    /// let mut buf = [0; 2];
+    /// assert_eq!(consumer.pop_slice(&mut buf), 2);
+    /// assert_eq!(buf, [1, 2]);
+    /// assert_eq!(consumer.pop(), Some(3));
+    /// ```
+    pub fn pop_slice(&mut self, buf: &mut [T]) -> usize {
+        let queue = self.queue;
+        let head = queue.head.load(Relaxed);
+        let len = distance(head, queue.tail.load(Acquire), N);
+        let count = if buf.len() < len { buf.len() } else { len };
+        let start = if head >= N { head - N } else { head };
+        let first = if count < N - start { count } else { N - start };
+        // SAFETY: The `count` slots starting at `head` were initialized by the producer before it
+        // published the tail, and the producer does not reuse them until we publish the new head.
+        unsafe {
+            ptr::copy_nonoverlapping(queue.slot(start), buf.as_mut_ptr(), first);
+            ptr::copy_nonoverlapping(queue.slot(0), buf.as_mut_ptr().add(first), count - first);
+        }
+        queue.head.store(advance(head, count, N), Release);
+        count
+    }
+}
+
+impl<T, const N: usize> fmt::Debug for Consumer<'_, T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Consumer").field("len", &self.len()).finish()
+    }
+}
+
+/// Return the number of elements between `head` and `tail`.
+#[inline]
+fn distance(head: usize, tail: usize, n: usize) -> usize {
+    if tail >= head {
+        tail - head
+    } else {
+        tail + 2 * n - head
+    }
+}
+
+/// Return the index after `index`.
+#[inline]
+fn next(index: usize, n: usize) -> usize {
+    advance(index, 1, n)
+}
+
+/// Return the index `count` elements after `index`.
+#[inline]
+fn advance(index: usize, count: usize, n: usize) -> usize {
+    let index = index + count;
+    if index >= 2 * n {
+        index - 2 * n
+    } else {
+        index
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::DropCounter;
+
+    use std::{thread, vec::Vec};
+
+    #[test]
+    fn slice_wraparound() {
+        let mut queue: Queue<usize, 5> = Queue::new();
+        let (mut producer, mut consumer) = queue.split();
+        let mut buf = [0; 4];
+        let mut next_push = 0;
+        let mut next_pop = 0;
+        // Different push and pop sizes move the indices through every offset and across the
+        // `2 * N` wraparound of the indices.
+        for round in 0..50 {
+            let values: Vec<_> = (next_push..next_push + 3 + round % 3).collect();
+            let free = producer.free_len();
+            let pushed = producer.push_slice(&values);
+            assert_eq!(pushed, values.len().min(free));
+            next_push += pushed;
+            let popped = consumer.pop_slice(&mut buf[..=round % 4]);
+            for &v in &buf[..popped] {
+                assert_eq!(v, next_pop);
+                next_pop += 1;
+            }
+            assert_eq!(consumer.len(), next_push - next_pop);
+            assert_eq!(producer.free_len(), 5 - (next_push - next_pop));
+        }
+        while let Some(v) = consumer.pop() {
+            assert_eq!(v, next_pop);
+            next_pop += 1;
+        }
+        assert_eq!(next_pop, next_push);
+    }
+
+    #[test]
+    fn drop_remaining() {
+        let count = AtomicUsize::new(0);
+        {
+            let mut queue: Queue<DropCounter<'_>, 3> = Queue::new();
+            let (mut producer, mut consumer) = queue.split();
+            // Move the indices so that the remaining elements wrap around the end of the buffer.
+            for _ in 0..2 {
+                assert!(producer.push(DropCounter(&count)).is_ok());
+                drop(consumer.pop());
+            }
+            for _ in 0..3 {
+                assert!(producer.push(DropCounter(&count)).is_ok());
+            }
+            assert!(producer.push(DropCounter(&count)).is_err());
+            assert_eq!(count.load(Relaxed), 3);
+            assert_eq!(queue.len(), 3);
+        }
+        assert_eq!(count.load(Relaxed), 6);
+    }
+
+    #[cfg(feature = "require-cas")]
+    #[test]
+    fn split_static_once() {
+        static QUEUE: Queue<usize, 2> = Queue::new();
+        let (mut producer, mut consumer) = QUEUE.split_static().unwrap();
+        assert!(QUEUE.split_static().is_none());
+        producer.push(1).unwrap();
+        assert!(QUEUE.split_static().is_none());
+        assert_eq!(consumer.pop(), Some(1));
+    }
+
+    #[test]
+    fn concurrent_push_pop() {
+        const COUNT: usize = 10_000;
+        let mut queue: Queue<usize, 8> = Queue::new();
+        let (mut producer, mut consumer) = queue.split();
+        thread::scope(|s| {
+            s.spawn(move || {
+                let mut i = 0;
+                while i < COUNT {
+                    if i % 3 == 0 {
+                        let values: Vec<_> = (i..COUNT.min(i + 5)).collect();
+                        i += producer.push_slice(&values);
+                    } else if producer.push(i).is_ok() {
+                        i += 1;
+                    }
+                    if producer.is_full() {
+                        thread::yield_now();
+                    }
+                }
+            });
+            let mut expected = 0;
+            let mut buf = [0; 3];
+            while expected < COUNT {
+                if expected % 2 == 0 {
+                    let popped = consumer.pop_slice(&mut buf);
+                    for &v in &buf[..popped] {
+                        assert_eq!(v, expected);
+                        expected += 1;
+                    }
+                } else if let Some(v) = consumer.pop() {
+                    assert_eq!(v, expected);
+                    expected += 1;
+                }
+                if consumer.is_empty() {
+                    thread::yield_now();
+                }
+            }
+            assert!(consumer.is_empty());
+        });
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,701 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! ABA-safe lock-free stacks.
+//!
+//! - [`TreiberStack`] is an unbounded stack of values. Popped nodes are recycled instead of freed,
+//!   so it does not need a memory reclamation scheme.
+//! - [`FreeList`] is an intrusive stack of borrowed nodes, which is useful as the free list of an
+//!   object pool.
+//!
+//! Both are Treiber stacks whose head is a `(pointer, generation)` pair updated with a
+//! double-width compare-and-swap. The generation is incremented on every update, so a pop that
+//! read a node that has since been popped and pushed again (the ABA problem) fails and retries.
+//!
+//! The double-width compare-and-swap uses portable-atomic's `AtomicU128` on 64-bit targets and
+//! `AtomicU64` on 32-bit targets. portable-atomic uses native instructions where the CPU supports
+//! them, detecting them at run time where needed (such as `cmpxchg16b` on x86_64), and a fallback
+//! implementation otherwise. If the `fallback` feature is disabled and the target has no native
+//! double-width atomics, the head is protected by a spin lock instead.
+//! [`TreiberStack::is_lock_free`] and [`FreeList::is_lock_free`] return whether the stacks are
+//! lock-free on the current CPU.
+
+use portable_atomic::{AtomicPtr, Ordering::Relaxed};
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+use alloc::boxed::Box;
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+use core::cell::UnsafeCell;
+use core::{fmt, marker::PhantomData, ptr};
+
+// Tagged head
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Tagged {
+    ptr: *mut (),
+    tag: usize,
+}
+
+// The head of a stack, packing the pointer in the low half and the generation in the high half of
+// `$atomic`.
+#[allow(unused_macros)]
+macro_rules! tagged_head {
+    ($atomic:ident, $int:ident, $bits:expr) => {
+        struct TaggedHead {
+            value: portable_atomic::$atomic,
+        }
+
+        impl TaggedHead {
+            const fn new() -> Self {
+                Self { value: portable_atomic::$atomic::new(0) }
+            }
+
+            fn is_lock_free() -> bool {
+                portable_atomic::$atomic::is_lock_free()
+            }
+
+            fn pack(value: Tagged) -> $int {
+                ((value.tag as $int) << $bits) | value.ptr as usize as $int
+            }
+
+            #[allow(clippy::cast_possible_truncation)]
+            fn unpack(value: $int) -> Tagged {
+                Tagged { ptr: value as usize as *mut (), tag: (value >> $bits) as usize }
+            }
+
+            fn load(&self) -> Tagged {
+                Self::unpack(self.value.load(portable_atomic::Ordering::Acquire))
+            }
+
+            fn compare_exchange_weak(&self, current: Tagged, new: *mut ()) -> Result<(), Tagged> {
+                let new = Tagged { ptr: new, tag: current.tag.wrapping_add(1) };
+                match self.value.compare_exchange_weak(
+                    Self::pack(current),
+                    Self::pack(new),
+                    portable_atomic::Ordering::AcqRel,
+                    portable_atomic::Ordering::Acquire,
+                ) {
+                    Ok(_) => Ok(()),
+                    Err(value) => Err(Self::unpack(value)),
+                }
+            }
+        }
+    };
+}
+
+// The head of a stack, using a spin lock. This is used if the double-width atomic type for this
+// target does not exist.
+#[allow(unused_macros)]
+macro_rules! locked_tagged_head {
+    () => {
+        struct TaggedHead {
+            lock: crate::RawSpinMutex,
+            value: core::cell::UnsafeCell<Tagged>,
+        }
+
+        // SAFETY: `value` is only accessed while holding `lock`.
+        unsafe impl Sync for TaggedHead {}
+        // SAFETY: See above.
+        unsafe impl Send for TaggedHead {}
+
+        impl TaggedHead {
+            const fn new() -> Self {
+                Self {
+                    lock: crate::RawSpinMutex::new(),
+                    value: core::cell::UnsafeCell::new(Tagged { ptr: ptr::null_mut(), tag: 0 }),
+                }
+            }
+
+            fn is_lock_free() -> bool {
+                false
+            }
+
+            fn load(&self) -> Tagged {
+                self.lock.lock();
+                // SAFETY: We hold the lock.
+                let value = unsafe { *self.value.get() };
+                // SAFETY: We hold the lock.
+                unsafe { self.lock.unlock() }
+                value
+            }
+
+            fn compare_exchange_weak(&self, current: Tagged, new: *mut ()) -> Result<(), Tagged> {
+                self.lock.lock();
+                // SAFETY: We hold the lock.
+                let value = unsafe { &mut *self.value.get() };
+                let result = if *value == current {
+                    *value = Tagged { ptr: new, tag: current.tag.wrapping_add(1) };
+                    Ok(())
+                } else {
+                    Err(*value)
+                };
+                // SAFETY: We hold the lock.
+                unsafe { self.lock.unlock() }
+                result
+            }
+        }
+    };
+}
+
+#[cfg(target_pointer_width = "64")]
+cfg_has_atomic_128! {
+    tagged_head!(AtomicU128, u128, 64);
+}
+#[cfg(target_pointer_width = "64")]
+cfg_no_atomic_128! {
+    locked_tagged_head!();
+}
+#[cfg(target_pointer_width = "32")]
+cfg_has_atomic_64! {
+    tagged_head!(AtomicU64, u64, 32);
+}
+#[cfg(target_pointer_width = "32")]
+cfg_no_atomic_64! {
+    locked_tagged_head!();
+}
+#[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
+locked_tagged_head!();
+
+// TreiberStack
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+struct Node<T> {
+    next: AtomicPtr<Node<T>>,
+    // Only accessed by the thread that owns the node: the pusher before the node is pushed, and
+    // the popper after it is popped.
+    value: UnsafeCell<Option<T>>,
+}
+
+/// An unbounded lock-free stack.
+///
+/// Nodes of popped values are kept in an internal free list and reused by later pushes, and are
+/// only freed when the stack is dropped. This means the stack does not need a memory reclamation
+/// scheme, but its memory usage is proportional to the maximum number of values it held.
+///
+/// See the [module-level documentation](self) for how ABA safety is ensured.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::stack::TreiberStack;
+///
+/// let stack = TreiberStack::new();
+/// stack.push(1);
+/// stack.push(2);
+/// assert_eq!(stack.pop(), Some(2));
+/// assert_eq!(stack.pop(), Some(1));
+/// assert_eq!(stack.pop(), None);
+/// ```
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+#[cfg_attr(docsrs, doc(cfg(any(feature = "alloc", feature = "std"))))]
+pub struct TreiberStack<T> {
+    head: TaggedHead,
+    free: TaggedHead,
+    _marker: PhantomData<Box<Node<T>>>,
+}
+
+// SAFETY: Values are moved in and out of the stack, and each is accessed by only one thread.
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+unsafe impl<T: Send> Send for TreiberStack<T> {}
+// SAFETY: See above.
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+unsafe impl<T: Send> Sync for TreiberStack<T> {}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+impl<T> TreiberStack<T> {
+    /// Create a new empty stack.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::stack::TreiberStack;
+    ///
+    /// static STACK: TreiberStack<u32> = TreiberStack::new();
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { head: TaggedHead::new(), free: TaggedHead::new(), _marker: PhantomData }
+    }
+
+    /// Return `true` if the stack is lock-free on this target and CPU.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::stack::TreiberStack;
+    ///
+    /// let is_lock_free = TreiberStack::<u32>::is_lock_free();
+    /// ```
+    #[must_use]
+    pub fn is_lock_free() -> bool {
+        TaggedHead::is_lock_free()
+    }
+
+    /// Push a value onto the stack.
+    ///
+    /// This reuses the node of a popped value if there is one, and allocates a new node
+    /// otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::stack::TreiberStack;
+    ///
+    /// let stack = TreiberStack::new();
+    /// stack.push(1);
+    /// assert!(!stack.is_empty());
+    /// ```
+    pub fn push(&self, value: T) {
+        let node = match Self::pop_node(&self.free) {
+            Some(node) => node,
+            None => Box::into_raw(Box::new(Node {
+                next: AtomicPtr::new(ptr::null_mut()),
+                value: UnsafeCell::new(None),
+            })),
+        };
+        // SAFETY: We own the node until it is pushed.
+        unsafe { *(*node).value.get() = Some(value) }
+        Self::push_node(&self.head, node);
+    }
+
+    /// Pop the value on the top of the stack, or return `None` if the stack is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::stack::TreiberStack;
+    ///
+    /// let stack = TreiberStack::new();
+    /// stack.push(1);
+    /// assert_eq!(stack.pop(), Some(1));
+    /// assert_eq!(stack.pop(), None);
+    /// ```
+    pub fn pop(&self) -> Option<T> {
+        let node = Self::pop_node(&self.head)?;
+        // SAFETY: We own the node after popping it.
+        let value = unsafe { (*(*node).value.get()).take() };
+        Self::push_node(&self.free, node);
+        value
+    }
+
+    /// Return `true` if the stack is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::stack::TreiberStack;
+    ///
+    /// let stack = TreiberStack::<u32>::new();
+    /// assert!(stack.is_empty());
+    /// ```
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.head.load().ptr.is_null()
+    }
+
+    fn push_node(head: &TaggedHead, node: *mut Node<T>) {
+        let mut current = head.load();
+        loop {
+            // SAFETY: Nodes are not freed while the stack is alive.
+            unsafe { (*node).next.store(current.ptr as *mut Node<T>, Relaxed) }
+            match head.compare_exchange_weak(current, node as *mut ()) {
+                Ok(()) => return,
+                Err(c) => current = c,
+            }
+        }
+    }
+
+    fn pop_node(head: &TaggedHead) -> Option<*mut Node<T>> {
+        let mut current = head.load();
+        loop {
+            let node = current.ptr as *mut Node<T>;
+            if node.is_null() {
+                return None;
+            }
+            // SAFETY: Nodes are not freed while the stack is alive. The node may have been popped
+            // and pushed again since we loaded it, but then the generation has changed and the
+            // compare-exchange fails.
+            let next = unsafe { (*node).next.load(Relaxed) };
+            match head.compare_exchange_weak(current, next as *mut ()) {
+                Ok(()) => return Some(node),
+                Err(c) => current = c,
+            }
+        }
+    }
+}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+impl<T> Default for TreiberStack<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+impl<T> Drop for TreiberStack<T> {
+    fn drop(&mut self) {
+        for head in &[&self.head, &self.free] {
+            let mut node = head.load().ptr as *mut Node<T>;
+            while !node.is_null() {
+                // SAFETY: We have exclusive access, and the nodes were created by `push`.
+                let n = unsafe { Box::from_raw(node) };
+                node = n.next.load(Relaxed);
+            }
+        }
+    }
+}
+
+#[cfg(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std"))]
+impl<T> fmt::Debug for TreiberStack<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TreiberStack").field("is_empty", &self.is_empty()).finish()
+    }
+}
+
+// FreeList
+
+/// The link embedded in nodes of a [`FreeList`].
+pub struct Link {
+    next: AtomicPtr<()>,
+}
+
+impl Link {
+    /// Create a new link.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::stack::Link;
+    ///
+    /// static LINK: Link = Link::new();
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { next: AtomicPtr::new(ptr::null_mut()) }
+    }
+}
+
+impl Default for Link {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Link").finish()
+    }
+}
+
+/// A node that can be pushed onto a [`FreeList`].
+pub trait Linked {
+    /// Return the link embedded in this node.
+    ///
+    /// This must return the same link every time, and the link must not be used by anything
+    /// else. Otherwise the free list may lose or duplicate nodes, but it is still memory safe.
+    fn link(&self) -> &Link;
+}
+
+impl Linked for Link {
+    fn link(&self) -> &Link {
+        self
+    }
+}
+
+/// An intrusive lock-free stack of borrowed nodes.
+///
+/// Nodes embed a [`Link`] and are borrowed for `'a`, so pushing and popping never allocates and
+/// nodes are never freed by the list. This is intended as the free list of an object pool whose
+/// slots live in a `static` or another long-lived allocation.
+///
+/// A node must not be pushed while it is already in a list; doing so is memory safe, but may lose
+/// nodes.
+///
+/// See the [module-level documentation](self) for how ABA safety is ensured.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic::{AtomicU32, Ordering};
+/// use portable_atomic_util::stack::{FreeList, Link, Linked};
+///
+/// struct Slot {
+///     link: Link,
+///     value: AtomicU32,
+/// }
+///
+/// impl Linked for Slot {
+///     fn link(&self) -> &Link {
+///         &self.link
+///     }
+/// }
+///
+/// static SLOTS: [Slot; 2] = [
+///     Slot { link: Link::new(), value: AtomicU32::new(0) },
+///     Slot { link: Link::new(), value: AtomicU32::new(0) },
+/// ];
+/// static FREE: FreeList<'static, Slot> = FreeList::new();
+///
+/// for slot in &SLOTS {
+///     FREE.push(slot);
+/// }
+/// let a = FREE.pop().unwrap();
+/// let b = FREE.pop().unwrap();
+/// assert!(FREE.pop().is_none());
+/// a.value.store(1, Ordering::Relaxed);
+/// FREE.push(a);
+/// FREE.push(b);
+/// ```
+pub struct FreeList<'a, T> {
+    head: TaggedHead,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<T> FreeList<'_, T> {
+    /// Create a new empty list.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::stack::{FreeList, Link, Linked};
+    ///
+    /// struct Node(Link);
+    /// impl Linked for Node {
+    ///     fn link(&self) -> &Link {
+    ///         &self.0
+    ///     }
+    /// }
+    ///
+    /// static FREE: FreeList<'static, Node> = FreeList::new();
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { head: TaggedHead::new(), _marker: PhantomData }
+    }
+
+    /// Return `true` if the list is lock-free on this target and CPU.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::stack::{FreeList, Link};
+    ///
+    /// let is_lock_free = FreeList::<Link>::is_lock_free();
+    /// ```
+    #[must_use]
+    pub fn is_lock_free() -> bool {
+        TaggedHead::is_lock_free()
+    }
+}
+
+impl<'a, T: Linked> FreeList<'a, T> {
+    /// Push a node onto the list.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::stack::{FreeList, Link};
+    ///
+    /// let link = Link::new();
+    /// let list = FreeList::new();
+    /// list.push(&link);
+    /// assert!(!list.is_empty());
+    /// ```
+    pub fn push(&self, node: &'a T) {
+        let ptr = node as *const T as *mut ();
+        let mut current = self.head.load();
+        loop {
+            node.link().next.store(current.ptr, Relaxed);
+            match self.head.compare_exchange_weak(current, ptr) {
+                Ok(()) => return,
+                Err(c) => current = c,
+            }
+        }
+    }
+
+    /// Pop the node on the top of the list, or return `None` if the list is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::stack::{FreeList, Link};
+    ///
+    /// let link = Link::new();
+    /// let list = FreeList::new();
+    /// list.push(&link);
+    /// assert!(std::ptr::eq(list.pop().unwrap(), &link));
+    /// assert!(list.pop().is_none());
+    /// ```
+    pub fn pop(&self) -> Option<&'a T> {
+        let mut current = self.head.load();
+        loop {
+            // SAFETY: Only nodes borrowed for `'a` are pushed.
+            let node = unsafe { (current.ptr as *const T).as_ref()? };
+            // The node may have been popped and pushed again since we loaded it, but then the
+            // generation has changed and the compare-exchange fails.
+            let next = node.link().next.load(Relaxed);
+            match self.head.compare_exchange_weak(current, next) {
+                Ok(()) => return Some(node),
+                Err(c) => current = c,
+            }
+        }
+    }
+
+    /// Return `true` if the list is empty.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::stack::{FreeList, Link};
+    ///
+    /// let list = FreeList::<Link>::new();
+    /// assert!(list.is_empty());
+    /// ```
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.head.load().ptr.is_null()
+    }
+}
+
+impl<T: Linked> Default for FreeList<'_, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> fmt::Debug for FreeList<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FreeList").field("is_empty", &self.head.load().ptr.is_null()).finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::DropCounter;
+
+    use portable_atomic::{AtomicBool, AtomicUsize};
+    use std::{sync::Barrier, thread, vec::Vec};
+
+    ::quickcheck::quickcheck! {
+        fn quickcheck_sequential(ops: Vec<Option<u8>>) -> bool {
+            let stack = TreiberStack::new();
+            let mut model = Vec::new();
+            for op in ops {
+                match op {
+                    Some(v) => {
+                        stack.push(v);
+                        model.push(v);
+                    }
+                    None => assert_eq!(stack.pop(), model.pop()),
+                }
+                assert_eq!(stack.is_empty(), model.is_empty());
+            }
+            true
+        }
+    }
+
+    #[test]
+    fn concurrent_push_pop() {
+        const THREADS: usize = 4;
+        const N: usize = 10_000;
+        let stack = TreiberStack::new();
+        let barrier = Barrier::new(THREADS);
+        let mut popped = thread::scope(|s| {
+            let handles: Vec<_> = (0..THREADS)
+                .map(|t| {
+                    let (stack, barrier) = (&stack, &barrier);
+                    s.spawn(move || {
+                        barrier.wait();
+                        let mut popped = Vec::new();
+                        for i in 0..N {
+                            stack.push(t * N + i);
+                            if i % 2 == 0 {
+                                popped.extend(stack.pop());
+                            }
+                        }
+                        popped
+                    })
+                })
+                .collect();
+            handles.into_iter().flat_map(|h| h.join().unwrap()).collect::<Vec<_>>()
+        });
+        while let Some(v) = stack.pop() {
+            popped.push(v);
+        }
+        // Every value is popped exactly once.
+        popped.sort_unstable();
+        assert_eq!(popped, (0..THREADS * N).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn drop_elements() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        const THREADS: usize = 4;
+        const N: usize = 1000;
+        let stack = TreiberStack::new();
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for i in 0..N {
+                        stack.push(DropCounter(&DROPS));
+                        if i % 3 == 0 {
+                            drop(stack.pop());
+                        }
+                    }
+                });
+            }
+        });
+        let popped = (0..N).step_by(3).count() * THREADS;
+        assert_eq!(DROPS.load(Relaxed), popped);
+        // Values remaining in the stack are dropped with it.
+        drop(stack);
+        assert_eq!(DROPS.load(Relaxed), THREADS * N);
+    }
+
+    #[test]
+    fn free_list_concurrent() {
+        struct Slot {
+            link: Link,
+            in_use: AtomicBool,
+        }
+        impl Linked for Slot {
+            fn link(&self) -> &Link {
+                &self.link
+            }
+        }
+
+        const THREADS: usize = 4;
+        const N: usize = 10_000;
+        let slots: Vec<_> = (0..THREADS)
+            .map(|_| Slot { link: Link::new(), in_use: AtomicBool::new(false) })
+            .collect();
+        let list = FreeList::new();
+        for slot in &slots {
+            list.push(slot);
+        }
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for _ in 0..N {
+                        if let Some(slot) = list.pop() {
+                            // A node is never handed out to two threads at once.
+                            assert!(!slot.in_use.swap(true, Relaxed));
+                            thread::yield_now();
+                            slot.in_use.store(false, Relaxed);
+                            list.push(slot);
+                        }
+                    }
+                });
+            }
+        });
+        // No node is lost or duplicated.
+        let mut popped = Vec::new();
+        while let Some(slot) = list.pop() {
+            popped.push(slot as *const Slot);
+        }
+        popped.sort_unstable();
+        popped.dedup();
+        assert_eq!(popped.len(), THREADS);
+    }
+}
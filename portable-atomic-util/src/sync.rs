@@ -0,0 +1,832 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+// Barrier, Latch, WaitGroup, and Semaphore.
+//
+// The state of each primitive is kept in atomics, and waiting is delegated to `WaitQueue`. With
+// the `std` feature, `WaitQueue` is a spin-locked list of parked threads: a waiter checks its
+// condition again while holding the lock before parking, and a notifier updates the state before
+// taking the lock to wake up the waiters, so wakeups cannot be lost. Without the `std` feature,
+// waiters spin on their condition instead, and notifying is a no-op, so the primitives can also
+// be signaled from interrupt handlers.
+
+use portable_atomic::{
+    AtomicUsize,
+    Ordering::{AcqRel, Acquire, Relaxed, Release},
+};
+
+#[cfg(feature = "std")]
+use portable_atomic::AtomicBool;
+
+#[cfg(not(feature = "std"))]
+use crate::utils::Backoff;
+#[cfg(feature = "std")]
+use crate::RawSpinMutex;
+
+use core::fmt;
+#[cfg(feature = "std")]
+use core::{
+    cell::{Cell, UnsafeCell},
+    ptr,
+};
+
+/// A barrier that enables `n` threads to synchronize the beginning of some computation.
+///
+/// Unlike [`std::sync::Barrier`], this can be created in a `static` and is available without
+/// the `std` feature. With the `std` feature, waiting threads are parked; otherwise they spin.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::Barrier;
+/// use std::thread;
+///
+/// static BARRIER: Barrier = Barrier::new(4);
+///
+/// let handles: Vec<_> = (0..4).map(|_| thread::spawn(|| BARRIER.wait().is_leader())).collect();
+/// let leaders = handles.into_iter().map(|h| h.join().unwrap());
+/// assert_eq!(leaders.filter(|&is_leader| is_leader).count(), 1);
+/// ```
+///
+/// [`std::sync::Barrier`]: https://doc.rust-lang.org/std/sync/struct.Barrier.html
+pub struct Barrier {
+    n: usize,
+    count: AtomicUsize,
+    generation: AtomicUsize,
+    queue: WaitQueue,
+}
+
+/// A `BarrierWaitResult` is returned by [`Barrier::wait`] when all threads in the barrier have
+/// rendezvoused.
+#[derive(Debug)]
+pub struct BarrierWaitResult {
+    is_leader: bool,
+}
+
+impl BarrierWaitResult {
+    /// Return `true` if this thread is the "leader thread" for the call to [`Barrier::wait`].
+    ///
+    /// Only one thread will have `true` returned from their result; all other threads will have
+    /// `false` returned.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Barrier;
+    ///
+    /// let barrier = Barrier::new(1);
+    /// assert!(barrier.wait().is_leader());
+    /// ```
+    #[must_use]
+    pub fn is_leader(&self) -> bool {
+        self.is_leader
+    }
+}
+
+impl Barrier {
+    /// Create a new barrier that can block `n` threads.
+    ///
+    /// A barrier will block `n - 1` threads which call [`wait`](Self::wait) and then wake up all
+    /// threads at once when the `n`th thread calls `wait`. If `n` is 0 or 1, `wait` never blocks.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Barrier;
+    ///
+    /// static BARRIER: Barrier = Barrier::new(10);
+    /// ```
+    #[must_use]
+    pub const fn new(n: usize) -> Self {
+        Self {
+            n,
+            count: AtomicUsize::new(0),
+            generation: AtomicUsize::new(0),
+            queue: WaitQueue::new(),
+        }
+    }
+
+    /// Block the current thread until all `n` threads have rendezvoused here.
+    ///
+    /// Barriers are re-usable after all threads have rendezvoused once, and can be used
+    /// continuously. Exactly one of the `n` threads is the leader, as reported by
+    /// [`BarrierWaitResult::is_leader`].
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Barrier;
+    /// use std::{sync::Arc, thread};
+    ///
+    /// let barrier = Arc::new(Barrier::new(2));
+    /// let b = Arc::clone(&barrier);
+    /// let handle = thread::spawn(move || b.wait().is_leader());
+    /// let leader = barrier.wait().is_leader();
+    /// assert_ne!(leader, handle.join().unwrap());
+    /// ```
+    pub fn wait(&self) -> BarrierWaitResult {
+        // All `n` threads must arrive before the generation can change, so this is the generation
+        // of this rendezvous.
+        let generation = self.generation.load(Relaxed);
+        let arrived = self.count.fetch_add(1, AcqRel) + 1;
+        if arrived >= self.n {
+            // No other thread can arrive until the generation changes.
+            self.count.store(0, Relaxed);
+            self.generation.store(generation.wrapping_add(1), Release);
+            self.queue.notify_all();
+            BarrierWaitResult { is_leader: true }
+        } else {
+            self.queue.wait_until(|| self.generation.load(Acquire) != generation);
+            BarrierWaitResult { is_leader: false }
+        }
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl fmt::Debug for Barrier {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Barrier").field("n", &self.n).finish()
+    }
+}
+
+/// A single-use counter that threads can count down and wait for to reach zero.
+///
+/// Once the count reaches zero, it stays zero and all [`wait`](Self::wait)s return immediately.
+/// With the `std` feature, waiting threads are parked; otherwise they spin. Counting down never
+/// blocks, so it can be done from interrupt handlers when the `std` feature is not enabled.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::Latch;
+/// use std::thread;
+///
+/// static READY: Latch = Latch::new(3);
+///
+/// for _ in 0..3 {
+///     thread::spawn(|| {
+///         // ... initialize something ...
+///         READY.count_down();
+///     });
+/// }
+/// READY.wait();
+/// assert_eq!(READY.count(), 0);
+/// ```
+pub struct Latch {
+    count: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Latch {
+    /// Create a new latch with the given count.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Latch;
+    ///
+    /// static LATCH: Latch = Latch::new(2);
+    /// assert_eq!(LATCH.count(), 2);
+    /// ```
+    #[must_use]
+    pub const fn new(count: usize) -> Self {
+        Self { count: AtomicUsize::new(count), queue: WaitQueue::new() }
+    }
+
+    /// Decrement the count, waking up the waiting threads if it reaches zero.
+    ///
+    /// If the count is already zero, this does nothing.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Latch;
+    ///
+    /// let latch = Latch::new(1);
+    /// latch.count_down();
+    /// latch.count_down();
+    /// assert_eq!(latch.count(), 0);
+    /// ```
+    pub fn count_down(&self) {
+        let mut count = self.count.load(Relaxed);
+        loop {
+            if count == 0 {
+                return;
+            }
+            match self.count.compare_exchange_weak(count, count - 1, AcqRel, Relaxed) {
+                Ok(_) => break,
+                Err(c) => count = c,
+            }
+        }
+        if count == 1 {
+            self.queue.notify_all();
+        }
+    }
+
+    /// Block the current thread until the count reaches zero.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Latch;
+    /// use std::{sync::Arc, thread};
+    ///
+    /// let latch = Arc::new(Latch::new(1));
+    /// let l = Arc::clone(&latch);
+    /// thread::spawn(move || l.count_down());
+    /// latch.wait();
+    /// ```
+    pub fn wait(&self) {
+        self.queue.wait_until(|| self.try_wait());
+    }
+
+    /// Return `true` if the count has reached zero, without blocking.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Latch;
+    ///
+    /// let latch = Latch::new(1);
+    /// assert!(!latch.try_wait());
+    /// latch.count_down();
+    /// assert!(latch.try_wait());
+    /// ```
+    #[must_use]
+    pub fn try_wait(&self) -> bool {
+        self.count.load(Acquire) == 0
+    }
+
+    /// Return the current count.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Latch;
+    ///
+    /// let latch = Latch::new(2);
+    /// latch.count_down();
+    /// assert_eq!(latch.count(), 1);
+    /// ```
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.count.load(Relaxed)
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl fmt::Debug for Latch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Latch").field("count", &self.count()).finish()
+    }
+}
+
+/// A counter of outstanding tasks that threads can wait for to reach zero.
+///
+/// This is like Go's `sync.WaitGroup`: [`add`](Self::add) registers tasks,
+/// [`done`](Self::done) marks one as finished, and [`wait`](Self::wait) blocks until all
+/// registered tasks are finished. Unlike a [`Latch`], the count can go up again after reaching
+/// zero, so a wait group can be reused.
+///
+/// With the `std` feature, waiting threads are parked; otherwise they spin.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::WaitGroup;
+/// use std::thread;
+///
+/// static TASKS: WaitGroup = WaitGroup::new();
+///
+/// for _ in 0..4 {
+///     TASKS.add(1);
+///     thread::spawn(|| {
+///         // ... do some work ...
+///         TASKS.done();
+///     });
+/// }
+/// TASKS.wait();
+/// assert_eq!(TASKS.count(), 0);
+/// ```
+pub struct WaitGroup {
+    count: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl WaitGroup {
+    /// Create a new wait group with no tasks.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::WaitGroup;
+    ///
+    /// static TASKS: WaitGroup = WaitGroup::new();
+    /// TASKS.wait();
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { count: AtomicUsize::new(0), queue: WaitQueue::new() }
+    }
+
+    /// Add `n` tasks.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the count overflows.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::WaitGroup;
+    ///
+    /// let tasks = WaitGroup::new();
+    /// tasks.add(2);
+    /// assert_eq!(tasks.count(), 2);
+    /// ```
+    pub fn add(&self, n: usize) {
+        let prev = self.count.fetch_add(n, Relaxed);
+        if prev.checked_add(n).is_none() {
+            self.count.fetch_sub(n, Relaxed);
+            panic!("WaitGroup count overflow");
+        }
+    }
+
+    /// Mark one task as finished, waking up the waiting threads if it was the last one.
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are no tasks.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::WaitGroup;
+    ///
+    /// let tasks = WaitGroup::new();
+    /// tasks.add(1);
+    /// tasks.done();
+    /// assert_eq!(tasks.count(), 0);
+    /// ```
+    pub fn done(&self) {
+        let mut count = self.count.load(Relaxed);
+        loop {
+            assert!(count != 0, "WaitGroup::done called more times than tasks were added");
+            match self.count.compare_exchange_weak(count, count - 1, AcqRel, Relaxed) {
+                Ok(_) => break,
+                Err(c) => count = c,
+            }
+        }
+        if count == 1 {
+            self.queue.notify_all();
+        }
+    }
+
+    /// Block the current thread until there are no tasks.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::WaitGroup;
+    /// use std::{sync::Arc, thread};
+    ///
+    /// let tasks = Arc::new(WaitGroup::new());
+    /// tasks.add(1);
+    /// let t = Arc::clone(&tasks);
+    /// thread::spawn(move || t.done());
+    /// tasks.wait();
+    /// ```
+    pub fn wait(&self) {
+        self.queue.wait_until(|| self.count.load(Acquire) == 0);
+    }
+
+    /// Return the current number of tasks.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::WaitGroup;
+    ///
+    /// let tasks = WaitGroup::new();
+    /// assert_eq!(tasks.count(), 0);
+    /// ```
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.count.load(Relaxed)
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl fmt::Debug for WaitGroup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WaitGroup").field("count", &self.count()).finish()
+    }
+}
+
+/// A counting semaphore.
+///
+/// A semaphore holds a number of permits. [`acquire`](Self::acquire) takes a permit, waiting
+/// until one is available, and [`release`](Self::release) returns one. Permits are not tied to
+/// the thread that acquired them.
+///
+/// With the `std` feature, waiting threads are parked; otherwise they spin. Releasing permits
+/// and the `try_*` methods never block, so they can be used from interrupt handlers when the
+/// `std` feature is not enabled.
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic_util::Semaphore;
+/// use std::thread;
+///
+/// // At most 2 concurrent connections.
+/// static CONNECTIONS: Semaphore = Semaphore::new(2);
+///
+/// let handles: Vec<_> = (0..8)
+///     .map(|_| {
+///         thread::spawn(|| {
+///             CONNECTIONS.acquire();
+///             // ... use the connection ...
+///             CONNECTIONS.release();
+///         })
+///     })
+///     .collect();
+/// for handle in handles {
+///     handle.join().unwrap();
+/// }
+/// assert_eq!(CONNECTIONS.available_permits(), 2);
+/// ```
+pub struct Semaphore {
+    permits: AtomicUsize,
+    queue: WaitQueue,
+}
+
+impl Semaphore {
+    /// Create a new semaphore with the given number of permits.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Semaphore;
+    ///
+    /// static SEMAPHORE: Semaphore = Semaphore::new(3);
+    /// assert_eq!(SEMAPHORE.available_permits(), 3);
+    /// ```
+    #[must_use]
+    pub const fn new(permits: usize) -> Self {
+        Self { permits: AtomicUsize::new(permits), queue: WaitQueue::new() }
+    }
+
+    /// Acquire a permit, blocking the current thread until one is available.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Semaphore;
+    ///
+    /// let semaphore = Semaphore::new(1);
+    /// semaphore.acquire();
+    /// assert_eq!(semaphore.available_permits(), 0);
+    /// ```
+    pub fn acquire(&self) {
+        self.acquire_many(1);
+    }
+
+    /// Acquire `n` permits at once, blocking the current thread until they are available.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Semaphore;
+    ///
+    /// let semaphore = Semaphore::new(5);
+    /// semaphore.acquire_many(3);
+    /// assert_eq!(semaphore.available_permits(), 2);
+    /// ```
+    pub fn acquire_many(&self, n: usize) {
+        self.queue.wait_until(|| self.try_acquire_many(n));
+    }
+
+    /// Attempt to acquire a permit without blocking.
+    ///
+    /// Return `true` if a permit was acquired.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Semaphore;
+    ///
+    /// let semaphore = Semaphore::new(1);
+    /// assert!(semaphore.try_acquire());
+    /// assert!(!semaphore.try_acquire());
+    /// ```
+    #[must_use]
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_many(1)
+    }
+
+    /// Attempt to acquire `n` permits at once without blocking.
+    ///
+    /// Return `true` if the permits were acquired. Either all `n` permits are acquired or none.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Semaphore;
+    ///
+    /// let semaphore = Semaphore::new(2);
+    /// assert!(!semaphore.try_acquire_many(3));
+    /// assert!(semaphore.try_acquire_many(2));
+    /// ```
+    #[must_use]
+    pub fn try_acquire_many(&self, n: usize) -> bool {
+        let mut permits = self.permits.load(Relaxed);
+        loop {
+            if permits < n {
+                return false;
+            }
+            match self.permits.compare_exchange_weak(permits, permits - n, Acquire, Relaxed) {
+                Ok(_) => return true,
+                Err(p) => permits = p,
+            }
+        }
+    }
+
+    /// Release a permit, waking up the waiting threads.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Semaphore;
+    ///
+    /// let semaphore = Semaphore::new(0);
+    /// semaphore.release();
+    /// assert!(semaphore.try_acquire());
+    /// ```
+    pub fn release(&self) {
+        self.release_many(1);
+    }
+
+    /// Release `n` permits, waking up the waiting threads.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Semaphore;
+    ///
+    /// let semaphore = Semaphore::new(0);
+    /// semaphore.release_many(2);
+    /// assert_eq!(semaphore.available_permits(), 2);
+    /// ```
+    pub fn release_many(&self, n: usize) {
+        self.permits.fetch_add(n, Release);
+        self.queue.notify_all();
+    }
+
+    /// Return the number of permits currently available.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::Semaphore;
+    ///
+    /// let semaphore = Semaphore::new(2);
+    /// assert!(semaphore.try_acquire());
+    /// assert_eq!(semaphore.available_permits(), 1);
+    /// ```
+    #[must_use]
+    pub fn available_permits(&self) -> usize {
+        self.permits.load(Relaxed)
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl fmt::Debug for Semaphore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Semaphore").field("permits", &self.available_permits()).finish()
+    }
+}
+
+// A queue of threads waiting for a condition.
+#[cfg(feature = "std")]
+struct WaitQueue {
+    lock: RawSpinMutex,
+    // A linked list of waiters, protected by `lock`.
+    head: UnsafeCell<*const Waiter>,
+}
+
+#[cfg(feature = "std")]
+struct Waiter {
+    thread: Cell<Option<std::thread::Thread>>,
+    signaled: AtomicBool,
+    next: Cell<*const Waiter>,
+}
+
+// SAFETY: `head` is only accessed while holding `lock`, and the waiters in the list are only
+// accessed by the thread that removes them from the list.
+#[cfg(feature = "std")]
+unsafe impl Send for WaitQueue {}
+// SAFETY: See above.
+#[cfg(feature = "std")]
+unsafe impl Sync for WaitQueue {}
+
+#[cfg(feature = "std")]
+impl WaitQueue {
+    const fn new() -> Self {
+        Self { lock: RawSpinMutex::new(), head: UnsafeCell::new(ptr::null()) }
+    }
+
+    // Block the current thread until `condition` returns `true`.
+    //
+    // `condition` may be called while holding the spin lock, so it must not block.
+    fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        while !condition() {
+            let node = Waiter {
+                thread: Cell::new(Some(std::thread::current())),
+                signaled: AtomicBool::new(false),
+                next: Cell::new(ptr::null()),
+            };
+            self.lock.lock();
+            // Check again while holding the lock: a notifier updates the state before taking the
+            // lock, so if the condition is still false, the notifier will see this waiter.
+            if condition() {
+                // SAFETY: The lock is held by the current thread.
+                unsafe { self.lock.unlock() }
+                return;
+            }
+            // SAFETY: `head` is protected by the lock, and the node stays alive until `signaled`
+            // is set, since we don't leave this iteration until then.
+            unsafe {
+                node.next.set(*self.head.get());
+                *self.head.get() = &node;
+                self.lock.unlock();
+            }
+            // Spurious wakeups are possible, so check `signaled`.
+            while !node.signaled.load(Acquire) {
+                std::thread::park();
+            }
+        }
+    }
+
+    // Wake up all waiting threads.
+    fn notify_all(&self) {
+        self.lock.lock();
+        // SAFETY: `head` is protected by the lock, which is held by the current thread.
+        let mut queue = unsafe {
+            let queue = *self.head.get();
+            *self.head.get() = ptr::null();
+            self.lock.unlock();
+            queue
+        };
+        while !queue.is_null() {
+            // SAFETY: The waiter stays alive until `signaled` is set, and we must not touch it
+            // after that.
+            unsafe {
+                let next = (*queue).next.get();
+                let thread = (*queue).thread.take().unwrap();
+                (*queue).signaled.store(true, Release);
+                thread.unpark();
+                queue = next;
+            }
+        }
+    }
+}
+
+// Without the `std` feature, waiting threads spin on the condition.
+#[cfg(not(feature = "std"))]
+struct WaitQueue {}
+
+#[cfg(not(feature = "std"))]
+#[allow(clippy::unused_self)]
+impl WaitQueue {
+    const fn new() -> Self {
+        Self {}
+    }
+
+    fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        let mut backoff = Backoff::new();
+        while !condition() {
+            backoff.snooze();
+        }
+    }
+
+    fn notify_all(&self) {}
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    #[test]
+    fn barrier_rounds() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 1000;
+        let barrier = Barrier::new(THREADS);
+        let arrived = AtomicUsize::new(0);
+        let leaders = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    for round in 0..ROUNDS {
+                        arrived.fetch_add(1, Relaxed);
+                        if barrier.wait().is_leader() {
+                            leaders.fetch_add(1, Relaxed);
+                        }
+                        // Every thread arrived at this round before any thread left it, and the
+                        // second wait keeps threads from arriving at the next round before all
+                        // threads checked this.
+                        assert_eq!(arrived.load(Relaxed), (round + 1) * THREADS);
+                        barrier.wait();
+                    }
+                });
+            }
+        });
+        // Exactly one leader per rendezvous.
+        assert_eq!(leaders.load(Relaxed), ROUNDS);
+    }
+
+    #[test]
+    fn latch_concurrent() {
+        const THREADS: usize = 4;
+        let latch = Latch::new(THREADS);
+        let work = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREADS {
+                s.spawn(|| {
+                    work.fetch_add(1, Relaxed);
+                    latch.count_down();
+                });
+                s.spawn(|| {
+                    latch.wait();
+                    // Counting down happens after the work, and waiting returns after counting
+                    // down.
+                    assert_eq!(work.load(Relaxed), THREADS);
+                    assert!(latch.try_wait());
+                });
+            }
+        });
+        assert_eq!(latch.count(), 0);
+    }
+
+    #[test]
+    fn wait_group_reuse() {
+        const THREADS: usize = 4;
+        const ROUNDS: usize = 100;
+        let wg = WaitGroup::new();
+        let work = AtomicUsize::new(0);
+        for round in 1..=ROUNDS {
+            thread::scope(|s| {
+                for _ in 0..THREADS {
+                    wg.add(1);
+                    s.spawn(|| {
+                        work.fetch_add(1, Relaxed);
+                        wg.done();
+                    });
+                }
+                wg.wait();
+                assert_eq!(work.load(Relaxed), round * THREADS);
+                assert_eq!(wg.count(), 0);
+            });
+        }
+    }
+
+    #[test]
+    fn semaphore_limits_concurrency() {
+        const THREADS: usize = 6;
+        const PERMITS: usize = 3;
+        const N: usize = 1000;
+        let sem = Semaphore::new(PERMITS);
+        let active = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for t in 0..THREADS {
+                let (sem, active) = (&sem, &active);
+                s.spawn(move || {
+                    for i in 0..N {
+                        // Take 1 or 2 permits, blocking or not.
+                        let n = 1 + (t + i) % 2;
+                        if i % 3 == 0 {
+                            if !sem.try_acquire_many(n) {
+                                continue;
+                            }
+                        } else {
+                            sem.acquire_many(n);
+                        }
+                        let prev = active.fetch_add(n, AcqRel);
+                        assert!(prev + n <= PERMITS);
+                        thread::yield_now();
+                        active.fetch_sub(n, AcqRel);
+                        sem.release_many(n);
+                    }
+                });
+            }
+        });
+        assert_eq!(sem.available_permits(), PERMITS);
+    }
+}
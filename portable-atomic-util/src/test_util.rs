@@ -0,0 +1,31 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+// Helpers shared by the unit tests of several modules.
+
+use portable_atomic::{AtomicUsize, Ordering::Relaxed};
+
+/// An object that counts how many times it was dropped.
+#[derive(Clone)]
+pub(crate) struct DropCounter<'a>(pub(crate) &'a AtomicUsize);
+
+impl Drop for DropCounter<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_add(1, Relaxed);
+    }
+}
+
+/// Poll `f` to completion, parking the current thread until it is woken.
+#[cfg(not(portable_atomic_no_futures_api))]
+pub(crate) fn block_on<F: core::future::Future>(f: F) -> F::Output {
+    use core::task::{Context, Poll};
+    use std::{pin::pin, thread};
+
+    let waker = crate::waker::current_thread_waker();
+    let mut f = pin!(f);
+    loop {
+        if let Poll::Ready(v) = f.as_mut().poll(&mut Context::from_waker(&waker)) {
+            return v;
+        }
+        thread::park();
+    }
+}
@@ -0,0 +1,435 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+//! A wait-free triple buffer for handing the latest value from one producer to one consumer.
+//!
+//! The producer always has a buffer to write the next value into, and the consumer always has
+//! the most recent complete value to read, without either side ever waiting for the other. Values
+//! that are published while the consumer is busy are overwritten by newer ones, which makes this
+//! a good fit for real-time loops (audio, motor control, rendering) that only care about the
+//! latest frame.
+//!
+//! The handles only use atomic loads and swaps of a single `AtomicU8`, so they can be used from
+//! interrupt handlers, including on single-core targets where portable-atomic provides the swap
+//! with the `critical-section` feature (or `unsafe-assume-single-core` cfg).
+//!
+//! # Examples
+//!
+//! ```
+//! use portable_atomic_util::triple_buffer::TripleBuffer;
+//! use std::thread;
+//!
+//! let mut buffer = TripleBuffer::new([0_i16; 64]);
+//! let (mut input, mut output) = buffer.split();
+//!
+//! thread::scope(|s| {
+//!     s.spawn(move || {
+//!         for i in 1..=100 {
+//!             input.write([i; 64]);
+//!         }
+//!     });
+//!     loop {
+//!         let frame = output.read();
+//!         // Frames are never torn.
+//!         assert!(frame.iter().all(|&x| x == frame[0]));
+//!         if frame[0] == 100 {
+//!             break;
+//!         }
+//!     }
+//! });
+//! ```
+//!
+//! To hand the input and output to interrupt handlers or other `'static` contexts, split a
+//! `&'static mut TripleBuffer`, for example one obtained from a `static` with a one-time
+//! initialization or from `Box::leak`.
+
+// Each of the three buffers is owned by exactly one of the input, the output, and the "back"
+// position at any time. The input and output indices are only accessed by their handles. The back
+// index is stored in `back`, along with `DIRTY` if the back buffer holds a value that was
+// published after the output last took it. Publishing and taking the back buffer are both a single
+// swap with `AcqRel` ordering: `Release` makes the writes to the buffer being handed over visible
+// to the other side, and `Acquire` makes the buffer being taken over safe to access.
+
+use portable_atomic::{
+    AtomicU8,
+    Ordering::{AcqRel, Relaxed},
+};
+
+use crate::utils::CachePadded;
+
+use core::{
+    cell::{Cell, UnsafeCell},
+    fmt,
+    marker::PhantomData,
+};
+
+const INDEX: u8 = 0b11;
+const DIRTY: u8 = 0b100;
+
+/// A triple buffer holding values of type `T`.
+///
+/// Use [`split`](Self::split) to get the [`Input`] and [`Output`] handles.
+pub struct TripleBuffer<T> {
+    buffers: [CachePadded<UnsafeCell<T>>; 3],
+    back: CachePadded<AtomicU8>,
+    input: Cell<u8>,
+    output: Cell<u8>,
+}
+
+/// The input handle of a [`TripleBuffer`].
+pub struct Input<'a, T> {
+    buffer: &'a TripleBuffer<T>,
+    // Not `Sync`: only one thread can publish at a time.
+    _marker: PhantomData<*const ()>,
+}
+
+/// The output handle of a [`TripleBuffer`].
+pub struct Output<'a, T> {
+    buffer: &'a TripleBuffer<T>,
+    // Not `Sync`: only one thread can read at a time.
+    _marker: PhantomData<*const ()>,
+}
+
+// SAFETY: The input only moves values of type `T` into the buffers, and only accesses the buffer
+// it owns.
+unsafe impl<T: Send> Send for Input<'_, T> {}
+// SAFETY: The output only accesses the buffer it owns, and values of type `T` are moved out of it
+// only through `&mut T`.
+unsafe impl<T: Send> Send for Output<'_, T> {}
+
+impl<T: Clone> TripleBuffer<T> {
+    /// Create a new triple buffer whose buffers all hold `value`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::triple_buffer::TripleBuffer;
+    ///
+    /// let mut buffer = TripleBuffer::new(0);
+    /// assert_eq!(*buffer.split().1.read(), 0);
+    /// ```
+    #[must_use]
+    pub fn new(value: T) -> Self {
+        Self::from_buffers(value.clone(), value.clone(), value)
+    }
+}
+
+impl<T> TripleBuffer<T> {
+    /// Create a new triple buffer from the initial values of its three buffers.
+    ///
+    /// The output initially reads `output`; the other two values are overwritten as values are
+    /// published. Unlike [`new`](Self::new), this does not require `T: Clone` and is a
+    /// `const fn`, so it can be used to initialize a `static`.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::triple_buffer::TripleBuffer;
+    ///
+    /// let mut buffer = TripleBuffer::from_buffers(0, 0, 1);
+    /// assert_eq!(*buffer.split().1.read(), 1);
+    /// ```
+    #[must_use]
+    pub const fn from_buffers(input: T, back: T, output: T) -> Self {
+        Self {
+            buffers: [
+                CachePadded::new(UnsafeCell::new(input)),
+                CachePadded::new(UnsafeCell::new(back)),
+                CachePadded::new(UnsafeCell::new(output)),
+            ],
+            back: CachePadded::new(AtomicU8::new(1)),
+            input: Cell::new(0),
+            output: Cell::new(2),
+        }
+    }
+
+    /// Split the triple buffer into its input and output handles.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::triple_buffer::TripleBuffer;
+    ///
+    /// let mut buffer = TripleBuffer::new(0);
+    /// let (mut input, mut output) = buffer.split();
+    /// input.write(1);
+    /// assert_eq!(*output.read(), 1);
+    /// ```
+    pub fn split(&mut self) -> (Input<'_, T>, Output<'_, T>) {
+        (
+            Input { buffer: self, _marker: PhantomData },
+            Output { buffer: self, _marker: PhantomData },
+        )
+    }
+
+    fn buffer(&self, index: u8) -> *mut T {
+        self.buffers[index as usize].get()
+    }
+}
+
+impl<T: Clone + Default> Default for TripleBuffer<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+#[allow(clippy::missing_fields_in_debug)]
+impl<T> fmt::Debug for TripleBuffer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TripleBuffer")
+            .field("updated", &(self.back.load(Relaxed) & DIRTY != 0))
+            .finish()
+    }
+}
+
+impl<T> Input<'_, T> {
+    /// Return a mutable reference to the input buffer.
+    ///
+    /// The input buffer holds an older value that is no longer visible to the output, which can
+    /// be overwritten in place before calling [`publish`](Self::publish). This avoids moving large
+    /// values.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::triple_buffer::TripleBuffer;
+    ///
+    /// let mut buffer = TripleBuffer::new([0_u8; 16]);
+    /// let (mut input, mut output) = buffer.split();
+    /// input.input_buffer()[3] = 1;
+    /// input.publish();
+    /// assert_eq!(output.read()[3], 1);
+    /// ```
+    pub fn input_buffer(&mut self) -> &mut T {
+        // SAFETY: the input buffer is owned by the input, and this is the only input.
+        unsafe { &mut *self.buffer.buffer(self.buffer.input.get()) }
+    }
+
+    /// Make the input buffer visible to the output, and take the back buffer as the new input
+    /// buffer.
+    ///
+    /// Return `true` if this overwrote a value that was published but never taken by the output.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::triple_buffer::TripleBuffer;
+    ///
+    /// let mut buffer = TripleBuffer::new(0);
+    /// let (mut input, mut output) = buffer.split();
+    /// *input.input_buffer() = 1;
+    /// assert!(!input.publish());
+    /// *input.input_buffer() = 2;
+    /// assert!(input.publish());
+    /// assert_eq!(*output.read(), 2);
+    /// ```
+    pub fn publish(&mut self) -> bool {
+        let back = self.buffer.back.swap(self.buffer.input.get() | DIRTY, AcqRel);
+        self.buffer.input.set(back & INDEX);
+        back & DIRTY != 0
+    }
+
+    /// Write `value` to the input buffer and publish it.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::triple_buffer::TripleBuffer;
+    ///
+    /// let mut buffer = TripleBuffer::new(0);
+    /// let (mut input, mut output) = buffer.split();
+    /// input.write(1);
+    /// input.write(2);
+    /// assert_eq!(*output.read(), 2);
+    /// ```
+    pub fn write(&mut self, value: T) {
+        *self.input_buffer() = value;
+        self.publish();
+    }
+
+    /// Return `true` if the output has taken the last published value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::triple_buffer::TripleBuffer;
+    ///
+    /// let mut buffer = TripleBuffer::new(0);
+    /// let (mut input, mut output) = buffer.split();
+    /// input.write(1);
+    /// assert!(!input.consumed());
+    /// output.read();
+    /// assert!(input.consumed());
+    /// ```
+    #[must_use]
+    pub fn consumed(&self) -> bool {
+        self.buffer.back.load(Relaxed) & DIRTY == 0
+    }
+}
+
+impl<T> Output<'_, T> {
+    /// Take the most recently published value, if any, and return a reference to it.
+    ///
+    /// If nothing was published since the last call, this returns the same value again.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::triple_buffer::TripleBuffer;
+    ///
+    /// let mut buffer = TripleBuffer::new(0);
+    /// let (mut input, mut output) = buffer.split();
+    /// assert_eq!(*output.read(), 0);
+    /// input.write(1);
+    /// assert_eq!(*output.read(), 1);
+    /// assert_eq!(*output.read(), 1);
+    /// ```
+    pub fn read(&mut self) -> &T {
+        self.update();
+        self.output_buffer()
+    }
+
+    /// Take the most recently published value as the new output buffer, if there is one.
+    ///
+    /// Return `true` if the output buffer was updated.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::triple_buffer::TripleBuffer;
+    ///
+    /// let mut buffer = TripleBuffer::new(0);
+    /// let (mut input, mut output) = buffer.split();
+    /// assert!(!output.update());
+    /// input.write(1);
+    /// assert!(output.update());
+    /// assert_eq!(*output.output_buffer(), 1);
+    /// ```
+    pub fn update(&mut self) -> bool {
+        if self.buffer.back.load(Relaxed) & DIRTY == 0 {
+            return false;
+        }
+        let back = self.buffer.back.swap(self.buffer.output.get(), AcqRel);
+        self.buffer.output.set(back & INDEX);
+        true
+    }
+
+    /// Return a mutable reference to the output buffer, without taking a newly published value.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::triple_buffer::TripleBuffer;
+    ///
+    /// let mut buffer = TripleBuffer::new(vec![1, 2]);
+    /// let (_input, mut output) = buffer.split();
+    /// let frame = std::mem::take(output.output_buffer());
+    /// assert_eq!(frame, [1, 2]);
+    /// ```
+    pub fn output_buffer(&mut self) -> &mut T {
+        // SAFETY: the output buffer is owned by the output, and this is the only output.
+        unsafe { &mut *self.buffer.buffer(self.buffer.output.get()) }
+    }
+
+    /// Return `true` if a value was published since the output last took one.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::triple_buffer::TripleBuffer;
+    ///
+    /// let mut buffer = TripleBuffer::new(0);
+    /// let (mut input, output) = buffer.split();
+    /// assert!(!output.updated());
+    /// input.write(1);
+    /// assert!(output.updated());
+    /// ```
+    #[must_use]
+    pub fn updated(&self) -> bool {
+        self.buffer.back.load(Relaxed) & DIRTY != 0
+    }
+}
+
+impl<T> fmt::Debug for Input<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Input").field("consumed", &self.consumed()).finish()
+    }
+}
+
+impl<T> fmt::Debug for Output<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Output").field("updated", &self.updated()).finish()
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use crate::test_util::DropCounter;
+
+    use portable_atomic::AtomicUsize;
+    use std::{thread, vec};
+
+    #[test]
+    fn concurrent_write_read() {
+        const N: usize = 100_000;
+        let mut buffer = TripleBuffer::new(vec![0; 16]);
+        let (mut input, mut output) = buffer.split();
+        let (overwritten, taken) = thread::scope(|s| {
+            let writer = s.spawn(move || {
+                let mut overwritten = 0;
+                for i in 1..=N {
+                    let frame = input.input_buffer();
+                    for v in frame.iter_mut() {
+                        *v = i;
+                    }
+                    if input.publish() {
+                        overwritten += 1;
+                    }
+                }
+                overwritten
+            });
+            let mut taken = 0;
+            let mut last = 0;
+            while last != N {
+                if output.update() {
+                    taken += 1;
+                }
+                let frame = output.output_buffer();
+                // Frames are never torn, and newer frames are never followed by older ones.
+                assert!(frame.iter().all(|&v| v == frame[0]));
+                assert!(frame[0] >= last);
+                last = frame[0];
+            }
+            (writer.join().unwrap(), taken)
+        });
+        // Every published value was either taken by the output or overwritten by a newer one.
+        assert_eq!(overwritten + taken, N);
+    }
+
+    #[test]
+    fn drop_buffers() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        const N: usize = 10_000;
+        let mut buffer = TripleBuffer::from_buffers(
+            DropCounter(&DROPS),
+            DropCounter(&DROPS),
+            DropCounter(&DROPS),
+        );
+        let (mut input, mut output) = buffer.split();
+        thread::scope(|s| {
+            s.spawn(move || {
+                for _ in 0..N {
+                    input.write(DropCounter(&DROPS));
+                }
+            });
+            for _ in 0..N {
+                let _ = output.read();
+            }
+        });
+        // Each write dropped the value it replaced.
+        assert_eq!(DROPS.load(Relaxed), N);
+        drop(buffer);
+        assert_eq!(DROPS.load(Relaxed), N + 3);
+    }
+}
@@ -0,0 +1,368 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+#![cfg_attr(
+    not(any(all(feature = "alloc", not(portable_atomic_no_alloc)), feature = "std")),
+    allow(dead_code, unused_macros)
+)]
+
+use core::ops;
+
+// #[doc = concat!(...)] requires Rust 1.54
+macro_rules! doc_comment {
+    ($doc:expr, $($tt:tt)*) => {
+        #[doc = $doc]
+        $($tt)*
+    };
+}
+
+pub(crate) const MAX_REFCOUNT: usize = (core::isize::MAX as usize) - 1;
+
+#[cfg(not(portable_atomic_sanitize_thread))]
+macro_rules! acquire {
+    ($x:expr) => {{
+        portable_atomic::fence(portable_atomic::Ordering::Acquire);
+    }};
+}
+
+#[cfg(portable_atomic_sanitize_thread)]
+macro_rules! acquire {
+    ($x:expr) => {{
+        ($x).load(portable_atomic::Ordering::Acquire);
+    }};
+}
+
+// Whether `portable_atomic::AtomicU128` and `portable_atomic::AtomicU64` exist.
+//
+// This mirrors the conditions of portable-atomic's own (non-public) `cfg_has_atomic_*` macros.
+// With the `fallback` feature, portable-atomic provides both types on every target with atomic
+// CAS, and uses native instructions where the CPU supports them (e.g., it detects `cmpxchg16b`
+// at run time on x86_64). Without it, only the natively supported types exist.
+#[cfg_attr(
+    not(feature = "fallback"),
+    cfg(any(
+        all(target_arch = "aarch64", not(portable_atomic_no_asm)),
+        all(
+            target_arch = "x86_64",
+            not(portable_atomic_no_asm),
+            any(target_feature = "cmpxchg16b", portable_atomic_target_feature = "cmpxchg16b"),
+        ),
+        all(
+            target_arch = "powerpc64",
+            portable_atomic_unstable_asm_experimental_arch,
+            any(
+                target_feature = "quadword-atomics",
+                portable_atomic_target_feature = "quadword-atomics"
+            ),
+        ),
+        all(target_arch = "s390x", portable_atomic_unstable_asm_experimental_arch),
+    ))
+)]
+#[allow(unused_macros)]
+#[macro_use]
+mod atomic_128_macros {
+    macro_rules! cfg_has_atomic_128 {
+        ($($tt:tt)*) => {
+            $($tt)*
+        };
+    }
+    macro_rules! cfg_no_atomic_128 {
+        ($($tt:tt)*) => {};
+    }
+}
+#[cfg_attr(
+    not(feature = "fallback"),
+    cfg(not(any(
+        all(target_arch = "aarch64", not(portable_atomic_no_asm)),
+        all(
+            target_arch = "x86_64",
+            not(portable_atomic_no_asm),
+            any(target_feature = "cmpxchg16b", portable_atomic_target_feature = "cmpxchg16b"),
+        ),
+        all(
+            target_arch = "powerpc64",
+            portable_atomic_unstable_asm_experimental_arch,
+            any(
+                target_feature = "quadword-atomics",
+                portable_atomic_target_feature = "quadword-atomics"
+            ),
+        ),
+        all(target_arch = "s390x", portable_atomic_unstable_asm_experimental_arch),
+    )))
+)]
+#[cfg_attr(feature = "fallback", cfg(any()))]
+#[allow(unused_macros)]
+#[macro_use]
+mod atomic_128_macros {
+    macro_rules! cfg_has_atomic_128 {
+        ($($tt:tt)*) => {};
+    }
+    macro_rules! cfg_no_atomic_128 {
+        ($($tt:tt)*) => {
+            $($tt)*
+        };
+    }
+}
+#[cfg_attr(
+    all(not(feature = "fallback"), portable_atomic_no_cfg_target_has_atomic),
+    cfg(not(any(target_pointer_width = "16", target_pointer_width = "32")))
+)]
+#[cfg_attr(
+    all(not(feature = "fallback"), not(portable_atomic_no_cfg_target_has_atomic)),
+    cfg(target_has_atomic = "64")
+)]
+#[allow(unused_macros)]
+#[macro_use]
+mod atomic_64_macros {
+    macro_rules! cfg_has_atomic_64 {
+        ($($tt:tt)*) => {
+            $($tt)*
+        };
+    }
+    macro_rules! cfg_no_atomic_64 {
+        ($($tt:tt)*) => {};
+    }
+}
+#[cfg_attr(
+    all(not(feature = "fallback"), portable_atomic_no_cfg_target_has_atomic),
+    cfg(any(target_pointer_width = "16", target_pointer_width = "32"))
+)]
+#[cfg_attr(
+    all(not(feature = "fallback"), not(portable_atomic_no_cfg_target_has_atomic)),
+    cfg(not(target_has_atomic = "64"))
+)]
+#[cfg_attr(feature = "fallback", cfg(any()))]
+#[allow(unused_macros)]
+#[macro_use]
+mod atomic_64_macros {
+    macro_rules! cfg_has_atomic_64 {
+        ($($tt:tt)*) => {};
+    }
+    macro_rules! cfg_no_atomic_64 {
+        ($($tt:tt)*) => {
+            $($tt)*
+        };
+    }
+}
+
+pub(crate) fn abort() -> ! {
+    struct Abort;
+
+    impl Drop for Abort {
+        fn drop(&mut self) {
+            panic!();
+        }
+    }
+
+    let _abort = Abort;
+
+    panic!("abort")
+}
+
+/// Emulate strict provenance.
+///
+/// Once strict_provenance is stable, migrate to the standard library's APIs.
+#[allow(
+    clippy::cast_possible_wrap,
+    clippy::transmutes_expressible_as_ptr_casts,
+    clippy::useless_transmute
+)]
+pub(crate) mod strict {
+    /// Create a new, invalid pointer from an address.
+    #[inline]
+    #[must_use]
+    pub(crate) const fn invalid<T>(addr: usize) -> *mut T {
+        // SAFETY: Every integer is a valid pointer as long as it is not dereferenced.
+        #[cfg(miri)]
+        unsafe {
+            core::mem::transmute(addr)
+        }
+        // const transmute requires Rust 1.56.
+        #[cfg(not(miri))]
+        {
+            addr as *mut T
+        }
+    }
+
+    /// Create a new pointer with the metadata of `other`.
+    #[inline]
+    #[must_use]
+    pub(crate) fn with_metadata_of<T, U: ?Sized>(this: *mut T, mut other: *mut U) -> *mut U {
+        let target = &mut other as *mut *mut U as *mut *mut u8;
+
+        // SAFETY: In case of a thin pointer, this operations is identical
+        // to a simple assignment. In case of a fat pointer, with the current
+        // fat pointer layout implementation, the first field of such a
+        // pointer is always the data pointer, which is likewise assigned.
+        unsafe { *target = this as *mut u8 };
+        other
+    }
+
+    /// Replace the address portion of this pointer with a new address.
+    #[inline]
+    #[must_use]
+    pub(crate) fn with_addr<T>(ptr: *mut T, addr: usize) -> *mut T {
+        // FIXME(strict_provenance_magic): I am magic and should be a compiler intrinsic.
+        //
+        // In the mean-time, this operation is defined to be "as if" it was
+        // a wrapping_offset, so we can emulate it as such. This should properly
+        // restore pointer provenance even under today's compiler.
+        let self_addr = ptr as usize as isize;
+        let dest_addr = addr as isize;
+        let offset = dest_addr.wrapping_sub(self_addr);
+
+        // This is the canonical desugaring of this operation.
+        (ptr as *mut u8).wrapping_offset(offset) as *mut T
+    }
+
+    /// Run an operation of some kind on a pointer.
+    #[inline]
+    #[must_use]
+    pub(crate) fn map_addr<T>(ptr: *mut T, f: impl FnOnce(usize) -> usize) -> *mut T {
+        with_addr(ptr, f(ptr as usize))
+    }
+}
+
+// Adapted from https://github.com/crossbeam-rs/crossbeam/blob/9384f1eb2b356364e201ad38545e03c837d55f3a/crossbeam-utils/src/cache_padded.rs.
+// This is the same as portable-atomic's src/imp/fallback/utils.rs.
+/// Pads and aligns a value to the length of a cache line.
+// Starting from Intel's Sandy Bridge, spatial prefetcher is now pulling pairs of 64-byte cache
+// lines at a time, so we have to align to 128 bytes rather than 64.
+//
+// Sources:
+// - https://www.intel.com/content/dam/www/public/us/en/documents/manuals/64-ia-32-architectures-optimization-manual.pdf
+// - https://github.com/facebook/folly/blob/1b5288e6eea6df074758f877c849b6e73bbb9fbb/folly/lang/Align.h#L107
+//
+// ARM's big.LITTLE architecture has asymmetric cores and "big" cores have 128-byte cache line size.
+//
+// Sources:
+// - https://www.mono-project.com/news/2016/09/12/arm64-icache/
+//
+// powerpc64 has 128-byte cache line size.
+//
+// Sources:
+// - https://github.com/golang/go/blob/3dd58676054223962cd915bb0934d1f9f489d4d2/src/internal/cpu/cpu_ppc64x.go#L9
+// - https://github.com/torvalds/linux/blob/3516bd729358a2a9b090c1905bd2a3fa926e24c6/arch/powerpc/include/asm/cache.h#L26
+#[cfg_attr(
+    any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "powerpc64"),
+    repr(align(128))
+)]
+// arm, mips, mips64, sparc, and hexagon have 32-byte cache line size.
+//
+// Sources:
+// - https://github.com/golang/go/blob/3dd58676054223962cd915bb0934d1f9f489d4d2/src/internal/cpu/cpu_arm.go#L7
+// - https://github.com/golang/go/blob/3dd58676054223962cd915bb0934d1f9f489d4d2/src/internal/cpu/cpu_mips.go#L7
+// - https://github.com/golang/go/blob/3dd58676054223962cd915bb0934d1f9f489d4d2/src/internal/cpu/cpu_mipsle.go#L7
+// - https://github.com/golang/go/blob/3dd58676054223962cd915bb0934d1f9f489d4d2/src/internal/cpu/cpu_mips64x.go#L9
+// - https://github.com/torvalds/linux/blob/3516bd729358a2a9b090c1905bd2a3fa926e24c6/arch/sparc/include/asm/cache.h#L17
+// - https://github.com/torvalds/linux/blob/3516bd729358a2a9b090c1905bd2a3fa926e24c6/arch/hexagon/include/asm/cache.h#L12
+#[cfg_attr(
+    any(
+        target_arch = "arm",
+        target_arch = "mips",
+        target_arch = "mips32r6",
+        target_arch = "mips64",
+        target_arch = "mips64r6",
+        target_arch = "sparc",
+        target_arch = "hexagon",
+    ),
+    repr(align(32))
+)]
+// m68k has 16-byte cache line size.
+//
+// Sources:
+// - https://github.com/torvalds/linux/blob/3516bd729358a2a9b090c1905bd2a3fa926e24c6/arch/m68k/include/asm/cache.h#L9
+#[cfg_attr(target_arch = "m68k", repr(align(16)))]
+// s390x has 256-byte cache line size.
+//
+// Sources:
+// - https://github.com/golang/go/blob/3dd58676054223962cd915bb0934d1f9f489d4d2/src/internal/cpu/cpu_s390x.go#L7
+// - https://github.com/torvalds/linux/blob/3516bd729358a2a9b090c1905bd2a3fa926e24c6/arch/s390/include/asm/cache.h#L13
+#[cfg_attr(target_arch = "s390x", repr(align(256)))]
+// x86, wasm, riscv, and sparc64 have 64-byte cache line size.
+//
+// Sources:
+// - https://github.com/golang/go/blob/dda2991c2ea0c5914714469c4defc2562a907230/src/internal/cpu/cpu_x86.go#L9
+// - https://github.com/golang/go/blob/3dd58676054223962cd915bb0934d1f9f489d4d2/src/internal/cpu/cpu_wasm.go#L7
+// - https://github.com/torvalds/linux/blob/3516bd729358a2a9b090c1905bd2a3fa926e24c6/arch/riscv/include/asm/cache.h#L10
+// - https://github.com/torvalds/linux/blob/3516bd729358a2a9b090c1905bd2a3fa926e24c6/arch/sparc/include/asm/cache.h#L19
+//
+// All others are assumed to have 64-byte cache line size.
+#[cfg_attr(
+    not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "powerpc64",
+        target_arch = "arm",
+        target_arch = "mips",
+        target_arch = "mips32r6",
+        target_arch = "mips64",
+        target_arch = "mips64r6",
+        target_arch = "sparc",
+        target_arch = "hexagon",
+        target_arch = "m68k",
+        target_arch = "s390x",
+    )),
+    repr(align(64))
+)]
+pub(crate) struct CachePadded<T> {
+    value: T,
+}
+
+impl<T> CachePadded<T> {
+    #[inline]
+    pub(crate) const fn new(value: T) -> Self {
+        Self { value }
+    }
+}
+
+impl<T> ops::Deref for CachePadded<T> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T> ops::DerefMut for CachePadded<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+// Adapted from https://github.com/crossbeam-rs/crossbeam/blob/crossbeam-utils-0.8.7/crossbeam-utils/src/backoff.rs.
+// Adjusted to reduce spinning.
+// This is the same as portable-atomic's src/imp/fallback/utils.rs.
+/// Performs exponential backoff in spin loops.
+pub(crate) struct Backoff {
+    step: u32,
+}
+
+// https://github.com/oneapi-src/oneTBB/blob/v2021.5.0/include/oneapi/tbb/detail/_utils.h#L46-L48
+const SPIN_LIMIT: u32 = 4;
+
+impl Backoff {
+    #[inline]
+    pub(crate) const fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    #[inline]
+    pub(crate) fn snooze(&mut self) {
+        if self.step <= SPIN_LIMIT {
+            for _ in 0..1 << self.step {
+                portable_atomic::hint::spin_loop();
+            }
+            self.step += 1;
+        } else {
+            #[cfg(not(feature = "std"))]
+            for _ in 0..1 << self.step {
+                portable_atomic::hint::spin_loop();
+            }
+
+            #[cfg(feature = "std")]
+            std::thread::yield_now();
+        }
+    }
+}
@@ -0,0 +1,343 @@
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+// Based on futures-core's AtomicWaker:
+// https://github.com/rust-lang/futures-rs/blob/0.3.30/futures-core/src/task/__internal/atomic_waker.rs
+//
+// `WAITING` means that the slot is idle. `REGISTERING` is set while a waker is being stored, and
+// `WAKING` is set while the waker is being taken out. If `WAKING` is set while a waker is being
+// registered, the registering side wakes the new waker itself.
+
+use portable_atomic::{
+    AtomicUsize,
+    Ordering::{AcqRel, Acquire, Release},
+};
+
+use core::{cell::UnsafeCell, fmt, task::Waker};
+
+const WAITING: usize = 0;
+const REGISTERING: usize = 1;
+const WAKING: usize = 2;
+
+/// A synchronization primitive for task wakeup.
+///
+/// `AtomicWaker` stores the [`Waker`] of a task that is waiting for an event, so that the event
+/// source (another thread, or an interrupt handler) can wake it. This is the same as
+/// [`futures::task::AtomicWaker`], but it only uses the atomic operations provided by
+/// portable-atomic, so it also works on targets without native atomic CAS when portable-atomic's
+/// `critical-section` feature (or `unsafe-assume-single-core` cfg) is enabled.
+///
+/// The consumer calls [`register`](Self::register) with the waker of the current task before
+/// checking whether the event has happened, and the producer calls [`wake`](Self::wake) after
+/// making the event visible. [`wake`](Self::wake) never blocks, so it can be called from an
+/// interrupt handler.
+///
+/// Only one waker is stored at a time; registering a new waker replaces the previous one.
+/// Concurrent calls to `register` are not a memory safety issue, but one of the wakers may be
+/// dropped without being woken.
+///
+/// [`futures::task::AtomicWaker`]: https://docs.rs/futures/latest/futures/task/struct.AtomicWaker.html
+///
+/// # Examples
+///
+/// ```
+/// use portable_atomic::{AtomicBool, Ordering};
+/// use portable_atomic_util::AtomicWaker;
+/// use std::{
+///     future::Future,
+///     pin::Pin,
+///     task::{Context, Poll},
+/// };
+///
+/// struct Flag {
+///     waker: AtomicWaker,
+///     set: AtomicBool,
+/// }
+///
+/// impl Flag {
+///     fn signal(&self) {
+///         self.set.store(true, Ordering::Release);
+///         self.waker.wake();
+///     }
+/// }
+///
+/// struct Wait<'a>(&'a Flag);
+///
+/// impl Future for Wait<'_> {
+///     type Output = ();
+///
+///     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+///         // Quick check to avoid registration if already done.
+///         if self.0.set.load(Ordering::Acquire) {
+///             return Poll::Ready(());
+///         }
+///         self.0.waker.register(cx.waker());
+///         // Check again after registering, in case `signal` ran in between.
+///         if self.0.set.load(Ordering::Acquire) {
+///             Poll::Ready(())
+///         } else {
+///             Poll::Pending
+///         }
+///     }
+/// }
+/// ```
+pub struct AtomicWaker {
+    state: AtomicUsize,
+    waker: UnsafeCell<Option<Waker>>,
+}
+
+// SAFETY: `waker` is only accessed by the thread that moved the state out of `WAITING`.
+unsafe impl Send for AtomicWaker {}
+// SAFETY: See above.
+unsafe impl Sync for AtomicWaker {}
+
+impl AtomicWaker {
+    /// Create a new `AtomicWaker` with no registered waker.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::AtomicWaker;
+    ///
+    /// static WAKER: AtomicWaker = AtomicWaker::new();
+    /// ```
+    #[must_use]
+    pub const fn new() -> Self {
+        Self { state: AtomicUsize::new(WAITING), waker: UnsafeCell::new(None) }
+    }
+
+    /// Register `waker` to be woken by the next call to [`wake`](Self::wake).
+    ///
+    /// If a waker is already registered and would wake the same task, it is kept; otherwise it
+    /// is replaced. If [`wake`](Self::wake) is called concurrently, `waker` is woken immediately.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic::{AtomicBool, Ordering};
+    /// use portable_atomic_util::AtomicWaker;
+    /// use std::{
+    ///     future::Future,
+    ///     pin::Pin,
+    ///     task::{Context, Poll},
+    /// };
+    ///
+    /// struct Wait<'a>(&'a AtomicWaker, &'a AtomicBool);
+    ///
+    /// impl Future for Wait<'_> {
+    ///     type Output = ();
+    ///
+    ///     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+    ///         self.0.register(cx.waker());
+    ///         if self.1.load(Ordering::Acquire) {
+    ///             Poll::Ready(())
+    ///         } else {
+    ///             Poll::Pending
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn register(&self, waker: &Waker) {
+        match self.state.compare_exchange(WAITING, REGISTERING, Acquire, Acquire) {
+            Ok(_) => {
+                // SAFETY: We moved the state from `WAITING` to `REGISTERING`, so we have
+                // exclusive access to `waker` until we release it.
+                let slot = unsafe { &mut *self.waker.get() };
+                match slot {
+                    Some(old) if old.will_wake(waker) => {}
+                    _ => *slot = Some(waker.clone()),
+                }
+                if let Err(actual) =
+                    self.state.compare_exchange(REGISTERING, WAITING, AcqRel, Acquire)
+                {
+                    // A concurrent `wake` saw `REGISTERING` and left the waker to us.
+                    debug_assert_eq!(actual, REGISTERING | WAKING);
+                    let waker = slot.take();
+                    self.state.swap(WAITING, AcqRel);
+                    if let Some(waker) = waker {
+                        waker.wake();
+                    }
+                }
+            }
+            Err(WAKING) => {
+                // A concurrent `wake` is taking the old waker out; make sure this task is polled
+                // again.
+                waker.wake_by_ref();
+            }
+            Err(_) => {
+                // `REGISTERING` or `REGISTERING | WAKING`: another `register` is in progress.
+                // One of the wakers is dropped; this is documented.
+            }
+        }
+    }
+
+    /// Wake the registered waker, if any, and remove it.
+    ///
+    /// This never blocks, so it can be called from an interrupt handler.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::AtomicWaker;
+    ///
+    /// let waker = AtomicWaker::new();
+    /// // Nothing is registered, so this does nothing.
+    /// waker.wake();
+    /// ```
+    pub fn wake(&self) {
+        if let Some(waker) = self.take() {
+            waker.wake();
+        }
+    }
+
+    /// Remove the registered waker, if any, and return it without waking it.
+    ///
+    /// Returns `None` if no waker is registered, or if a concurrent call to
+    /// [`register`](Self::register) or [`wake`](Self::wake) is in progress (in which case that
+    /// call wakes the task).
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use portable_atomic_util::AtomicWaker;
+    ///
+    /// let waker = AtomicWaker::new();
+    /// assert!(waker.take().is_none());
+    /// ```
+    #[must_use]
+    pub fn take(&self) -> Option<Waker> {
+        match self.state.fetch_or(WAKING, AcqRel) {
+            WAITING => {
+                // SAFETY: We moved the state from `WAITING` to `WAKING`, so we have exclusive
+                // access to `waker` until we release it.
+                let waker = unsafe { (*self.waker.get()).take() };
+                self.state.fetch_and(!WAKING, Release);
+                waker
+            }
+            // The consumer is registering and will wake the new waker itself, or another
+            // producer is already waking it.
+            _ => None,
+        }
+    }
+}
+
+impl Default for AtomicWaker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for AtomicWaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AtomicWaker").finish()
+    }
+}
+
+/// Returns a waker that unparks the current thread.
+#[cfg(feature = "std")]
+pub(crate) fn current_thread_waker() -> Waker {
+    use crate::Arc;
+    use core::{
+        mem::ManuallyDrop,
+        task::{RawWaker, RawWakerVTable},
+    };
+    use std::thread::{self, Thread};
+
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop_waker);
+
+    fn raw(thread: Arc<Thread>) -> RawWaker {
+        RawWaker::new(Arc::into_raw(thread) as *const (), &VTABLE)
+    }
+    unsafe fn clone(data: *const ()) -> RawWaker {
+        // SAFETY: `data` was created by `raw`, and we do not drop the borrowed reference.
+        let thread = ManuallyDrop::new(unsafe { Arc::from_raw(data as *const Thread) });
+        raw(Arc::clone(&thread))
+    }
+    unsafe fn wake(data: *const ()) {
+        // SAFETY: `data` was created by `raw`, and `wake` consumes the reference.
+        let thread = unsafe { Arc::from_raw(data as *const Thread) };
+        thread.unpark();
+    }
+    unsafe fn wake_by_ref(data: *const ()) {
+        // SAFETY: `data` was created by `raw`, and we do not drop the borrowed reference.
+        let thread = ManuallyDrop::new(unsafe { Arc::from_raw(data as *const Thread) });
+        thread.unpark();
+    }
+    unsafe fn drop_waker(data: *const ()) {
+        // SAFETY: `data` was created by `raw`, and `drop` consumes the reference.
+        drop(unsafe { Arc::from_raw(data as *const Thread) });
+    }
+
+    // SAFETY: The vtable functions above uphold the `RawWaker` contract: the data pointer is an
+    // `Arc<Thread>`, which is `Send + Sync`.
+    unsafe { Waker::from_raw(raw(Arc::new(thread::current()))) }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::{sync::Arc, task::Wake, thread};
+
+    use portable_atomic::Ordering::Relaxed;
+
+    // A waker that counts how many times it was woken.
+    struct CountWaker(AtomicUsize);
+    impl Wake for CountWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Relaxed);
+        }
+    }
+
+    #[test]
+    fn drop_wakers() {
+        let counter = Arc::new(CountWaker(AtomicUsize::new(0)));
+        let other = Arc::new(CountWaker(AtomicUsize::new(0)));
+        let waker = AtomicWaker::new();
+        waker.register(&Waker::from(Arc::clone(&counter)));
+        assert_eq!(Arc::strong_count(&counter), 2);
+        // Registering a waker of another task replaces and drops the old one.
+        waker.register(&Waker::from(Arc::clone(&other)));
+        assert_eq!(Arc::strong_count(&counter), 1);
+        assert_eq!(Arc::strong_count(&other), 2);
+        waker.wake();
+        assert_eq!(other.0.load(Relaxed), 1);
+        assert_eq!(Arc::strong_count(&other), 1);
+        // Nothing is registered anymore.
+        waker.wake();
+        assert_eq!(other.0.load(Relaxed), 1);
+
+        waker.register(&Waker::from(Arc::clone(&counter)));
+        drop(waker.take());
+        assert_eq!(Arc::strong_count(&counter), 1);
+        assert_eq!(counter.0.load(Relaxed), 0);
+        waker.register(&Waker::from(Arc::clone(&counter)));
+        drop(waker);
+        assert_eq!(Arc::strong_count(&counter), 1);
+    }
+
+    #[test]
+    fn concurrent_wake() {
+        // The consumer waits for each increment of `value`; a lost wake-up hangs the test.
+        const N: usize = 10_000;
+        let waker = AtomicWaker::new();
+        let value = AtomicUsize::new(0);
+        thread::scope(|s| {
+            s.spawn(|| {
+                for _ in 0..N {
+                    value.fetch_add(1, Release);
+                    waker.wake();
+                }
+            });
+            let thread_waker = current_thread_waker();
+            let mut seen = 0;
+            while seen < N {
+                waker.register(&thread_waker);
+                let v = value.load(Acquire);
+                if v == seen {
+                    thread::park();
+                }
+                seen = v;
+            }
+        });
+    }
+}
@@ -348,6 +348,9 @@ build() {
         if is_no_std "${target}"; then
             local build_util_with_critical_section=''
             if [[ -z "${has_atomic_cas}" ]]; then
+                # portable-atomic-util's spsc only requires atomic load/store.
+                RUSTFLAGS="${target_rustflags}" \
+                    x_cargo "${args[@]}" --no-default-features --manifest-path portable-atomic-util/Cargo.toml "$@"
                 case "${target}" in
                     thumbv[4-5]t* | armv[4-5]t* | thumbv6m* | riscv??i-*-none* | riscv??im-*-none* | riscv??imc-*-none*)
                         target_rustflags+=" --cfg portable_atomic_unsafe_assume_single_core"